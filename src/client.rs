@@ -0,0 +1,783 @@
+// Builders for every PledgeInstruction, for off-chain clients. Each one
+// derives the program's PDAs itself and lists the accounts in the order the
+// instruction documents, so callers only supply the keys the program cannot
+// derive. Behind the `client` feature, which also drops the entrypoint.
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_program,
+};
+use std::fmt;
+
+use crate::{
+    claim_fee_address, find_config_address, find_group_address, find_pledge_vault_address, find_receipt_address, find_snapshots_address,
+    find_solhit_vault_address, find_treasury_address, find_user_state_address, find_whitelist_address,
+    ContractConfig, PledgeError, PledgeInstruction, UserState, CONFIG_DISCRIMINATOR, USER_STATE_DISCRIMINATOR,
+    USER_STATE_DISCRIMINATOR_VERSION, USERS_UNCOUNTED,
+};
+
+// Account data as an RPC node returns it, decoded the way the program reads
+// it: any layout version, with or without the discriminator, and ignoring
+// whatever padding follows the state. Data of another kind, or too short for
+// its version, is InvalidAccountType. Both are caught here, before unpack, so
+// that off-chain callers get no msg! output for them.
+impl UserState {
+    pub fn try_from_account_data(data: &[u8]) -> Result<Self, PledgeError> {
+        let len = match data.first() {
+            _ if data.starts_with(&USER_STATE_DISCRIMINATOR) => match data.get(USER_STATE_DISCRIMINATOR.len()) {
+                Some(&version) if version >= USER_STATE_DISCRIMINATOR_VERSION => Self::required_len(version),
+                _ => None,
+            },
+            Some(&version) if version < USER_STATE_DISCRIMINATOR_VERSION => Self::required_len(version),
+            _ => None,
+        };
+        if len.is_none_or(|len| data.len() < len) {
+            return Err(PledgeError::InvalidAccountType);
+        }
+        Self::unpack(data).map_err(decode_error)
+    }
+}
+
+impl ContractConfig {
+    pub fn try_from_account_data(data: &[u8]) -> Result<Self, PledgeError> {
+        if !data.starts_with(&CONFIG_DISCRIMINATOR) && !matches!(data.first(), Some(0 | 1)) {
+            return Err(PledgeError::InvalidAccountType);
+        }
+        Self::unpack(data).map_err(decode_error)
+    }
+}
+
+fn decode_error(error: ProgramError) -> PledgeError {
+    match error {
+        ProgramError::Custom(code) => PledgeError::from_code(code).unwrap_or(PledgeError::InvalidAccountType),
+        _ => PledgeError::InvalidAccountType,
+    }
+}
+
+// One line summaries for logs and command line tools; serde, behind its own
+// feature, gives every field.
+impl fmt::Display for UserState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "user {}: {} pledge tokens locked in {} lots until {}, {} Solheist unclaimed, {} claimed",
+            self.owner,
+            self.locked_pledge_tokens,
+            self.lot_count,
+            self.vesting_end_time,
+            self.solhit_rewards,
+            self.total_claimed
+        )
+    }
+}
+
+impl fmt::Display for ContractConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "config v{}: {} of {} pledge tokens sold, {} Solheist claimed, ",
+            self.config_version, self.total_pledge_sold, self.total_pledge_supply, self.global_solhit_claimed
+        )?;
+        if self.initialized_users == USERS_UNCOUNTED {
+            f.write_str("users uncounted")?;
+        } else {
+            write!(f, "{} users", self.initialized_users)?;
+        }
+        write!(f, ", admin {}", self.admin)?;
+        if self.paused {
+            f.write_str(", paused")?;
+        }
+        if self.sale_finalized {
+            f.write_str(", finalized")?;
+        }
+        Ok(())
+    }
+}
+
+fn instruction(program_id: &Pubkey, instruction: PledgeInstruction, accounts: Vec<AccountMeta>) -> Instruction {
+    Instruction::new_with_bytes(*program_id, &instruction.pack(), accounts)
+}
+
+fn user_state(program_id: &Pubkey, wallet: &Pubkey) -> Pubkey {
+    find_user_state_address(program_id, wallet).0
+}
+
+fn config(program_id: &Pubkey) -> Pubkey {
+    find_config_address(program_id).0
+}
+
+// The wallet's user state and the config, both read only, the accounts every
+// per-user view takes.
+fn view_instruction(program_id: &Pubkey, wallet: &Pubkey, pledge_instruction: PledgeInstruction) -> Instruction {
+    instruction(
+        program_id,
+        pledge_instruction,
+        vec![
+            AccountMeta::new_readonly(user_state(program_id, wallet), false),
+            AccountMeta::new_readonly(config(program_id), false),
+        ],
+    )
+}
+
+// The config followed by the admin, the accounts every admin setting takes.
+fn admin_instruction(program_id: &Pubkey, admin: &Pubkey, pledge_instruction: PledgeInstruction) -> Instruction {
+    instruction(
+        program_id,
+        pledge_instruction,
+        vec![AccountMeta::new(config(program_id), false), AccountMeta::new_readonly(*admin, true)],
+    )
+}
+
+pub fn initialize_user_ix(program_id: &Pubkey, wallet: &Pubkey) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::InitializeUser,
+        vec![
+            AccountMeta::new(user_state(program_id, wallet), false),
+            AccountMeta::new(*wallet, true),
+            AccountMeta::new_readonly(system_program::ID, false),
+            AccountMeta::new(config(program_id), false),
+        ],
+    )
+}
+
+pub fn initialize_contract_ix(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    sale_start_time: u64,
+    phase_caps: [u64; 5],
+    max_per_user: u64,
+) -> Instruction {
+    let no_guardian = Pubkey::default();
+    initialize_contract_with_guardian_ix(program_id, admin, sale_start_time, phase_caps, max_per_user, no_guardian, no_guardian)
+}
+
+// Like initialize_contract_ix, with `guardian` allowed to EmergencySweep the
+// vaults to token accounts of `recovery_destination`.
+pub fn initialize_contract_with_guardian_ix(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    sale_start_time: u64,
+    phase_caps: [u64; 5],
+    max_per_user: u64,
+    guardian: Pubkey,
+    recovery_destination: Pubkey,
+) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::InitializeContract { sale_start_time, phase_caps, max_per_user, guardian, recovery_destination },
+        vec![
+            AccountMeta::new(config(program_id), false),
+            AccountMeta::new(*admin, true),
+            AccountMeta::new_readonly(system_program::ID, false),
+            AccountMeta::new(find_treasury_address(program_id).0, false),
+        ],
+    )
+}
+
+// `referrer` is the referring wallet's user state, and `group_id` names the
+// buy group the wallet joined, which members must pass. The buyer's whitelist
+// PDA is always passed; the program only reads it in the first sale phase.
+pub fn buy_pledge_ix(
+    program_id: &Pubkey,
+    wallet: &Pubkey,
+    amount: u64,
+    tier: u8,
+    referrer: Option<Pubkey>,
+    allow_partial: bool,
+    group_id: Option<Pubkey>,
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(user_state(program_id, wallet), false),
+        AccountMeta::new(*wallet, true),
+        AccountMeta::new(config(program_id), false),
+        AccountMeta::new(find_treasury_address(program_id).0, false),
+        AccountMeta::new_readonly(system_program::ID, false),
+    ];
+    if let Some(referrer) = referrer {
+        accounts.push(AccountMeta::new(referrer, false));
+    }
+    if let Some(group_id) = group_id {
+        accounts.push(AccountMeta::new(find_group_address(program_id, &group_id).0, false));
+    }
+    accounts.push(AccountMeta::new_readonly(find_whitelist_address(program_id, wallet).0, false));
+    instruction(program_id, PledgeInstruction::BuyPledge { amount, tier, referrer, allow_partial }, accounts)
+}
+
+// Has a BuyPledge record its receipt. `purchase_count` is the user's count
+// before the purchase, which numbers the receipt.
+pub fn with_receipt(mut buy: Instruction, wallet: &Pubkey, purchase_count: u32) -> Instruction {
+    let receipt = find_receipt_address(&buy.program_id, wallet, purchase_count).0;
+    buy.accounts.push(AccountMeta::new(receipt, false));
+    buy
+}
+
+// Where the receipts of a wallet's first `purchase_count` purchases would be,
+// in purchase order. Purchases made without a receipt leave no account.
+pub fn receipt_addresses(program_id: &Pubkey, wallet: &Pubkey, purchase_count: u32) -> Vec<Pubkey> {
+    (0..purchase_count).map(|index| find_receipt_address(program_id, wallet, index).0).collect()
+}
+
+// Pays from `buyer_token`, the wallet's account of `payment_mint`, into
+// `treasury_token`, an account of the same mint held by the treasury PDA.
+// `group_id` is as for buy_pledge_ix.
+pub fn buy_pledge_with_token_ix(
+    program_id: &Pubkey,
+    wallet: &Pubkey,
+    buyer_token: &Pubkey,
+    treasury_token: &Pubkey,
+    payment_mint: &Pubkey,
+    amount: u64,
+    group_id: Option<Pubkey>,
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(user_state(program_id, wallet), false),
+        AccountMeta::new_readonly(*wallet, true),
+        AccountMeta::new(config(program_id), false),
+        AccountMeta::new(*buyer_token, false),
+        AccountMeta::new(*treasury_token, false),
+        AccountMeta::new_readonly(*payment_mint, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+    ];
+    if let Some(group_id) = group_id {
+        accounts.push(AccountMeta::new(find_group_address(program_id, &group_id).0, false));
+    }
+    accounts.push(AccountMeta::new_readonly(find_whitelist_address(program_id, wallet).0, false));
+    instruction(program_id, PledgeInstruction::BuyPledgeWithToken { amount }, accounts)
+}
+
+// `wallet` pays for the group's PDA if it is the first to join.
+pub fn join_group_ix(program_id: &Pubkey, wallet: &Pubkey, group_id: Pubkey) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::JoinGroup { group_id },
+        vec![
+            AccountMeta::new(user_state(program_id, wallet), false),
+            AccountMeta::new(*wallet, true),
+            AccountMeta::new(find_group_address(program_id, &group_id).0, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+        ],
+    )
+}
+
+pub fn update_reward_ix(program_id: &Pubkey, wallet: &Pubkey) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::UpdateReward,
+        vec![AccountMeta::new(user_state(program_id, wallet), false), AccountMeta::new(config(program_id), false)],
+    )
+}
+
+pub fn update_reward_batch_ix(program_id: &Pubkey, wallets: &[Pubkey]) -> Instruction {
+    let mut accounts = vec![AccountMeta::new(config(program_id), false)];
+    accounts.extend(wallets.iter().map(|wallet| AccountMeta::new(user_state(program_id, wallet), false)));
+    instruction(program_id, PledgeInstruction::UpdateRewardBatch, accounts)
+}
+
+// Like update_reward_batch_ix, paying the crank bounty into `cranker_token`.
+pub fn update_reward_batch_with_bounty_ix(
+    program_id: &Pubkey,
+    wallets: &[Pubkey],
+    cranker_token: &Pubkey,
+    solhit_mint: &Pubkey,
+) -> Instruction {
+    let mut instruction = update_reward_batch_ix(program_id, wallets);
+    instruction.accounts.extend([
+        AccountMeta::new(find_solhit_vault_address(program_id).0, false),
+        AccountMeta::new(*cranker_token, false),
+        AccountMeta::new_readonly(*solhit_mint, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+    ]);
+    instruction
+}
+
+pub fn view_rewards_ix(program_id: &Pubkey, wallet: &Pubkey) -> Instruction {
+    view_instruction(program_id, wallet, PledgeInstruction::ViewRewards { soft: false })
+}
+
+// Like view_rewards_ix, answering for a wallet without an initialized user
+// state with `initialized` unset rather than failing.
+pub fn view_rewards_soft_ix(program_id: &Pubkey, wallet: &Pubkey) -> Instruction {
+    view_instruction(program_id, wallet, PledgeInstruction::ViewRewards { soft: true })
+}
+
+pub fn get_vesting_schedule_ix(program_id: &Pubkey, wallet: &Pubkey) -> Instruction {
+    view_instruction(program_id, wallet, PledgeInstruction::GetVestingSchedule)
+}
+
+pub fn preview_reward_ix(program_id: &Pubkey, wallet: &Pubkey) -> Instruction {
+    view_instruction(program_id, wallet, PledgeInstruction::PreviewReward { soft: false })
+}
+
+// Like preview_reward_ix, answering for a wallet without an initialized user
+// state with `initialized` unset rather than failing.
+pub fn preview_reward_soft_ix(program_id: &Pubkey, wallet: &Pubkey) -> Instruction {
+    view_instruction(program_id, wallet, PledgeInstruction::PreviewReward { soft: true })
+}
+
+// Quotes a purchase of `amount` lamports, for `wallet` when given.
+pub fn quote_buy_ix(program_id: &Pubkey, wallet: Option<&Pubkey>, amount: u64) -> Instruction {
+    let mut accounts = vec![AccountMeta::new_readonly(config(program_id), false)];
+    accounts.extend(wallet.map(|wallet| AccountMeta::new_readonly(user_state(program_id, wallet), false)));
+    instruction(program_id, PledgeInstruction::QuoteBuy { amount }, accounts)
+}
+
+pub fn get_global_stats_ix(program_id: &Pubkey) -> Instruction {
+    instruction(program_id, PledgeInstruction::GetGlobalStats, vec![AccountMeta::new_readonly(config(program_id), false)])
+}
+
+// `beneficiary_token` is the Solheist token account of the user's
+// beneficiary, the wallet's own unless changed with SetBeneficiary.
+pub fn claim_rewards_ix(
+    program_id: &Pubkey,
+    wallet: &Pubkey,
+    beneficiary_token: &Pubkey,
+    solhit_mint: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::ClaimRewards { amount },
+        vec![
+            AccountMeta::new(user_state(program_id, wallet), false),
+            AccountMeta::new_readonly(*wallet, true),
+            AccountMeta::new(config(program_id), false),
+            AccountMeta::new(find_solhit_vault_address(program_id).0, false),
+            AccountMeta::new(*beneficiary_token, false),
+            AccountMeta::new_readonly(*solhit_mint, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ],
+    )
+}
+
+// Like claim_rewards_ix, but pays into `beneficiary`'s associated token
+// account, creating it at `wallet`'s expense if it does not exist yet.
+pub fn claim_rewards_to_ata_ix(
+    program_id: &Pubkey,
+    wallet: &Pubkey,
+    beneficiary: &Pubkey,
+    solhit_mint: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    let ata = spl_associated_token_account::get_associated_token_address(beneficiary, solhit_mint);
+    with_ata_creation(claim_rewards_ix(program_id, wallet, &ata, solhit_mint, amount), beneficiary)
+}
+
+// Adds the accounts a ClaimRewards needs to create `beneficiary`'s
+// associated token account, paid for by its signer.
+fn with_ata_creation(mut claim: Instruction, beneficiary: &Pubkey) -> Instruction {
+    let payer = claim.accounts[1].pubkey;
+    claim.accounts[1] = AccountMeta::new(payer, true);
+    claim.accounts.push(AccountMeta::new_readonly(system_program::ID, false));
+    claim.accounts.push(AccountMeta::new_readonly(spl_associated_token_account::id(), false));
+    if *beneficiary != payer {
+        claim.accounts.push(AccountMeta::new_readonly(*beneficiary, false));
+    }
+    claim
+}
+
+// Has a ClaimRewards pay the config's claim fee, which it must while
+// claim_fee_bps is not 0, into the treasury's Solheist token account. That
+// goes right after the token program, ahead of any accounts creating an
+// associated token account.
+pub fn with_claim_fee(mut claim: Instruction, solhit_mint: &Pubkey) -> Instruction {
+    let fee_account = claim_fee_address(&claim.program_id, solhit_mint);
+    claim.accounts.insert(7, AccountMeta::new(fee_account, false));
+    claim
+}
+
+// Like claim_rewards_ix, but signed by `wallet`'s delegate. The rewards still
+// go to the beneficiary's `beneficiary_token`.
+pub fn delegate_claim_rewards_ix(
+    program_id: &Pubkey,
+    wallet: &Pubkey,
+    delegate: &Pubkey,
+    beneficiary_token: &Pubkey,
+    solhit_mint: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    let mut instruction = claim_rewards_ix(program_id, wallet, beneficiary_token, solhit_mint, amount);
+    instruction.accounts[1] = AccountMeta::new_readonly(*delegate, true);
+    instruction
+}
+
+// Like delegate_claim_rewards_ix, but pays into `beneficiary`'s associated
+// token account, creating it at `delegate`'s expense if it does not exist yet.
+pub fn delegate_claim_rewards_to_ata_ix(
+    program_id: &Pubkey,
+    wallet: &Pubkey,
+    delegate: &Pubkey,
+    beneficiary: &Pubkey,
+    solhit_mint: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    let ata = spl_associated_token_account::get_associated_token_address(beneficiary, solhit_mint);
+    with_ata_creation(delegate_claim_rewards_ix(program_id, wallet, delegate, &ata, solhit_mint, amount), beneficiary)
+}
+
+pub fn compound_rewards_ix(program_id: &Pubkey, wallet: &Pubkey) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::CompoundRewards,
+        vec![
+            AccountMeta::new(user_state(program_id, wallet), false),
+            AccountMeta::new_readonly(*wallet, true),
+            AccountMeta::new(config(program_id), false),
+        ],
+    )
+}
+
+// UnlockPledge and EarlyExit take the same accounts: `pledge_vault` is the
+// pledge token account held by the vault authority PDA, `user_token` the
+// wallet's pledge token account.
+fn pledge_vault_accounts(
+    program_id: &Pubkey,
+    wallet: &Pubkey,
+    pledge_vault: &Pubkey,
+    user_token: &Pubkey,
+) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new(user_state(program_id, wallet), false),
+        AccountMeta::new_readonly(*wallet, true),
+        AccountMeta::new(config(program_id), false),
+        AccountMeta::new(*pledge_vault, false),
+        AccountMeta::new(*user_token, false),
+        AccountMeta::new_readonly(find_pledge_vault_address(program_id).0, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+    ]
+}
+
+pub fn unlock_pledge_ix(program_id: &Pubkey, wallet: &Pubkey, pledge_vault: &Pubkey, user_token: &Pubkey) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::UnlockPledge,
+        pledge_vault_accounts(program_id, wallet, pledge_vault, user_token),
+    )
+}
+
+pub fn early_exit_ix(program_id: &Pubkey, wallet: &Pubkey, pledge_vault: &Pubkey, user_token: &Pubkey) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::EarlyExit {},
+        pledge_vault_accounts(program_id, wallet, pledge_vault, user_token),
+    )
+}
+
+pub fn set_beneficiary_ix(program_id: &Pubkey, wallet: &Pubkey, new: Pubkey) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::SetBeneficiary { new },
+        vec![AccountMeta::new(user_state(program_id, wallet), false), AccountMeta::new_readonly(*wallet, true)],
+    )
+}
+
+// Moves `wallet`'s position into the user state of `destination_wallet`.
+pub fn transfer_position_ix(program_id: &Pubkey, wallet: &Pubkey, destination_wallet: &Pubkey) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::TransferPosition,
+        vec![
+            AccountMeta::new(user_state(program_id, wallet), false),
+            AccountMeta::new_readonly(*wallet, true),
+            AccountMeta::new(user_state(program_id, destination_wallet), false),
+            AccountMeta::new(config(program_id), false),
+        ],
+    )
+}
+
+pub fn set_delegate_ix(program_id: &Pubkey, wallet: &Pubkey, delegate: Pubkey) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::SetDelegate { delegate },
+        vec![AccountMeta::new(user_state(program_id, wallet), false), AccountMeta::new_readonly(*wallet, true)],
+    )
+}
+
+pub fn revoke_delegate_ix(program_id: &Pubkey, wallet: &Pubkey) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::RevokeDelegate,
+        vec![AccountMeta::new(user_state(program_id, wallet), false), AccountMeta::new_readonly(*wallet, true)],
+    )
+}
+
+pub fn migrate_user_state_ix(program_id: &Pubkey, wallet: &Pubkey, payer: &Pubkey) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::MigrateUserState,
+        vec![
+            AccountMeta::new(user_state(program_id, wallet), false),
+            AccountMeta::new(*payer, true),
+            AccountMeta::new_readonly(system_program::ID, false),
+        ],
+    )
+}
+
+// `wallet` pays for the extra rent.
+pub fn resize_user_state_ix(program_id: &Pubkey, wallet: &Pubkey, new_len: u64) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::ResizeUserState { new_len },
+        vec![
+            AccountMeta::new(user_state(program_id, wallet), false),
+            AccountMeta::new(*wallet, true),
+            AccountMeta::new_readonly(system_program::ID, false),
+        ],
+    )
+}
+
+pub fn close_user_state_ix(program_id: &Pubkey, wallet: &Pubkey) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::CloseUserState,
+        vec![
+            AccountMeta::new(user_state(program_id, wallet), false),
+            AccountMeta::new(*wallet, true),
+            AccountMeta::new(config(program_id), false),
+        ],
+    )
+}
+
+// `duplicate` is the wallet's second, non-PDA user state.
+pub fn merge_user_states_ix(program_id: &Pubkey, wallet: &Pubkey, duplicate: &Pubkey) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::MergeUserStates,
+        vec![
+            AccountMeta::new(user_state(program_id, wallet), false),
+            AccountMeta::new(*duplicate, false),
+            AccountMeta::new(*wallet, true),
+            AccountMeta::new_readonly(config(program_id), false),
+        ],
+    )
+}
+
+pub fn set_paused_ix(program_id: &Pubkey, admin: &Pubkey, paused: bool) -> Instruction {
+    admin_instruction(program_id, admin, PledgeInstruction::SetPaused { paused })
+}
+
+pub fn update_phase_config_ix(program_id: &Pubkey, admin: &Pubkey, durations: [u64; 5], rates: [u64; 5]) -> Instruction {
+    admin_instruction(program_id, admin, PledgeInstruction::UpdatePhaseConfig { durations, rates })
+}
+
+pub fn nominate_admin_ix(program_id: &Pubkey, admin: &Pubkey, new_admin: Pubkey) -> Instruction {
+    admin_instruction(program_id, admin, PledgeInstruction::NominateAdmin { new_admin })
+}
+
+// Signed by the nominated admin.
+pub fn accept_admin_ix(program_id: &Pubkey, new_admin: &Pubkey) -> Instruction {
+    admin_instruction(program_id, new_admin, PledgeInstruction::AcceptAdmin)
+}
+
+pub fn set_payment_mint_ix(program_id: &Pubkey, admin: &Pubkey, payment_mint: Pubkey) -> Instruction {
+    admin_instruction(program_id, admin, PledgeInstruction::SetPaymentMint { payment_mint })
+}
+
+pub fn set_purchase_cooldown_ix(program_id: &Pubkey, admin: &Pubkey, seconds: u64) -> Instruction {
+    admin_instruction(program_id, admin, PledgeInstruction::SetPurchaseCooldown { seconds })
+}
+
+pub fn set_referral_bonus_ix(program_id: &Pubkey, admin: &Pubkey, percent: u64) -> Instruction {
+    admin_instruction(program_id, admin, PledgeInstruction::SetReferralBonus { percent })
+}
+
+pub fn finalize_sale_ix(program_id: &Pubkey, admin: &Pubkey) -> Instruction {
+    admin_instruction(program_id, admin, PledgeInstruction::FinalizeSale)
+}
+
+pub fn repair_user_state_ix(program_id: &Pubkey, admin: &Pubkey, wallet: &Pubkey) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::RepairUserState { wallet: *wallet },
+        vec![
+            AccountMeta::new(user_state(program_id, wallet), false),
+            AccountMeta::new(config(program_id), false),
+            AccountMeta::new_readonly(*admin, true),
+        ],
+    )
+}
+
+// Holds `wallet` until `hold_until`, or lifts its hold with 0.
+pub fn set_compliance_hold_ix(program_id: &Pubkey, admin: &Pubkey, wallet: &Pubkey, hold_until: u64) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::SetComplianceHold { wallet: *wallet, hold_until },
+        vec![
+            AccountMeta::new(user_state(program_id, wallet), false),
+            AccountMeta::new(config(program_id), false),
+            AccountMeta::new_readonly(*admin, true),
+        ],
+    )
+}
+
+// Opens a sale initialized with SALE_UNSCHEDULED, at `start_time` or when
+// the instruction runs.
+pub fn start_sale_ix(program_id: &Pubkey, admin: &Pubkey, start_time: Option<u64>) -> Instruction {
+    admin_instruction(program_id, admin, PledgeInstruction::StartSale { start_time })
+}
+
+pub fn set_snapshot_interval_ix(program_id: &Pubkey, admin: &Pubkey, seconds: u64) -> Instruction {
+    admin_instruction(program_id, admin, PledgeInstruction::SetSnapshotInterval { seconds })
+}
+
+pub fn set_post_vesting_grace_ix(program_id: &Pubkey, admin: &Pubkey, seconds: u64) -> Instruction {
+    admin_instruction(program_id, admin, PledgeInstruction::SetPostVestingGrace { seconds })
+}
+
+pub fn set_group_cap_enforced_ix(program_id: &Pubkey, admin: &Pubkey, enforced: bool) -> Instruction {
+    admin_instruction(program_id, admin, PledgeInstruction::SetGroupCapEnforced { enforced })
+}
+
+pub fn set_crank_bounty_ix(program_id: &Pubkey, admin: &Pubkey, crank_fee: u64, crank_budget: u64) -> Instruction {
+    admin_instruction(program_id, admin, PledgeInstruction::SetCrankBounty { crank_fee, crank_budget })
+}
+
+pub fn set_reward_expiry_ix(program_id: &Pubkey, admin: &Pubkey, seconds: u64, permissionless: bool) -> Instruction {
+    admin_instruction(program_id, admin, PledgeInstruction::SetRewardExpiry { seconds, permissionless })
+}
+
+pub fn set_claim_fee_ix(program_id: &Pubkey, admin: &Pubkey, bps: u16) -> Instruction {
+    admin_instruction(program_id, admin, PledgeInstruction::SetClaimFee { bps })
+}
+
+pub fn set_token_decimals_ix(program_id: &Pubkey, admin: &Pubkey, payment_decimals: u8, pledge_decimals: u8) -> Instruction {
+    admin_instruction(program_id, admin, PledgeInstruction::SetTokenDecimals { payment_decimals, pledge_decimals })
+}
+
+pub fn set_noop_events_ix(program_id: &Pubkey, admin: &Pubkey, enabled: bool) -> Instruction {
+    admin_instruction(program_id, admin, PledgeInstruction::SetNoOpEvents { enabled })
+}
+
+// `admin` signs unless the config lets anyone expire rewards, then None.
+pub fn expire_rewards_ix(program_id: &Pubkey, wallet: &Pubkey, admin: Option<&Pubkey>) -> Instruction {
+    let mut accounts =
+        vec![AccountMeta::new(user_state(program_id, wallet), false), AccountMeta::new(config(program_id), false)];
+    accounts.extend(admin.map(|admin| AccountMeta::new_readonly(*admin, true)));
+    instruction(program_id, PledgeInstruction::ExpireRewards, accounts)
+}
+
+// `source_token` is the admin's own Solheist token account.
+pub fn fund_rewards_ix(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    source_token: &Pubkey,
+    solhit_mint: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::FundRewards { amount },
+        vec![
+            AccountMeta::new(config(program_id), false),
+            AccountMeta::new_readonly(*admin, true),
+            AccountMeta::new(*source_token, false),
+            AccountMeta::new(find_solhit_vault_address(program_id).0, false),
+            AccountMeta::new_readonly(*solhit_mint, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ],
+    )
+}
+
+pub fn migrate_config_ix(program_id: &Pubkey, admin: &Pubkey) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::MigrateConfig,
+        vec![
+            AccountMeta::new(config(program_id), false),
+            AccountMeta::new(*admin, true),
+            AccountMeta::new_readonly(system_program::ID, false),
+        ],
+    )
+}
+
+pub fn withdraw_treasury_ix(program_id: &Pubkey, admin: &Pubkey, destination: &Pubkey, amount: u64) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::WithdrawTreasury { amount },
+        vec![
+            AccountMeta::new(config(program_id), false),
+            AccountMeta::new_readonly(*admin, true),
+            AccountMeta::new(find_treasury_address(program_id).0, false),
+            AccountMeta::new(*destination, false),
+        ],
+    )
+}
+
+pub fn release_team_tokens_ix(program_id: &Pubkey, admin: &Pubkey, team_token: &Pubkey, solhit_mint: &Pubkey) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::ReleaseTeamTokens,
+        vec![
+            AccountMeta::new(config(program_id), false),
+            AccountMeta::new_readonly(*admin, true),
+            AccountMeta::new(find_solhit_vault_address(program_id).0, false),
+            AccountMeta::new(*team_token, false),
+            AccountMeta::new_readonly(*solhit_mint, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ],
+    )
+}
+
+// Sweeps `vault`, the pledge vault or the Solheist vault PDA, into
+// `recovery_token`, the recovery destination's account of the vault's mint.
+pub fn emergency_sweep_ix(program_id: &Pubkey, guardian: &Pubkey, vault: &Pubkey, recovery_token: &Pubkey) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(config(program_id), false),
+        AccountMeta::new_readonly(*guardian, true),
+        AccountMeta::new(*vault, false),
+        AccountMeta::new(*recovery_token, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+    ];
+    if *vault != find_solhit_vault_address(program_id).0 {
+        accounts.push(AccountMeta::new_readonly(find_pledge_vault_address(program_id).0, false));
+    }
+    instruction(program_id, PledgeInstruction::EmergencySweep, accounts)
+}
+
+pub fn add_to_whitelist_ix(program_id: &Pubkey, admin: &Pubkey, wallet: Pubkey) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::AddToWhitelist { wallet },
+        vec![
+            AccountMeta::new_readonly(config(program_id), false),
+            AccountMeta::new(*admin, true),
+            AccountMeta::new(find_whitelist_address(program_id, &wallet).0, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+        ],
+    )
+}
+
+pub fn remove_from_whitelist_ix(program_id: &Pubkey, admin: &Pubkey, wallet: Pubkey) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::RemoveFromWhitelist { wallet },
+        vec![
+            AccountMeta::new_readonly(config(program_id), false),
+            AccountMeta::new(*admin, true),
+            AccountMeta::new(find_whitelist_address(program_id, &wallet).0, false),
+        ],
+    )
+}
+
+// `payer` only pays when the snapshots PDA does not exist yet.
+pub fn record_snapshot_ix(program_id: &Pubkey, payer: &Pubkey) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::RecordSnapshot,
+        vec![
+            AccountMeta::new(config(program_id), false),
+            AccountMeta::new(find_snapshots_address(program_id).0, false),
+            AccountMeta::new(*payer, true),
+            AccountMeta::new_readonly(system_program::ID, false),
+        ],
+    )
+}
+
+pub fn get_latest_snapshot_ix(program_id: &Pubkey) -> Instruction {
+    instruction(
+        program_id,
+        PledgeInstruction::GetLatestSnapshot,
+        vec![AccountMeta::new_readonly(find_snapshots_address(program_id).0, false)],
+    )
+}
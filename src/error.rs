@@ -0,0 +1,230 @@
+use solana_program::program_error::ProgramError;
+use std::fmt;
+
+// Program specific failures, surfaced to clients as ProgramError::Custom(code).
+// The code is the variant's position, so new variants are only ever appended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PledgeError {
+    AlreadyInitialized,
+    SaleNotStarted,
+    InvalidAmount,
+    LotLimitReached,
+    StillVesting,
+    VestingEnded,
+    InvalidVault,
+    InsufficientRewards,
+    ArithmeticOverflow,
+    SupplyExhausted,
+    NothingToClaim,
+    RewardsExhausted,
+    PhaseCapExceeded,
+    UserCapExceeded,
+    ContractPaused,
+    Unauthorized,
+    SaleStarted,
+    InvalidPhaseConfig,
+    InsufficientTreasury,
+    InvalidPaymentMint,
+    InvalidClock,
+    BatchTooLarge,
+    OutstandingBalance,
+    CooldownActive,
+    SelfReferral,
+    NotWhitelisted,
+    InvalidLockTier,
+    NothingToCompound,
+    DuplicateAccount,
+    SaleEnded,
+    SaleInProgress,
+    InvalidConfig,
+    SnapshotTooSoon,
+    NothingToTransfer,
+    NothingToRelease,
+    ClockWentBackwards,
+    ConfigVersionMismatch,
+    InsufficientVaultBalance,
+    GroupCapExceeded,
+    AlreadyInGroup,
+    AccountShrinkNotSupported,
+    InvalidAccountType,
+    StateCorrupted,
+    NotExpired,
+    AccountNotWritable,
+    ExecutableAccount,
+    NotRentExempt,
+    InvalidStartTime,
+    StateNotCorrupted,
+    InvalidFeeAccount,
+    PurchaseTooSmall,
+    ContractNotPaused,
+    InvalidRecoveryDestination,
+    ComplianceHold,
+    InvalidProgramAccount,
+    UserStateInUse,
+}
+
+impl PledgeError {
+    const ALL: [PledgeError; 56] = [
+        PledgeError::AlreadyInitialized,
+        PledgeError::SaleNotStarted,
+        PledgeError::InvalidAmount,
+        PledgeError::LotLimitReached,
+        PledgeError::StillVesting,
+        PledgeError::VestingEnded,
+        PledgeError::InvalidVault,
+        PledgeError::InsufficientRewards,
+        PledgeError::ArithmeticOverflow,
+        PledgeError::SupplyExhausted,
+        PledgeError::NothingToClaim,
+        PledgeError::RewardsExhausted,
+        PledgeError::PhaseCapExceeded,
+        PledgeError::UserCapExceeded,
+        PledgeError::ContractPaused,
+        PledgeError::Unauthorized,
+        PledgeError::SaleStarted,
+        PledgeError::InvalidPhaseConfig,
+        PledgeError::InsufficientTreasury,
+        PledgeError::InvalidPaymentMint,
+        PledgeError::InvalidClock,
+        PledgeError::BatchTooLarge,
+        PledgeError::OutstandingBalance,
+        PledgeError::CooldownActive,
+        PledgeError::SelfReferral,
+        PledgeError::NotWhitelisted,
+        PledgeError::InvalidLockTier,
+        PledgeError::NothingToCompound,
+        PledgeError::DuplicateAccount,
+        PledgeError::SaleEnded,
+        PledgeError::SaleInProgress,
+        PledgeError::InvalidConfig,
+        PledgeError::SnapshotTooSoon,
+        PledgeError::NothingToTransfer,
+        PledgeError::NothingToRelease,
+        PledgeError::ClockWentBackwards,
+        PledgeError::ConfigVersionMismatch,
+        PledgeError::InsufficientVaultBalance,
+        PledgeError::GroupCapExceeded,
+        PledgeError::AlreadyInGroup,
+        PledgeError::AccountShrinkNotSupported,
+        PledgeError::InvalidAccountType,
+        PledgeError::StateCorrupted,
+        PledgeError::NotExpired,
+        PledgeError::AccountNotWritable,
+        PledgeError::ExecutableAccount,
+        PledgeError::NotRentExempt,
+        PledgeError::InvalidStartTime,
+        PledgeError::StateNotCorrupted,
+        PledgeError::InvalidFeeAccount,
+        PledgeError::PurchaseTooSmall,
+        PledgeError::ContractNotPaused,
+        PledgeError::InvalidRecoveryDestination,
+        PledgeError::ComplianceHold,
+        PledgeError::InvalidProgramAccount,
+        PledgeError::UserStateInUse,
+    ];
+
+    // Maps a ProgramError::Custom code back to the error, for clients.
+    pub fn from_code(code: u32) -> Option<Self> {
+        Self::ALL.get(code as usize).copied()
+    }
+}
+
+impl fmt::Display for PledgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            PledgeError::AlreadyInitialized => "Account is already initialized",
+            PledgeError::SaleNotStarted => "The pledge sale has not started yet",
+            PledgeError::InvalidAmount => "Amount must be greater than zero",
+            PledgeError::LotLimitReached => "Too many open pledge lots",
+            PledgeError::StillVesting => "Pledge tokens are still vesting",
+            PledgeError::VestingEnded => "Vesting has already ended",
+            PledgeError::InvalidVault => "Vault account does not match the program vault",
+            PledgeError::InsufficientRewards => "Claim exceeds the accrued rewards",
+            PledgeError::ArithmeticOverflow => "Arithmetic overflow",
+            PledgeError::SupplyExhausted => "Purchase exceeds the remaining pledge supply",
+            PledgeError::NothingToClaim => "No rewards to claim",
+            PledgeError::RewardsExhausted => "Not enough Solheist left to pay rewards",
+            PledgeError::PhaseCapExceeded => "Purchase exceeds the current sale phase allocation",
+            PledgeError::UserCapExceeded => "Purchase exceeds the per-user cap",
+            PledgeError::ContractPaused => "The contract is paused",
+            PledgeError::Unauthorized => "Signer is not the contract admin",
+            PledgeError::SaleStarted => "The pledge sale has already started",
+            PledgeError::InvalidPhaseConfig => "Phase rates and durations must be positive and fit the timeline",
+            PledgeError::InsufficientTreasury => "Withdrawal would leave the treasury below its rent exempt minimum",
+            PledgeError::InvalidPaymentMint => "Payment mint is not accepted by the sale",
+            PledgeError::InvalidClock => "Clock reports a negative unix timestamp",
+            PledgeError::BatchTooLarge => "Too many accounts for one batch",
+            PledgeError::OutstandingBalance => "User still has locked pledge tokens or unclaimed rewards",
+            PledgeError::CooldownActive => "Too soon after the previous purchase",
+            PledgeError::SelfReferral => "A wallet cannot refer itself",
+            PledgeError::NotWhitelisted => "Wallet is not whitelisted for the first sale phase",
+            PledgeError::InvalidLockTier => "Unknown lock duration tier",
+            PledgeError::NothingToCompound => "No rewards to compound",
+            PledgeError::DuplicateAccount => "The same account was passed more than once",
+            PledgeError::SaleEnded => "The pledge sale has been finalized",
+            PledgeError::SaleInProgress => "The last sale phase has not ended yet",
+            PledgeError::InvalidConfig => "Contract config is inconsistent",
+            PledgeError::SnapshotTooSoon => "A snapshot was already recorded this interval",
+            PledgeError::NothingToTransfer => "No locked pledge tokens or rewards to transfer",
+            PledgeError::NothingToRelease => "No vested team tokens to release",
+            PledgeError::ClockWentBackwards => "Clock is behind the user's last activity",
+            PledgeError::ConfigVersionMismatch => "Config account is not at the program's config version",
+            PledgeError::InsufficientVaultBalance => "Solheist vault holds too few reward tokens",
+            PledgeError::GroupCapExceeded => "Purchase exceeds the per-user cap shared by the buy group",
+            PledgeError::AlreadyInGroup => "Wallet already belongs to a buy group",
+            PledgeError::AccountShrinkNotSupported => "Accounts can only grow, not shrink",
+            PledgeError::InvalidAccountType => "Account holds a different kind of state",
+            PledgeError::StateCorrupted => "User state fails its sanity checks",
+            PledgeError::NotExpired => "User's rewards have not expired yet",
+            PledgeError::AccountNotWritable => "Account the instruction writes was passed read-only",
+            PledgeError::ExecutableAccount => "Program account passed where state was expected",
+            PledgeError::NotRentExempt => "Account does not hold enough lamports to be rent exempt",
+            PledgeError::InvalidStartTime => "Sale start time is already in the past",
+            PledgeError::StateNotCorrupted => "User state passes its sanity checks and needs no repair",
+            PledgeError::InvalidFeeAccount => "Claim fee account is not the treasury's Solheist token account",
+            PledgeError::PurchaseTooSmall => "Payment is too small to buy a single pledge token base unit",
+            PledgeError::ContractNotPaused => "The contract must be paused first",
+            PledgeError::InvalidRecoveryDestination => "Destination is not a token account of the recovery destination",
+            PledgeError::ComplianceHold => "The user is on a compliance hold",
+            PledgeError::InvalidProgramAccount => "Program account is not the expected program",
+            PledgeError::UserStateInUse => "User state has bought pledge tokens or joined a buy group and cannot be closed",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for PledgeError {}
+
+impl From<PledgeError> for ProgramError {
+    fn from(e: PledgeError) -> Self {
+        ProgramError::Custom(e as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_codes_are_stable() {
+        assert_eq!(ProgramError::from(PledgeError::AlreadyInitialized), ProgramError::Custom(0));
+        assert_eq!(ProgramError::from(PledgeError::SaleNotStarted), ProgramError::Custom(1));
+        assert_eq!(ProgramError::from(PledgeError::StillVesting), ProgramError::Custom(4));
+        assert_eq!(ProgramError::from(PledgeError::ArithmeticOverflow), ProgramError::Custom(8));
+        assert_eq!(ProgramError::from(PledgeError::RewardsExhausted), ProgramError::Custom(11));
+    }
+
+    #[test]
+    fn test_from_code_round_trip() {
+        for error in PledgeError::ALL {
+            assert_eq!(PledgeError::from_code(error as u32), Some(error));
+        }
+        assert_eq!(PledgeError::from_code(PledgeError::ALL.len() as u32), None);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(PledgeError::NothingToClaim.to_string(), "No rewards to claim");
+        assert_eq!(PledgeError::SupplyExhausted.to_string(), "Purchase exceeds the remaining pledge supply");
+    }
+}
@@ -0,0 +1,365 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{log::sol_log_data, pubkey::Pubkey};
+
+// Events emitted by the program. Each one is logged once through sol_log_data
+// as two fields: the EVENT_SCHEMA_VERSION byte, then the event's Borsh
+// encoding, the discriminant byte below followed by the fields as
+// little-endian u64s. Indexers decode the second base64 field of the
+// "Program data:" log lines with `PledgeEvent::try_from_slice`, after checking
+// the version they were written for. Discriminants are part of the wire
+// format and never change.
+//
+// Every event starts with `event_seq`, taken from the config's counter, which
+// orders events across transactions. Events about one user then carry its
+// user state account as `user` and the wallet owning it as `wallet`.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+#[borsh(use_discriminant = true)]
+#[repr(u8)]
+pub enum PledgeEvent {
+    // `rate` is the one the sale `phase` applied to the purchase, and
+    // `phase_time_remaining` the seconds that phase still had to run, u64::MAX
+    // for the open-ended final phase.
+    Purchase {
+        event_seq: u64,
+        user: Pubkey,
+        wallet: Pubkey,
+        lamports_paid: u64,
+        pledge_tokens: u64,
+        rate: u64,
+        total_pledge_tokens: u64,
+        phase: u8,
+        phase_time_remaining: u64,
+    } = 0,
+    RewardUpdate { event_seq: u64, user: Pubkey, wallet: Pubkey, solhit_rewards: u64, elapsed_time: u64 } = 1,
+    // `claimed` includes the `fee` paid to the treasury.
+    RewardClaim { event_seq: u64, user: Pubkey, wallet: Pubkey, claimed: u64, remaining: u64, fee: u64 } = 2,
+    Unlock { event_seq: u64, user: Pubkey, wallet: Pubkey, amount: u64, locked_pledge_tokens: u64 } = 3,
+    EarlyExit { event_seq: u64, user: Pubkey, wallet: Pubkey, released: u64, penalty: u64 } = 4,
+    // `paid` is in base units of the configured payment mint.
+    TokenPurchase {
+        event_seq: u64,
+        user: Pubkey,
+        wallet: Pubkey,
+        paid: u64,
+        pledge_tokens: u64,
+        rate: u64,
+        total_pledge_tokens: u64,
+    } = 5,
+    // Summary of an UpdateRewardBatch, about no single user; the counts are
+    // u32 little-endian.
+    RewardBatch { event_seq: u64, processed: u32, skipped: u32 } = 6,
+    // Credited to the referrer: `user` and `wallet` are the referrer's.
+    ReferralBonus { event_seq: u64, user: Pubkey, wallet: Pubkey, pledge_tokens: u64, total_pledge_tokens: u64 } = 7,
+    // The rewards compounded into `pledge_tokens`, and the pledge tokens
+    // locked before and after.
+    Compound {
+        event_seq: u64,
+        user: Pubkey,
+        wallet: Pubkey,
+        solhit_rewards: u64,
+        pledge_tokens: u64,
+        locked_before: u64,
+        locked_after: u64,
+    } = 8,
+    // The sale closed for good, with what it sold and the supply left over.
+    SaleFinalized { event_seq: u64, total_pledge_sold: u64, unsold_supply: u64 } = 9,
+    // A purchase cut down to the remaining supply, alongside its Purchase:
+    // the pledge tokens asked for and those actually credited.
+    PartialFill { event_seq: u64, user: Pubkey, wallet: Pubkey, requested: u64, filled: u64 } = 10,
+    // A whole position moved from `user` to the `destination` user state.
+    PositionTransfer {
+        event_seq: u64,
+        user: Pubkey,
+        wallet: Pubkey,
+        destination: Pubkey,
+        pledge_tokens: u64,
+        solhit_rewards: u64,
+    } = 11,
+    // Team tokens moved out of the Solheist vault to the `destination` token
+    // account, about no single user, and the team tokens released in total.
+    TeamRelease { event_seq: u64, destination: Pubkey, amount: u64, total_released: u64 } = 12,
+    // Solheist moved into the vault from the `source` token account to pay
+    // rewards, and the rewards funded in total.
+    RewardsFunded { event_seq: u64, source: Pubkey, amount: u64, total_funded: u64 } = 13,
+    // Unclaimed rewards taken from the user once they expired, and the
+    // rewards expired in total.
+    Expire { event_seq: u64, user: Pubkey, wallet: Pubkey, amount: u64, total_expired: u64 } = 14,
+    // The sale opened by StartSale and when its first phase begins.
+    SaleStarted { event_seq: u64, start_time: u64 } = 15,
+    // The whole `amount` a vault held moved by EmergencySweep to the
+    // `destination` token account of the recovery destination.
+    Sweep { event_seq: u64, vault: Pubkey, destination: Pubkey, amount: u64 } = 16,
+    // An UpdateReward that found nothing to accrue and left the user state as
+    // it was. Emitted in place of RewardUpdate unless the config turns it off.
+    NoOp { event_seq: u64, user: Pubkey, wallet: Pubkey } = 17,
+    // A compliance hold set, moved or lifted by `admin` at `timestamp`: the
+    // user's hold_until before and after, 0 for no hold.
+    ComplianceHold {
+        event_seq: u64,
+        user: Pubkey,
+        wallet: Pubkey,
+        admin: Pubkey,
+        previous_hold_until: u64,
+        hold_until: u64,
+        timestamp: u64,
+    } = 18,
+}
+
+// Logged ahead of every event. Version 1 events came as a lone field; version
+// 2 added the phase to Purchase.
+pub const EVENT_SCHEMA_VERSION: u8 = 2;
+
+pub fn emit_event(event: PledgeEvent) {
+    #[cfg(feature = "debug-logs")]
+    log_event(&event);
+
+    let data = borsh::to_vec(&event).expect("PledgeEvent serialization cannot fail");
+    sol_log_data(&[&[EVENT_SCHEMA_VERSION], &data]);
+}
+
+#[cfg(feature = "debug-logs")]
+fn log_event(event: &PledgeEvent) {
+    use solana_program::msg;
+
+    match event {
+        PledgeEvent::Purchase { lamports_paid, pledge_tokens, rate, total_pledge_tokens, phase, .. } => msg!(
+            "Pledge tokens purchased: {} for {} lamports at rate {} of phase {} for total: {}",
+            pledge_tokens,
+            lamports_paid,
+            rate,
+            phase,
+            total_pledge_tokens
+        ),
+        PledgeEvent::RewardUpdate { solhit_rewards, elapsed_time, .. } => {
+            msg!("Rewards updated: Solheist Rewards: {} after elapsed time: {}", solhit_rewards, elapsed_time)
+        }
+        PledgeEvent::RewardClaim { claimed, remaining, fee, .. } => {
+            msg!("Rewards claimed: Solheist Rewards: {} with {} remaining, {} of it as fee", claimed, remaining, fee)
+        }
+        PledgeEvent::Unlock { amount, locked_pledge_tokens, .. } => {
+            msg!("Pledge tokens unlocked: {} with {} still locked", amount, locked_pledge_tokens)
+        }
+        PledgeEvent::EarlyExit { released, penalty, .. } => {
+            msg!("Pledge exited early: {} released, {} penalty", released, penalty)
+        }
+        PledgeEvent::TokenPurchase { paid, pledge_tokens, rate, total_pledge_tokens, .. } => msg!(
+            "Pledge tokens purchased: {} for {} payment tokens at rate {} for total: {}",
+            pledge_tokens,
+            paid,
+            rate,
+            total_pledge_tokens
+        ),
+        PledgeEvent::RewardBatch { processed, skipped, .. } => {
+            msg!("Rewards updated for {} users, {} skipped", processed, skipped)
+        }
+        PledgeEvent::ReferralBonus { pledge_tokens, total_pledge_tokens, .. } => {
+            msg!("Referral bonus: {} pledge tokens for total: {}", pledge_tokens, total_pledge_tokens)
+        }
+        PledgeEvent::Compound { solhit_rewards, pledge_tokens, locked_before, locked_after, .. } => msg!(
+            "Rewards compounded: {} Solheist Rewards into {} pledge tokens, locked {} -> {}",
+            solhit_rewards,
+            pledge_tokens,
+            locked_before,
+            locked_after
+        ),
+        PledgeEvent::SaleFinalized { total_pledge_sold, unsold_supply, .. } => {
+            msg!("Sale finalized: {} pledge tokens sold, {} unsold", total_pledge_sold, unsold_supply)
+        }
+        PledgeEvent::PartialFill { requested, filled, .. } => {
+            msg!("Purchase partially filled: {} of {} pledge tokens", filled, requested)
+        }
+        PledgeEvent::PositionTransfer { destination, pledge_tokens, solhit_rewards, .. } => msg!(
+            "Position transferred to {}: {} pledge tokens, {} Solheist Rewards",
+            destination,
+            pledge_tokens,
+            solhit_rewards
+        ),
+        PledgeEvent::TeamRelease { destination, amount, total_released, .. } => {
+            msg!("Team tokens released to {}: {} for total: {}", destination, amount, total_released)
+        }
+        PledgeEvent::RewardsFunded { source, amount, total_funded, .. } => {
+            msg!("Rewards funded from {}: {} for total: {}", source, amount, total_funded)
+        }
+        PledgeEvent::Expire { amount, total_expired, .. } => {
+            msg!("Rewards expired: {} for total: {}", amount, total_expired)
+        }
+        PledgeEvent::SaleStarted { start_time, .. } => msg!("Sale starts at {}", start_time),
+        PledgeEvent::Sweep { vault, destination, amount, .. } => {
+            msg!("Vault {} swept to {}: {}", vault, destination, amount)
+        }
+        PledgeEvent::NoOp { user, .. } => msg!("Nothing to accrue for {}", user),
+        PledgeEvent::ComplianceHold { wallet, previous_hold_until, hold_until, .. } => {
+            msg!("Compliance hold of {} moved from {} to {}", wallet, previous_hold_until, hold_until)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_round_trip() {
+        let (user, wallet) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let events = [
+            PledgeEvent::Purchase {
+                event_seq: 1,
+                user,
+                wallet,
+                lamports_paid: 100,
+                pledge_tokens: 200,
+                rate: 200,
+                total_pledge_tokens: 200,
+                phase: 4,
+                phase_time_remaining: u64::MAX,
+            },
+            PledgeEvent::RewardUpdate { event_seq: 2, user, wallet, solhit_rewards: 8_000, elapsed_time: 60 },
+            PledgeEvent::RewardClaim { event_seq: 3, user, wallet, claimed: 500, remaining: 0, fee: 5 },
+            PledgeEvent::Unlock { event_seq: 4, user, wallet, amount: 200, locked_pledge_tokens: 0 },
+            PledgeEvent::EarlyExit { event_seq: 5, user, wallet, released: 150, penalty: 50 },
+            PledgeEvent::TokenPurchase {
+                event_seq: 6,
+                user,
+                wallet,
+                paid: 1_000_000,
+                pledge_tokens: 2_000_000_000,
+                rate: 200,
+                total_pledge_tokens: 2_000_000_000,
+            },
+            PledgeEvent::RewardBatch { event_seq: 7, processed: 4, skipped: 1 },
+            PledgeEvent::ReferralBonus { event_seq: 8, user, wallet, pledge_tokens: 100, total_pledge_tokens: 2_100 },
+            PledgeEvent::Compound {
+                event_seq: u64::MAX,
+                user,
+                wallet,
+                solhit_rewards: 8_000,
+                pledge_tokens: 8_000,
+                locked_before: 200,
+                locked_after: 8_200,
+            },
+            PledgeEvent::SaleFinalized { event_seq: 10, total_pledge_sold: 2_100, unsold_supply: 97_900 },
+            PledgeEvent::PartialFill { event_seq: 11, user, wallet, requested: 5_000, filled: 1_200 },
+            PledgeEvent::PositionTransfer {
+                event_seq: 12,
+                user,
+                wallet,
+                destination: Pubkey::new_unique(),
+                pledge_tokens: 2_000,
+                solhit_rewards: 80,
+            },
+            PledgeEvent::TeamRelease { event_seq: 13, destination: Pubkey::new_unique(), amount: 500, total_released: 1_500 },
+            PledgeEvent::RewardsFunded { event_seq: 14, source: Pubkey::new_unique(), amount: 700, total_funded: 2_700 },
+            PledgeEvent::Expire { event_seq: 15, user, wallet, amount: 90, total_expired: 1_090 },
+            PledgeEvent::SaleStarted { event_seq: 16, start_time: 1_700_000_000 },
+            PledgeEvent::Sweep { event_seq: 17, vault: Pubkey::new_unique(), destination: Pubkey::new_unique(), amount: 9_000 },
+            PledgeEvent::NoOp { event_seq: 18, user, wallet },
+            PledgeEvent::ComplianceHold {
+                event_seq: 19,
+                user,
+                wallet,
+                admin: Pubkey::new_unique(),
+                previous_hold_until: 0,
+                hold_until: 1_800_000_000,
+                timestamp: 1_700_000_000,
+            },
+        ];
+
+        for event in events {
+            let data = borsh::to_vec(&event).unwrap();
+            assert_eq!(PledgeEvent::try_from_slice(&data).unwrap(), event);
+        }
+    }
+
+    #[test]
+    fn test_event_layout() {
+        let (user, wallet) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let data =
+            borsh::to_vec(&PledgeEvent::RewardClaim { event_seq: 9, user, wallet, claimed: 500, remaining: 7, fee: 5 }).unwrap();
+        let mut expected = vec![2u8];
+        expected.extend_from_slice(&9u64.to_le_bytes());
+        expected.extend_from_slice(user.as_ref());
+        expected.extend_from_slice(wallet.as_ref());
+        expected.extend_from_slice(&500u64.to_le_bytes());
+        expected.extend_from_slice(&7u64.to_le_bytes());
+        expected.extend_from_slice(&5u64.to_le_bytes());
+        assert_eq!(data, expected);
+
+        let user = Pubkey::default();
+        let wallet = user;
+        let discriminants: Vec<u8> = [
+            PledgeEvent::Purchase {
+                event_seq: 0,
+                user,
+                wallet,
+                lamports_paid: 0,
+                pledge_tokens: 0,
+                rate: 0,
+                total_pledge_tokens: 0,
+                phase: 0,
+                phase_time_remaining: 0,
+            },
+            PledgeEvent::RewardUpdate { event_seq: 0, user, wallet, solhit_rewards: 0, elapsed_time: 0 },
+            PledgeEvent::RewardClaim { event_seq: 0, user, wallet, claimed: 0, remaining: 0, fee: 0 },
+            PledgeEvent::Unlock { event_seq: 0, user, wallet, amount: 0, locked_pledge_tokens: 0 },
+            PledgeEvent::EarlyExit { event_seq: 0, user, wallet, released: 0, penalty: 0 },
+            PledgeEvent::TokenPurchase { event_seq: 0, user, wallet, paid: 0, pledge_tokens: 0, rate: 0, total_pledge_tokens: 0 },
+            PledgeEvent::RewardBatch { event_seq: 0, processed: 0, skipped: 0 },
+            PledgeEvent::ReferralBonus { event_seq: 0, user, wallet, pledge_tokens: 0, total_pledge_tokens: 0 },
+            PledgeEvent::Compound {
+                event_seq: 0,
+                user,
+                wallet,
+                solhit_rewards: 0,
+                pledge_tokens: 0,
+                locked_before: 0,
+                locked_after: 0,
+            },
+            PledgeEvent::SaleFinalized { event_seq: 0, total_pledge_sold: 0, unsold_supply: 0 },
+            PledgeEvent::PartialFill { event_seq: 0, user, wallet, requested: 0, filled: 0 },
+            PledgeEvent::PositionTransfer {
+                event_seq: 0,
+                user,
+                wallet,
+                destination: user,
+                pledge_tokens: 0,
+                solhit_rewards: 0,
+            },
+            PledgeEvent::TeamRelease { event_seq: 0, destination: user, amount: 0, total_released: 0 },
+            PledgeEvent::RewardsFunded { event_seq: 0, source: user, amount: 0, total_funded: 0 },
+            PledgeEvent::Expire { event_seq: 0, user, wallet, amount: 0, total_expired: 0 },
+            PledgeEvent::SaleStarted { event_seq: 0, start_time: 0 },
+            PledgeEvent::Sweep { event_seq: 0, vault: user, destination: user, amount: 0 },
+            PledgeEvent::NoOp { event_seq: 0, user, wallet },
+            PledgeEvent::ComplianceHold {
+                event_seq: 0,
+                user,
+                wallet,
+                admin: user,
+                previous_hold_until: 0,
+                hold_until: 0,
+                timestamp: 0,
+            },
+        ]
+        .iter()
+        .map(|event| borsh::to_vec(event).unwrap()[0])
+        .collect();
+        assert_eq!(discriminants, vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18]);
+        let purchase = PledgeEvent::Purchase {
+            event_seq: 0,
+            user,
+            wallet,
+            lamports_paid: 0,
+            pledge_tokens: 0,
+            rate: 0,
+            total_pledge_tokens: 0,
+            phase: 3,
+            phase_time_remaining: 60,
+        };
+        let data = borsh::to_vec(&purchase).unwrap();
+        assert_eq!(data.len(), 1 + 8 + 32 * 2 + 8 * 4 + 1 + 8);
+        assert_eq!(data[105..], [&[3u8][..], &60u64.to_le_bytes()].concat());
+        // The batch summary has the sequence number but no user.
+        let batch = borsh::to_vec(&PledgeEvent::RewardBatch { event_seq: 3, processed: 1, skipped: 2 }).unwrap();
+        assert_eq!(batch, [&[6u8][..], &3u64.to_le_bytes(), &1u32.to_le_bytes(), &2u32.to_le_bytes()].concat());
+    }
+}
@@ -0,0 +1,870 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{program_error::ProgramError, pubkey::Pubkey};
+
+// Instructions accepted by the pledge program. The Borsh variant tag doubles
+// as the old leading instruction byte, so 0 = BuyPledge, 1 = UpdateReward, ...
+// Instructions that depend on the time read it from the Clock sysvar. A Clock
+// sysvar account may be appended to any of them to supply the time instead.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub enum PledgeInstruction {
+    /// Pays `amount` lamports into the treasury for pledge tokens at the
+    /// current phase rate, locked for the config's lock tier `tier`.
+    /// `referrer` is the user state of another wallet that referred the buyer
+    /// and receives a bonus share of the tokens. With `allow_partial` a
+    /// purchase larger than the remaining supply buys what is left, charging
+    /// its share of `amount` rounded up, instead of failing.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` User state account
+    /// 1. `[writable, signer]` User wallet, pays for the pledge tokens
+    /// 2. `[writable]` Config account
+    /// 3. `[writable]` Treasury PDA derived from `[b"treasury"]`
+    /// 4. `[]` System program
+    /// 5. `[writable]` Referrer's user state account, only with a referrer
+    /// 6. `[writable]` BuyGroup PDA the buyer joined, only for group members
+    /// 7. `[]` Buyer's whitelist PDA derived from `[b"wl", wallet]`, only in
+    ///    the first sale phase
+    /// 8. `[writable]` Receipt PDA derived from `[b"receipt", wallet,
+    ///    purchase_count]`, with the user's purchase_count before this
+    ///    purchase as little-endian u32, only to record a receipt
+    ///
+    /// Accounts 6 to 8 move up in place of any that are left out.
+    BuyPledge { amount: u64, tier: u8, referrer: Option<Pubkey>, allow_partial: bool },
+    /// Permissionless crank, anyone may accrue rewards for a user. When
+    /// nothing accrues the user state is left untouched, and a NoOp event
+    /// takes the place of RewardUpdate.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` User state account
+    /// 1. `[writable]` Config account, which counts the events
+    UpdateReward,
+    /// Logs the user's rewards and sets a Borsh encoded `RewardsView` as the
+    /// return data. A user state yet to be initialized fails with
+    /// UninitializedAccount, or with `soft` answers a view with
+    /// `initialized` unset.
+    ///
+    /// Accounts expected:
+    /// 0. `[]` User state account
+    /// 1. `[]` Config account, whose reward rate applies
+    ViewRewards { soft: bool },
+    /// Claims `amount` of the accrued rewards; 0, or no amount at all, claims
+    /// everything.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` User state account
+    /// 1. `[signer]` User wallet, or its delegate
+    /// 2. `[writable]` Config account
+    /// 3. `[writable]` Solheist vault token account, the PDA derived from
+    ///    `[b"solhit_vault"]`, which is also its authority
+    /// 4. `[writable]` Solheist token account of the user's beneficiary,
+    ///    the wallet itself unless changed with SetBeneficiary
+    /// 5. `[]` Solheist mint
+    /// 6. `[]` SPL token program
+    ///
+    /// While the config charges a claim fee, claim_fee_bps of the claim,
+    /// rounded down, goes to the treasury instead. That needs, even when the
+    /// fee rounds down to 0:
+    /// 7. `[writable]` Associated Solheist token account of the treasury PDA
+    ///
+    /// With account 4 still empty, the claim first creates it as the
+    /// beneficiary's associated token account, paid for by account 1, which
+    /// must then be writable. That needs, from n = 7, or 8 after a fee account:
+    /// n. `[]` System program
+    /// n+1. `[]` Associated token program
+    /// n+2. `[]` Beneficiary wallet, unless it is account 1
+    ClaimRewards { amount: u64 },
+    /// Creates the user state PDA derived from `[b"user", wallet]`.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` User state account
+    /// 1. `[writable, signer]` User wallet, pays for the account
+    /// 2. `[]` System program
+    /// 3. `[writable]` Config account, counts the new user
+    InitializeUser,
+    /// Creates the global config PDA derived from `[b"config"]`. Run once.
+    /// Sale phases are measured from `sale_start_time`, and each phase sells at
+    /// most its entry in `phase_caps`. A `sale_start_time` of
+    /// `SALE_UNSCHEDULED` keeps the sale closed until StartSale. A non-zero `max_per_user` caps the
+    /// pledge tokens any one wallet may ever buy. `guardian` may later
+    /// EmergencySweep the vaults to `recovery_destination`; neither can be
+    /// changed, and the default pubkey for both sets no guardian.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1. `[writable, signer]` Admin, pays for the accounts
+    /// 2. `[]` System program
+    /// 3. `[writable]` Treasury PDA derived from `[b"treasury"]`
+    InitializeContract {
+        sale_start_time: u64,
+        phase_caps: [u64; 5],
+        max_per_user: u64,
+        guardian: Pubkey,
+        recovery_destination: Pubkey,
+    },
+    /// Withdraws the pledge tokens of every vested lot from the program vault.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` User state account
+    /// 1. `[signer]` User wallet
+    /// 2. `[writable]` Config account
+    /// 3. `[writable]` Pledge vault token account
+    /// 4. `[writable]` User pledge token account
+    /// 5. `[]` Pledge vault authority PDA derived from `[b"pledge_vault"]`
+    /// 6. `[]` SPL token program
+    UnlockPledge,
+    /// Releases every lot still vesting, minus the configured penalty, and
+    /// forfeits unclaimed rewards. The penalty goes back on sale out of the
+    /// global supply; the phase caps still count it as sold.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` User state account
+    /// 1. `[signer]` User wallet
+    /// 2. `[writable]` Config account
+    /// 3. `[writable]` Pledge vault token account
+    /// 4. `[writable]` User pledge token account
+    /// 5. `[]` Pledge vault authority PDA derived from `[b"pledge_vault"]`
+    /// 6. `[]` SPL token program
+    EarlyExit {},
+    /// Pauses or resumes BuyPledge, UpdateReward, ClaimRewards and
+    /// CompoundRewards.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1. `[signer]` Admin recorded at InitializeContract
+    SetPaused { paused: bool },
+    /// Replaces the sale phase durations and rates. Only allowed before
+    /// `sale_start_time`.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1. `[signer]` Admin
+    UpdatePhaseConfig { durations: [u64; 5], rates: [u64; 5] },
+    /// Nominates `new_admin` to take over the config. A later nomination
+    /// replaces it, and `Pubkey::default()` cancels it.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1. `[signer]` Current admin
+    NominateAdmin { new_admin: Pubkey },
+    /// Completes the handover started by NominateAdmin.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1. `[signer]` Nominated admin
+    AcceptAdmin,
+    /// Sends `amount` lamports from the treasury to the destination, keeping
+    /// the treasury rent exempt.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1. `[signer]` Admin
+    /// 2. `[writable]` Treasury PDA derived from `[b"treasury"]`
+    /// 3. `[writable]` Destination account
+    WithdrawTreasury { amount: u64 },
+    /// Pays `amount` base units of the configured payment mint into the
+    /// treasury for pledge tokens. The amount is scaled to 9 decimals before
+    /// the current phase rate applies.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` User state account
+    /// 1. `[signer]` User wallet
+    /// 2. `[writable]` Config account
+    /// 3. `[writable]` Buyer token account of the payment mint
+    /// 4. `[writable]` Treasury token account, held by the treasury PDA
+    /// 5. `[]` Payment mint
+    /// 6. `[]` SPL token program
+    /// 7. `[writable]` BuyGroup PDA the buyer joined, only for group members
+    /// 8. `[]` Buyer's whitelist PDA, only in the first sale phase; 7 outside
+    ///    a group
+    BuyPledgeWithToken { amount: u64 },
+    /// Sets the mint BuyPledgeWithToken accepts; `Pubkey::default()` disables
+    /// token payments.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1. `[signer]` Admin
+    SetPaymentMint { payment_mint: Pubkey },
+    /// Rewrites a user state written in an older version in the latest
+    /// layout, growing the account as needed. Does nothing for accounts that
+    /// are already current.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` User state account
+    /// 1. `[writable, signer]` Payer of any extra rent
+    /// 2. `[]` System program
+    MigrateUserState,
+    /// Permissionless crank running UpdateReward for many users at once.
+    /// User states that fail validation are skipped rather than failing the
+    /// batch. At most `MAX_REWARD_BATCH` user states per instruction. With
+    /// the bounty accounts, the cranker is paid the config's crank_fee for
+    /// every user state whose accrual added rewards, within the crank budget.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1.. `[writable]` User state accounts
+    /// Optionally, to collect the bounty:
+    /// n. `[writable]` Solheist vault token account
+    /// n+1. `[writable]` Cranker's Solheist token account
+    /// n+2. `[]` Solheist mint
+    /// n+3. `[]` SPL token program
+    UpdateRewardBatch,
+    /// Closes a user state with nothing locked and no unclaimed rewards and
+    /// returns its rent to the wallet. One that has ever bought, or is in a
+    /// buy group, fails with UserStateInUse: reopening it would forget both.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` User state account
+    /// 1. `[writable, signer]` User wallet, receives the rent
+    /// 2. `[writable]` Config account, counts the closed user
+    CloseUserState,
+    /// Sets the minimum number of seconds between a user's purchases; 0
+    /// disables the cooldown.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1. `[signer]` Admin
+    SetPurchaseCooldown { seconds: u64 },
+    /// Sets the percentage of a referred purchase credited to the referrer;
+    /// 0 disables referral bonuses.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1. `[signer]` Admin
+    SetReferralBonus { percent: u64 },
+    /// Whitelists `wallet` for the first sale phase by creating its whitelist
+    /// PDA.
+    ///
+    /// Accounts expected:
+    /// 0. `[]` Config account
+    /// 1. `[writable, signer]` Admin, pays the rent
+    /// 2. `[writable]` Whitelist PDA derived from `[b"wl", wallet]`
+    /// 3. `[]` System program
+    AddToWhitelist { wallet: Pubkey },
+    /// Closes the whitelist PDA of `wallet`, returning its rent to the admin.
+    ///
+    /// Accounts expected:
+    /// 0. `[]` Config account
+    /// 1. `[writable, signer]` Admin
+    /// 2. `[writable]` Whitelist PDA derived from `[b"wl", wallet]`
+    RemoveFromWhitelist { wallet: Pubkey },
+    /// Accrues the user's rewards up to now and locks all of them as new
+    /// pledge tokens at the config's compound rate, pushing the user's
+    /// vesting end back by the config's compound boost.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` User state account
+    /// 1. `[signer]` User wallet
+    /// 2. `[writable]` Config account
+    CompoundRewards,
+    /// Sends the user's future reward claims to the token account of `new`
+    /// instead of the wallet's own.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` User state account
+    /// 1. `[signer]` User wallet
+    SetBeneficiary { new: Pubkey },
+    /// Sets a Borsh encoded `Vec<VestingCheckpoint>` as the return data: the
+    /// user's unlockable pledge tokens and accrued rewards a month apart from
+    /// the earliest lock start to the last vesting end, at most
+    /// `MAX_VESTING_CHECKPOINTS` of them.
+    ///
+    /// Accounts expected:
+    /// 0. `[]` User state account
+    /// 1. `[]` Config account
+    GetVestingSchedule,
+    /// Closes the sale for good once its last finite phase has ended,
+    /// recording the pledge supply left unsold. Admin only; every later
+    /// purchase fails.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1. `[signer]` Admin
+    FinalizeSale,
+    /// Records the sale's progress in the snapshots PDA, a ring buffer of the
+    /// last `MAX_SNAPSHOTS` snapshots. Permissionless, but at most once per
+    /// the config's snapshot interval.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1. `[writable]` Snapshots PDA derived from `[b"snapshots"]`
+    /// 2. `[writable, signer]` Payer, creates the snapshots PDA on first use
+    /// 3. `[]` System program
+    RecordSnapshot,
+    /// Sets the latest Borsh encoded `Snapshot` as the return data.
+    ///
+    /// Accounts expected:
+    /// 0. `[]` Snapshots PDA
+    GetLatestSnapshot,
+    /// Sets the minimum number of seconds between snapshots; 0 removes the
+    /// limit.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1. `[signer]` Admin
+    SetSnapshotInterval { seconds: u64 },
+    /// Moves the wallet's locked pledge tokens and accrued rewards into
+    /// another wallet's user state, which must be initialized. The lots keep
+    /// their own lock and vesting times, so the destination ends up with the
+    /// earlier lock start and the later vesting end of the two.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Source user state account
+    /// 1. `[signer]` Source user wallet
+    /// 2. `[writable]` Destination user state account
+    /// 3. `[writable]` Config account, which counts the events
+    TransferPosition,
+    /// Lets `delegate` claim the user's rewards, which still go to the
+    /// beneficiary. Replaces any earlier delegate.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` User state account
+    /// 1. `[signer]` User wallet
+    SetDelegate { delegate: Pubkey },
+    /// Removes the user's delegate.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` User state account
+    /// 1. `[signer]` User wallet
+    RevokeDelegate,
+    /// Moves the team tokens vested so far and not yet released from the
+    /// Solheist vault to a team token account. Admin only, from the config's
+    /// team lock cliff on.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1. `[signer]` Admin
+    /// 2. `[writable]` Solheist vault PDA derived from `[b"solhit_vault"]`
+    /// 3. `[writable]` Team's Solheist token account
+    /// 4. `[]` Solheist mint
+    /// 5. `[]` SPL token program
+    ReleaseTeamTokens,
+    /// Sets a Borsh encoded `BuyQuote` for a BuyPledge of `amount` lamports
+    /// on the default tier as the return data, without writing any account.
+    /// A quote past the remaining supply is cut down the way BuyPledge with
+    /// `allow_partial` would be. Whitelisting is not checked.
+    ///
+    /// Accounts expected:
+    /// 0. `[]` Config account
+    /// 1. `[]` Buyer's user state account, optional; without it the quote
+    ///    ignores the buyer's cap and cooldown
+    QuoteBuy { amount: u64 },
+    /// Sets how many seconds lots keep accruing rewards past their vesting
+    /// end, at the pace they accrued while vesting; 0 stops them at the
+    /// vesting end and u64::MAX never does.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1. `[signer]` Admin
+    SetPostVestingGrace { seconds: u64 },
+    /// Upgrades a config written by an older program to the current layout
+    /// version, growing it with rent from the admin. Every other instruction
+    /// refuses a config at another version. Does nothing to a current one.
+    /// One from before version 8 leaves users uncounted from then on, its
+    /// `initialized_users` set to `USERS_UNCOUNTED`.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1. `[writable, signer]` Admin
+    /// 2. `[]` System program
+    MigrateConfig,
+    /// Moves `amount` Solheist from an admin token account into the vault
+    /// rewards are claimed from, and adds it to the config's rewards_funded.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1. `[signer]` Admin, owner of account 2
+    /// 2. `[writable]` Admin's Solheist token account
+    /// 3. `[writable]` Solheist vault PDA derived from `[b"solhit_vault"]`
+    /// 4. `[]` Solheist mint
+    /// 5. `[]` SPL token program
+    FundRewards { amount: u64 },
+    /// Sets a Borsh encoded `RewardPreview` of what UpdateReward would do
+    /// right now as the return data, without writing any account or emitting
+    /// an event. `soft` treats a user state yet to be initialized as
+    /// ViewRewards does.
+    ///
+    /// Accounts expected:
+    /// 0. `[]` User state account
+    /// 1. `[]` Config account
+    PreviewReward { soft: bool },
+    /// Adds the user to the buy group derived from `[b"group", group_id]`,
+    /// creating it on the first join, and counts everything the user has
+    /// bought so far towards the group. A wallet joins at most one group and
+    /// stays in it. Joining a group already at the cap is allowed, but with
+    /// the config's enforce_group_cap no member can buy any more.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` User state account
+    /// 1. `[writable, signer]` User wallet, pays for a new group
+    /// 2. `[writable]` BuyGroup PDA
+    /// 3. `[]` System program
+    JoinGroup { group_id: Pubkey },
+    /// Turns on or off whether max_per_user caps each buy group's combined
+    /// purchases as well as each wallet's.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1. `[signer]` Admin
+    SetGroupCapEnforced { enforced: bool },
+    /// Grows the user state account to `new_len` bytes, at least the size of
+    /// the latest layout, and rewrites the state in that layout. The wallet
+    /// pays the extra rent. A length below the account's current size fails;
+    /// accounts never shrink.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` User state account
+    /// 1. `[writable, signer]` User wallet
+    /// 2. `[]` System program
+    ResizeUserState { new_len: u64 },
+    /// Sets the Solheist bounty UpdateRewardBatch pays per user state whose
+    /// accrual added rewards, and the total budget for bounties. Bounties
+    /// stop once what has been paid reaches the budget.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1. `[signer]` Admin
+    SetCrankBounty { crank_fee: u64, crank_budget: u64 },
+    /// Sets how many seconds after a user's vesting end unclaimed rewards
+    /// expire, 0 for never, and whether anyone may run ExpireRewards.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1. `[signer]` Admin
+    SetRewardExpiry { seconds: u64, permissionless: bool },
+    /// Moves a user's unclaimed rewards into the config's expired_rewards,
+    /// once reward_expiry_secs have passed since the user's vesting end or,
+    /// with nothing locked, since the user's last activity. Fails with
+    /// NotExpired before then.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` User state account
+    /// 1. `[writable]` Config account
+    /// 2. `[signer]` Admin, left out when the config lets anyone expire rewards
+    ExpireRewards,
+    /// Sets a Borsh encoded `GlobalStats` as the return data, without
+    /// writing any account. A config migrated from before version 8 reports
+    /// `initialized_users` as `USERS_UNCOUNTED`, as it never saw the users
+    /// from back then. Before the sale starts `active_phase` is
+    /// `PHASE_NOT_STARTED`.
+    ///
+    /// Accounts expected:
+    /// 0. `[]` Config account
+    GetGlobalStats,
+    /// Opens a sale initialized with a `sale_start_time` of
+    /// `SALE_UNSCHEDULED`, at `start_time` when given or now otherwise. The
+    /// team lock starts along with it. Admin only, and only once: fails with
+    /// SaleStarted when the sale already has a start time, and with
+    /// InvalidStartTime for one in the past.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1. `[signer]` Admin
+    StartSale { start_time: Option<u64> },
+    /// Zeroes the user state of `wallet` when it fails to load, so the
+    /// wallet can start over with InitializeUser. Admin only; fails with
+    /// StateNotCorrupted for a state that loads, or is already zeroed.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` User state account of `wallet`
+    /// 1. `[writable]` Config account, stops counting the user if it counted
+    ///    the zeroed state
+    /// 2. `[signer]` Admin
+    RepairUserState { wallet: Pubkey },
+    /// Sets the share of each claim, in basis points, ClaimRewards sends to
+    /// the treasury, 0 for none. At most 10_000.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1. `[signer]` Admin
+    SetClaimFee { bps: u16 },
+    /// Sets the decimals purchases convert between: those of the payment,
+    /// 9 for lamports, and those of the pledge token. A phase rate of 100
+    /// buys one whole pledge token per whole payment token. Fails with
+    /// SaleStarted once anything has sold.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1. `[signer]` Admin
+    SetTokenDecimals { payment_decimals: u8, pledge_decimals: u8 },
+    /// Merges a second user state of the same owner, left over from before
+    /// user states had to be PDAs, into the owner's PDA: lots, rewards and
+    /// purchase history add up, with the earlier lock start and the later
+    /// vesting end. The second account is zeroed and closed.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` User state account, the PDA derived from
+    ///    `[b"user", wallet]`, which keeps the merged state
+    /// 1. `[writable]` Second user state account of the same owner
+    /// 2. `[writable, signer]` User wallet, receives the second account's rent
+    /// 3. `[]` Config account
+    MergeUserStates,
+    /// Moves the whole balance of a vault to a token account of the recovery
+    /// destination. Guardian only, and only while the contract is paused.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1. `[signer]` Guardian recorded at InitializeContract
+    /// 2. `[writable]` Vault: the pledge vault, or the Solheist vault PDA
+    ///    derived from `[b"solhit_vault"]`
+    /// 3. `[writable]` Token account of the vault's mint held by the recovery
+    ///    destination
+    /// 4. `[]` SPL token program
+    /// 5. `[]` Pledge vault authority PDA derived from `[b"pledge_vault"]`,
+    ///    when sweeping the pledge vault
+    EmergencySweep,
+    /// Turns on or off the NoOp event an UpdateReward emits when it finds
+    /// nothing to accrue. Off, such an UpdateReward emits nothing.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Config account
+    /// 1. `[signer]` Admin
+    SetNoOpEvents { enabled: bool },
+    /// Puts the user state of `wallet` on a compliance hold until
+    /// `hold_until`: UnlockPledge, EarlyExit, ClaimRewards and
+    /// TransferPosition fail with ComplianceHold before then, while rewards
+    /// keep accruing. A `hold_until` of 0 lifts the hold. Admin only.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` User state account of `wallet`
+    /// 1. `[writable]` Config account, which counts the events
+    /// 2. `[signer]` Admin
+    SetComplianceHold { wallet: Pubkey, hold_until: u64 },
+}
+
+const BUY_PLEDGE_TAG: u8 = 0;
+const VIEW_REWARDS_TAG: u8 = 2;
+const CLAIM_REWARDS_TAG: u8 = 3;
+const INITIALIZE_CONTRACT_TAG: u8 = 5;
+const PREVIEW_REWARD_TAG: u8 = 37;
+
+impl PledgeInstruction {
+    pub fn unpack(instruction_data: &[u8]) -> Result<Self, ProgramError> {
+        // BuyPledge used to carry only the amount; keep reading it on the
+        // default tier without a referrer.
+        if let [BUY_PLEDGE_TAG, amount @ ..] = instruction_data {
+            if let Ok(amount) = <[u8; 8]>::try_from(amount) {
+                return Ok(Self::BuyPledge {
+                    amount: u64::from_le_bytes(amount),
+                    tier: crate::DEFAULT_LOCK_TIER,
+                    referrer: None,
+                    allow_partial: false,
+                });
+            }
+            // Before `allow_partial` it carried the amount, tier and referrer:
+            // 8 + 1 + 1 bytes, or 8 + 1 + 33 with a referrer.
+            if matches!(amount.len(), 10 | 42) {
+                let mut data = instruction_data.to_vec();
+                data.push(0);
+                if let Ok(instruction) = Self::try_from_slice(&data) {
+                    return Ok(instruction);
+                }
+            }
+        }
+        // ClaimRewards used to carry no amount; a bare tag still claims everything.
+        if instruction_data == [CLAIM_REWARDS_TAG] {
+            return Ok(Self::ClaimRewards { amount: 0 });
+        }
+        // Nor did the views carry `soft`; a bare tag fails on an uninitialized
+        // user state as they always have.
+        if instruction_data == [VIEW_REWARDS_TAG] {
+            return Ok(Self::ViewRewards { soft: false });
+        }
+        if instruction_data == [PREVIEW_REWARD_TAG] {
+            return Ok(Self::PreviewReward { soft: false });
+        }
+        // InitializeContract used to carry only the sale start; keep the default
+        // phase caps and no per-user cap.
+        if let [INITIALIZE_CONTRACT_TAG, sale_start_time @ ..] = instruction_data {
+            if let Ok(sale_start_time) = <[u8; 8]>::try_from(sale_start_time) {
+                return Ok(Self::InitializeContract {
+                    sale_start_time: u64::from_le_bytes(sale_start_time),
+                    phase_caps: crate::PHASE_CAPS,
+                    max_per_user: 0,
+                    guardian: Pubkey::default(),
+                    recovery_destination: Pubkey::default(),
+                });
+            }
+            // Before the guardian it carried the sale start, the phase caps and
+            // the per-user cap, 8 + 40 + 8 bytes; read it without a guardian.
+            if sale_start_time.len() == 56 {
+                let mut data = instruction_data.to_vec();
+                data.extend_from_slice(&[0; 64]);
+                if let Ok(instruction) = Self::try_from_slice(&data) {
+                    return Ok(instruction);
+                }
+            }
+        }
+        Self::try_from_slice(instruction_data).map_err(|_| ProgramError::InvalidInstructionData)
+    }
+
+    pub fn pack(&self) -> Vec<u8> {
+        borsh::to_vec(self).expect("PledgeInstruction serialization cannot fail")
+    }
+
+    // The variant name, for logs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::BuyPledge { .. } => "BuyPledge",
+            Self::UpdateReward => "UpdateReward",
+            Self::ViewRewards { .. } => "ViewRewards",
+            Self::ClaimRewards { .. } => "ClaimRewards",
+            Self::InitializeUser => "InitializeUser",
+            Self::InitializeContract { .. } => "InitializeContract",
+            Self::UnlockPledge => "UnlockPledge",
+            Self::EarlyExit { .. } => "EarlyExit",
+            Self::SetPaused { .. } => "SetPaused",
+            Self::UpdatePhaseConfig { .. } => "UpdatePhaseConfig",
+            Self::NominateAdmin { .. } => "NominateAdmin",
+            Self::AcceptAdmin => "AcceptAdmin",
+            Self::WithdrawTreasury { .. } => "WithdrawTreasury",
+            Self::BuyPledgeWithToken { .. } => "BuyPledgeWithToken",
+            Self::SetPaymentMint { .. } => "SetPaymentMint",
+            Self::MigrateUserState => "MigrateUserState",
+            Self::UpdateRewardBatch => "UpdateRewardBatch",
+            Self::CloseUserState => "CloseUserState",
+            Self::SetPurchaseCooldown { .. } => "SetPurchaseCooldown",
+            Self::SetReferralBonus { .. } => "SetReferralBonus",
+            Self::AddToWhitelist { .. } => "AddToWhitelist",
+            Self::RemoveFromWhitelist { .. } => "RemoveFromWhitelist",
+            Self::CompoundRewards => "CompoundRewards",
+            Self::SetBeneficiary { .. } => "SetBeneficiary",
+            Self::GetVestingSchedule => "GetVestingSchedule",
+            Self::FinalizeSale => "FinalizeSale",
+            Self::RecordSnapshot => "RecordSnapshot",
+            Self::GetLatestSnapshot => "GetLatestSnapshot",
+            Self::SetSnapshotInterval { .. } => "SetSnapshotInterval",
+            Self::TransferPosition => "TransferPosition",
+            Self::SetDelegate { .. } => "SetDelegate",
+            Self::RevokeDelegate => "RevokeDelegate",
+            Self::ReleaseTeamTokens => "ReleaseTeamTokens",
+            Self::QuoteBuy { .. } => "QuoteBuy",
+            Self::SetPostVestingGrace { .. } => "SetPostVestingGrace",
+            Self::MigrateConfig => "MigrateConfig",
+            Self::FundRewards { .. } => "FundRewards",
+            Self::PreviewReward { .. } => "PreviewReward",
+            Self::JoinGroup { .. } => "JoinGroup",
+            Self::SetGroupCapEnforced { .. } => "SetGroupCapEnforced",
+            Self::ResizeUserState { .. } => "ResizeUserState",
+            Self::SetCrankBounty { .. } => "SetCrankBounty",
+            Self::SetRewardExpiry { .. } => "SetRewardExpiry",
+            Self::ExpireRewards => "ExpireRewards",
+            Self::GetGlobalStats => "GetGlobalStats",
+            Self::StartSale { .. } => "StartSale",
+            Self::RepairUserState { .. } => "RepairUserState",
+            Self::SetClaimFee { .. } => "SetClaimFee",
+            Self::SetTokenDecimals { .. } => "SetTokenDecimals",
+            Self::MergeUserStates => "MergeUserStates",
+            Self::EmergencySweep => "EmergencySweep",
+            Self::SetNoOpEvents { .. } => "SetNoOpEvents",
+            Self::SetComplianceHold { .. } => "SetComplianceHold",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instruction_round_trip() {
+        let instructions = [
+            PledgeInstruction::BuyPledge { amount: 1_000, tier: 0, referrer: None, allow_partial: false },
+            PledgeInstruction::BuyPledge { amount: u64::MAX, tier: 2, referrer: Some(Pubkey::new_unique()), allow_partial: true },
+            PledgeInstruction::UpdateReward,
+            PledgeInstruction::ViewRewards { soft: false },
+            PledgeInstruction::ViewRewards { soft: true },
+            PledgeInstruction::ClaimRewards { amount: 0 },
+            PledgeInstruction::ClaimRewards { amount: 250 },
+            PledgeInstruction::InitializeUser,
+            PledgeInstruction::InitializeContract {
+                sale_start_time: 1_700_000_000,
+                phase_caps: [20_000_000; 5],
+                max_per_user: 0,
+                guardian: Pubkey::default(),
+                recovery_destination: Pubkey::default(),
+            },
+            PledgeInstruction::InitializeContract {
+                sale_start_time: 0,
+                phase_caps: [0, 1, 2, 3, u64::MAX],
+                max_per_user: 5_000,
+                guardian: Pubkey::new_unique(),
+                recovery_destination: Pubkey::new_unique(),
+            },
+            PledgeInstruction::UnlockPledge,
+            PledgeInstruction::EarlyExit {},
+            PledgeInstruction::SetPaused { paused: true },
+            PledgeInstruction::SetPaused { paused: false },
+            PledgeInstruction::UpdatePhaseConfig { durations: [1, 2, 3, 4, u64::MAX], rates: [5, 4, 3, 2, 1] },
+            PledgeInstruction::NominateAdmin { new_admin: Pubkey::new_unique() },
+            PledgeInstruction::AcceptAdmin,
+            PledgeInstruction::WithdrawTreasury { amount: 1_000_000 },
+            PledgeInstruction::BuyPledgeWithToken { amount: 5_000_000 },
+            PledgeInstruction::SetPaymentMint { payment_mint: Pubkey::new_unique() },
+            PledgeInstruction::MigrateUserState,
+            PledgeInstruction::UpdateRewardBatch,
+            PledgeInstruction::CloseUserState,
+            PledgeInstruction::SetPurchaseCooldown { seconds: 3_600 },
+            PledgeInstruction::SetReferralBonus { percent: 10 },
+            PledgeInstruction::AddToWhitelist { wallet: Pubkey::new_unique() },
+            PledgeInstruction::RemoveFromWhitelist { wallet: Pubkey::new_unique() },
+            PledgeInstruction::CompoundRewards,
+            PledgeInstruction::SetBeneficiary { new: Pubkey::new_unique() },
+            PledgeInstruction::GetVestingSchedule,
+            PledgeInstruction::FinalizeSale,
+            PledgeInstruction::RecordSnapshot,
+            PledgeInstruction::GetLatestSnapshot,
+            PledgeInstruction::SetSnapshotInterval { seconds: 86_400 },
+            PledgeInstruction::TransferPosition,
+            PledgeInstruction::SetDelegate { delegate: Pubkey::new_unique() },
+            PledgeInstruction::RevokeDelegate,
+            PledgeInstruction::ReleaseTeamTokens,
+            PledgeInstruction::QuoteBuy { amount: 1_000 },
+            PledgeInstruction::SetPostVestingGrace { seconds: 2_592_000 },
+            PledgeInstruction::MigrateConfig,
+            PledgeInstruction::FundRewards { amount: 1_000_000 },
+            PledgeInstruction::PreviewReward { soft: false },
+            PledgeInstruction::PreviewReward { soft: true },
+            PledgeInstruction::JoinGroup { group_id: Pubkey::new_unique() },
+            PledgeInstruction::SetGroupCapEnforced { enforced: true },
+            PledgeInstruction::ResizeUserState { new_len: 1_024 },
+            PledgeInstruction::SetCrankBounty { crank_fee: 10, crank_budget: 1_000 },
+            PledgeInstruction::SetRewardExpiry { seconds: 31_536_000, permissionless: true },
+            PledgeInstruction::ExpireRewards,
+            PledgeInstruction::GetGlobalStats,
+            PledgeInstruction::StartSale { start_time: None },
+            PledgeInstruction::StartSale { start_time: Some(1_700_000_000) },
+            PledgeInstruction::RepairUserState { wallet: Pubkey::new_unique() },
+            PledgeInstruction::SetClaimFee { bps: 100 },
+            PledgeInstruction::SetTokenDecimals { payment_decimals: 9, pledge_decimals: 6 },
+            PledgeInstruction::MergeUserStates,
+            PledgeInstruction::EmergencySweep,
+            PledgeInstruction::SetNoOpEvents { enabled: false },
+            PledgeInstruction::SetComplianceHold { wallet: Pubkey::new_unique(), hold_until: 1_800_000_000 },
+        ];
+
+        for instruction in instructions {
+            let data = instruction.pack();
+            assert_eq!(PledgeInstruction::unpack(&data).unwrap(), instruction);
+            assert_eq!(format!("{:?}", instruction).split([' ', '{']).next(), Some(instruction.name()));
+        }
+    }
+
+    #[test]
+    fn test_instruction_layout_matches_tag_byte() {
+        let data = PledgeInstruction::BuyPledge { amount: 500, tier: 2, referrer: None, allow_partial: true }.pack();
+        let mut expected = vec![0u8];
+        expected.extend_from_slice(&500u64.to_le_bytes());
+        expected.extend_from_slice(&[2, 0, 1]);
+        assert_eq!(data, expected);
+
+        assert_eq!(PledgeInstruction::UpdateReward.pack(), vec![1]);
+        assert_eq!(PledgeInstruction::ViewRewards { soft: true }.pack(), vec![2, 1]);
+        assert_eq!(PledgeInstruction::ClaimRewards { amount: 0 }.pack(), vec![3, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(PledgeInstruction::InitializeUser.pack(), vec![4]);
+        let (guardian, recovery_destination) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let data = PledgeInstruction::InitializeContract {
+            sale_start_time: 7,
+            phase_caps: [1, 2, 3, 4, 5],
+            max_per_user: 6,
+            guardian,
+            recovery_destination,
+        }
+        .pack();
+        let mut expected = vec![5u8];
+        for value in [7u64, 1, 2, 3, 4, 5, 6] {
+            expected.extend_from_slice(&value.to_le_bytes());
+        }
+        expected.extend_from_slice(guardian.as_ref());
+        expected.extend_from_slice(recovery_destination.as_ref());
+        assert_eq!(data, expected);
+        assert_eq!(PledgeInstruction::UnlockPledge.pack(), vec![6]);
+        assert_eq!(PledgeInstruction::EarlyExit {}.pack(), vec![7]);
+        assert_eq!(PledgeInstruction::SetPaused { paused: true }.pack(), vec![8, 1]);
+    }
+
+    #[test]
+    fn test_buy_pledge_without_referrer() {
+        let mut data = vec![0];
+        data.extend_from_slice(&500u64.to_le_bytes());
+        assert_eq!(
+            PledgeInstruction::unpack(&data),
+            Ok(PledgeInstruction::BuyPledge { amount: 500, tier: crate::DEFAULT_LOCK_TIER, referrer: None, allow_partial: false })
+        );
+        data.push(1);
+        assert_eq!(PledgeInstruction::unpack(&data), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn test_buy_pledge_without_allow_partial() {
+        let referrer = Pubkey::new_unique();
+        for referrer in [None, Some(referrer)] {
+            let mut data = vec![0];
+            data.extend_from_slice(&500u64.to_le_bytes());
+            data.push(1);
+            data.extend_from_slice(&borsh::to_vec(&referrer).unwrap());
+            assert_eq!(
+                PledgeInstruction::unpack(&data),
+                Ok(PledgeInstruction::BuyPledge { amount: 500, tier: 1, referrer, allow_partial: false })
+            );
+        }
+    }
+
+    #[test]
+    fn test_claim_rewards_without_amount() {
+        assert_eq!(PledgeInstruction::unpack(&[3]), Ok(PledgeInstruction::ClaimRewards { amount: 0 }));
+        assert_eq!(PledgeInstruction::unpack(&[3, 1]), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn test_views_without_soft() {
+        assert_eq!(PledgeInstruction::unpack(&[2]), Ok(PledgeInstruction::ViewRewards { soft: false }));
+        assert_eq!(PledgeInstruction::unpack(&[37]), Ok(PledgeInstruction::PreviewReward { soft: false }));
+        assert_eq!(PledgeInstruction::PreviewReward { soft: false }.pack(), vec![37, 0]);
+        assert_eq!(PledgeInstruction::unpack(&[2, 2]), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn test_initialize_contract_without_phase_caps() {
+        assert_eq!(
+            PledgeInstruction::unpack(&[5, 1, 0, 0, 0, 0, 0, 0, 0]),
+            Ok(PledgeInstruction::InitializeContract {
+                sale_start_time: 1,
+                phase_caps: crate::PHASE_CAPS,
+                max_per_user: 0,
+                guardian: Pubkey::default(),
+                recovery_destination: Pubkey::default(),
+            })
+        );
+        assert_eq!(PledgeInstruction::unpack(&[5, 1]), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn test_initialize_contract_without_guardian() {
+        let mut data = vec![5];
+        for value in [7u64, 1, 2, 3, 4, 5, 6] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        assert_eq!(
+            PledgeInstruction::unpack(&data),
+            Ok(PledgeInstruction::InitializeContract {
+                sale_start_time: 7,
+                phase_caps: [1, 2, 3, 4, 5],
+                max_per_user: 6,
+                guardian: Pubkey::default(),
+                recovery_destination: Pubkey::default(),
+            })
+        );
+        data.push(0);
+        assert_eq!(PledgeInstruction::unpack(&data), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn test_malformed_instruction_data() {
+        assert_eq!(PledgeInstruction::unpack(&[]), Err(ProgramError::InvalidInstructionData));
+        assert_eq!(PledgeInstruction::unpack(&[0]), Err(ProgramError::InvalidInstructionData));
+        assert_eq!(PledgeInstruction::unpack(&[0, 1, 2, 3, 4]), Err(ProgramError::InvalidInstructionData));
+        assert_eq!(PledgeInstruction::unpack(&[42]), Err(ProgramError::InvalidInstructionData));
+        // Trailing bytes after a complete instruction are rejected too.
+        assert_eq!(PledgeInstruction::unpack(&[1, 0]), Err(ProgramError::InvalidInstructionData));
+    }
+}
@@ -1,391 +1,8865 @@
+// Standard output does not exist on-chain; debug output goes through msg!
+// behind the `debug-logs` feature instead.
+#![cfg_attr(not(test), deny(clippy::print_stdout, clippy::print_stderr, clippy::dbg_macro))]
+
 use borsh::{BorshDeserialize, BorshSerialize};
-use borsh::io::Write;
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
-    entrypoint,
     entrypoint::ProgramResult,
     msg,
+    program::{invoke, invoke_signed, set_return_data},
     program_error::ProgramError,
+    program_pack::Pack,
     pubkey::Pubkey,
-    sysvar::{clock::Clock, Sysvar},
+    system_instruction, system_program,
+    sysvar::{clock, clock::Clock, rent::Rent, Sysvar},
 };
-use std::convert::TryInto;
+
+#[cfg(feature = "client")]
+pub mod client;
+pub mod error;
+pub mod event;
+pub mod instruction;
+pub mod logic;
+pub mod math;
+pub mod validation;
+pub mod zero_copy;
+
+pub use error::PledgeError;
+pub use event::{emit_event, PledgeEvent, EVENT_SCHEMA_VERSION};
+pub use instruction::PledgeInstruction;
+pub use zero_copy::UserStateData;
+use validation::{assert_is_program, assert_not_executable, assert_rent_exempt, assert_writable};
 
 // Define constants
 pub const TOTAL_PLEDGE_SUPPLY: u64 = 100_000_000;
 pub const TOTAL_SOLHIT_SUPPLY: u64 = 14_000_000;
 pub const LOCKED_SOLHIT_TOKENS: u64 = 4_000_000;
+// The locked Solheist is the team's tranche. It vests linearly over two years
+// from the start of the sale, but none of it is released in the first year.
+pub const TEAM_UNLOCK_CLIFF: u64 = 31_536_000;
+pub const TEAM_UNLOCK_DURATION: u64 = 63_072_000;
 pub const VESTING_PERIOD: u64 = 63_072_000;
-pub const REWARD_RATE: u64 = 40;
+// Solheist earned per pledge token over a full lock at 1x, in basis points:
+// 5%. Even with every pledge token sold at the 2x tier the rewards come to
+// TOTAL_SOLHIT_SUPPLY - LOCKED_SOLHIT_TOKENS and no more.
+pub const REWARD_RATE_BPS: u64 = 500;
 
 pub const PHASE_DURATIONS: [u64; 5] = [1_296_000, 1_296_000, 1_296_000, 1_296_000, u64::MAX];
 pub const PHASE_RATES: [u64; 5] = [200, 175, 150, 125, 100];
+// Pledge tokens each phase may sell, so no single phase takes the whole supply.
+pub const PHASE_CAPS: [u64; 5] = [20_000_000; 5];
+// A sale_start_time of SALE_UNSCHEDULED leaves the sale closed until the admin
+// opens it with StartSale.
+pub const SALE_UNSCHEDULED: u64 = u64::MAX;
+// The active_phase GetGlobalStats reports before the sale starts, with the
+// seconds left until it does, or u64::MAX while it is unscheduled.
+pub const PHASE_NOT_STARTED: u8 = u8::MAX;
+// The initialized_users of a config migrated from before it counted users,
+// whose user states from back then it never saw. It stays that way for good.
+pub const USERS_UNCOUNTED: u64 = u64::MAX;
+
+// Share of the exited pledge tokens kept by the program on an early exit.
+pub const EARLY_EXIT_PENALTY_PERCENT: u64 = 25;
+
+// Share of a referred purchase's pledge tokens credited to the referrer.
+pub const REFERRAL_BONUS_PERCENT: u64 = 5;
+
+// Pledge tokens locked per 100 Solheist rewards compounded, and how far each
+// compound pushes the user's vesting end back.
+pub const COMPOUND_RATE: u64 = 100;
+pub const COMPOUND_VESTING_BOOST: u64 = 2_592_000;
+
+// Lock lengths a buyer can pick from, with the multiplier in basis points
+// applied to the reward rate: 6 months at 0.5x, 12 at 1x and 24 at 2x.
+pub const LOCK_TIERS: [(u64, u64); 3] = [(15_768_000, 5_000), (31_536_000, 10_000), (63_072_000, 20_000)];
+// The tier of purchases that do not pick one, the 1x tier.
+pub const DEFAULT_LOCK_TIER: u8 = 1;
+pub const BASIS_POINTS: u64 = 10_000;
+
+pub const MAX_PLEDGE_LOTS: usize = 16;
+
+// How far the clock may read behind a user's last activity before a handler
+// refuses to act on that user. Validators' clocks disagree by a few seconds,
+// but a time well before what the user has already done means the clock went
+// backwards.
+pub const MAX_CLOCK_SKEW: u64 = 60;
+
+// User states one UpdateRewardBatch may accrue. Accruing a user with every lot
+// slot in use costs a few thousand compute units, so a full batch stays well
+// inside the default 200k budget; a legacy transaction cannot list many more
+// accounts anyway.
+pub const MAX_REWARD_BATCH: usize = 20;
+
+// GetVestingSchedule spaces its checkpoints a month apart, a twelfth of a
+// 365 day year, and returns at most this many so the encoded list stays
+// within the 1024 bytes of return data.
+pub const SECONDS_PER_MONTH: u64 = 2_628_000;
+pub const MAX_VESTING_CHECKPOINTS: usize = 40;
+
+// RecordSnapshot keeps the last MAX_SNAPSHOTS snapshots of the sale, one per
+// snapshot interval at most; the interval starts out at a day.
+pub const MAX_SNAPSHOTS: usize = 30;
+pub const SNAPSHOT_INTERVAL: u64 = 86_400;
+
+// How long a lot keeps accruing past its vesting end, at the pace it accrued
+// while vesting. Lots stop at their vesting end unless the admin sets a grace
+// period; u64::MAX lets them accrue until they are unlocked.
+pub const POST_VESTING_GRACE: u64 = 0;
+
+// Layout version written in every UserState, after the discriminator.
+pub const USER_STATE_VERSION: u8 = 9;
+
+// The first eight bytes of every user state and config, the start of the
+// SHA-256 of "pledge:user" and "pledge:config", so neither passes for the
+// other. Accounts written before them start with a version byte or the
+// initialized flag instead, small numbers neither discriminator starts with.
+pub const USER_STATE_DISCRIMINATOR: [u8; 8] = [0x76, 0x29, 0xd8, 0x67, 0x7b, 0xc2, 0x96, 0xec];
+pub const CONFIG_DISCRIMINATOR: [u8; 8] = [0xf6, 0x82, 0xd0, 0xff, 0x94, 0x4d, 0xdd, 0x7d];
+// The user state version that brought in the discriminator. Later versions
+// keep it; earlier ones start with their version byte.
+pub const USER_STATE_DISCRIMINATOR_VERSION: u8 = 8;
+
+// Layout version of the ContractConfig this program reads. Version 1 configs
+// predate the field; MigrateConfig upgrades them.
+pub const CONFIG_VERSION: u16 = 12;
 
-// Define state variables
-pub struct PledgeContract {
+// Decimals of a lamport, the default payment_decimals. Token payments are
+// scaled to payment_decimals before the phase rate applies, so a whole
+// payment token buys what one SOL does.
+pub const PAYMENT_DECIMALS: u8 = 9;
+// The default pledge_decimals, matching PAYMENT_DECIMALS so a lamport buys
+// one base unit at a 100% rate.
+pub const PLEDGE_DECIMALS: u8 = 9;
+
+pub const USER_STATE_SEED: &[u8] = b"user";
+pub const CONFIG_SEED: &[u8] = b"config";
+pub const PLEDGE_VAULT_SEED: &[u8] = b"pledge_vault";
+pub const SOLHIT_VAULT_SEED: &[u8] = b"solhit_vault";
+pub const TREASURY_SEED: &[u8] = b"treasury";
+pub const WHITELIST_SEED: &[u8] = b"wl";
+pub const SNAPSHOTS_SEED: &[u8] = b"snapshots";
+pub const GROUP_SEED: &[u8] = b"group";
+pub const RECEIPT_SEED: &[u8] = b"receipt";
+
+// Define state variables. A single instance lives in the `[b"config"]` PDA.
+#[derive(BorshSerialize, BorshDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ContractConfig {
+    pub discriminator: [u8; 8],
+    pub is_initialized: bool,
     pub total_pledge_supply: u64,
     pub solhit_token_supply: u64,
     pub locked_solhit_tokens: u64,
+    // No longer read: purchases take their lock length from lock_tiers. Kept
+    // so existing configs keep their layout.
     pub vesting_period: u64,
-    pub reward_rate: u64,
+    pub reward_rate_bps: u64,
     pub phase_durations: [u64; 5],
     pub phase_rates: [u64; 5],
+    pub total_pledge_sold: u64,
+    pub sale_start_time: u64,
+    pub early_exit_penalty_percent: u64,
+    pub total_penalties: u64,
+    pub global_solhit_claimed: u64,
+    pub bump: u8,
+    pub phase_caps: [u64; 5],
+    pub phase_sold: [u64; 5],
+    pub max_per_user: u64,
+    pub admin: Pubkey,
+    pub paused: bool,
+    pub pending_admin: Option<Pubkey>,
+    pub total_withdrawn: u64,
+    pub payment_mint: Pubkey,
+    pub purchase_cooldown_secs: u64,
+    pub referral_bonus_percent: u64,
+    pub lock_tiers: [(u64, u64); 3],
+    pub compound_rate: u64,
+    pub compound_vesting_boost: u64,
+    pub event_seq: u64,
+    pub sale_finalized: bool,
+    pub unsold_supply: u64,
+    pub last_snapshot_time: u64,
+    pub snapshot_interval_secs: u64,
+    pub team_lock: TeamLock,
+    pub team_released: u64,
+    pub post_vesting_grace_secs: u64,
+    pub config_version: u16,
+    pub rewards_funded: u64,
+    pub enforce_group_cap: bool,
+    pub crank_fee: u64,
+    pub crank_budget: u64,
+    pub crank_bounties_paid: u64,
+    pub reward_expiry_secs: u64,
+    pub expire_permissionless: bool,
+    pub expired_rewards: u64,
+    // Open user states, or USERS_UNCOUNTED.
+    pub initialized_users: u64,
+    pub claim_fee_bps: u16,
+    pub payment_decimals: u8,
+    pub pledge_decimals: u8,
+    // The only key allowed to EmergencySweep a vault, and the wallet whose
+    // token accounts receive the sweep. Fixed at InitializeContract; the
+    // default pubkey for both leaves the contract without a guardian.
+    pub guardian: Pubkey,
+    pub recovery_destination: Pubkey,
+    // Whether an UpdateReward with nothing to accrue emits a NoOp event, or
+    // nothing at all.
+    pub noop_events: bool,
+}
+
+impl Default for ContractConfig {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl PledgeContract {
+impl ContractConfig {
+    // Serialized size: the version 11 layout plus the noop_events flag.
+    pub const LEN: usize = Self::LEN_V11 + 1;
+    // Everything up to recovery_destination.
+    pub const LEN_V11: usize = Self::LEN_V10 + 32 + 32;
+    // Everything up to pledge_decimals.
+    pub const LEN_V10: usize = Self::LEN_V9 + 1 + 1;
+    // Everything up to claim_fee_bps.
+    pub const LEN_V9: usize = Self::LEN_V8 + 2;
+    // Everything up to initialized_users.
+    pub const LEN_V8: usize = Self::LEN_V7 + 8;
+    // Everything up to expired_rewards.
+    pub const LEN_V7: usize = Self::LEN_V6 + 8 + 1 + 8;
+    // Everything up to crank_bounties_paid.
+    pub const LEN_V6: usize = Self::LEN_V5 + 8 * 3;
+    // Everything up to enforce_group_cap, behind the discriminator.
+    pub const LEN_V5: usize = 8 + Self::LEN_V4;
+    // Everything after the discriminator, which older versions lack.
+    pub const LEN_V4: usize = Self::LEN_V3 + 1;
+    // Everything up to rewards_funded.
+    pub const LEN_V3: usize = Self::LEN_V2 + 8;
+    // Everything up to config_version.
+    pub const LEN_V2: usize = Self::LEN_V1 + 2;
+    // Everything up to post_vesting_grace_secs.
+    pub const LEN_V1: usize = 1 + 8 * 5 + 8 * 5 * 2 + 8 + 8 + 8 + 8 + 8 + 1 + 8 * 5 * 2 + 8 + 32 + 1 + 1 + 32 + 8 + 32
+        + 8 + 8 + 8 * 2 * 3 + 8 + 8 + 8 + 1 + 8 + 8 + 8 + TeamLock::LEN + 8 + 8;
+    // Where pending_admin starts in an account without the discriminator;
+    // every field before it is fixed width.
+    const PENDING_ADMIN_OFFSET: usize = 1 + 8 * 5 + 8 * 5 * 2 + 8 * 5 + 1 + 8 * 5 * 2 + 8 + 32 + 1;
+
     pub fn new() -> Self {
         Self {
+            discriminator: CONFIG_DISCRIMINATOR,
+            is_initialized: false,
             total_pledge_supply: TOTAL_PLEDGE_SUPPLY,
             solhit_token_supply: TOTAL_SOLHIT_SUPPLY,
             locked_solhit_tokens: LOCKED_SOLHIT_TOKENS,
             vesting_period: VESTING_PERIOD,
-            reward_rate: REWARD_RATE,
+            reward_rate_bps: REWARD_RATE_BPS,
             phase_durations: PHASE_DURATIONS,
             phase_rates: PHASE_RATES,
+            total_pledge_sold: 0,
+            sale_start_time: 0,
+            early_exit_penalty_percent: EARLY_EXIT_PENALTY_PERCENT,
+            total_penalties: 0,
+            global_solhit_claimed: 0,
+            bump: 0,
+            phase_caps: PHASE_CAPS,
+            phase_sold: [0; 5],
+            max_per_user: 0,
+            admin: Pubkey::default(),
+            paused: false,
+            pending_admin: None,
+            total_withdrawn: 0,
+            payment_mint: Pubkey::default(),
+            purchase_cooldown_secs: 0,
+            referral_bonus_percent: REFERRAL_BONUS_PERCENT,
+            lock_tiers: LOCK_TIERS,
+            compound_rate: COMPOUND_RATE,
+            compound_vesting_boost: COMPOUND_VESTING_BOOST,
+            event_seq: 0,
+            sale_finalized: false,
+            unsold_supply: 0,
+            last_snapshot_time: 0,
+            snapshot_interval_secs: SNAPSHOT_INTERVAL,
+            team_lock: TeamLock {
+                locked_amount: LOCKED_SOLHIT_TOKENS,
+                unlock_start: 0,
+                unlock_cliff: TEAM_UNLOCK_CLIFF,
+                unlock_duration: TEAM_UNLOCK_DURATION,
+            },
+            team_released: 0,
+            post_vesting_grace_secs: POST_VESTING_GRACE,
+            config_version: CONFIG_VERSION,
+            rewards_funded: 0,
+            enforce_group_cap: false,
+            crank_fee: 0,
+            crank_budget: 0,
+            crank_bounties_paid: 0,
+            reward_expiry_secs: 0,
+            expire_permissionless: false,
+            expired_rewards: 0,
+            initialized_users: 0,
+            claim_fee_bps: 0,
+            payment_decimals: PAYMENT_DECIMALS,
+            pledge_decimals: PLEDGE_DECIMALS,
+            guardian: Pubkey::default(),
+            recovery_destination: Pubkey::default(),
+            noop_events: true,
+        }
+    }
+
+    // Advances the event counter, returning the sequence number of the event
+    // about to be emitted. The first event is 1.
+    pub fn next_event_seq(&mut self) -> Result<u64, PledgeError> {
+        self.event_seq = self.event_seq.checked_add(1).ok_or(PledgeError::ArithmeticOverflow)?;
+        Ok(self.event_seq)
+    }
+
+    // Counts a newly initialized user state, unless users go uncounted.
+    pub fn add_user(&mut self) -> Result<(), PledgeError> {
+        if self.initialized_users != USERS_UNCOUNTED {
+            self.initialized_users = self.initialized_users.checked_add(1).ok_or(PledgeError::ArithmeticOverflow)?;
+        }
+        Ok(())
+    }
+
+    // Stops counting a user state add_user counted.
+    pub fn remove_user(&mut self) -> Result<(), PledgeError> {
+        if self.initialized_users != USERS_UNCOUNTED {
+            self.initialized_users = self.initialized_users.checked_sub(1).ok_or(PledgeError::ArithmeticOverflow)?;
+        }
+        Ok(())
+    }
+
+    // Checks the invariants every stored config keeps: a usable phase schedule
+    // and lock tier table, nonzero reward and compound rates, percentages of at
+    // most 100, locked Solheist within the Solheist supply, a team lock that
+    // fits in it and nothing sold, claimed or released beyond what there is.
+    // Run when the config is created and after every admin update.
+    pub fn validate(&self) -> Result<(), PledgeError> {
+        logic::validate_phase_config(&self.phase_durations, &self.phase_rates)?;
+        if self.lock_tiers.iter().any(|&(duration, multiplier_bps)| duration == 0 || multiplier_bps == 0) {
+            msg!("Lock tiers need a positive duration and multiplier");
+            return Err(PledgeError::InvalidLockTier);
+        }
+        if self.reward_rate_bps == 0 || self.compound_rate == 0 {
+            msg!("Reward and compound rates must be greater than zero");
+            return Err(PledgeError::InvalidConfig);
+        }
+        // So that 10^decimals fits a u64.
+        if self.payment_decimals > 19 || self.pledge_decimals > 19 {
+            msg!("Payment and pledge decimals must not exceed 19");
+            return Err(PledgeError::InvalidConfig);
+        }
+        if (self.guardian == Pubkey::default()) != (self.recovery_destination == Pubkey::default()) {
+            msg!("A guardian needs a recovery destination and the other way around");
+            return Err(PledgeError::InvalidConfig);
+        }
+        if self.claim_fee_bps as u64 > BASIS_POINTS {
+            msg!("Claim fee must not exceed 100%");
+            return Err(PledgeError::InvalidConfig);
+        }
+        if self.early_exit_penalty_percent > 100 || self.referral_bonus_percent > 100 {
+            msg!("Early exit penalty and referral bonus must not exceed 100%");
+            return Err(PledgeError::InvalidConfig);
+        }
+        if self.locked_solhit_tokens > self.solhit_token_supply {
+            msg!("Locked Solheist exceeds the Solheist supply");
+            return Err(PledgeError::InvalidConfig);
+        }
+        if self.total_pledge_sold > self.total_pledge_supply {
+            msg!("Pledge tokens sold exceed the pledge supply");
+            return Err(PledgeError::InvalidConfig);
+        }
+        if self.global_solhit_claimed > self.solhit_token_supply - self.locked_solhit_tokens {
+            msg!("Solheist claimed exceeds the unlocked supply");
+            return Err(PledgeError::InvalidConfig);
+        }
+        let team_lock = &self.team_lock;
+        if team_lock.locked_amount > self.locked_solhit_tokens {
+            msg!("Team lock exceeds the locked Solheist");
+            return Err(PledgeError::InvalidConfig);
+        }
+        // The team lock of an unscheduled sale starts along with it.
+        if team_lock.unlock_duration == 0
+            || team_lock.unlock_cliff > team_lock.unlock_duration
+            || (team_lock.unlock_start != SALE_UNSCHEDULED
+                && team_lock.unlock_start.checked_add(team_lock.unlock_duration).is_none())
+        {
+            msg!("Team lock needs a positive duration no shorter than its cliff");
+            return Err(PledgeError::InvalidConfig);
+        }
+        if self.team_released > team_lock.locked_amount {
+            msg!("Team tokens released exceed the team lock");
+            return Err(PledgeError::InvalidConfig);
+        }
+        Ok(())
+    }
+
+    // Reads the config from the start of `src`. LEN leaves room for a pending
+    // admin, so the bytes after an absent one are ignored. That slack may hold
+    // leftovers of an earlier pending admin, so in an older, shorter account
+    // it is dropped along with the missing fields, which all read as 0 until
+    // MigrateConfig sets them, and an account too short for the version field
+    // is taken for version 1.
+    // Accounts from before the discriminator are read as if they had it.
+    pub fn unpack(src: &[u8]) -> Result<Self, ProgramError> {
+        Self::check_account_type(src)?;
+        if src.len() >= Self::LEN && src.starts_with(&CONFIG_DISCRIMINATOR) {
+            return Ok(Self::deserialize(&mut &src[..])?);
+        }
+        let (body, max_len) = match src.strip_prefix(&CONFIG_DISCRIMINATOR) {
+            Some(body) => (body, Self::LEN - 8),
+            None => (src, Self::LEN_V4),
+        };
+        let mut end = body.len().min(max_len);
+        if body.get(Self::PENDING_ADMIN_OFFSET) == Some(&0) {
+            end = end.saturating_sub(32);
+        }
+        let mut padded = vec![0; Self::LEN];
+        padded[..8].copy_from_slice(&CONFIG_DISCRIMINATOR);
+        padded[8..8 + end].copy_from_slice(&body[..end]);
+        let pledge_contract = Self::deserialize(&mut &padded[..])?;
+        if src.len() < Self::LEN_V2 {
+            return Ok(Self { config_version: 1, ..pledge_contract });
+        }
+        Ok(pledge_contract)
+    }
+
+    // Refuses data that starts neither with the discriminator nor like a
+    // config from before it, such as a user state.
+    pub fn check_account_type(src: &[u8]) -> ProgramResult {
+        if src.is_empty() || src.starts_with(&CONFIG_DISCRIMINATOR) || matches!(src[0], 0 | 1) {
+            return Ok(());
+        }
+        msg!("Account is not a config");
+        Err(PledgeError::InvalidAccountType.into())
+    }
+}
+
+// The team's share of the locked Solheist, vesting linearly from
+// unlock_start over unlock_duration seconds. Nothing vests until unlock_cliff
+// seconds after unlock_start, when everything vested so far becomes available
+// at once.
+#[derive(BorshSerialize, BorshDeserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TeamLock {
+    pub locked_amount: u64,
+    pub unlock_start: u64,
+    pub unlock_cliff: u64,
+    pub unlock_duration: u64,
+}
+
+impl TeamLock {
+    pub const LEN: usize = 8 * 4;
+
+    // Team tokens vested by `time`, released or not.
+    pub fn vested(&self, time: u64) -> Result<u64, PledgeError> {
+        let elapsed = time.saturating_sub(self.unlock_start);
+        if elapsed < self.unlock_cliff {
+            return Ok(0);
+        }
+        if elapsed >= self.unlock_duration {
+            return Ok(self.locked_amount);
+        }
+        math::mul_div_floor(self.locked_amount, elapsed, self.unlock_duration)
+    }
+}
+
+// A single purchase, vesting independently of the user's other purchases.
+// The lock length of its tier is vesting_end_time - lock_start_time, and
+// multiplier_bps the tier's reward multiplier.
+#[derive(BorshSerialize, BorshDeserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PledgeLot {
+    pub amount: u64,
+    pub rate: u64,
+    pub lock_start_time: u64,
+    pub vesting_end_time: u64,
+    pub multiplier_bps: u64,
+}
+
+impl PledgeLot {
+    pub const LEN: usize = 8 * 5;
+    // Lots before version 4 lack multiplier_bps and accrue at 1x.
+    pub const LEN_V3: usize = 8 * 4;
+
+    // Rewards earned by `time`, accruing linearly from lock_start_time up to
+    // the full amount * reward_rate_bps * multiplier_bps / BASIS_POINTS^2 at
+    // vesting_end_time, then on at the same pace for grace_secs more.
+    pub fn accrued_rewards(&self, reward_rate_bps: u64, grace_secs: u64, time: u64) -> Result<u64, PledgeError> {
+        let duration = self.vesting_end_time.saturating_sub(self.lock_start_time);
+        let accrual_end = self.vesting_end_time.saturating_add(grace_secs);
+        let elapsed = time.min(accrual_end).saturating_sub(self.lock_start_time);
+        let rate_bps = reward_rate_bps.checked_mul(self.multiplier_bps).ok_or(PledgeError::ArithmeticOverflow)?;
+        let full_reward = math::mul_div_floor(self.amount, rate_bps, BASIS_POINTS * BASIS_POINTS)?;
+        // A lot without a vesting window has no pace to keep up.
+        if duration == 0 {
+            return Ok(full_reward);
         }
+        math::mul_div_floor(full_reward, elapsed, duration)
     }
 }
 
+// locked_pledge_tokens, lock_start_time and vesting_end_time summarize the
+// open lots: their total, the earliest start and the latest end.
+// last_reward_update is when rewards were last accrued into solhit_rewards,
+// total_claimed is everything the user has ever claimed, total_purchased
+// every pledge token ever bought, including lots since unlocked or exited,
+// and purchase_count how many purchases that took. last_activity is the
+// latest time any instruction acted on the user; the clock may not run back
+// from it by more than MAX_CLOCK_SKEW. Claimed rewards go to the
+// beneficiary's token account, which starts out as the owner. The delegate,
+// if any, may claim on the owner's behalf but do nothing else. group is the
+// BuyGroup PDA the wallet joined, if any. Until hold_until the admin's
+// compliance hold keeps the user from taking anything out; 0 is no hold.
+#[derive(Default, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct UserState {
+    pub is_initialized: bool,
     pub locked_pledge_tokens: u64,
     pub solhit_rewards: u64,
     pub lock_start_time: u64,
     pub vesting_end_time: u64,
+    pub last_reward_update: u64,
+    pub total_claimed: u64,
+    pub total_purchased: u64,
+    pub purchase_count: u32,
+    pub last_activity: u64,
+    pub owner: Pubkey,
+    pub bump: u8,
+    pub lot_count: u8,
+    pub lots: [PledgeLot; MAX_PLEDGE_LOTS],
+    pub beneficiary: Pubkey,
+    pub delegate: Option<Pubkey>,
+    pub group: Option<Pubkey>,
+    pub hold_until: u64,
 }
 
-impl BorshSerialize for UserState {
-    fn serialize<W: Write>(&self, writer: &mut W) -> std::result::Result<(), std::io::Error> {
-        self.locked_pledge_tokens.serialize(writer)?;
-        self.solhit_rewards.serialize(writer)?;
-        self.lock_start_time.serialize(writer)?;
-        self.vesting_end_time.serialize(writer)?;
+impl UserState {
+    // Serialized size: discriminator, version byte, initialized flag, seven
+    // u64 fields, the purchase count, last_activity, the owner pubkey, the
+    // PDA bump, the lot count, every lot slot, the beneficiary, and the
+    // delegate and group, each a presence flag followed by the key, zeroed
+    // when there is none, and hold_until.
+    pub const LEN: usize = Self::LEN_V8 + 8;
+    // Version 8 brought in the discriminator and ends with the group.
+    pub const LEN_V8: usize = 8 + Self::LEN_V7;
+    // Version 7 is version 8 without the discriminator.
+    pub const LEN_V7: usize = Self::LEN_V6 + 1 + 32;
+    // Version 6 ends with the delegate, before the group.
+    pub const LEN_V6: usize = Self::LEN_V5 + 1 + 32;
+    // Version 5 ends with the beneficiary, before the delegate.
+    pub const LEN_V5: usize = Self::LEN_V4 + 32;
+    // Version 4 ends with the lots, before the beneficiary.
+    pub const LEN_V4: usize = Self::LEN_V3 + (PledgeLot::LEN - PledgeLot::LEN_V3) * MAX_PLEDGE_LOTS;
+    // Version 3 has the shorter lots without multiplier_bps as well.
+    pub const LEN_V3: usize = Self::LEN_V2 + 4 + 8;
+    // Version 2 lacks purchase_count and last_activity as well.
+    pub const LEN_V2: usize = 1 + Self::LEN_V1;
+    // Version 1 accounts predate the version byte and start with the
+    // initialized flag, which is always 0 or 1.
+    pub const LEN_V1: usize = 1 + 8 * 7 + 32 + 1 + 1 + PledgeLot::LEN_V3 * MAX_PLEDGE_LOTS;
+    // Bytes of a version 2 state up to total_purchased, after which version
+    // 3 inserted its fields.
+    const V2_HEAD: usize = 1 + 1 + 8 * 7;
+
+    // Serializes the latest version into the first LEN bytes of `dst` without
+    // allocating. Every handler stores its changes through here, so none can
+    // store an inconsistent state.
+    pub fn pack_into_slice(&self, dst: &mut [u8]) -> ProgramResult {
+        self.assert_consistent()?;
+        if dst.len() < Self::LEN {
+            msg!("User state account is too small: {} < {}", dst.len(), Self::LEN);
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        *UserStateData::load_mut_unchecked(dst)? = UserStateData::from(self);
         Ok(())
     }
-}
 
-impl BorshDeserialize for UserState {
-    fn deserialize(buf: &mut &[u8]) -> std::result::Result<Self, std::io::Error> {
-        let locked_pledge_tokens = u64::deserialize(buf)?;
-        let solhit_rewards = u64::deserialize(buf)?;
-        let lock_start_time = u64::deserialize(buf)?;
-        let vesting_end_time = u64::deserialize(buf)?;
-        Ok(Self {
-            locked_pledge_tokens,
-            solhit_rewards,
-            lock_start_time,
-            vesting_end_time,
-        })
-    }
+    // Reads the state in any known version from the start of `src`, ignoring
+    // any padding after it.
+    pub fn unpack(src: &[u8]) -> Result<Self, ProgramError> {
+        Self::check_account_type(src)?;
+        if src.starts_with(&USER_STATE_DISCRIMINATOR) && src.get(8) == Some(&USER_STATE_DISCRIMINATOR_VERSION) {
+            // Version 8 is the latest layout without the trailing hold_until.
+            if src.len() < Self::LEN_V8 {
+                msg!("User state account is too small: {} < {}", src.len(), Self::LEN_V8);
+                return Err(ProgramError::AccountDataTooSmall);
+            }
+            let mut latest = [0; Self::LEN];
+            latest[..Self::LEN_V8].copy_from_slice(&src[..Self::LEN_V8]);
+            latest[8] = USER_STATE_VERSION;
+            return Ok(Self::from(UserStateData::load(&latest)?));
+        }
+        if src.is_empty() || src.starts_with(&USER_STATE_DISCRIMINATOR) {
+            return Ok(Self::from(UserStateData::load(src)?));
+        }
+        // Older versions lack the discriminator and are laid out as version 4
+        // to 7 first, which are version 8 without the discriminator and some
+        // of the trailing beneficiary, delegate and group.
+        let version = src[0];
+        let len = Self::required_len(version).ok_or(ProgramError::InvalidAccountData)?;
+        let body = if version < 2 { 0 } else { 1 };
+        if src.len() < len {
+            msg!("User state account is too small: {} < {}", src.len(), len);
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        let mut latest = [0; Self::LEN];
+        latest[..8].copy_from_slice(&USER_STATE_DISCRIMINATOR);
+        if version >= 4 {
+            latest[8..8 + len].copy_from_slice(&src[..len]);
+        } else {
+            Self::widen_v3(src, len, body, &mut latest[8..]);
+        }
+        latest[8] = USER_STATE_VERSION;
 
-    fn deserialize_reader<R: std::io::Read>(reader: &mut R) -> std::io::Result<Self> {
-        let mut buf = vec![];
-        reader.read_to_end(&mut buf)?;
-        Self::deserialize(&mut buf.as_slice())
+        let mut user_state = Self::from(UserStateData::load(&latest)?);
+        if version < 4 {
+            for lot in &mut user_state.lots[..user_state.lot_count as usize] {
+                lot.multiplier_bps = BASIS_POINTS;
+            }
+        }
+        if version < 5 {
+            user_state.beneficiary = user_state.owner;
+        }
+        Ok(user_state)
     }
-}
 
-entrypoint!(process_instruction);
+    // Lays the first `len` bytes of a version 1 to 3 state in `src`, starting
+    // at `body`, out as version 4 at the start of `latest`, going through
+    // version 3.
+    // Versions 1 and 2 are version 3 without the version byte (version 1) and
+    // without the fields version 3 added (both), so they are copied around
+    // the missing bytes.
+    fn widen_v3(src: &[u8], len: usize, body: usize, latest: &mut [u8]) {
+        let mut v3 = [0; Self::LEN_V3];
+        if len == Self::LEN_V3 {
+            v3.copy_from_slice(&src[..len]);
+        } else {
+            let head = Self::V2_HEAD - 1;
+            let (before, after) = src[body..len].split_at(head);
+            v3[1..Self::V2_HEAD].copy_from_slice(before);
+            v3[Self::LEN_V3 - after.len()..].copy_from_slice(after);
+        }
 
-pub fn process_instruction(
-    _program_id: &Pubkey,
-    accounts: &[AccountInfo],
-    instruction_data: &[u8],
-) -> ProgramResult {
-    let account_info_iter = &mut accounts.iter();
-    let account_info = next_account_info(account_info_iter)?;
+        // Every lot is widened; the caller sets the multiplier of open ones.
+        let lots = Self::LEN_V3 - PledgeLot::LEN_V3 * MAX_PLEDGE_LOTS;
+        latest[..lots].copy_from_slice(&v3[..lots]);
+        let old_lots = v3[lots..].chunks_exact(PledgeLot::LEN_V3);
+        for (old_lot, lot) in old_lots.zip(latest[lots..Self::LEN_V4].chunks_exact_mut(PledgeLot::LEN)) {
+            lot[..PledgeLot::LEN_V3].copy_from_slice(old_lot);
+        }
+    }
 
-    match instruction_data[0] {
-        0 => buy_pledge(
-            account_info,
-            u64::from_le_bytes(instruction_data[1..9].try_into().unwrap()),
-            Clock::get()?.unix_timestamp.try_into().expect("Conversion from i64 to u64 failed"), 
-        ),
-        1 => update_reward(account_info, Clock::get()?.unix_timestamp.try_into().expect("Conversion from i64 to u64 failed")),
-        2 => view_rewards(account_info),
-        3 => claim_rewards(
-            &accounts,
-        ),
-        _ => {
-            msg!("Instruction not recognized");
-            Err(ProgramError::InvalidInstructionData)
+    // The bytes a state of layout `version` takes, None for versions this
+    // program does not know. Version 0 is an uninitialized version 1 state.
+    pub fn required_len(version: u8) -> Option<usize> {
+        match version {
+            USER_STATE_VERSION => Some(Self::LEN),
+            USER_STATE_DISCRIMINATOR_VERSION => Some(Self::LEN_V8),
+            7 => Some(Self::LEN_V7),
+            6 => Some(Self::LEN_V6),
+            5 => Some(Self::LEN_V5),
+            4 => Some(Self::LEN_V4),
+            3 => Some(Self::LEN_V3),
+            2 => Some(Self::LEN_V2),
+            0 | 1 => Some(Self::LEN_V1),
+            _ => None,
         }
     }
-}
 
+    // Whether `src` still holds an older state that MigrateUserState should
+    // rewrite.
+    pub fn needs_migration(src: &[u8]) -> bool {
+        match src.strip_prefix(&USER_STATE_DISCRIMINATOR) {
+            Some(rest) => matches!(rest.first(), Some(&version) if version < USER_STATE_VERSION),
+            None => matches!(src.first(), Some(&version) if version < USER_STATE_DISCRIMINATOR_VERSION),
+        }
+    }
 
-pub fn buy_pledge(
-    account_info: &AccountInfo,
-    amount: u64,
-    current_time: u64,
-) -> ProgramResult {
-    let mut user_state = UserState::try_from_slice(&account_info.data.borrow())?;
-    let pledge_contract = PledgeContract::new();
+    // Refuses data that starts neither with the discriminator nor with the
+    // version byte of an older state, such as a config.
+    pub fn check_account_type(src: &[u8]) -> ProgramResult {
+        if src.is_empty() || src.starts_with(&USER_STATE_DISCRIMINATOR) || src[0] < USER_STATE_DISCRIMINATOR_VERSION {
+            return Ok(());
+        }
+        msg!("Account is not a user state");
+        Err(PledgeError::InvalidAccountType.into())
+    }
 
-    let sale_phase = get_sale_phase(current_time, &pledge_contract.phase_durations);
-    let rate = pledge_contract.phase_rates[sale_phase];
+    pub fn active_lots(&self) -> &[PledgeLot] {
+        &self.lots[..self.lot_count as usize]
+    }
 
-    let pledge_tokens = (amount * rate) / 100;
+    pub fn add_lot(&mut self, lot: PledgeLot) -> Result<(), PledgeError> {
+        let index = self.lot_count as usize;
+        if index >= MAX_PLEDGE_LOTS {
+            msg!("A user can hold at most {} pledge lots", MAX_PLEDGE_LOTS);
+            return Err(PledgeError::LotLimitReached);
+        }
+        self.lots[index] = lot;
+        self.lot_count += 1;
+        self.refresh_totals()
+    }
 
-    if pledge_tokens > pledge_contract.total_pledge_supply - user_state.locked_pledge_tokens {
-        return Err(ProgramError::InvalidArgument);
+    // Credits what every open lot has earned since the last update. Each lot
+    // accrues pro rata over its own vesting window and grace_secs past it,
+    // and only the part earned since last_reward_update is added, so repeated
+    // calls never count the same interval twice.
+    pub fn accrue_rewards(&mut self, reward_rate_bps: u64, grace_secs: u64, current_time: u64) -> Result<(), PledgeError> {
+        for index in 0..self.lot_count as usize {
+            let lot = self.lots[index];
+            let solhit_rewards = lot
+                .accrued_rewards(reward_rate_bps, grace_secs, current_time)?
+                .saturating_sub(lot.accrued_rewards(reward_rate_bps, grace_secs, self.last_reward_update)?);
+            #[cfg(feature = "debug-logs")]
+            msg!("Calculated solhit_rewards: {}", solhit_rewards);
+            self.solhit_rewards = self
+                .solhit_rewards
+                .checked_add(solhit_rewards)
+                .ok_or(PledgeError::ArithmeticOverflow)?;
+            #[cfg(feature = "debug-logs")]
+            msg!("Updated solhit_rewards in UserState: {}", self.solhit_rewards);
+        }
+        self.last_reward_update = self.last_reward_update.max(current_time);
+        Ok(())
     }
 
-    user_state.locked_pledge_tokens += pledge_tokens;
-    user_state.lock_start_time = current_time;
-    user_state.vesting_end_time = user_state.vesting_end_time.max(current_time + pledge_contract.vesting_period);
+    // Removes every lot that has vested by `current_time`, compacting the rest,
+    // and returns the removed lots.
+    fn remove_vested_lots(&mut self, current_time: u64) -> Result<Vec<PledgeLot>, PledgeError> {
+        self.remove_lots(|lot| current_time >= lot.vesting_end_time)
+    }
 
-    let serialized_user_state = serialize_user_state(&user_state)?;
-    account_info.data.borrow_mut().copy_from_slice(&serialized_user_state);
+    // Removes every lot still vesting at `current_time`.
+    fn remove_vesting_lots(&mut self, current_time: u64) -> Result<Vec<PledgeLot>, PledgeError> {
+        self.remove_lots(|lot| current_time < lot.vesting_end_time)
+    }
 
-    emit_event(PledgeEvent::Purchase(amount, rate, user_state.locked_pledge_tokens));
+    fn remove_lots(&mut self, remove: impl Fn(&PledgeLot) -> bool) -> Result<Vec<PledgeLot>, PledgeError> {
+        let mut removed = Vec::new();
+        let mut kept = 0;
+        for index in 0..self.lot_count as usize {
+            let lot = self.lots[index];
+            if remove(&lot) {
+                removed.push(lot);
+            } else {
+                self.lots[kept] = lot;
+                kept += 1;
+            }
+        }
+        for lot in &mut self.lots[kept..] {
+            *lot = PledgeLot::default();
+        }
+        self.lot_count = kept as u8;
+        self.refresh_totals()?;
+        Ok(removed)
+    }
 
-    Ok(())
-}
+    // Whether any pledge tokens are locked. Their lots all end by
+    // vesting_end_time, which is 0 only once nothing is locked.
+    pub fn is_vesting(&self) -> bool {
+        self.locked_pledge_tokens != 0
+    }
 
-pub fn update_reward(
-    account_info: &AccountInfo,
-    current_time: u64,
-) -> ProgramResult {
-    let mut user_state = UserState::try_from_slice(&account_info.data.borrow())?;
-    let pledge_contract = PledgeContract::new();
+    // Locked tokens with a vesting end of 0 would unlock at any time, so a
+    // state like that is refused wherever it is read or written.
+    pub fn assert_consistent(&self) -> Result<(), PledgeError> {
+        if self.is_vesting() && self.vesting_end_time == 0 {
+            msg!("User state locks {} pledge tokens without a vesting end", self.locked_pledge_tokens);
+            return Err(PledgeError::StateCorrupted);
+        }
+        Ok(())
+    }
 
-    let elapsed_time = current_time.saturating_sub(user_state.lock_start_time);
+    // What no stored state breaks, checked on every load on top of
+    // assert_consistent so that garbage in a program owned account is
+    // refused instead of acted on. The log names the broken invariant.
+    pub fn sanity_check(&self) -> Result<(), PledgeError> {
+        self.assert_consistent()?;
+        let corrupted = |invariant: &str| {
+            msg!("User state is corrupted: {}", invariant);
+            PledgeError::StateCorrupted
+        };
+        if self.vesting_end_time != 0 && self.vesting_end_time < self.lock_start_time {
+            return Err(corrupted("vesting ends before the lock starts"));
+        }
+        if self.active_lots().iter().any(|lot| lot.vesting_end_time < lot.lock_start_time) {
+            return Err(corrupted("a lot vests before it is locked"));
+        }
+        let lots_total = self.active_lots().iter().try_fold(0u64, |total, lot| total.checked_add(lot.amount));
+        if lots_total != Some(self.locked_pledge_tokens) {
+            return Err(corrupted("locked pledge tokens differ from the lots' total"));
+        }
+        Ok(())
+    }
 
-    if elapsed_time >= pledge_contract.vesting_period {
-        let solhit_rewards = (user_state.locked_pledge_tokens as u128 * pledge_contract.reward_rate as u128) as u64;
-        println!("Calculated solhit_rewards: {}", solhit_rewards);  // Debug print
-        user_state.solhit_rewards = user_state.solhit_rewards.saturating_add(solhit_rewards);
-        println!("Updated solhit_rewards in UserState: {}", user_state.solhit_rewards);  // Debug print
-        user_state.lock_start_time = current_time;
-        unlock_vested_tokens(&mut user_state);
-    } else if current_time >= user_state.vesting_end_time {
-        unlock_vested_tokens(&mut user_state);
+    // The totals only summarize the open lots: vesting_end_time is the latest
+    // lot end, but every lot unlocks at its own, so a later purchase never
+    // pushes back tokens bought earlier.
+    fn refresh_totals(&mut self) -> Result<(), PledgeError> {
+        let mut locked_pledge_tokens: u64 = 0;
+        for lot in self.active_lots() {
+            locked_pledge_tokens = locked_pledge_tokens
+                .checked_add(lot.amount)
+                .ok_or(PledgeError::ArithmeticOverflow)?;
+        }
+        self.locked_pledge_tokens = locked_pledge_tokens;
+        self.lock_start_time = self.active_lots().iter().map(|lot| lot.lock_start_time).min().unwrap_or(0);
+        self.vesting_end_time = self.active_lots().iter().map(|lot| lot.vesting_end_time).max().unwrap_or(0);
+        Ok(())
     }
+}
 
-    let serialized_user_state = serialize_user_state(&user_state)?;
-    account_info.data.borrow_mut().copy_from_slice(&serialized_user_state);
+// Lets `wallet` buy during the first sale phase. The account only exists, at
+// the PDA derived from the wallet, while the wallet is whitelisted.
+#[derive(BorshSerialize, BorshDeserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Whitelist {
+    pub is_initialized: bool,
+    pub wallet: Pubkey,
+    pub bump: u8,
+}
 
-    emit_event(PledgeEvent::RewardUpdate(user_state.solhit_rewards, elapsed_time));
+impl Whitelist {
+    pub const LEN: usize = 1 + 32 + 1;
+}
 
-    Ok(())
+// Wallets that chose to buy together, at the `[b"group", group_id]` PDA.
+// total_purchased adds up everything its members ever bought, including
+// before they joined; with the config's enforce_group_cap it may not pass
+// max_per_user, so one buyer spreading purchases across wallets gets a single
+// allowance. Wallets join with JoinGroup and never leave.
+#[derive(BorshSerialize, BorshDeserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuyGroup {
+    pub is_initialized: bool,
+    pub group_id: Pubkey,
+    pub bump: u8,
+    pub member_count: u32,
+    pub total_purchased: u64,
 }
 
-fn unlock_vested_tokens(user_state: &mut UserState) {
-    user_state.locked_pledge_tokens = 0;
-    user_state.vesting_end_time = 0;
+impl BuyGroup {
+    pub const LEN: usize = 1 + 32 + 1 + 4 + 8;
 }
 
-pub fn view_rewards(account_info: &AccountInfo) -> ProgramResult {
-    let user_state = UserState::try_from_slice(&account_info.data.borrow())?;
+// What one purchase paid and got, at the `[b"receipt", wallet, index]` PDA,
+// `index` being the wallet's purchase_count before it. BuyPledge writes it
+// once, at the buyer's expense, when passed the account; nothing changes it
+// afterwards, so it outlives the user state's running totals.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(BorshSerialize, BorshDeserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Receipt {
+    pub is_initialized: bool,
+    pub wallet: Pubkey,
+    pub index: u32,
+    pub bump: u8,
+    pub lamports_paid: u64,
+    pub pledge_tokens: u64,
+    pub rate: u64,
+    pub phase: u8,
+    pub timestamp: u64,
+}
 
-    msg!("Solheist Rewards: {}", user_state.solhit_rewards);
+impl Receipt {
+    pub const LEN: usize = 1 + 32 + 4 + 1 + 8 * 3 + 1 + 8;
 
-    Ok(())
+    // Decodes a receipt account's data, refusing one that was never written.
+    pub fn unpack(src: &[u8]) -> Result<Self, ProgramError> {
+        let receipt = Self::deserialize(&mut &src[..])?;
+        if !receipt.is_initialized {
+            return Err(ProgramError::UninitializedAccount);
+        }
+        Ok(receipt)
+    }
 }
 
-pub fn claim_rewards(
-    accounts: &[AccountInfo],
-) -> ProgramResult {
-    let account_info_iter = &mut accounts.iter();
-    let account_info = next_account_info(account_info_iter)?;
-
-    let user_state = UserState::try_from_slice(&account_info.data.borrow())?;
-    let pledge_contract = PledgeContract::new();
+// Sale progress at `timestamp`: the pledge tokens sold, the Solheist claimed
+// by every user and the sale phase then running.
+#[derive(BorshSerialize, BorshDeserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshot {
+    pub timestamp: u64,
+    pub total_sold: u64,
+    pub total_claimed: u64,
+    pub active_phase: u8,
+}
 
-    if user_state.solhit_rewards == 0 {
-        msg!("No rewards to claim");
-        return Ok(());
-    }
+impl Snapshot {
+    pub const LEN: usize = 8 * 3 + 1;
+}
 
-    let solhit_token_account_info = next_account_info(account_info_iter)?;
+// The ring buffer of snapshots in the `[b"snapshots"]` PDA. `count` is every
+// snapshot ever recorded, so the next one goes to count % MAX_SNAPSHOTS and
+// overwrites the oldest once the buffer is full.
+#[derive(BorshSerialize, BorshDeserialize, Default, Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotBuffer {
+    pub is_initialized: bool,
+    pub bump: u8,
+    pub count: u64,
+    pub snapshots: [Snapshot; MAX_SNAPSHOTS],
+}
 
-    let transfer_to_user_amount = user_state.solhit_rewards;
-    let remaining_solhit_tokens = pledge_contract.solhit_token_supply.saturating_sub(pledge_contract.locked_solhit_tokens);
+impl SnapshotBuffer {
+    pub const LEN: usize = 1 + 1 + 8 + Snapshot::LEN * MAX_SNAPSHOTS;
 
-    if transfer_to_user_amount > remaining_solhit_tokens {
-        msg!("Not enough Solheist tokens in the contract");
-        return Err(ProgramError::InsufficientFunds);
+    pub fn push(&mut self, snapshot: Snapshot) -> Result<(), PledgeError> {
+        self.snapshots[(self.count % MAX_SNAPSHOTS as u64) as usize] = snapshot;
+        self.count = self.count.checked_add(1).ok_or(PledgeError::ArithmeticOverflow)?;
+        Ok(())
     }
 
-    // Transfer Solheist tokens to the user
-    solana_program::program::invoke_signed(
-        &solana_program::system_instruction::transfer(
-            &solhit_token_account_info.key,
-            account_info.key,
-            transfer_to_user_amount,
-        ),
-        &[solhit_token_account_info.clone(), account_info.clone()],
-        &[],
-    )?;
+    pub fn latest(&self) -> Option<Snapshot> {
+        let index = self.count.checked_sub(1)? % MAX_SNAPSHOTS as u64;
+        Some(self.snapshots[index as usize])
+    }
+}
 
-    let mut user_state = UserState::try_from_slice(&account_info.data.borrow())?;
-    user_state.solhit_rewards = 0;
+// What ViewRewards hands back through the transaction return data, Borsh
+// encoded. pending_rewards includes everything accrued up to the view. A soft
+// view of a user state yet to be initialized leaves `initialized` unset and
+// every other field 0.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RewardsView {
+    pub pending_rewards: u64,
+    pub locked_pledge_tokens: u64,
+    pub vesting_end_time: u64,
+    pub seconds_remaining: u64,
+    pub total_purchased: u64,
+    pub purchase_count: u32,
+    pub last_activity: u64,
+    pub initialized: bool,
+}
 
-    let serialized_user_state = serialize_user_state(&user_state)?;
-    account_info.data.borrow_mut().copy_from_slice(&serialized_user_state);
+// One point of the timetable GetVestingSchedule hands back, a Borsh encoded
+// Vec of these: the pledge tokens unlockable and the rewards accrued by
+// `timestamp`.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VestingCheckpoint {
+    pub timestamp: u64,
+    pub unlocked_amount: u64,
+    pub accrued_rewards: u64,
+}
 
-    msg!("Rewards claimed successfully");
-    emit_event(PledgeEvent::RewardClaim(user_state.solhit_rewards));
+// What QuoteBuy hands back, Borsh encoded: the sale phase and rate a purchase
+// would get right now, the pledge tokens the amount asks for, those it would
+// actually receive once cut down to the remaining supply, and the lamports
+// that would be charged for them.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuyQuote {
+    pub phase: u8,
+    pub rate: u64,
+    pub requested_tokens: u64,
+    pub pledge_tokens: u64,
+    pub paid: u64,
+}
 
-    Ok(())
+// What GetGlobalStats hands back, Borsh encoded: the pledge tokens sold, the
+// Solheist claimed by every user, the sale phase running now with the seconds
+// left until it ends, u64::MAX for a phase that never does, or
+// PHASE_NOT_STARTED before the sale starts, the number of
+// open user states, USERS_UNCOUNTED for a config migrated from before it
+// counted them, and whether the contract is paused.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GlobalStats {
+    pub total_sold: u64,
+    pub total_claimed: u64,
+    pub active_phase: u8,
+    pub phase_time_remaining: u64,
+    pub initialized_users: u64,
+    pub paused: bool,
 }
 
+// What PreviewReward hands back, Borsh encoded: the rewards an UpdateReward
+// would credit right now, the unclaimed rewards it would leave, and whether
+// a lot has vested, so that UnlockPledge would release pledge tokens. Like
+// RewardsView it leaves `initialized` unset for a soft preview of a user
+// state yet to be initialized.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RewardPreview {
+    pub reward_delta: u64,
+    pub solhit_rewards: u64,
+    pub unlockable: bool,
+    pub initialized: bool,
+}
 
-fn serialize_user_state(user_state: &UserState) -> Result<Vec<u8>, ProgramError> {
-    let mut buf = vec![];
-    user_state.serialize(&mut buf)?;
-    println!("Serialized UserState: {:?}", buf);  // Debug print
-    Ok(buf)
+// The program reads and writes UserState through UserStateData; the Borsh
+// encoding is the same layout, kept for off-chain clients.
+#[cfg(feature = "borsh-user-state")]
+impl BorshSerialize for UserState {
+    fn serialize<W: borsh::io::Write>(&self, writer: &mut W) -> std::result::Result<(), std::io::Error> {
+        USER_STATE_DISCRIMINATOR.serialize(writer)?;
+        USER_STATE_VERSION.serialize(writer)?;
+        self.is_initialized.serialize(writer)?;
+        self.locked_pledge_tokens.serialize(writer)?;
+        self.solhit_rewards.serialize(writer)?;
+        self.lock_start_time.serialize(writer)?;
+        self.vesting_end_time.serialize(writer)?;
+        self.last_reward_update.serialize(writer)?;
+        self.total_claimed.serialize(writer)?;
+        self.total_purchased.serialize(writer)?;
+        self.purchase_count.serialize(writer)?;
+        self.last_activity.serialize(writer)?;
+        self.owner.serialize(writer)?;
+        self.bump.serialize(writer)?;
+        self.lot_count.serialize(writer)?;
+        for lot in &self.lots {
+            lot.serialize(writer)?;
+        }
+        self.beneficiary.serialize(writer)?;
+        // Fixed width, unlike Borsh's own Option, to match UserStateData.
+        self.delegate.is_some().serialize(writer)?;
+        self.delegate.unwrap_or_default().serialize(writer)?;
+        self.group.is_some().serialize(writer)?;
+        self.group.unwrap_or_default().serialize(writer)?;
+        self.hold_until.serialize(writer)?;
+        Ok(())
+    }
 }
 
-fn get_sale_phase(current_time: u64, phase_durations: &[u64; 5]) -> usize {
-    let mut elapsed_time = 0;
-    for (i, &duration) in phase_durations.iter().enumerate() {
-        elapsed_time += duration;
-        if current_time < elapsed_time {
-            return i;
+#[cfg(feature = "borsh-user-state")]
+impl BorshDeserialize for UserState {
+    fn deserialize(buf: &mut &[u8]) -> std::result::Result<Self, std::io::Error> {
+        // Only versions 8 on have the discriminator, and version 1 has no
+        // version byte either, so its first byte is the flag itself.
+        let current = buf.starts_with(&USER_STATE_DISCRIMINATOR);
+        if current {
+            *buf = &buf[USER_STATE_DISCRIMINATOR.len()..];
+        }
+        let version = u8::deserialize(buf)?;
+        let is_initialized = match version {
+            USER_STATE_DISCRIMINATOR_VERSION | USER_STATE_VERSION if current => bool::deserialize(buf)?,
+            2..=7 if !current => bool::deserialize(buf)?,
+            0 if !current => false,
+            1 if !current => true,
+            _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "unknown user state version")),
+        };
+        let locked_pledge_tokens = u64::deserialize(buf)?;
+        let solhit_rewards = u64::deserialize(buf)?;
+        let lock_start_time = u64::deserialize(buf)?;
+        let vesting_end_time = u64::deserialize(buf)?;
+        let last_reward_update = u64::deserialize(buf)?;
+        let total_claimed = u64::deserialize(buf)?;
+        let total_purchased = u64::deserialize(buf)?;
+        let (purchase_count, last_activity) = if version >= 3 {
+            (u32::deserialize(buf)?, u64::deserialize(buf)?)
+        } else {
+            (0, 0)
+        };
+        let owner = Pubkey::deserialize(buf)?;
+        let bump = u8::deserialize(buf)?;
+        let lot_count = u8::deserialize(buf)?;
+        if lot_count as usize > MAX_PLEDGE_LOTS {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "too many pledge lots"));
+        }
+        let mut lots = [PledgeLot::default(); MAX_PLEDGE_LOTS];
+        for (index, lot) in lots.iter_mut().enumerate() {
+            *lot = if version >= 4 {
+                PledgeLot::deserialize(buf)?
+            } else {
+                PledgeLot {
+                    amount: u64::deserialize(buf)?,
+                    rate: u64::deserialize(buf)?,
+                    lock_start_time: u64::deserialize(buf)?,
+                    vesting_end_time: u64::deserialize(buf)?,
+                    multiplier_bps: if index < lot_count as usize { BASIS_POINTS } else { 0 },
+                }
+            };
         }
+        let beneficiary = if version >= 5 { Pubkey::deserialize(buf)? } else { owner };
+        let delegate = if version >= 6 {
+            let has_delegate = bool::deserialize(buf)?;
+            let delegate = Pubkey::deserialize(buf)?;
+            has_delegate.then_some(delegate)
+        } else {
+            None
+        };
+        let group = if version >= 7 {
+            let has_group = bool::deserialize(buf)?;
+            let group = Pubkey::deserialize(buf)?;
+            has_group.then_some(group)
+        } else {
+            None
+        };
+        let hold_until = if version > USER_STATE_DISCRIMINATOR_VERSION { u64::deserialize(buf)? } else { 0 };
+        Ok(Self {
+            is_initialized,
+            locked_pledge_tokens,
+            solhit_rewards,
+            lock_start_time,
+            vesting_end_time,
+            last_reward_update,
+            total_claimed,
+            total_purchased,
+            purchase_count,
+            last_activity,
+            owner,
+            bump,
+            lot_count,
+            lots,
+            beneficiary,
+            delegate,
+            group,
+            hold_until,
+        })
+    }
+
+    // Reads exactly the bytes of the layout the leading bytes name into a
+    // fixed buffer, never the rest of the reader, which may be any length.
+    fn deserialize_reader<R: std::io::Read>(reader: &mut R) -> std::io::Result<Self> {
+        let unknown = || std::io::Error::new(std::io::ErrorKind::InvalidData, "unknown user state version");
+        let mut buf = [0; Self::LEN];
+        reader.read_exact(&mut buf[..1])?;
+        let (header, version) = if buf[0] < USER_STATE_DISCRIMINATOR_VERSION {
+            (1, buf[0])
+        } else {
+            let header = USER_STATE_DISCRIMINATOR.len() + 1;
+            reader.read_exact(&mut buf[1..header])?;
+            if buf[..header - 1] != USER_STATE_DISCRIMINATOR || buf[header - 1] < USER_STATE_DISCRIMINATOR_VERSION {
+                return Err(unknown());
+            }
+            (header, buf[header - 1])
+        };
+        let len = Self::required_len(version).ok_or_else(unknown)?;
+        reader.read_exact(&mut buf[header..len])?;
+        Self::deserialize(&mut &buf[..len])
     }
-    phase_durations.len() - 1
 }
 
-pub enum PledgeEvent {
-    Purchase(u64, u64, u64), // amount, rate, total_pledge_tokens
-    RewardUpdate(u64, u64), // solhit_rewards, elapsed_time
-    RewardClaim(u64),       // solhit_rewards
+// Each wallet has exactly one UserState account, derived from its pubkey.
+pub fn find_user_state_address(program_id: &Pubkey, wallet: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[USER_STATE_SEED, wallet.as_ref()], program_id)
 }
 
-pub fn emit_event(event: PledgeEvent) {
-    let event_data = match event {
-        PledgeEvent::Purchase(amount, rate, total_pledge_tokens) => {
-            format!("Pledge tokens purchased: {} at rate {} for total: {}", amount, rate, total_pledge_tokens)
-        },
-        PledgeEvent::RewardUpdate(solhit_rewards, elapsed_time) => {
-            format!("Rewards updated: Solheist Rewards: {} after elapsed time: {}", solhit_rewards, elapsed_time)
-        },
-        PledgeEvent::RewardClaim(solhit_rewards) => {
-            format!("Rewards claimed: Solheist Rewards: {}", solhit_rewards)
-        },
-    };
+pub fn find_config_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[CONFIG_SEED], program_id)
+}
 
-    msg!("{}", event_data);
-    solana_program::log::sol_log(&event_data);
+// Program-owned account holding the sale proceeds.
+pub fn find_treasury_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[TREASURY_SEED], program_id)
 }
 
+// The treasury's associated token account for `solhit_mint`, which takes the
+// claim fee.
+pub fn claim_fee_address(program_id: &Pubkey, solhit_mint: &Pubkey) -> Pubkey {
+    spl_associated_token_account::get_associated_token_address(&find_treasury_address(program_id).0, solhit_mint)
+}
 
-#[cfg(test)]
-mod tests {
-    use super::*;    
-use crate::{buy_pledge, UserState, PledgeContract};
-use solana_program::{pubkey::Pubkey, account_info::AccountInfo};
+// Present and initialized for every wallet allowed to buy in phase 0.
+pub fn find_whitelist_address(program_id: &Pubkey, wallet: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[WHITELIST_SEED, wallet.as_ref()], program_id)
+}
 
+pub fn find_group_address(program_id: &Pubkey, group_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[GROUP_SEED, group_id.as_ref()], program_id)
+}
 
-    #[test]
-fn test_buy_pledge() {
-    let mut account_data = vec![0u8; std::mem::size_of::<UserState>()];
-    let pubkey1 = Pubkey::new_unique();
-    let pubkey2 = Pubkey::new_unique();
-    let mut lamports = 0;
-    let account_info = AccountInfo::new(
-        &pubkey1,
-        false,
-        true,
-        &mut lamports,
-        &mut account_data,
-        &pubkey2,
-        false,
-        0,
-    );
+// Holds the Receipt of the wallet's purchase number `index`, counting from 0.
+pub fn find_receipt_address(program_id: &Pubkey, wallet: &Pubkey, index: u32) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[RECEIPT_SEED, wallet.as_ref(), &index.to_le_bytes()], program_id)
+}
 
-    let amount = 1000;
-    let current_time = 1_000_000;
-    let result = buy_pledge(&account_info, amount, current_time);
-    assert!(result.is_ok());
+// Holds the SnapshotBuffer written by RecordSnapshot.
+pub fn find_snapshots_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[SNAPSHOTS_SEED], program_id)
+}
 
-    let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
-    let pledge_contract = PledgeContract::new();
-    let sale_phase = get_sale_phase(current_time, &pledge_contract.phase_durations);
-    let rate = pledge_contract.phase_rates[sale_phase];
-    let expected_pledge_tokens = (amount * rate) / 100;
+// The pledge vault token account is held by this PDA, which signs unlocks.
+pub fn find_pledge_vault_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[PLEDGE_VAULT_SEED], program_id)
+}
 
-    assert_eq!(user_state.locked_pledge_tokens, expected_pledge_tokens);
-    assert_eq!(user_state.lock_start_time, current_time);
-    assert_eq!(user_state.vesting_end_time, current_time + pledge_contract.vesting_period);
+// Rewards are paid out of the vault at this PDA.
+pub fn find_solhit_vault_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[SOLHIT_VAULT_SEED], program_id)
 }
-#[test]
-fn test_buy_pledge_vesting_period() {
-  let mut account_data = vec![0u8; std::mem::size_of::<UserState>()];
-  let pubkey = Pubkey::new_unique();
-  let mut lamports = 1000;
-  let account_info = AccountInfo::new(
-    &pubkey,
-    false,
-    true,
-    &mut lamports,
-    &mut account_data,
-    &pubkey,
+
+#[cfg(not(feature = "no-entrypoint"))]
+solana_program::entrypoint!(process_instruction);
+
+pub fn process_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let instruction = PledgeInstruction::unpack(instruction_data)?;
+
+    #[cfg(feature = "profiling")]
+    let name = instruction.name();
+    #[cfg(feature = "profiling")]
+    log_compute_units(name, "start");
+    let result = dispatch(program_id, accounts, instruction);
+    #[cfg(feature = "profiling")]
+    log_compute_units(name, "end");
+
+    if let Err(ProgramError::Custom(code)) = result {
+        if let Some(error) = PledgeError::from_code(code) {
+            msg!("Error: {}", error);
+        }
+    }
+    result
+}
+
+// Brackets each handler in a profiling build, so devnet runs log the compute
+// units every instruction leaves, and with them what it spends.
+#[cfg(feature = "profiling")]
+fn log_compute_units(name: &str, marker: &str) {
+    msg!("Profile {} {}", name, marker);
+    solana_program::log::sol_log_compute_units();
+}
+
+fn dispatch(program_id: &Pubkey, accounts: &[AccountInfo], instruction: PledgeInstruction) -> ProgramResult {
+    match instruction {
+        PledgeInstruction::InitializeUser => initialize_user(program_id, accounts, &Rent::get()?),
+        PledgeInstruction::InitializeContract {
+            sale_start_time,
+            phase_caps,
+            max_per_user,
+            guardian,
+            recovery_destination,
+        } => initialize_contract(
+            program_id,
+            accounts,
+            sale_start_time,
+            phase_caps,
+            max_per_user,
+            guardian,
+            recovery_destination,
+            &Rent::get()?,
+        ),
+        PledgeInstruction::BuyPledge { amount, tier, referrer, allow_partial } => {
+            buy_pledge(program_id, accounts, amount, tier, referrer.as_ref(), allow_partial, current_time(accounts)?)
+        }
+        PledgeInstruction::UpdateReward => {
+            let account_info_iter = &mut accounts.iter();
+            let account_info = next_account_info(account_info_iter)?;
+            let config_info = next_account_info(account_info_iter)?;
+            update_reward(program_id, account_info, config_info, current_time(accounts)?)
+        }
+        PledgeInstruction::ViewRewards { soft } => {
+            let account_info_iter = &mut accounts.iter();
+            let account_info = next_account_info(account_info_iter)?;
+            let config_info = next_account_info(account_info_iter)?;
+            view_rewards(program_id, account_info, config_info, soft, current_time(accounts)?)
+        }
+        // Accounts: user state, wallet, config, Solheist vault, beneficiary's
+        // Solheist token account, Solheist mint, SPL token program.
+        PledgeInstruction::ClaimRewards { amount } => claim_rewards(program_id, accounts, amount, current_time(accounts)?),
+        PledgeInstruction::UnlockPledge => unlock_pledge(program_id, accounts, current_time(accounts)?),
+        PledgeInstruction::EarlyExit {} => early_exit(program_id, accounts, current_time(accounts)?),
+        PledgeInstruction::SetPaused { paused } => set_paused(program_id, accounts, paused),
+        PledgeInstruction::UpdatePhaseConfig { durations, rates } => {
+            update_phase_config(program_id, accounts, durations, rates, current_time(accounts)?)
+        }
+        PledgeInstruction::NominateAdmin { new_admin } => nominate_admin(program_id, accounts, new_admin),
+        PledgeInstruction::AcceptAdmin => accept_admin(program_id, accounts),
+        PledgeInstruction::WithdrawTreasury { amount } => withdraw_treasury(program_id, accounts, amount, &Rent::get()?),
+        PledgeInstruction::BuyPledgeWithToken { amount } => {
+            buy_pledge_with_token(program_id, accounts, amount, current_time(accounts)?)
+        }
+        PledgeInstruction::SetPaymentMint { payment_mint } => set_payment_mint(program_id, accounts, payment_mint),
+        PledgeInstruction::MigrateUserState => migrate_user_state(program_id, accounts, &Rent::get()?),
+        PledgeInstruction::UpdateRewardBatch => update_reward_batch(program_id, accounts, current_time(accounts)?),
+        PledgeInstruction::CloseUserState => close_user_state(program_id, accounts),
+        PledgeInstruction::SetPurchaseCooldown { seconds } => set_purchase_cooldown(program_id, accounts, seconds),
+        PledgeInstruction::SetReferralBonus { percent } => set_referral_bonus(program_id, accounts, percent),
+        PledgeInstruction::AddToWhitelist { wallet } => add_to_whitelist(program_id, accounts, &wallet, &Rent::get()?),
+        PledgeInstruction::RemoveFromWhitelist { wallet } => remove_from_whitelist(program_id, accounts, &wallet),
+        PledgeInstruction::CompoundRewards => compound_rewards(program_id, accounts, current_time(accounts)?),
+        PledgeInstruction::SetBeneficiary { new } => set_beneficiary(program_id, accounts, new),
+        PledgeInstruction::GetVestingSchedule => get_vesting_schedule(program_id, accounts),
+        PledgeInstruction::FinalizeSale => finalize_sale(program_id, accounts, current_time(accounts)?),
+        PledgeInstruction::RecordSnapshot => {
+            record_snapshot(program_id, accounts, current_time(accounts)?, &Rent::get()?)
+        }
+        PledgeInstruction::GetLatestSnapshot => get_latest_snapshot(program_id, accounts),
+        PledgeInstruction::SetSnapshotInterval { seconds } => set_snapshot_interval(program_id, accounts, seconds),
+        PledgeInstruction::TransferPosition => transfer_position(program_id, accounts, current_time(accounts)?),
+        PledgeInstruction::SetDelegate { delegate } => set_delegate(program_id, accounts, Some(delegate)),
+        PledgeInstruction::RevokeDelegate => set_delegate(program_id, accounts, None),
+        PledgeInstruction::ReleaseTeamTokens => release_team_tokens(program_id, accounts, current_time(accounts)?),
+        PledgeInstruction::QuoteBuy { amount } => quote_buy(program_id, accounts, amount, current_time(accounts)?),
+        PledgeInstruction::SetPostVestingGrace { seconds } => set_post_vesting_grace(program_id, accounts, seconds),
+        PledgeInstruction::MigrateConfig => migrate_config(program_id, accounts, &Rent::get()?),
+        PledgeInstruction::FundRewards { amount } => fund_rewards(program_id, accounts, amount),
+        PledgeInstruction::PreviewReward { soft } => preview_reward(program_id, accounts, soft, current_time(accounts)?),
+        PledgeInstruction::JoinGroup { group_id } => join_group(program_id, accounts, &group_id, &Rent::get()?),
+        PledgeInstruction::SetGroupCapEnforced { enforced } => set_group_cap_enforced(program_id, accounts, enforced),
+        PledgeInstruction::ResizeUserState { new_len } => resize_user_state(program_id, accounts, new_len, &Rent::get()?),
+        PledgeInstruction::SetCrankBounty { crank_fee, crank_budget } => {
+            set_crank_bounty(program_id, accounts, crank_fee, crank_budget)
+        }
+        PledgeInstruction::SetRewardExpiry { seconds, permissionless } => {
+            set_reward_expiry(program_id, accounts, seconds, permissionless)
+        }
+        PledgeInstruction::ExpireRewards => expire_rewards(program_id, accounts, current_time(accounts)?),
+        PledgeInstruction::GetGlobalStats => get_global_stats(program_id, accounts, current_time(accounts)?),
+        PledgeInstruction::StartSale { start_time } => {
+            start_sale(program_id, accounts, start_time, current_time(accounts)?)
+        }
+        PledgeInstruction::RepairUserState { wallet } => repair_user_state(program_id, accounts, &wallet),
+        PledgeInstruction::SetClaimFee { bps } => set_claim_fee(program_id, accounts, bps),
+        PledgeInstruction::SetTokenDecimals { payment_decimals, pledge_decimals } => {
+            set_token_decimals(program_id, accounts, payment_decimals, pledge_decimals)
+        }
+        PledgeInstruction::MergeUserStates => merge_user_states(program_id, accounts, current_time(accounts)?),
+        PledgeInstruction::EmergencySweep => emergency_sweep(program_id, accounts),
+        PledgeInstruction::SetNoOpEvents { enabled } => set_noop_events(program_id, accounts, enabled),
+        PledgeInstruction::SetComplianceHold { wallet, hold_until } => {
+            set_compliance_hold(program_id, accounts, &wallet, hold_until, current_time(accounts)?)
+        }
+    }
+}
+
+// Seconds since the epoch from the Clock sysvar. A Clock sysvar account
+// passed anywhere among the instruction's accounts takes precedence over
+// Clock::get, which lets tests inject a time.
+fn current_time(accounts: &[AccountInfo]) -> Result<u64, ProgramError> {
+    let clock = match accounts.iter().find(|info| clock::check_id(info.key)) {
+        Some(clock_info) => Clock::from_account_info(clock_info)?,
+        None => Clock::get()?,
+    };
+    unix_time(clock.unix_timestamp).map_err(Into::into)
+}
+
+// Misconfigured validators can report a negative timestamp; refuse it rather
+// than treating it as a time.
+fn unix_time(unix_timestamp: i64) -> Result<u64, PledgeError> {
+    u64::try_from(unix_timestamp).map_err(|_| {
+        msg!("Clock reports a negative unix timestamp: {}", unix_timestamp);
+        PledgeError::InvalidClock
+    })
+}
+
+// Every UserState account must belong to this program; accounts that are
+// about to be written must also be passed as writable.
+fn check_user_state_account(
+    program_id: &Pubkey,
+    account_info: &AccountInfo,
+    writable: bool,
+) -> ProgramResult {
+    assert_not_executable(account_info, "User state")?;
+    if account_info.owner != program_id {
+        msg!("User state account is not owned by the pledge program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    if writable {
+        assert_writable(account_info, "User state")?;
+    }
+    UserState::check_account_type(&account_info.data.borrow())
+}
+
+// A user state about to be written must already hold the latest layout.
+// Checked before the handler does any work, so a short account fails up front
+// instead of when the result is stored. Older, shorter layouts stay readable
+// and closable, and MigrateUserState or ResizeUserState grows them.
+fn check_user_state_len(account_info: &AccountInfo) -> ProgramResult {
+    if account_info.data_len() < UserState::LEN {
+        msg!("User state account is too small: {} < {}", account_info.data_len(), UserState::LEN);
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+    Ok(())
+}
+
+fn check_signer(account_info: &AccountInfo) -> ProgramResult {
+    if !account_info.is_signer {
+        msg!("User wallet must sign the transaction");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    Ok(())
+}
+
+// Passing one account in two roles, say the vault as the destination token
+// account or the config as a user state, would let a handler read and write
+// the same data under different assumptions. Every handler checks the
+// accounts that hold state or value here before touching any of them.
+fn assert_unique_accounts(accounts: &[&AccountInfo]) -> ProgramResult {
+    for (index, account) in accounts.iter().enumerate() {
+        if accounts[index + 1..].iter().any(|other| other.key == account.key) {
+            msg!("Account {} is passed more than once", account.key);
+            return Err(PledgeError::DuplicateAccount.into());
+        }
+    }
+    Ok(())
+}
+
+// The state account must be the PDA of the wallet recorded in it.
+fn check_user_state_address(
+    program_id: &Pubkey,
+    account_info: &AccountInfo,
+    user_state: &UserState,
+) -> ProgramResult {
+    let expected = Pubkey::create_program_address(
+        &[USER_STATE_SEED, user_state.owner.as_ref(), &[user_state.bump]],
+        program_id,
+    )
+    .map_err(|_| ProgramError::InvalidSeeds)?;
+    if expected != *account_info.key {
+        msg!("User state account does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+    Ok(())
+}
+
+// What a signer wants to do with a user state. Claim covers claiming rewards,
+// which the owner's delegate may do too; everything else is Owner only.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    Claim,
+    Owner,
+}
+
+// The one check of who may act on a user state: `signer` must sign, and be
+// the owner or, where `permission` allows, the delegate.
+fn assert_authorized(user_state: &UserState, signer: &AccountInfo, permission: Permission) -> ProgramResult {
+    check_signer(signer)?;
+    if user_state.owner == *signer.key {
+        return Ok(());
+    }
+    if permission == Permission::Claim && user_state.delegate == Some(*signer.key) {
+        return Ok(());
+    }
+    if user_state.delegate == Some(*signer.key) {
+        msg!("Only the owner may do this, not its delegate");
+    } else {
+        msg!("User state account belongs to a different wallet");
+    }
+    Err(ProgramError::InvalidSeeds)
+}
+
+pub fn initialize_user(program_id: &Pubkey, accounts: &[AccountInfo], rent: &Rent) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let account_info = next_account_info(account_info_iter)?;
+    let user_wallet_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+    let config_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[account_info, user_wallet_info, config_info])?;
+    assert_is_program(system_program_info, &system_program::ID, "System program")?;
+
+    check_signer(user_wallet_info)?;
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+
+    let (expected, bump) = find_user_state_address(program_id, user_wallet_info.key);
+    if expected != *account_info.key {
+        msg!("User state account does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    let seeds: &[&[u8]] = &[USER_STATE_SEED, user_wallet_info.key.as_ref(), &[bump]];
+    if *account_info.owner == system_program::ID {
+        create_pda_account(
+            user_wallet_info,
+            account_info,
+            system_program_info,
+            program_id,
+            rent,
+            UserState::LEN,
+            seeds,
+        )?;
+    }
+
+    check_user_state_account(program_id, account_info, true)?;
+    check_user_state_len(account_info)?;
+    assert_rent_exempt(account_info, rent, "User state")?;
+    if UserState::unpack(&account_info.data.borrow())?.is_initialized {
+        msg!("User state account is already initialized");
+        return Err(PledgeError::AlreadyInitialized.into());
+    }
+
+    let user_state = UserState {
+        is_initialized: true,
+        owner: *user_wallet_info.key,
+        bump,
+        beneficiary: *user_wallet_info.key,
+        ..UserState::default()
+    };
+    user_state.pack_into_slice(&mut account_info.data.borrow_mut())?;
+
+    pledge_contract.add_user()?;
+    save_config(config_info, &pledge_contract)
+}
+
+// Creates a program owned PDA. Someone may have sent lamports to the address
+// ahead of time, in which case create_account would fail, so top up, allocate
+// and assign separately instead.
+fn create_pda_account<'a>(
+    payer: &AccountInfo<'a>,
+    account_info: &AccountInfo<'a>,
+    system_program_info: &AccountInfo<'a>,
+    program_id: &Pubkey,
+    rent: &Rent,
+    space: usize,
+    seeds: &[&[u8]],
+) -> ProgramResult {
+    let required_lamports = rent.minimum_balance(space);
+    let accounts = [payer.clone(), account_info.clone(), system_program_info.clone()];
+
+    if account_info.lamports() == 0 {
+        return invoke_signed(
+            &system_instruction::create_account(
+                payer.key,
+                account_info.key,
+                required_lamports,
+                space as u64,
+                program_id,
+            ),
+            &accounts,
+            &[seeds],
+        );
+    }
+
+    let top_up = required_lamports.saturating_sub(account_info.lamports());
+    if top_up > 0 {
+        invoke(
+            &system_instruction::transfer(payer.key, account_info.key, top_up),
+            &accounts,
+        )?;
+    }
+    invoke_signed(
+        &system_instruction::allocate(account_info.key, space as u64),
+        &accounts,
+        &[seeds],
+    )?;
+    invoke_signed(
+        &system_instruction::assign(account_info.key, program_id),
+        &accounts,
+        &[seeds],
+    )
+}
+
+// Rewrites an older user state in the latest layout, growing the account
+// and topping up its rent from the payer. Anyone may pay for it.
+pub fn migrate_user_state(program_id: &Pubkey, accounts: &[AccountInfo], rent: &Rent) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let account_info = next_account_info(account_info_iter)?;
+    let payer_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[account_info, payer_info])?;
+    assert_is_program(system_program_info, &system_program::ID, "System program")?;
+
+    check_signer(payer_info)?;
+    check_user_state_account(program_id, account_info, true)?;
+    let user_state = load_user_state(account_info)?;
+    check_user_state_address(program_id, account_info, &user_state)?;
+    if !UserState::needs_migration(&account_info.data.borrow()) {
+        msg!("User state account is already at version {}", USER_STATE_VERSION);
+        return Ok(());
+    }
+
+    grow_account(account_info, payer_info, system_program_info, rent, UserState::LEN)?;
+    user_state.pack_into_slice(&mut account_info.data.borrow_mut())
+}
+
+// Grows the user state account to `new_len` bytes, no fewer than the latest
+// layout takes, with the wallet paying the extra rent, and rewrites the state
+// in the latest layout. Accounts only ever grow.
+pub fn resize_user_state(program_id: &Pubkey, accounts: &[AccountInfo], new_len: u64, rent: &Rent) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let account_info = next_account_info(account_info_iter)?;
+    let user_wallet_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[account_info, user_wallet_info])?;
+    assert_is_program(system_program_info, &system_program::ID, "System program")?;
+
+    check_user_state_account(program_id, account_info, true)?;
+    let user_state = load_user_state(account_info)?;
+    assert_authorized(&user_state, user_wallet_info, Permission::Owner)?;
+    check_user_state_address(program_id, account_info, &user_state)?;
+    let new_len = usize::try_from(new_len).map_err(|_| ProgramError::InvalidArgument)?;
+    if new_len < account_info.data_len() {
+        msg!("User state account cannot shrink from {} to {} bytes", account_info.data_len(), new_len);
+        return Err(PledgeError::AccountShrinkNotSupported.into());
+    }
+    if new_len < UserState::LEN {
+        msg!("User state account needs at least {} bytes, not {}", UserState::LEN, new_len);
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+
+    grow_account(account_info, user_wallet_info, system_program_info, rent, new_len)?;
+    user_state.pack_into_slice(&mut account_info.data.borrow_mut())
+}
+
+// Grows a program owned account to `len` bytes, zeroing the new ones, after
+// `payer` tops its lamports up to the rent exempt minimum for that size. An
+// account already that big is left alone.
+fn grow_account<'a>(
+    account_info: &AccountInfo<'a>,
+    payer_info: &AccountInfo<'a>,
+    system_program_info: &AccountInfo<'a>,
+    rent: &Rent,
+    len: usize,
+) -> ProgramResult {
+    if account_info.data_len() >= len {
+        return Ok(());
+    }
+    let top_up = rent.minimum_balance(len).saturating_sub(account_info.lamports());
+    if top_up > 0 {
+        invoke(
+            &system_instruction::transfer(payer_info.key, account_info.key, top_up),
+            &[payer_info.clone(), account_info.clone(), system_program_info.clone()],
+        )?;
+    }
+    account_info.realloc(len, true)
+}
+
+// Closes a user state holding nothing, returning its rent to the wallet. The
+// drained account is handed back to the system program so the runtime drops
+// it, and InitializeUser can create it again later.
+pub fn close_user_state(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let account_info = next_account_info(account_info_iter)?;
+    let user_wallet_info = next_account_info(account_info_iter)?;
+    let config_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[account_info, user_wallet_info, config_info])?;
+
+    check_user_state_account(program_id, account_info, true)?;
+    check_signer(user_wallet_info)?;
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    let user_state = load_user_state(account_info)?;
+    assert_authorized(&user_state, user_wallet_info, Permission::Owner)?;
+    check_user_state_address(program_id, account_info, &user_state)?;
+    if user_state.is_vesting() || user_state.solhit_rewards != 0 {
+        msg!(
+            "User state still holds {} pledge tokens and {} Solheist rewards",
+            user_state.locked_pledge_tokens,
+            user_state.solhit_rewards
+        );
+        return Err(PledgeError::OutstandingBalance.into());
+    }
+    // InitializeUser would bring the account back with its purchases and
+    // group forgotten: past max_per_user, onto receipt indexes already taken,
+    // and out of a group that still counts it.
+    if user_state.total_purchased != 0 || user_state.group.is_some() {
+        msg!("User state has bought {} pledge tokens or is in a group, so it stays open", user_state.total_purchased);
+        return Err(PledgeError::UserStateInUse.into());
+    }
+
+    let wallet_lamports = user_wallet_info
+        .lamports()
+        .checked_add(account_info.lamports())
+        .ok_or(PledgeError::ArithmeticOverflow)?;
+    account_info.data.borrow_mut().fill(0);
+    **account_info.try_borrow_mut_lamports()? = 0;
+    **user_wallet_info.try_borrow_mut_lamports()? = wallet_lamports;
+    account_info.assign(&system_program::ID);
+
+    pledge_contract.remove_user()?;
+    save_config(config_info, &pledge_contract)
+}
+
+// Merges a second user state of the wallet's, created before user states had
+// to be PDAs, into the wallet's PDA and closes it, returning its rent to the
+// wallet. See logic::apply_merge for how the two combine. InitializeUser only
+// ever counted the PDA, so initialized_users stays as it is.
+pub fn merge_user_states(program_id: &Pubkey, accounts: &[AccountInfo], current_time: u64) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let account_info = next_account_info(account_info_iter)?;
+    let duplicate_info = next_account_info(account_info_iter)?;
+    let user_wallet_info = next_account_info(account_info_iter)?;
+    let config_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[account_info, duplicate_info, user_wallet_info, config_info])?;
+
+    check_user_state_account(program_id, account_info, true)?;
+    check_user_state_len(account_info)?;
+    check_signer(user_wallet_info)?;
+    let mut user_state = load_user_state(account_info)?;
+    assert_authorized(&user_state, user_wallet_info, Permission::Owner)?;
+    check_user_state_address(program_id, account_info, &user_state)?;
+    // The duplicate is closed rather than written, so any layout will do.
+    check_user_state_account(program_id, duplicate_info, true)?;
+    let duplicate = load_user_state(duplicate_info)?;
+    assert_authorized(&duplicate, user_wallet_info, Permission::Owner)?;
+    let pledge_contract = load_config(program_id, config_info, false)?;
+    check_not_paused(&pledge_contract)?;
+
+    logic::apply_merge(&mut user_state, &duplicate, &pledge_contract, current_time)?;
+    user_state.pack_into_slice(&mut account_info.data.borrow_mut())?;
+
+    let wallet_lamports = user_wallet_info
+        .lamports()
+        .checked_add(duplicate_info.lamports())
+        .ok_or(PledgeError::ArithmeticOverflow)?;
+    duplicate_info.data.borrow_mut().fill(0);
+    **duplicate_info.try_borrow_mut_lamports()? = 0;
+    **user_wallet_info.try_borrow_mut_lamports()? = wallet_lamports;
+    duplicate_info.assign(&system_program::ID);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn initialize_contract(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    sale_start_time: u64,
+    phase_caps: [u64; 5],
+    max_per_user: u64,
+    guardian: Pubkey,
+    recovery_destination: Pubkey,
+    rent: &Rent,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+    let treasury_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, admin_info, treasury_info])?;
+    assert_is_program(system_program_info, &system_program::ID, "System program")?;
+
+    check_signer(admin_info)?;
+
+    let (expected, bump) = find_config_address(program_id);
+    if expected != *config_info.key {
+        msg!("Config account does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+    let (expected_treasury, treasury_bump) = find_treasury_address(program_id);
+    if expected_treasury != *treasury_info.key {
+        msg!("Treasury account does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    if *config_info.owner == system_program::ID {
+        create_pda_account(
+            admin_info,
+            config_info,
+            system_program_info,
+            program_id,
+            rent,
+            ContractConfig::LEN,
+            &[CONFIG_SEED, &[bump]],
+        )?;
+    }
+    if *treasury_info.owner == system_program::ID {
+        create_pda_account(
+            admin_info,
+            treasury_info,
+            system_program_info,
+            program_id,
+            rent,
+            0,
+            &[TREASURY_SEED, &[treasury_bump]],
+        )?;
+    }
+
+    check_config_account(program_id, config_info, true)?;
+    if config_info.data_len() != ContractConfig::LEN {
+        msg!("Config account has the wrong size");
+        return Err(ProgramError::InvalidAccountData);
+    }
+    assert_rent_exempt(config_info, rent, "Config")?;
+    if ContractConfig::unpack(&config_info.data.borrow())?.is_initialized {
+        msg!("Config account is already initialized");
+        return Err(PledgeError::AlreadyInitialized.into());
+    }
+
+    let pledge_contract = ContractConfig {
+        is_initialized: true,
+        sale_start_time,
+        bump,
+        phase_caps,
+        max_per_user,
+        admin: *admin_info.key,
+        team_lock: TeamLock { unlock_start: sale_start_time, ..ContractConfig::new().team_lock },
+        guardian,
+        recovery_destination,
+        ..ContractConfig::new()
+    };
+    pledge_contract.validate()?;
+    save_config(config_info, &pledge_contract)
+}
+
+fn check_config_account(program_id: &Pubkey, config_info: &AccountInfo, writable: bool) -> ProgramResult {
+    assert_not_executable(config_info, "Config")?;
+    if config_info.owner != program_id {
+        msg!("Config account is not owned by the pledge program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    if writable {
+        assert_writable(config_info, "Config")?;
+    }
+    ContractConfig::check_account_type(&config_info.data.borrow())
+}
+
+// Reads the config, refusing one at another layout version than this program's
+// until MigrateConfig has upgraded it.
+fn load_config(
+    program_id: &Pubkey,
+    config_info: &AccountInfo,
+    writable: bool,
+) -> Result<ContractConfig, ProgramError> {
+    let pledge_contract = load_config_any_version(program_id, config_info, writable)?;
+    if pledge_contract.config_version != CONFIG_VERSION {
+        msg!("Config account is at version {}, the program at {}", pledge_contract.config_version, CONFIG_VERSION);
+        return Err(PledgeError::ConfigVersionMismatch.into());
+    }
+    Ok(pledge_contract)
+}
+
+fn load_config_any_version(
+    program_id: &Pubkey,
+    config_info: &AccountInfo,
+    writable: bool,
+) -> Result<ContractConfig, ProgramError> {
+    check_config_account(program_id, config_info, writable)?;
+    let pledge_contract = ContractConfig::unpack(&config_info.data.borrow())?;
+    if !pledge_contract.is_initialized {
+        msg!("Config account is not initialized");
+        return Err(ProgramError::UninitializedAccount);
+    }
+    let expected = Pubkey::create_program_address(&[CONFIG_SEED, &[pledge_contract.bump]], program_id)
+        .map_err(|_| ProgramError::InvalidSeeds)?;
+    if expected != *config_info.key {
+        msg!("Config account does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+    Ok(pledge_contract)
+}
+
+// Upgrades the config to CONFIG_VERSION, growing the account and topping up
+// its rent from the admin. Admin only. Each step only adds fields:
+// - version 1 to 2 appends config_version; every other field keeps its offset.
+// - version 2 to 3 appends rewards_funded, starting at 0: whatever the vault
+//   held before was never counted.
+// - version 3 to 4 appends enforce_group_cap, off.
+// - version 4 to 5 prepends CONFIG_DISCRIMINATOR, moving every field along.
+// - version 5 to 6 appends the crank bounty, off, with no budget.
+// - version 6 to 7 appends reward expiry, off.
+// - version 7 to 8 appends the user count as USERS_UNCOUNTED: the user states
+//   opened before the upgrade are unknown, so opening and closing accounts
+//   leaves the sentinel alone and GetGlobalStats reports no count.
+// - version 8 to 9 appends the claim fee, off.
+// - version 9 to 10 appends the payment and pledge decimals, both at the
+//   lamport scale, which prices purchases as before.
+// - version 10 to 11 appends the guardian and recovery destination, unset:
+//   they are only chosen at InitializeContract, so an upgraded config never
+//   has a guardian.
+// - version 11 to 12 appends noop_events, on, so every UpdateReward still
+//   emits an event.
+pub fn migrate_config(program_id: &Pubkey, accounts: &[AccountInfo], rent: &Rent) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, admin_info])?;
+    assert_is_program(system_program_info, &system_program::ID, "System program")?;
+
+    let mut pledge_contract = load_config_any_version(program_id, config_info, true)?;
+    check_admin(&pledge_contract, admin_info)?;
+    if pledge_contract.config_version == CONFIG_VERSION {
+        msg!("Config account is already at version {}", CONFIG_VERSION);
+        return Ok(());
+    }
+    if pledge_contract.config_version > CONFIG_VERSION {
+        msg!("Config account is at version {}, newer than the program", pledge_contract.config_version);
+        return Err(PledgeError::ConfigVersionMismatch.into());
+    }
+
+    grow_account(config_info, admin_info, system_program_info, rent, ContractConfig::LEN)?;
+    if pledge_contract.config_version < 3 {
+        pledge_contract.rewards_funded = 0;
+    }
+    if pledge_contract.config_version < 4 {
+        pledge_contract.enforce_group_cap = false;
+    }
+    if pledge_contract.config_version < 6 {
+        pledge_contract.crank_fee = 0;
+        pledge_contract.crank_budget = 0;
+        pledge_contract.crank_bounties_paid = 0;
+    }
+    if pledge_contract.config_version < 7 {
+        pledge_contract.reward_expiry_secs = 0;
+        pledge_contract.expire_permissionless = false;
+        pledge_contract.expired_rewards = 0;
+    }
+    if pledge_contract.config_version < 8 {
+        pledge_contract.initialized_users = USERS_UNCOUNTED;
+    }
+    if pledge_contract.config_version < 9 {
+        pledge_contract.claim_fee_bps = 0;
+    }
+    if pledge_contract.config_version < 10 {
+        pledge_contract.payment_decimals = PAYMENT_DECIMALS;
+        pledge_contract.pledge_decimals = PLEDGE_DECIMALS;
+    }
+    if pledge_contract.config_version < 11 {
+        pledge_contract.guardian = Pubkey::default();
+        pledge_contract.recovery_destination = Pubkey::default();
+    }
+    if pledge_contract.config_version < 12 {
+        pledge_contract.noop_events = true;
+    }
+    pledge_contract.config_version = CONFIG_VERSION;
+    pledge_contract.validate()?;
+    save_config(config_info, &pledge_contract)
+}
+
+fn save_config(config_info: &AccountInfo, pledge_contract: &ContractConfig) -> ProgramResult {
+    pledge_contract.serialize(&mut &mut config_info.data.borrow_mut()[..])?;
+    Ok(())
+}
+
+fn check_not_paused(pledge_contract: &ContractConfig) -> ProgramResult {
+    if pledge_contract.paused {
+        msg!("Contract is paused");
+        return Err(PledgeError::ContractPaused.into());
+    }
+    Ok(())
+}
+
+fn check_admin(pledge_contract: &ContractConfig, admin_info: &AccountInfo) -> ProgramResult {
+    check_signer(admin_info)?;
+    if pledge_contract.admin != *admin_info.key {
+        msg!("Signer is not the contract admin");
+        return Err(PledgeError::Unauthorized.into());
+    }
+    Ok(())
+}
+
+// Pauses or resumes purchases, reward updates and claims. Admin only.
+pub fn set_paused(program_id: &Pubkey, accounts: &[AccountInfo], paused: bool) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, admin_info])?;
+
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_admin(&pledge_contract, admin_info)?;
+
+    pledge_contract.paused = paused;
+    pledge_contract.validate()?;
+    save_config(config_info, &pledge_contract)
+}
+
+// First half of an admin handover: the current admin names its successor,
+// who takes over once it signs AcceptAdmin. Nominating the default pubkey
+// cancels a pending handover.
+pub fn nominate_admin(program_id: &Pubkey, accounts: &[AccountInfo], new_admin: Pubkey) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, admin_info])?;
+
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_admin(&pledge_contract, admin_info)?;
+
+    pledge_contract.pending_admin = if new_admin == Pubkey::default() { None } else { Some(new_admin) };
+    pledge_contract.validate()?;
+    save_config(config_info, &pledge_contract)
+}
+
+pub fn accept_admin(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let new_admin_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, new_admin_info])?;
+
+    check_signer(new_admin_info)?;
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    if pledge_contract.pending_admin != Some(*new_admin_info.key) {
+        msg!("Signer is not the nominated admin");
+        return Err(PledgeError::Unauthorized.into());
+    }
+
+    pledge_contract.admin = *new_admin_info.key;
+    pledge_contract.pending_admin = None;
+    pledge_contract.validate()?;
+    save_config(config_info, &pledge_contract)
+}
+
+// Moves `amount` lamports of sale proceeds from the treasury PDA to any
+// account. Admin only; the treasury always stays rent exempt.
+pub fn withdraw_treasury(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64, rent: &Rent) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    let treasury_info = next_account_info(account_info_iter)?;
+    let destination_info = next_account_info(account_info_iter)?;
+    // The admin may withdraw to their own wallet.
+    assert_unique_accounts(&[config_info, treasury_info, destination_info])?;
+
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_admin(&pledge_contract, admin_info)?;
+    check_treasury(program_id, treasury_info)?;
+
+    if amount == 0 {
+        msg!("Withdrawal amount must be greater than zero");
+        return Err(PledgeError::InvalidAmount.into());
+    }
+    let available = treasury_info.lamports().saturating_sub(rent.minimum_balance(treasury_info.data_len()));
+    if amount > available {
+        msg!("Cannot withdraw {} lamports, only {} available", amount, available);
+        return Err(PledgeError::InsufficientTreasury.into());
+    }
+
+    let destination_lamports = destination_info
+        .lamports()
+        .checked_add(amount)
+        .ok_or(PledgeError::ArithmeticOverflow)?;
+    **treasury_info.try_borrow_mut_lamports()? -= amount;
+    **destination_info.try_borrow_mut_lamports()? = destination_lamports;
+
+    pledge_contract.total_withdrawn = pledge_contract
+        .total_withdrawn
+        .checked_add(amount)
+        .ok_or(PledgeError::ArithmeticOverflow)?;
+    pledge_contract.validate()?;
+    save_config(config_info, &pledge_contract)
+}
+
+// Moves every team token vested by now and not yet released from the
+// Solheist vault to any Solheist token account. Admin only.
+pub fn release_team_tokens(program_id: &Pubkey, accounts: &[AccountInfo], current_time: u64) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    let vault_info = next_account_info(account_info_iter)?;
+    let team_token_info = next_account_info(account_info_iter)?;
+    let mint_info = next_account_info(account_info_iter)?;
+    let token_program_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, admin_info, vault_info, team_token_info])?;
+
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_admin(&pledge_contract, admin_info)?;
+    let (vault_bump, decimals) =
+        check_solhit_vault(program_id, vault_info, team_token_info, mint_info, token_program_info, None)?;
+
+    let amount = logic::releasable_team_tokens(&pledge_contract, current_time)?;
+    pledge_contract.team_released =
+        pledge_contract.team_released.checked_add(amount).ok_or(PledgeError::ArithmeticOverflow)?;
+
+    invoke_signed(
+        &spl_token::instruction::transfer_checked(
+            token_program_info.key,
+            vault_info.key,
+            mint_info.key,
+            team_token_info.key,
+            vault_info.key,
+            &[],
+            amount,
+            decimals,
+        )?,
+        &[vault_info.clone(), mint_info.clone(), team_token_info.clone(), token_program_info.clone()],
+        &[&[SOLHIT_VAULT_SEED, &[vault_bump]]],
+    )?;
+
+    emit_event(PledgeEvent::TeamRelease {
+        event_seq: pledge_contract.next_event_seq()?,
+        destination: *team_token_info.key,
+        amount,
+        total_released: pledge_contract.team_released,
+    });
+    pledge_contract.validate()?;
+    save_config(config_info, &pledge_contract)
+}
+
+// Moves `amount` Solheist from the admin's token account into the vault that
+// pays rewards out. Admin only.
+pub fn fund_rewards(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    let source_info = next_account_info(account_info_iter)?;
+    let vault_info = next_account_info(account_info_iter)?;
+    let mint_info = next_account_info(account_info_iter)?;
+    let token_program_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, admin_info, source_info, vault_info])?;
+
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_admin(&pledge_contract, admin_info)?;
+    if amount == 0 {
+        msg!("Nothing to fund");
+        return Err(PledgeError::InvalidAmount.into());
+    }
+    let (_, decimals) = check_solhit_vault(program_id, vault_info, source_info, mint_info, token_program_info, None)?;
+
+    pledge_contract.rewards_funded =
+        pledge_contract.rewards_funded.checked_add(amount).ok_or(PledgeError::ArithmeticOverflow)?;
+    let event_seq = pledge_contract.next_event_seq()?;
+    save_config(config_info, &pledge_contract)?;
+
+    invoke(
+        &spl_token::instruction::transfer_checked(
+            token_program_info.key,
+            source_info.key,
+            mint_info.key,
+            vault_info.key,
+            admin_info.key,
+            &[],
+            amount,
+            decimals,
+        )?,
+        &[source_info.clone(), mint_info.clone(), vault_info.clone(), admin_info.clone(), token_program_info.clone()],
+    )?;
+
+    emit_event(PledgeEvent::RewardsFunded {
+        event_seq,
+        source: *source_info.key,
+        amount,
+        total_funded: pledge_contract.rewards_funded,
+    });
+    Ok(())
+}
+
+// Moves everything in the pledge vault or the Solheist vault to the recovery
+// destination's token account of the same mint. Guardian only, and only once
+// the admin has paused the contract.
+pub fn emergency_sweep(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let guardian_info = next_account_info(account_info_iter)?;
+    let vault_info = next_account_info(account_info_iter)?;
+    let destination_info = next_account_info(account_info_iter)?;
+    let token_program_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, guardian_info, vault_info, destination_info])?;
+    assert_is_program(token_program_info, &spl_token::id(), "Token program")?;
+
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_signer(guardian_info)?;
+    if pledge_contract.guardian == Pubkey::default() || pledge_contract.guardian != *guardian_info.key {
+        msg!("Signer is not the contract guardian");
+        return Err(PledgeError::Unauthorized.into());
+    }
+    if !pledge_contract.paused {
+        msg!("Contract must be paused before its vaults are swept");
+        return Err(PledgeError::ContractNotPaused.into());
+    }
+
+    // The Solheist vault is its own authority; the pledge vault answers to
+    // the pledge vault PDA.
+    let (solhit_vault, solhit_vault_bump) = find_solhit_vault_address(program_id);
+    let (vault_authority_info, seed, vault_bump) = if *vault_info.key == solhit_vault {
+        (vault_info, SOLHIT_VAULT_SEED, solhit_vault_bump)
+    } else {
+        let vault_authority_info = next_account_info(account_info_iter)?;
+        let vault_bump = check_pledge_vault(program_id, vault_info, vault_authority_info, token_program_info)?;
+        (vault_authority_info, PLEDGE_VAULT_SEED, vault_bump)
+    };
+    if *vault_info.owner != spl_token::id() {
+        msg!("Vault is not an SPL token account");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let vault = spl_token::state::Account::unpack(&vault_info.data.borrow())?;
+    if vault.owner != *vault_authority_info.key {
+        msg!("Vault is not held by the program");
+        return Err(PledgeError::InvalidVault.into());
+    }
+    if *destination_info.owner != spl_token::id() {
+        msg!("Sweep destination is not an SPL token account");
+        return Err(PledgeError::InvalidRecoveryDestination.into());
+    }
+    let destination = spl_token::state::Account::unpack(&destination_info.data.borrow())?;
+    if destination.owner != pledge_contract.recovery_destination || destination.mint != vault.mint {
+        msg!("Sweep destination is not the recovery destination's account of the vault's mint");
+        return Err(PledgeError::InvalidRecoveryDestination.into());
+    }
+
+    let event_seq = pledge_contract.next_event_seq()?;
+    save_config(config_info, &pledge_contract)?;
+
+    invoke_signed(
+        &spl_token::instruction::transfer(
+            token_program_info.key,
+            vault_info.key,
+            destination_info.key,
+            vault_authority_info.key,
+            &[],
+            vault.amount,
+        )?,
+        &[
+            vault_info.clone(),
+            destination_info.clone(),
+            vault_authority_info.clone(),
+            token_program_info.clone(),
+        ],
+        &[&[seed, &[vault_bump]]],
+    )?;
+
+    emit_event(PledgeEvent::Sweep {
+        event_seq,
+        vault: *vault_info.key,
+        destination: *destination_info.key,
+        amount: vault.amount,
+    });
+    Ok(())
+}
+
+fn check_treasury(program_id: &Pubkey, treasury_info: &AccountInfo) -> ProgramResult {
+    if *treasury_info.key != find_treasury_address(program_id).0 {
+        msg!("Treasury account does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+    if treasury_info.owner != program_id {
+        msg!("Treasury account is not owned by the pledge program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    assert_writable(treasury_info, "Treasury")
+}
+
+// Sets the mint accepted by BuyPledgeWithToken. Admin only; the default
+// pubkey turns token payments off.
+pub fn set_payment_mint(program_id: &Pubkey, accounts: &[AccountInfo], payment_mint: Pubkey) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, admin_info])?;
+
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_admin(&pledge_contract, admin_info)?;
+
+    pledge_contract.payment_mint = payment_mint;
+    pledge_contract.validate()?;
+    save_config(config_info, &pledge_contract)
+}
+
+// Creates the whitelist PDA of `wallet`, paid for by the admin. Admin only.
+pub fn add_to_whitelist(program_id: &Pubkey, accounts: &[AccountInfo], wallet: &Pubkey, rent: &Rent) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    let whitelist_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, admin_info, whitelist_info])?;
+    assert_is_program(system_program_info, &system_program::ID, "System program")?;
+
+    let pledge_contract = load_config(program_id, config_info, false)?;
+    check_admin(&pledge_contract, admin_info)?;
+    let (expected, bump) = find_whitelist_address(program_id, wallet);
+    if expected != *whitelist_info.key {
+        msg!("Whitelist account does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    if *whitelist_info.owner == system_program::ID {
+        let seeds: &[&[u8]] = &[WHITELIST_SEED, wallet.as_ref(), &[bump]];
+        create_pda_account(admin_info, whitelist_info, system_program_info, program_id, rent, Whitelist::LEN, seeds)?;
+    }
+    if whitelist_info.owner != program_id {
+        msg!("Whitelist account is not owned by the pledge program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    assert_writable(whitelist_info, "Whitelist")?;
+    let mut data = whitelist_info.data.borrow_mut();
+    if data.len() < Whitelist::LEN {
+        msg!("Whitelist account is too small");
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+    if Whitelist::deserialize(&mut &data[..])?.is_initialized {
+        msg!("Wallet is already whitelisted");
+        return Err(PledgeError::AlreadyInitialized.into());
+    }
+    let whitelist = Whitelist { is_initialized: true, wallet: *wallet, bump };
+    whitelist.serialize(&mut &mut data[..])?;
+    Ok(())
+}
+
+// Closes the whitelist PDA of `wallet` and returns its rent to the admin.
+// Admin only.
+pub fn remove_from_whitelist(program_id: &Pubkey, accounts: &[AccountInfo], wallet: &Pubkey) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    let whitelist_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, admin_info, whitelist_info])?;
+
+    let pledge_contract = load_config(program_id, config_info, false)?;
+    check_admin(&pledge_contract, admin_info)?;
+    let (expected, _) = find_whitelist_address(program_id, wallet);
+    if expected != *whitelist_info.key {
+        msg!("Whitelist account does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+    if whitelist_info.owner != program_id {
+        msg!("Wallet is not whitelisted");
+        return Err(PledgeError::NotWhitelisted.into());
+    }
+    assert_writable(whitelist_info, "Whitelist")?;
+
+    let admin_lamports = admin_info
+        .lamports()
+        .checked_add(whitelist_info.lamports())
+        .ok_or(PledgeError::ArithmeticOverflow)?;
+    whitelist_info.data.borrow_mut().fill(0);
+    **whitelist_info.try_borrow_mut_lamports()? = 0;
+    **admin_info.try_borrow_mut_lamports()? = admin_lamports;
+    whitelist_info.assign(&system_program::ID);
+    Ok(())
+}
+
+// Phase 0 purchases need the buyer's whitelist PDA, passed after the other
+// accounts of the purchase.
+fn check_whitelisted(program_id: &Pubkey, wallet: &Pubkey, whitelist_info: Option<&AccountInfo>) -> ProgramResult {
+    let whitelisted = whitelist_info.is_some_and(|whitelist_info| {
+        let (expected, _) = find_whitelist_address(program_id, wallet);
+        *whitelist_info.key == expected
+            && whitelist_info.owner == program_id
+            && Whitelist::deserialize(&mut &whitelist_info.data.borrow()[..])
+                .is_ok_and(|whitelist| whitelist.is_initialized && whitelist.wallet == *wallet)
+    });
+    if !whitelisted {
+        msg!("The first sale phase is reserved for whitelisted wallets");
+        return Err(PledgeError::NotWhitelisted.into());
+    }
+    Ok(())
+}
+
+// Reads the next account as the buy group `user_state` belongs to, if it
+// belongs to one. A member's purchases always count towards its group, so
+// the group cannot be left out.
+fn load_user_group<'a, 'b>(
+    program_id: &Pubkey,
+    user_state: &UserState,
+    account_info_iter: &mut std::slice::Iter<'a, AccountInfo<'b>>,
+) -> Result<Option<(&'a AccountInfo<'b>, BuyGroup)>, ProgramError> {
+    let Some(group) = user_state.group else {
+        return Ok(None);
+    };
+    let group_info = next_account_info(account_info_iter)?;
+    if *group_info.key != group {
+        msg!("Group account does not match the user's buy group");
+        return Err(ProgramError::InvalidSeeds);
+    }
+    Ok(Some((group_info, load_group(program_id, group_info)?)))
+}
+
+fn load_group(program_id: &Pubkey, group_info: &AccountInfo) -> Result<BuyGroup, ProgramError> {
+    if group_info.owner != program_id {
+        msg!("Group account is not owned by the pledge program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    assert_writable(group_info, "Group")?;
+    let group = BuyGroup::deserialize(&mut &group_info.data.borrow()[..])?;
+    if !group.is_initialized {
+        msg!("Group account is not initialized");
+        return Err(ProgramError::UninitializedAccount);
+    }
+    Ok(group)
+}
+
+// Adds the user to the buy group of `group_id`, creating its PDA, paid for by
+// the wallet, on the first join. Everything the user bought before counts
+// towards the group, even past the cap; the cap only stops later purchases.
+pub fn join_group(program_id: &Pubkey, accounts: &[AccountInfo], group_id: &Pubkey, rent: &Rent) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let account_info = next_account_info(account_info_iter)?;
+    let user_wallet_info = next_account_info(account_info_iter)?;
+    let group_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[account_info, user_wallet_info, group_info])?;
+    assert_is_program(system_program_info, &system_program::ID, "System program")?;
+
+    check_user_state_account(program_id, account_info, true)?;
+    check_user_state_len(account_info)?;
+    let mut user_state = load_user_state(account_info)?;
+    assert_authorized(&user_state, user_wallet_info, Permission::Owner)?;
+    check_user_state_address(program_id, account_info, &user_state)?;
+    if let Some(group) = user_state.group {
+        msg!("Wallet already belongs to buy group {}", group);
+        return Err(PledgeError::AlreadyInGroup.into());
+    }
+    let (expected, bump) = find_group_address(program_id, group_id);
+    if expected != *group_info.key {
+        msg!("Group account does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    let mut group = if *group_info.owner == system_program::ID {
+        let seeds: &[&[u8]] = &[GROUP_SEED, group_id.as_ref(), &[bump]];
+        create_pda_account(user_wallet_info, group_info, system_program_info, program_id, rent, BuyGroup::LEN, seeds)?;
+        BuyGroup { is_initialized: true, group_id: *group_id, bump, ..BuyGroup::default() }
+    } else {
+        load_group(program_id, group_info)?
+    };
+    group.member_count = group.member_count.checked_add(1).ok_or(PledgeError::ArithmeticOverflow)?;
+    group.total_purchased =
+        group.total_purchased.checked_add(user_state.total_purchased).ok_or(PledgeError::ArithmeticOverflow)?;
+    user_state.group = Some(*group_info.key);
+
+    group.serialize(&mut &mut group_info.data.borrow_mut()[..])?;
+    user_state.pack_into_slice(&mut account_info.data.borrow_mut())
+}
+
+// Sets the share of each referred purchase credited to the referrer. Admin
+// only; 0 turns referral bonuses off.
+pub fn set_referral_bonus(program_id: &Pubkey, accounts: &[AccountInfo], percent: u64) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, admin_info])?;
+
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_admin(&pledge_contract, admin_info)?;
+    if percent > 100 {
+        msg!("Referral bonus of {}% exceeds 100%", percent);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    pledge_contract.referral_bonus_percent = percent;
+    pledge_contract.validate()?;
+    save_config(config_info, &pledge_contract)
+}
+
+// Sets the minimum time between a user's purchases. Admin only; 0 turns the
+// cooldown off.
+pub fn set_purchase_cooldown(program_id: &Pubkey, accounts: &[AccountInfo], seconds: u64) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, admin_info])?;
+
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_admin(&pledge_contract, admin_info)?;
+
+    pledge_contract.purchase_cooldown_secs = seconds;
+    pledge_contract.validate()?;
+    save_config(config_info, &pledge_contract)
+}
+
+// Replaces the phase schedule. Admin only, and only before the sale starts so
+// no purchase is ever priced under two schedules.
+pub fn update_phase_config(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    durations: [u64; 5],
+    rates: [u64; 5],
+    current_time: u64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, admin_info])?;
+
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_admin(&pledge_contract, admin_info)?;
+    if current_time >= pledge_contract.sale_start_time {
+        msg!("Sale started at {}", pledge_contract.sale_start_time);
+        return Err(PledgeError::SaleStarted.into());
+    }
+
+    pledge_contract.phase_durations = durations;
+    pledge_contract.phase_rates = rates;
+    pledge_contract.validate()?;
+    save_config(config_info, &pledge_contract)
+}
+
+// Opens a sale initialized without a start time, at `start_time` or now, and
+// starts the team lock with it. Admin only, and only once.
+pub fn start_sale(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    start_time: Option<u64>,
+    current_time: u64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, admin_info])?;
+
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_admin(&pledge_contract, admin_info)?;
+    if pledge_contract.sale_start_time != SALE_UNSCHEDULED {
+        msg!("Sale starts at {}", pledge_contract.sale_start_time);
+        return Err(PledgeError::SaleStarted.into());
+    }
+    let start_time = start_time.unwrap_or(current_time);
+    if start_time < current_time || start_time == SALE_UNSCHEDULED {
+        msg!("Sale cannot start at {}", start_time);
+        return Err(PledgeError::InvalidStartTime.into());
+    }
+
+    pledge_contract.sale_start_time = start_time;
+    if pledge_contract.team_lock.unlock_start == SALE_UNSCHEDULED {
+        pledge_contract.team_lock.unlock_start = start_time;
+    }
+    emit_event(PledgeEvent::SaleStarted { event_seq: pledge_contract.next_event_seq()?, start_time });
+    pledge_contract.validate()?;
+    save_config(config_info, &pledge_contract)
+}
+
+// Closes the sale once its last finite phase is over. Admin only, and only
+// once: whatever was not sold by then stays unsold.
+pub fn finalize_sale(program_id: &Pubkey, accounts: &[AccountInfo], current_time: u64) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, admin_info])?;
+
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_admin(&pledge_contract, admin_info)?;
+    if pledge_contract.sale_finalized {
+        msg!("Sale is already finalized");
+        return Err(PledgeError::SaleEnded.into());
+    }
+    let sale_end_time = logic::sale_end_time(&pledge_contract)?;
+    if current_time < sale_end_time {
+        msg!("Sale runs until {}", sale_end_time);
+        return Err(PledgeError::SaleInProgress.into());
+    }
+
+    pledge_contract.sale_finalized = true;
+    pledge_contract.unsold_supply = pledge_contract.total_pledge_supply.saturating_sub(pledge_contract.total_pledge_sold);
+    emit_event(PledgeEvent::SaleFinalized {
+        event_seq: pledge_contract.next_event_seq()?,
+        total_pledge_sold: pledge_contract.total_pledge_sold,
+        unsold_supply: pledge_contract.unsold_supply,
+    });
+    pledge_contract.validate()?;
+    save_config(config_info, &pledge_contract)
+}
+
+// Appends the sale's progress to the snapshots PDA, creating it on first use
+// at the payer's expense. Anyone may record one, but only once per snapshot
+// interval.
+pub fn record_snapshot(program_id: &Pubkey, accounts: &[AccountInfo], current_time: u64, rent: &Rent) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let snapshots_info = next_account_info(account_info_iter)?;
+    let payer_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, snapshots_info, payer_info])?;
+    assert_is_program(system_program_info, &system_program::ID, "System program")?;
+
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    let (expected, bump) = find_snapshots_address(program_id);
+    if expected != *snapshots_info.key {
+        msg!("Snapshots account does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+    if *snapshots_info.owner == system_program::ID {
+        check_signer(payer_info)?;
+        let seeds: &[&[u8]] = &[SNAPSHOTS_SEED, &[bump]];
+        create_pda_account(payer_info, snapshots_info, system_program_info, program_id, rent, SnapshotBuffer::LEN, seeds)?;
+    }
+    if snapshots_info.owner != program_id {
+        msg!("Snapshots account is not owned by the pledge program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    assert_writable(snapshots_info, "Snapshots")?;
+    let mut data = snapshots_info.data.borrow_mut();
+    if data.len() < SnapshotBuffer::LEN {
+        msg!("Snapshots account is too small");
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+    let mut buffer = SnapshotBuffer::deserialize(&mut &data[..])?;
+
+    // The interval counts from the last snapshot, so the first is always allowed.
+    if buffer.count > 0 {
+        let ready_at = pledge_contract.last_snapshot_time.saturating_add(pledge_contract.snapshot_interval_secs);
+        if current_time < ready_at {
+            msg!("Next snapshot allowed in {} seconds", ready_at - current_time);
+            return Err(PledgeError::SnapshotTooSoon.into());
+        }
+    }
+
+    let elapsed = current_time.saturating_sub(pledge_contract.sale_start_time);
+    buffer.push(Snapshot {
+        timestamp: current_time,
+        total_sold: pledge_contract.total_pledge_sold,
+        total_claimed: pledge_contract.global_solhit_claimed,
+        active_phase: get_sale_phase(elapsed, &pledge_contract.phase_durations) as u8,
+    })?;
+    buffer.is_initialized = true;
+    buffer.bump = bump;
+    buffer.serialize(&mut &mut data[..])?;
+
+    pledge_contract.last_snapshot_time = current_time;
+    save_config(config_info, &pledge_contract)
+}
+
+// Sets the most recent snapshot as the return data, without writing anything.
+pub fn get_latest_snapshot(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let snapshots_info = next_account_info(account_info_iter)?;
+
+    let (expected, _) = find_snapshots_address(program_id);
+    if expected != *snapshots_info.key {
+        msg!("Snapshots account does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+    if snapshots_info.owner != program_id {
+        msg!("No snapshot has been recorded");
+        return Err(ProgramError::UninitializedAccount);
+    }
+    let buffer = SnapshotBuffer::deserialize(&mut &snapshots_info.data.borrow()[..])?;
+    let snapshot = buffer.latest().ok_or_else(|| {
+        msg!("No snapshot has been recorded");
+        ProgramError::UninitializedAccount
+    })?;
+    set_return_data(&borsh::to_vec(&snapshot)?);
+
+    Ok(())
+}
+
+// Sets the minimum time between snapshots. Admin only; 0 lets every
+// RecordSnapshot through.
+pub fn set_snapshot_interval(program_id: &Pubkey, accounts: &[AccountInfo], seconds: u64) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, admin_info])?;
+
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_admin(&pledge_contract, admin_info)?;
+
+    pledge_contract.snapshot_interval_secs = seconds;
+    pledge_contract.validate()?;
+    save_config(config_info, &pledge_contract)
+}
+
+// Sets how long lots keep accruing past their vesting end. Admin only; 0
+// stops them at the vesting end and u64::MAX never does.
+pub fn set_post_vesting_grace(program_id: &Pubkey, accounts: &[AccountInfo], seconds: u64) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, admin_info])?;
+
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_admin(&pledge_contract, admin_info)?;
+
+    pledge_contract.post_vesting_grace_secs = seconds;
+    pledge_contract.validate()?;
+    save_config(config_info, &pledge_contract)
+}
+
+// Sets whether max_per_user also caps each buy group's combined purchases.
+// Admin only.
+pub fn set_group_cap_enforced(program_id: &Pubkey, accounts: &[AccountInfo], enforced: bool) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, admin_info])?;
+
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_admin(&pledge_contract, admin_info)?;
+
+    pledge_contract.enforce_group_cap = enforced;
+    pledge_contract.validate()?;
+    save_config(config_info, &pledge_contract)
+}
+
+// Sets the Solheist bounty UpdateRewardBatch pays per account it accrues,
+// and the total it may ever pay. A budget below what has been paid already
+// stops further bounties. Admin only.
+pub fn set_crank_bounty(program_id: &Pubkey, accounts: &[AccountInfo], crank_fee: u64, crank_budget: u64) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, admin_info])?;
+
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_admin(&pledge_contract, admin_info)?;
+
+    pledge_contract.crank_fee = crank_fee;
+    pledge_contract.crank_budget = crank_budget;
+    pledge_contract.validate()?;
+    save_config(config_info, &pledge_contract)
+}
+
+// Sets how long after a user's vesting end unclaimed rewards expire, 0 for
+// never, and whether anyone may run ExpireRewards or only the admin. Admin
+// only.
+pub fn set_reward_expiry(program_id: &Pubkey, accounts: &[AccountInfo], seconds: u64, permissionless: bool) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, admin_info])?;
+
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_admin(&pledge_contract, admin_info)?;
+
+    pledge_contract.reward_expiry_secs = seconds;
+    pledge_contract.expire_permissionless = permissionless;
+    pledge_contract.validate()?;
+    save_config(config_info, &pledge_contract)
+}
+
+// Sets the share of each claim, in basis points, that goes to the treasury
+// instead of the beneficiary. Admin only.
+pub fn set_claim_fee(program_id: &Pubkey, accounts: &[AccountInfo], bps: u16) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, admin_info])?;
+
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_admin(&pledge_contract, admin_info)?;
+
+    pledge_contract.claim_fee_bps = bps;
+    pledge_contract.validate()?;
+    save_config(config_info, &pledge_contract)
+}
+
+// Sets the decimals of the payments prices are quoted in and of the pledge
+// token. Admin only, and only before anything has sold, so every purchase is
+// priced the same way.
+pub fn set_token_decimals(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    payment_decimals: u8,
+    pledge_decimals: u8,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, admin_info])?;
+
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_admin(&pledge_contract, admin_info)?;
+    if pledge_contract.total_pledge_sold != 0 {
+        msg!("Token decimals cannot change once pledge tokens have sold");
+        return Err(PledgeError::SaleStarted.into());
+    }
+
+    pledge_contract.payment_decimals = payment_decimals;
+    pledge_contract.pledge_decimals = pledge_decimals;
+    pledge_contract.validate()?;
+    save_config(config_info, &pledge_contract)
+}
+
+// Sets whether an UpdateReward that finds nothing to accrue emits a NoOp
+// event. Admin only.
+pub fn set_noop_events(program_id: &Pubkey, accounts: &[AccountInfo], enabled: bool) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[config_info, admin_info])?;
+
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_admin(&pledge_contract, admin_info)?;
+
+    pledge_contract.noop_events = enabled;
+    pledge_contract.validate()?;
+    save_config(config_info, &pledge_contract)
+}
+
+// Moves a user's unclaimed rewards into the config's expired_rewards once
+// they have expired. Admin only, unless the config lets anyone do it.
+pub fn expire_rewards(program_id: &Pubkey, accounts: &[AccountInfo], current_time: u64) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let account_info = next_account_info(account_info_iter)?;
+    let config_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[account_info, config_info])?;
+
+    check_user_state_account(program_id, account_info, true)?;
+    check_user_state_len(account_info)?;
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    if !pledge_contract.expire_permissionless {
+        check_admin(&pledge_contract, next_account_info(account_info_iter)?)?;
+    }
+    let mut user_state = load_user_state(account_info)?;
+    check_user_state_address(program_id, account_info, &user_state)?;
+
+    let amount = logic::expire_rewards(&mut user_state, &pledge_contract, current_time)?;
+    pledge_contract.expired_rewards = pledge_contract
+        .expired_rewards
+        .checked_add(amount)
+        .ok_or(PledgeError::ArithmeticOverflow)?;
+    user_state.pack_into_slice(&mut account_info.data.borrow_mut())?;
+
+    emit_event(PledgeEvent::Expire {
+        event_seq: pledge_contract.next_event_seq()?,
+        user: *account_info.key,
+        wallet: user_state.owner,
+        amount,
+        total_expired: pledge_contract.expired_rewards,
+    });
+    save_config(config_info, &pledge_contract)
+}
+
+// Zeroes the user state of `wallet` when it no longer loads, leaving it for
+// InitializeUser to set up afresh. Only the wallet's own PDA can be zeroed,
+// and only by the admin. A state that loads, or one already zeroed, is left
+// alone. Garbage that never was a user state leaves initialized_users as is.
+pub fn repair_user_state(program_id: &Pubkey, accounts: &[AccountInfo], wallet: &Pubkey) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let account_info = next_account_info(account_info_iter)?;
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[account_info, config_info, admin_info])?;
+
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_admin(&pledge_contract, admin_info)?;
+    if find_user_state_address(program_id, wallet).0 != *account_info.key {
+        msg!("User state account does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+    // Not check_user_state_account, which refuses data of the wrong type.
+    assert_not_executable(account_info, "User state")?;
+    if account_info.owner != program_id {
+        msg!("User state account is not owned by the pledge program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    assert_writable(account_info, "User state")?;
+
+    if account_info.data.borrow().iter().all(|&byte| byte == 0) {
+        msg!("User state is already zeroed");
+        return Err(PledgeError::StateNotCorrupted.into());
+    }
+    if load_user_state(account_info).is_ok_and(|user_state| user_state.owner == *wallet) {
+        msg!("User state of {} loads and needs no repair", wallet);
+        return Err(PledgeError::StateNotCorrupted.into());
+    }
+    // Only a state InitializeUser got as far as writing was ever counted: one
+    // with the discriminator, past the version byte a set is_initialized.
+    let counted = {
+        let data = account_info.data.borrow();
+        data.starts_with(&USER_STATE_DISCRIMINATOR) && data.get(USER_STATE_DISCRIMINATOR.len() + 1) == Some(&1)
+    };
+    account_info.data.borrow_mut().fill(0);
+
+    if counted {
+        pledge_contract.remove_user()?;
+    }
+    save_config(config_info, &pledge_contract)
+}
+
+// Puts the user state of `wallet` on a compliance hold until `hold_until`,
+// or lifts it with 0. Admin only. The hold only blocks what would take
+// tokens or rewards out of the state, so accrual goes on as before.
+pub fn set_compliance_hold(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    wallet: &Pubkey,
+    hold_until: u64,
+    current_time: u64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let account_info = next_account_info(account_info_iter)?;
+    let config_info = next_account_info(account_info_iter)?;
+    let admin_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[account_info, config_info, admin_info])?;
+
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_admin(&pledge_contract, admin_info)?;
+    if find_user_state_address(program_id, wallet).0 != *account_info.key {
+        msg!("User state account does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+    check_user_state_account(program_id, account_info, true)?;
+    check_user_state_len(account_info)?;
+    let mut user_state = load_user_state(account_info)?;
+    check_user_state_address(program_id, account_info, &user_state)?;
+
+    let previous_hold_until = user_state.hold_until;
+    user_state.hold_until = hold_until;
+    user_state.pack_into_slice(&mut account_info.data.borrow_mut())?;
+
+    emit_event(PledgeEvent::ComplianceHold {
+        event_seq: pledge_contract.next_event_seq()?,
+        user: *account_info.key,
+        wallet: *wallet,
+        admin: *admin_info.key,
+        previous_hold_until,
+        hold_until,
+        timestamp: current_time,
+    });
+    save_config(config_info, &pledge_contract)
+}
+
+// Reads a user state, refusing accounts that never went through InitializeUser
+// and ones that fail the sanity checks.
+fn load_user_state(account_info: &AccountInfo) -> Result<UserState, ProgramError> {
+    let user_state = UserState::unpack(&account_info.data.borrow())?;
+    if !user_state.is_initialized {
+        msg!("User state account is not initialized");
+        return Err(ProgramError::UninitializedAccount);
+    }
+    user_state.sanity_check()?;
+    Ok(user_state)
+}
+
+// Reads the user state a view is of. One yet to be initialized, whether never
+// created or zeroed by RepairUserState, is UninitializedAccount, or None with
+// `soft`.
+fn load_viewed_user_state(program_id: &Pubkey, account_info: &AccountInfo, soft: bool) -> Result<Option<UserState>, ProgramError> {
+    let user_state = if *account_info.owner == system_program::ID && account_info.data_is_empty() {
+        msg!("User state account is not initialized");
+        Err(ProgramError::UninitializedAccount)
+    } else {
+        check_user_state_account(program_id, account_info, false)?;
+        load_user_state(account_info)
+    };
+    match user_state {
+        Err(ProgramError::UninitializedAccount) if soft => Ok(None),
+        user_state => user_state.map(Some),
+    }
+}
+
+// `tier` indexes the config's lock tiers. `referrer` is the user state
+// address of whoever referred the buyer, which then follows the fixed
+// accounts. With `allow_partial` a purchase bigger than the remaining supply
+// is cut down to it, and only its share of `amount` is charged.
+pub fn buy_pledge(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    amount: u64,
+    tier: u8,
+    referrer: Option<&Pubkey>,
+    allow_partial: bool,
+    current_time: u64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let account_info = next_account_info(account_info_iter)?;
+    let user_wallet_info = next_account_info(account_info_iter)?;
+    let config_info = next_account_info(account_info_iter)?;
+    let treasury_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+    let referrer_info = referrer.map(|_| next_account_info(account_info_iter)).transpose()?;
+    let mut unique = vec![account_info, user_wallet_info, config_info, treasury_info];
+    unique.extend(referrer_info);
+    assert_unique_accounts(&unique)?;
+    assert_is_program(system_program_info, &system_program::ID, "System program")?;
+
+    check_user_state_account(program_id, account_info, true)?;
+    check_user_state_len(account_info)?;
+    check_signer(user_wallet_info)?;
+    let mut user_state = load_user_state(account_info)?;
+    assert_authorized(&user_state, user_wallet_info, Permission::Owner)?;
+    check_user_state_address(program_id, account_info, &user_state)?;
+    let receipt = find_receipt(program_id, &accounts[5..], &user_state)?;
+    let mut group = load_user_group(program_id, &user_state, account_info_iter)?;
+    let whitelist_info = account_info_iter.next();
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_not_paused(&pledge_contract)?;
+    check_treasury(program_id, treasury_info)?;
+
+    let outcome = logic::apply_buy(&mut user_state, &pledge_contract, amount, tier, allow_partial, current_time)?;
+    if outcome.phase == 0 {
+        check_whitelisted(program_id, user_wallet_info.key, whitelist_info)?;
+    }
+    if let Some((_, group)) = &mut group {
+        logic::apply_group_buy(group, &pledge_contract, &outcome)?;
+    }
+    pledge_contract.total_pledge_sold = outcome.total_pledge_sold;
+    pledge_contract.phase_sold[outcome.phase] = outcome.phase_sold;
+
+    let referral = match (referrer, referrer_info) {
+        (Some(referrer), Some(referrer_info)) => {
+            let mut referrer_state = load_referrer(program_id, referrer, referrer_info, user_wallet_info)?;
+            let referral =
+                logic::apply_referral(&mut referrer_state, &pledge_contract, &outcome, current_time)?;
+            pledge_contract.total_pledge_sold = referral.total_pledge_sold;
+            Some((referrer_info, referrer_state, referral.bonus))
+        }
+        _ => None,
+    };
+
+    // Payment comes first; if the buyer cannot cover it the system program
+    // fails the transfer and with it the whole purchase.
+    invoke(
+        &system_instruction::transfer(user_wallet_info.key, treasury_info.key, outcome.paid),
+        &[user_wallet_info.clone(), treasury_info.clone(), system_program_info.clone()],
+    )?;
+
+    if let Some((receipt_info, index, bump)) = receipt {
+        let receipt = Receipt {
+            is_initialized: true,
+            wallet: user_state.owner,
+            index,
+            bump,
+            lamports_paid: outcome.paid,
+            pledge_tokens: outcome.pledge_tokens,
+            rate: outcome.rate,
+            phase: outcome.phase as u8,
+            timestamp: current_time,
+        };
+        create_pda_account(
+            user_wallet_info,
+            receipt_info,
+            system_program_info,
+            program_id,
+            &Rent::get()?,
+            Receipt::LEN,
+            &[RECEIPT_SEED, user_state.owner.as_ref(), &index.to_le_bytes(), &[bump]],
+        )?;
+        receipt.serialize(&mut &mut receipt_info.data.borrow_mut()[..])?;
+    }
+
+    user_state.pack_into_slice(&mut account_info.data.borrow_mut())?;
+    if let Some((referrer_info, referrer_state, _)) = &referral {
+        referrer_state.pack_into_slice(&mut referrer_info.data.borrow_mut())?;
+    }
+    if let Some((group_info, group)) = &group {
+        group.serialize(&mut &mut group_info.data.borrow_mut()[..])?;
+    }
+
+    emit_event(PledgeEvent::Purchase {
+        event_seq: pledge_contract.next_event_seq()?,
+        user: *account_info.key,
+        wallet: *user_wallet_info.key,
+        lamports_paid: outcome.paid,
+        pledge_tokens: outcome.pledge_tokens,
+        rate: outcome.rate,
+        total_pledge_tokens: user_state.locked_pledge_tokens,
+        phase: outcome.phase as u8,
+        phase_time_remaining: outcome.phase_time_remaining,
+    });
+    if outcome.pledge_tokens < outcome.requested_tokens {
+        emit_event(PledgeEvent::PartialFill {
+            event_seq: pledge_contract.next_event_seq()?,
+            user: *account_info.key,
+            wallet: *user_wallet_info.key,
+            requested: outcome.requested_tokens,
+            filled: outcome.pledge_tokens,
+        });
+    }
+    if let Some((referrer_info, referrer_state, bonus)) = referral {
+        emit_event(PledgeEvent::ReferralBonus {
+            event_seq: pledge_contract.next_event_seq()?,
+            user: *referrer_info.key,
+            wallet: referrer_state.owner,
+            pledge_tokens: bonus,
+            total_pledge_tokens: referrer_state.locked_pledge_tokens,
+        });
+    }
+    // Saved after the events so the config keeps their sequence numbers.
+    save_config(config_info, &pledge_contract)
+
+}
+
+// The receipt PDA for the user's next purchase, when it is among `accounts`,
+// with the purchase's index and the PDA's bump. Rent comes from the sysvar
+// only then, so purchases without a receipt never read it. A receipt is
+// written once: an existing one at the address fails the purchase.
+fn find_receipt<'a, 'b>(
+    program_id: &Pubkey,
+    accounts: &'b [AccountInfo<'a>],
+    user_state: &UserState,
+) -> Result<Option<(&'b AccountInfo<'a>, u32, u8)>, ProgramError> {
+    if accounts.is_empty() {
+        return Ok(None);
+    }
+    let index = user_state.purchase_count;
+    let (expected, bump) = find_receipt_address(program_id, &user_state.owner, index);
+    let Some(receipt_info) = accounts.iter().find(|info| *info.key == expected) else {
+        return Ok(None);
+    };
+    assert_writable(receipt_info, "Receipt")?;
+    if *receipt_info.owner != system_program::ID {
+        msg!("Receipt for purchase {} already exists", index);
+        return Err(PledgeError::AlreadyInitialized.into());
+    }
+    Ok(Some((receipt_info, index, bump)))
+}
+
+// The referrer must be someone else's initialized user state, passed at the
+// address named in the instruction.
+fn load_referrer(
+    program_id: &Pubkey,
+    referrer: &Pubkey,
+    referrer_info: &AccountInfo,
+    user_wallet_info: &AccountInfo,
+) -> Result<UserState, ProgramError> {
+    if referrer_info.key != referrer {
+        msg!("Referrer account does not match the referrer in the instruction");
+        return Err(ProgramError::InvalidArgument);
+    }
+    check_user_state_account(program_id, referrer_info, true)?;
+    check_user_state_len(referrer_info)?;
+    let referrer_state = load_user_state(referrer_info)?;
+    check_user_state_address(program_id, referrer_info, &referrer_state)?;
+    if referrer_state.owner == *user_wallet_info.key {
+        msg!("A wallet cannot refer itself");
+        return Err(PledgeError::SelfReferral.into());
+    }
+    Ok(referrer_state)
+}
+
+// Like buy_pledge, but pays `amount` of the configured payment mint, in its
+// own base units, into the treasury's token account. Token purchases lock
+// on the default tier.
+pub fn buy_pledge_with_token(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    amount: u64,
+    current_time: u64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let account_info = next_account_info(account_info_iter)?;
+    let user_wallet_info = next_account_info(account_info_iter)?;
+    let config_info = next_account_info(account_info_iter)?;
+    let buyer_token_info = next_account_info(account_info_iter)?;
+    let treasury_token_info = next_account_info(account_info_iter)?;
+    let mint_info = next_account_info(account_info_iter)?;
+    let token_program_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[account_info, user_wallet_info, config_info, buyer_token_info, treasury_token_info])?;
+
+    check_user_state_account(program_id, account_info, true)?;
+    check_user_state_len(account_info)?;
+    check_signer(user_wallet_info)?;
+    let mut user_state = load_user_state(account_info)?;
+    assert_authorized(&user_state, user_wallet_info, Permission::Owner)?;
+    check_user_state_address(program_id, account_info, &user_state)?;
+    let mut group = load_user_group(program_id, &user_state, account_info_iter)?;
+    let whitelist_info = account_info_iter.next();
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_not_paused(&pledge_contract)?;
+    let decimals = check_payment_accounts(
+        program_id,
+        &pledge_contract,
+        buyer_token_info,
+        treasury_token_info,
+        mint_info,
+        token_program_info,
+        user_wallet_info,
+        amount,
+    )?;
+
+    let scaled_amount = logic::scale_payment(amount, decimals, pledge_contract.payment_decimals)?;
+    let outcome = logic::apply_buy(&mut user_state, &pledge_contract, scaled_amount, DEFAULT_LOCK_TIER, false, current_time)?;
+    if outcome.phase == 0 {
+        check_whitelisted(program_id, user_wallet_info.key, whitelist_info)?;
+    }
+    if let Some((_, group)) = &mut group {
+        logic::apply_group_buy(group, &pledge_contract, &outcome)?;
+    }
+    pledge_contract.total_pledge_sold = outcome.total_pledge_sold;
+    pledge_contract.phase_sold[outcome.phase] = outcome.phase_sold;
+
+    invoke(
+        &spl_token::instruction::transfer_checked(
+            token_program_info.key,
+            buyer_token_info.key,
+            mint_info.key,
+            treasury_token_info.key,
+            user_wallet_info.key,
+            &[],
+            amount,
+            decimals,
+        )?,
+        &[
+            buyer_token_info.clone(),
+            mint_info.clone(),
+            treasury_token_info.clone(),
+            user_wallet_info.clone(),
+            token_program_info.clone(),
+        ],
+    )?;
+
+    user_state.pack_into_slice(&mut account_info.data.borrow_mut())?;
+    if let Some((group_info, group)) = &group {
+        group.serialize(&mut &mut group_info.data.borrow_mut()[..])?;
+    }
+
+    emit_event(PledgeEvent::TokenPurchase {
+        event_seq: pledge_contract.next_event_seq()?,
+        user: *account_info.key,
+        wallet: *user_wallet_info.key,
+        paid: amount,
+        pledge_tokens: outcome.pledge_tokens,
+        rate: outcome.rate,
+        total_pledge_tokens: user_state.locked_pledge_tokens,
+    });
+    save_config(config_info, &pledge_contract)
+}
+
+// The payment must move the configured mint from the buyer's own token
+// account into a token account held by the treasury PDA. Returns the mint's
+// decimals for transfer_checked.
+#[allow(clippy::too_many_arguments)]
+fn check_payment_accounts<'a>(
+    program_id: &Pubkey,
+    pledge_contract: &ContractConfig,
+    buyer_token_info: &AccountInfo<'a>,
+    treasury_token_info: &AccountInfo<'a>,
+    mint_info: &AccountInfo<'a>,
+    token_program_info: &AccountInfo,
+    user_wallet_info: &AccountInfo,
+    amount: u64,
+) -> Result<u8, ProgramError> {
+    assert_is_program(token_program_info, &spl_token::id(), "Token program")?;
+    if *mint_info.key != pledge_contract.payment_mint {
+        msg!("Payment mint {} is not accepted", mint_info.key);
+        return Err(PledgeError::InvalidPaymentMint.into());
+    }
+    for info in [buyer_token_info, treasury_token_info, mint_info] {
+        if *info.owner != spl_token::id() {
+            msg!("Payment token accounts must belong to the SPL token program");
+            return Err(ProgramError::IncorrectProgramId);
+        }
+    }
+
+    let buyer_token = spl_token::state::Account::unpack(&buyer_token_info.data.borrow())?;
+    if buyer_token.owner != *user_wallet_info.key || buyer_token.mint != *mint_info.key {
+        msg!("Payment source is not the buyer's token account for the payment mint");
+        return Err(ProgramError::InvalidAccountData);
+    }
+    if buyer_token.amount < amount {
+        msg!("Cannot pay {}, only {} available", amount, buyer_token.amount);
+        return Err(ProgramError::InsufficientFunds);
+    }
+    let treasury_token = spl_token::state::Account::unpack(&treasury_token_info.data.borrow())?;
+    if treasury_token.owner != find_treasury_address(program_id).0 || treasury_token.mint != *mint_info.key {
+        msg!("Payment destination is not a treasury token account for the payment mint");
+        return Err(PledgeError::InvalidVault.into());
+    }
+    let mint = spl_token::state::Mint::unpack(&mint_info.data.borrow())?;
+    Ok(mint.decimals)
+}
+
+pub fn update_reward<'a>(
+    program_id: &Pubkey,
+    account_info: &AccountInfo<'a>,
+    config_info: &AccountInfo<'a>,
+    current_time: u64,
+) -> ProgramResult {
+    assert_unique_accounts(&[account_info, config_info])?;
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_not_paused(&pledge_contract)?;
+
+    let (user_state, accrued) = accrue_user_state(program_id, account_info, &pledge_contract, current_time)?;
+    if accrued.is_none() {
+        if !pledge_contract.noop_events {
+            return Ok(());
+        }
+        emit_event(PledgeEvent::NoOp {
+            event_seq: pledge_contract.next_event_seq()?,
+            user: *account_info.key,
+            wallet: user_state.owner,
+        });
+        return save_config(config_info, &pledge_contract);
+    }
+    let elapsed_time = current_time.saturating_sub(user_state.lock_start_time);
+
+    emit_event(PledgeEvent::RewardUpdate {
+        event_seq: pledge_contract.next_event_seq()?,
+        user: *account_info.key,
+        wallet: user_state.owner,
+        solhit_rewards: user_state.solhit_rewards,
+        elapsed_time,
+    });
+    save_config(config_info, &pledge_contract)
+}
+
+// Accrues a user's rewards up to `current_time` and stores them. Returns the
+// state along with the rewards the accrual added, or None when it added
+// nothing and no lot finished vesting, in which case the account is left
+// untouched: accrual only ever looks at the time since last_reward_update,
+// so the next one picks up from there just the same.
+fn accrue_user_state(
+    program_id: &Pubkey,
+    account_info: &AccountInfo,
+    pledge_contract: &ContractConfig,
+    current_time: u64,
+) -> Result<(UserState, Option<u64>), ProgramError> {
+    check_user_state_account(program_id, account_info, true)?;
+    check_user_state_len(account_info)?;
+    let before = load_user_state(account_info)?;
+    check_user_state_address(program_id, account_info, &before)?;
+    let user_state = accrued_copy(&before, pledge_contract, current_time)?;
+    if logic::is_noop_accrual(&before, &user_state) {
+        return Ok((before, None));
+    }
+    let accrued = user_state.solhit_rewards.saturating_sub(before.solhit_rewards);
+    user_state.pack_into_slice(&mut account_info.data.borrow_mut())?;
+    Ok((user_state, Some(accrued)))
+}
+
+// A copy of `user_state` with its rewards accrued up to `current_time`.
+fn accrued_copy(user_state: &UserState, pledge_contract: &ContractConfig, current_time: u64) -> Result<UserState, ProgramError> {
+    let mut accrued = user_state.clone();
+    logic::record_activity(&mut accrued, current_time)?;
+    logic::accrue(&mut accrued, pledge_contract, current_time)?;
+    Ok(accrued)
+}
+
+// Sets a RewardPreview of an UpdateReward at `current_time` as the return
+// data. The accrual runs on a copy of the user state, so nothing is written
+// and no event is emitted.
+pub fn preview_reward(program_id: &Pubkey, accounts: &[AccountInfo], soft: bool, current_time: u64) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let account_info = next_account_info(account_info_iter)?;
+    let config_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[account_info, config_info])?;
+
+    let pledge_contract = load_config(program_id, config_info, false)?;
+    let Some(user_state_before) = load_viewed_user_state(program_id, account_info, soft)? else {
+        set_return_data(&borsh::to_vec(&RewardPreview::default())?);
+        return Ok(());
+    };
+    check_user_state_address(program_id, account_info, &user_state_before)?;
+    let user_state = accrued_copy(&user_state_before, &pledge_contract, current_time)?;
+    let preview = RewardPreview {
+        reward_delta: user_state.solhit_rewards.saturating_sub(user_state_before.solhit_rewards),
+        solhit_rewards: user_state.solhit_rewards,
+        unlockable: user_state.active_lots().iter().any(|lot| current_time >= lot.vesting_end_time),
+        initialized: true,
+    };
+    set_return_data(&borsh::to_vec(&preview)?);
+
+    Ok(())
+}
+
+// UpdateReward for every user state after the config account. A user state
+// that fails validation or accrual is logged and skipped, so one bad account
+// cannot hold up the crank for the rest.
+pub fn update_reward_batch(program_id: &Pubkey, accounts: &[AccountInfo], current_time: u64) -> ProgramResult {
+    let (config_info, user_infos) = accounts.split_first().ok_or(ProgramError::NotEnoughAccountKeys)?;
+    assert_unique_accounts(&accounts.iter().collect::<Vec<_>>())?;
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_not_paused(&pledge_contract)?;
+
+    // A Clock sysvar supplying the time rides along with the user states, and
+    // the accounts to pay a bounty through follow them, ending in the token
+    // program.
+    let user_infos: Vec<_> = user_infos.iter().filter(|info| !clock::check_id(info.key)).collect();
+    let (user_infos, bounty_infos) = match user_infos.as_slice() {
+        [user_infos @ .., vault_info, cranker_token_info, mint_info, token_program_info]
+            if *token_program_info.key == spl_token::id() =>
+        {
+            (user_infos, Some([*vault_info, *cranker_token_info, *mint_info, *token_program_info]))
+        }
+        user_infos => (user_infos, None),
+    };
+    let batch_size = user_infos.len();
+    if batch_size > MAX_REWARD_BATCH {
+        msg!("At most {} user states per batch, got {}", MAX_REWARD_BATCH, batch_size);
+        return Err(PledgeError::BatchTooLarge.into());
+    }
+
+    let mut processed = 0;
+    let mut accrued_accounts = 0;
+    for account_info in user_infos {
+        match accrue_user_state(program_id, account_info, &pledge_contract, current_time) {
+            Ok((_, accrued)) => {
+                processed += 1;
+                if accrued.is_some_and(|accrued| accrued > 0) {
+                    accrued_accounts += 1;
+                }
+            }
+            Err(error) => msg!("Skipping user state {}: {}", account_info.key, error),
+        }
+    }
+
+    emit_event(PledgeEvent::RewardBatch {
+        event_seq: pledge_contract.next_event_seq()?,
+        processed,
+        skipped: batch_size as u32 - processed,
+    });
+    match bounty_infos {
+        Some(bounty_infos) => pay_crank_bounty(program_id, config_info, &mut pledge_contract, bounty_infos, accrued_accounts),
+        None => save_config(config_info, &pledge_contract),
+    }
+}
+
+// Pays the cranker crank_fee for each of `accrued_accounts` from the Solheist
+// vault, as far as the budget and the vault's reward tokens go, and stores
+// the config. Only accounts whose accrual added rewards earn the bounty.
+fn pay_crank_bounty<'a>(
+    program_id: &Pubkey,
+    config_info: &AccountInfo<'a>,
+    pledge_contract: &mut ContractConfig,
+    [vault_info, cranker_token_info, mint_info, token_program_info]: [&AccountInfo<'a>; 4],
+    accrued_accounts: u64,
+) -> ProgramResult {
+    assert_unique_accounts(&[config_info, vault_info, cranker_token_info])?;
+    let (vault_bump, decimals) = check_solhit_vault(program_id, vault_info, cranker_token_info, mint_info, token_program_info, None)?;
+
+    // The bounty never draws on team tokens the vault still holds.
+    let unreleased = pledge_contract.team_lock.locked_amount.saturating_sub(pledge_contract.team_released);
+    let vault_balance = spl_token::state::Account::unpack(&vault_info.data.borrow())?.amount;
+    let bounty = accrued_accounts
+        .saturating_mul(pledge_contract.crank_fee)
+        .min(pledge_contract.crank_budget.saturating_sub(pledge_contract.crank_bounties_paid))
+        .min(vault_balance.saturating_sub(unreleased));
+    if bounty == 0 {
+        return save_config(config_info, pledge_contract);
+    }
+    pledge_contract.crank_bounties_paid =
+        pledge_contract.crank_bounties_paid.checked_add(bounty).ok_or(PledgeError::ArithmeticOverflow)?;
+    save_config(config_info, pledge_contract)?;
+
+    msg!("Paying a crank bounty of {} for {} accounts", bounty, accrued_accounts);
+    invoke_signed(
+        &spl_token::instruction::transfer_checked(
+            token_program_info.key,
+            vault_info.key,
+            mint_info.key,
+            cranker_token_info.key,
+            vault_info.key,
+            &[],
+            bounty,
+            decimals,
+        )?,
+        &[
+            vault_info.clone(),
+            mint_info.clone(),
+            cranker_token_info.clone(),
+            token_program_info.clone(),
+        ],
+        &[&[SOLHIT_VAULT_SEED, &[vault_bump]]],
+    )
+}
+
+pub fn view_rewards(
+    program_id: &Pubkey,
+    account_info: &AccountInfo,
+    config_info: &AccountInfo,
+    soft: bool,
+    current_time: u64,
+) -> ProgramResult {
+    // A soft view of a user state yet to be initialized logs no balances,
+    // so that no zeros can be taken for real ones.
+    let Some(mut user_state) = load_viewed_user_state(program_id, account_info, soft)? else {
+        set_return_data(&borsh::to_vec(&RewardsView::default())?);
+        return Ok(());
+    };
+    check_user_state_address(program_id, account_info, &user_state)?;
+    let pledge_contract = load_config(program_id, config_info, false)?;
+
+    // Accrue on the local copy only, viewing never writes the account.
+    logic::accrue(&mut user_state, &pledge_contract, current_time)?;
+    let view = RewardsView {
+        pending_rewards: user_state.solhit_rewards,
+        locked_pledge_tokens: user_state.locked_pledge_tokens,
+        vesting_end_time: user_state.vesting_end_time,
+        seconds_remaining: user_state.vesting_end_time.saturating_sub(current_time),
+        total_purchased: user_state.total_purchased,
+        purchase_count: user_state.purchase_count,
+        last_activity: user_state.last_activity,
+        initialized: true,
+    };
+
+    msg!("Solheist Rewards: {}", view.pending_rewards);
+    msg!("Solheist Claimed: {}", user_state.total_claimed);
+    for (index, lot) in user_state.active_lots().iter().enumerate() {
+        msg!(
+            "Lot {}: {} pledge tokens at rate {}, {} seconds remaining",
+            index,
+            lot.amount,
+            lot.rate,
+            lot.vesting_end_time.saturating_sub(current_time)
+        );
+    }
+
+    set_return_data(&borsh::to_vec(&view)?);
+
+    Ok(())
+}
+
+// Sets the user's vesting timetable as the return data, without writing
+// anything.
+pub fn get_vesting_schedule(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let account_info = next_account_info(account_info_iter)?;
+    let config_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[account_info, config_info])?;
+
+    let user_state = load_viewed_user_state(program_id, account_info, false)?.ok_or(ProgramError::UninitializedAccount)?;
+    check_user_state_address(program_id, account_info, &user_state)?;
+    let pledge_contract = load_config(program_id, config_info, false)?;
+
+    let schedule = logic::vesting_schedule(&user_state, &pledge_contract)?;
+    set_return_data(&borsh::to_vec(&schedule)?);
+
+    Ok(())
+}
+
+// Prices a purchase of `amount` lamports at `current_time` on the default
+// tier without buying anything. The quote runs apply_buy itself on a copy of
+// the user state, or of a fresh one when none is passed, so it cannot drift
+// from what BuyPledge does; it allows a partial fill so a purchase past the
+// remaining supply shows what it would be cut down to.
+pub fn quote_buy(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64, current_time: u64) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let account_info = account_info_iter.next().filter(|info| !clock::check_id(info.key));
+
+    let pledge_contract = load_config(program_id, config_info, false)?;
+    let mut user_state = match account_info {
+        Some(account_info) => {
+            assert_unique_accounts(&[config_info, account_info])?;
+            check_user_state_account(program_id, account_info, false)?;
+            let user_state = load_user_state(account_info)?;
+            check_user_state_address(program_id, account_info, &user_state)?;
+            user_state
+        }
+        None => UserState::default(),
+    };
+
+    let outcome = logic::apply_buy(&mut user_state, &pledge_contract, amount, DEFAULT_LOCK_TIER, true, current_time)?;
+    let quote = BuyQuote {
+        phase: outcome.phase as u8,
+        rate: outcome.rate,
+        requested_tokens: outcome.requested_tokens,
+        pledge_tokens: outcome.pledge_tokens,
+        paid: outcome.paid,
+    };
+    set_return_data(&borsh::to_vec(&quote)?);
+
+    Ok(())
+}
+
+// Sets the sale-wide GlobalStats at `current_time` as the return data.
+pub fn get_global_stats(program_id: &Pubkey, accounts: &[AccountInfo], current_time: u64) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let config_info = next_account_info(account_info_iter)?;
+    let pledge_contract = load_config(program_id, config_info, false)?;
+
+    // Before the start no phase is running, just as BuyPledge fails with
+    // SaleNotStarted, so the countdown is to the start itself.
+    let (active_phase, phase_time_remaining) = if pledge_contract.sale_start_time == SALE_UNSCHEDULED {
+        (PHASE_NOT_STARTED, u64::MAX)
+    } else if current_time < pledge_contract.sale_start_time {
+        (PHASE_NOT_STARTED, pledge_contract.sale_start_time - current_time)
+    } else {
+        let phase = get_sale_phase(current_time - pledge_contract.sale_start_time, &pledge_contract.phase_durations);
+        (phase as u8, get_phase_time_remaining(phase, &pledge_contract, current_time))
+    };
+    let stats = GlobalStats {
+        total_sold: pledge_contract.total_pledge_sold,
+        total_claimed: pledge_contract.global_solhit_claimed,
+        active_phase,
+        phase_time_remaining,
+        initialized_users: pledge_contract.initialized_users,
+        paused: pledge_contract.paused,
+    };
+    set_return_data(&borsh::to_vec(&stats)?);
+
+    Ok(())
+}
+
+// Claims `amount` of the accrued rewards, or all of them when `amount` is 0.
+pub fn claim_rewards(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    amount: u64,
+    current_time: u64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let account_info = next_account_info(account_info_iter)?;
+    let user_wallet_info = next_account_info(account_info_iter)?;
+    let config_info = next_account_info(account_info_iter)?;
+    let vault_info = next_account_info(account_info_iter)?;
+    let user_token_info = next_account_info(account_info_iter)?;
+    let mint_info = next_account_info(account_info_iter)?;
+    let token_program_info = next_account_info(account_info_iter)?;
+    // The vault paying out must not double as the user state, the config or
+    // the account it pays into.
+    assert_unique_accounts(&[account_info, user_wallet_info, config_info, vault_info, user_token_info])?;
+
+    check_user_state_account(program_id, account_info, true)?;
+    check_user_state_len(account_info)?;
+    check_signer(user_wallet_info)?;
+
+    let mut user_state = load_user_state(account_info)?;
+    assert_authorized(&user_state, user_wallet_info, Permission::Claim)?;
+    check_user_state_address(program_id, account_info, &user_state)?;
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_not_paused(&pledge_contract)?;
+    logic::check_compliance_hold(&user_state, current_time)?;
+    logic::record_activity(&mut user_state, current_time)?;
+    // While the config charges a claim fee, the fee account follows the token
+    // program, ahead of any that create the beneficiary's token account.
+    let fee_info = if pledge_contract.claim_fee_bps > 0 {
+        Some(check_fee_account(program_id, account_info_iter.next(), mint_info)?)
+    } else {
+        None
+    };
+
+    // Claim everything earned up to now, not just what the last
+    // UpdateReward happened to credit.
+    logic::accrue(&mut user_state, &pledge_contract, current_time)?;
+
+    let claimed = logic::compute_claim(&user_state, amount)?;
+
+    // An empty destination is the beneficiary's associated token account,
+    // yet to be created at the signer's expense.
+    if user_token_info.data_is_empty() {
+        create_associated_token_account(
+            user_wallet_info,
+            user_token_info,
+            mint_info,
+            token_program_info,
+            &user_state.beneficiary,
+            account_info_iter,
+        )?;
+    }
+    let (vault_bump, decimals) =
+        check_solhit_vault(program_id, vault_info, user_token_info, mint_info, token_program_info, Some(&user_state.beneficiary))?;
+    // The fee rounds down, so it never takes more than claim_fee_bps of the
+    // claim, and the beneficiary gets the rest.
+    let fee = math::apply_bps(claimed, pledge_contract.claim_fee_bps)?;
+
+    // Payouts across all users may never exceed the unlocked Solheist supply.
+    let claimable_supply = pledge_contract.solhit_token_supply.saturating_sub(pledge_contract.locked_solhit_tokens);
+    let global_solhit_claimed = pledge_contract
+        .global_solhit_claimed
+        .checked_add(claimed)
+        .ok_or(PledgeError::ArithmeticOverflow)?;
+    if global_solhit_claimed > claimable_supply {
+        msg!("Not enough Solheist tokens in the contract");
+        return Err(PledgeError::RewardsExhausted.into());
+    }
+    // Nor may they draw on team tokens the vault still holds for the team.
+    let unreleased = pledge_contract.team_lock.locked_amount.saturating_sub(pledge_contract.team_released);
+    let vault_balance = spl_token::state::Account::unpack(&vault_info.data.borrow())?.amount;
+    if vault_balance.saturating_sub(unreleased) < claimed {
+        msg!("Solheist vault is short of reward tokens; FundRewards tops it up");
+        return Err(PledgeError::InsufficientVaultBalance.into());
+    }
+    pledge_contract.global_solhit_claimed = global_solhit_claimed;
+    user_state.total_claimed = user_state
+        .total_claimed
+        .checked_add(claimed)
+        .ok_or(PledgeError::ArithmeticOverflow)?;
+    user_state.solhit_rewards = user_state
+        .solhit_rewards
+        .checked_sub(claimed)
+        .ok_or(PledgeError::ArithmeticOverflow)?;
+
+    // Both accounts are written before the transfer, so anything the transfer
+    // reaches that calls back into this program sees the rewards already
+    // paid. Should the transfer fail, the whole transaction reverts.
+    user_state.pack_into_slice(&mut account_info.data.borrow_mut())?;
+    let event_seq = pledge_contract.next_event_seq()?;
+    save_config(config_info, &pledge_contract)?;
+
+    // Transfer Solheist tokens to the user, and any fee to the treasury. The
+    // vault PDA is its own authority.
+    let fee_transfer = fee_info.filter(|_| fee > 0).map(|fee_info| (fee_info, fee));
+    for (destination_info, amount) in [(user_token_info, claimed - fee)].into_iter().chain(fee_transfer) {
+        invoke_signed(
+            &spl_token::instruction::transfer_checked(
+                token_program_info.key,
+                vault_info.key,
+                mint_info.key,
+                destination_info.key,
+                vault_info.key,
+                &[],
+                amount,
+                decimals,
+            )?,
+            &[
+                vault_info.clone(),
+                mint_info.clone(),
+                destination_info.clone(),
+                token_program_info.clone(),
+            ],
+            &[&[SOLHIT_VAULT_SEED, &[vault_bump]]],
+        )?;
+    }
+
+    msg!("Rewards claimed successfully");
+    emit_event(PledgeEvent::RewardClaim {
+        event_seq,
+        user: *account_info.key,
+        wallet: *user_wallet_info.key,
+        claimed,
+        remaining: user_state.solhit_rewards,
+        fee,
+    });
+    Ok(())
+}
+
+// Points the user's future reward claims at `new`'s token account. Only the
+// owner may change it.
+pub fn set_beneficiary(program_id: &Pubkey, accounts: &[AccountInfo], new: Pubkey) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let account_info = next_account_info(account_info_iter)?;
+    let user_wallet_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[account_info, user_wallet_info])?;
+
+    check_user_state_account(program_id, account_info, true)?;
+    check_user_state_len(account_info)?;
+    check_signer(user_wallet_info)?;
+    let mut user_state = load_user_state(account_info)?;
+    assert_authorized(&user_state, user_wallet_info, Permission::Owner)?;
+    check_user_state_address(program_id, account_info, &user_state)?;
+
+    user_state.beneficiary = new;
+    user_state.pack_into_slice(&mut account_info.data.borrow_mut())
+}
+
+// Sets or, with None, removes the wallet allowed to claim for the user. Only
+// the owner may change it.
+pub fn set_delegate(program_id: &Pubkey, accounts: &[AccountInfo], delegate: Option<Pubkey>) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let account_info = next_account_info(account_info_iter)?;
+    let user_wallet_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[account_info, user_wallet_info])?;
+
+    check_user_state_account(program_id, account_info, true)?;
+    check_user_state_len(account_info)?;
+    let mut user_state = load_user_state(account_info)?;
+    assert_authorized(&user_state, user_wallet_info, Permission::Owner)?;
+    check_user_state_address(program_id, account_info, &user_state)?;
+
+    user_state.delegate = delegate;
+    user_state.pack_into_slice(&mut account_info.data.borrow_mut())
+}
+
+// Locks every reward accrued up to now as new pledge tokens instead of paying
+// it out.
+pub fn compound_rewards(program_id: &Pubkey, accounts: &[AccountInfo], current_time: u64) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let account_info = next_account_info(account_info_iter)?;
+    let user_wallet_info = next_account_info(account_info_iter)?;
+    let config_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[account_info, user_wallet_info, config_info])?;
+
+    check_user_state_account(program_id, account_info, true)?;
+    check_user_state_len(account_info)?;
+    check_signer(user_wallet_info)?;
+    let mut user_state = load_user_state(account_info)?;
+    assert_authorized(&user_state, user_wallet_info, Permission::Owner)?;
+    check_user_state_address(program_id, account_info, &user_state)?;
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_not_paused(&pledge_contract)?;
+
+    logic::accrue(&mut user_state, &pledge_contract, current_time)?;
+    let locked_before = user_state.locked_pledge_tokens;
+    let outcome = logic::apply_compound(&mut user_state, &pledge_contract, current_time)?;
+    pledge_contract.total_pledge_sold = outcome.total_pledge_sold;
+
+    user_state.pack_into_slice(&mut account_info.data.borrow_mut())?;
+
+    emit_event(PledgeEvent::Compound {
+        event_seq: pledge_contract.next_event_seq()?,
+        user: *account_info.key,
+        wallet: *user_wallet_info.key,
+        solhit_rewards: outcome.solhit_rewards,
+        pledge_tokens: outcome.pledge_tokens,
+        locked_before,
+        locked_after: user_state.locked_pledge_tokens,
+    });
+    save_config(config_info, &pledge_contract)
+}
+
+// Moves the signer's whole position into another wallet's initialized user
+// state; see logic::apply_transfer for how the two merge.
+pub fn transfer_position(program_id: &Pubkey, accounts: &[AccountInfo], current_time: u64) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let account_info = next_account_info(account_info_iter)?;
+    let user_wallet_info = next_account_info(account_info_iter)?;
+    let destination_info = next_account_info(account_info_iter)?;
+    let config_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[account_info, user_wallet_info, destination_info, config_info])?;
+
+    check_user_state_account(program_id, account_info, true)?;
+    check_user_state_len(account_info)?;
+    check_signer(user_wallet_info)?;
+    let mut user_state = load_user_state(account_info)?;
+    assert_authorized(&user_state, user_wallet_info, Permission::Owner)?;
+    check_user_state_address(program_id, account_info, &user_state)?;
+    check_user_state_account(program_id, destination_info, true)?;
+    check_user_state_len(destination_info)?;
+    let mut destination = load_user_state(destination_info)?;
+    check_user_state_address(program_id, destination_info, &destination)?;
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    check_not_paused(&pledge_contract)?;
+    // Moving the position elsewhere would take it out of the hold.
+    logic::check_compliance_hold(&user_state, current_time)?;
+
+    let outcome = logic::apply_transfer(&mut user_state, &mut destination, &pledge_contract, current_time)?;
+    user_state.pack_into_slice(&mut account_info.data.borrow_mut())?;
+    destination.pack_into_slice(&mut destination_info.data.borrow_mut())?;
+
+    emit_event(PledgeEvent::PositionTransfer {
+        event_seq: pledge_contract.next_event_seq()?,
+        user: *account_info.key,
+        wallet: *user_wallet_info.key,
+        destination: *destination_info.key,
+        pledge_tokens: outcome.pledge_tokens,
+        solhit_rewards: outcome.solhit_rewards,
+    });
+    save_config(config_info, &pledge_contract)
+}
+
+pub fn unlock_pledge(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    current_time: u64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let account_info = next_account_info(account_info_iter)?;
+    let user_wallet_info = next_account_info(account_info_iter)?;
+    let config_info = next_account_info(account_info_iter)?;
+    let vault_info = next_account_info(account_info_iter)?;
+    let user_token_info = next_account_info(account_info_iter)?;
+    let vault_authority_info = next_account_info(account_info_iter)?;
+    let token_program_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[account_info, user_wallet_info, config_info, vault_info, user_token_info])?;
+
+    check_user_state_account(program_id, account_info, true)?;
+    check_user_state_len(account_info)?;
+    check_signer(user_wallet_info)?;
+
+    let mut user_state = load_user_state(account_info)?;
+    assert_authorized(&user_state, user_wallet_info, Permission::Owner)?;
+    check_user_state_address(program_id, account_info, &user_state)?;
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    let vault_bump = check_pledge_vault(program_id, vault_info, vault_authority_info, token_program_info)?;
+    logic::check_compliance_hold(&user_state, current_time)?;
+
+    logic::record_activity(&mut user_state, current_time)?;
+
+    // Settle rewards up to now before the vested lots stop being tracked.
+    logic::accrue(&mut user_state, &pledge_contract, current_time)?;
+    let vested = user_state.remove_vested_lots(current_time)?;
+    if vested.is_empty() {
+        match user_state.active_lots().iter().map(|lot| lot.vesting_end_time.saturating_sub(current_time)).min() {
+            Some(remaining) => msg!("Pledge tokens are still vesting, {} seconds remaining", remaining),
+            None => msg!("No locked pledge tokens to unlock"),
+        }
+        return Err(PledgeError::StillVesting.into());
+    }
+
+    let mut amount: u64 = 0;
+    for lot in &vested {
+        amount = amount.checked_add(lot.amount).ok_or(PledgeError::ArithmeticOverflow)?;
+    }
+
+    transfer_from_pledge_vault(
+        vault_info,
+        user_token_info,
+        vault_authority_info,
+        token_program_info,
+        vault_bump,
+        amount,
+    )?;
+
+    user_state.pack_into_slice(&mut account_info.data.borrow_mut())?;
+
+    emit_event(PledgeEvent::Unlock {
+        event_seq: pledge_contract.next_event_seq()?,
+        user: *account_info.key,
+        wallet: *user_wallet_info.key,
+        amount,
+        locked_pledge_tokens: user_state.locked_pledge_tokens,
+    });
+    save_config(config_info, &pledge_contract)
+}
+
+pub fn early_exit(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    current_time: u64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let account_info = next_account_info(account_info_iter)?;
+    let user_wallet_info = next_account_info(account_info_iter)?;
+    let config_info = next_account_info(account_info_iter)?;
+    let vault_info = next_account_info(account_info_iter)?;
+    let user_token_info = next_account_info(account_info_iter)?;
+    let vault_authority_info = next_account_info(account_info_iter)?;
+    let token_program_info = next_account_info(account_info_iter)?;
+    assert_unique_accounts(&[account_info, user_wallet_info, config_info, vault_info, user_token_info])?;
+
+    check_user_state_account(program_id, account_info, true)?;
+    check_user_state_len(account_info)?;
+    check_signer(user_wallet_info)?;
+
+    let mut user_state = load_user_state(account_info)?;
+    assert_authorized(&user_state, user_wallet_info, Permission::Owner)?;
+    check_user_state_address(program_id, account_info, &user_state)?;
+    let mut pledge_contract = load_config(program_id, config_info, true)?;
+    let vault_bump = check_pledge_vault(program_id, vault_info, vault_authority_info, token_program_info)?;
+    logic::check_compliance_hold(&user_state, current_time)?;
+
+    logic::record_activity(&mut user_state, current_time)?;
+
+    if user_state.lot_count == 0 {
+        msg!("No locked pledge tokens to exit");
+        return Err(PledgeError::InvalidAmount.into());
+    }
+
+    // Only lots still vesting can be exited; vested lots go through UnlockPledge.
+    let exited = user_state.remove_vesting_lots(current_time)?;
+    if exited.is_empty() {
+        msg!("Vesting has ended, use UnlockPledge instead");
+        return Err(PledgeError::VestingEnded.into());
+    }
+
+    let mut amount: u64 = 0;
+    for lot in &exited {
+        amount = amount.checked_add(lot.amount).ok_or(PledgeError::ArithmeticOverflow)?;
+    }
+    let penalty = math::apply_percent(amount, pledge_contract.early_exit_penalty_percent)?;
+    let released = amount.checked_sub(penalty).ok_or(PledgeError::ArithmeticOverflow)?;
+
+    // Accrued but unclaimed rewards are forfeited.
+    user_state.solhit_rewards = 0;
+    user_state.last_reward_update = user_state.last_reward_update.max(current_time);
+
+    // The penalty stays in the vault and goes back on sale. Lots do not
+    // record the phase they were bought in, so it frees global supply only,
+    // and phase_sold stays as it was.
+    pledge_contract.total_penalties = pledge_contract
+        .total_penalties
+        .checked_add(penalty)
+        .ok_or(PledgeError::ArithmeticOverflow)?;
+    pledge_contract.total_pledge_sold = pledge_contract.total_pledge_sold.saturating_sub(penalty);
+
+    transfer_from_pledge_vault(
+        vault_info,
+        user_token_info,
+        vault_authority_info,
+        token_program_info,
+        vault_bump,
+        released,
+    )?;
+
+    user_state.pack_into_slice(&mut account_info.data.borrow_mut())?;
+
+    emit_event(PledgeEvent::EarlyExit {
+        event_seq: pledge_contract.next_event_seq()?,
+        user: *account_info.key,
+        wallet: *user_wallet_info.key,
+        released,
+        penalty,
+    });
+    save_config(config_info, &pledge_contract)
+}
+
+fn transfer_from_pledge_vault<'a>(
+    vault_info: &AccountInfo<'a>,
+    destination_info: &AccountInfo<'a>,
+    vault_authority_info: &AccountInfo<'a>,
+    token_program_info: &AccountInfo<'a>,
+    vault_bump: u8,
+    amount: u64,
+) -> ProgramResult {
+    invoke_signed(
+        &spl_token::instruction::transfer(
+            token_program_info.key,
+            vault_info.key,
+            destination_info.key,
+            vault_authority_info.key,
+            &[],
+            amount,
+        )?,
+        &[
+            vault_info.clone(),
+            destination_info.clone(),
+            vault_authority_info.clone(),
+            token_program_info.clone(),
+        ],
+        &[&[PLEDGE_VAULT_SEED, &[vault_bump]]],
+    )
+}
+
+// The vault must be an SPL token account held by the pledge vault PDA.
+// Returns the PDA bump used to sign for it.
+fn check_pledge_vault(
+    program_id: &Pubkey,
+    vault_info: &AccountInfo,
+    vault_authority_info: &AccountInfo,
+    token_program_info: &AccountInfo,
+) -> Result<u8, ProgramError> {
+    assert_is_program(token_program_info, &spl_token::id(), "Token program")?;
+    let (expected_authority, bump) = find_pledge_vault_address(program_id);
+    if *vault_authority_info.key != expected_authority {
+        msg!("Pledge vault authority does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+    if *vault_info.owner != spl_token::id() {
+        msg!("Pledge vault is not an SPL token account");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let vault = spl_token::state::Account::unpack(&vault_info.data.borrow())?;
+    if vault.owner != expected_authority {
+        msg!("Pledge vault is not held by the pledge vault authority");
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Ok(bump)
+}
+
+// The Solheist vault is the SPL token account at the `[b"solhit_vault"]` PDA,
+// held by that same PDA. The user's token account must be theirs and share
+// the vault's mint. Returns the vault bump and the mint decimals.
+fn check_solhit_vault<'a>(
+    program_id: &Pubkey,
+    vault_info: &AccountInfo<'a>,
+    user_token_info: &AccountInfo<'a>,
+    mint_info: &AccountInfo<'a>,
+    token_program_info: &AccountInfo,
+    beneficiary: Option<&Pubkey>,
+) -> Result<(u8, u8), ProgramError> {
+    let (expected_vault, vault_bump) = find_solhit_vault_address(program_id);
+    if *vault_info.key != expected_vault {
+        msg!("Solheist vault does not match the derived address");
+        return Err(PledgeError::InvalidVault.into());
+    }
+    assert_is_program(token_program_info, &spl_token::id(), "Token program")?;
+    for info in [vault_info, user_token_info, mint_info] {
+        if *info.owner != spl_token::id() {
+            msg!("Solheist token accounts must belong to the SPL token program");
+            return Err(ProgramError::IncorrectProgramId);
+        }
+    }
+
+    let vault = spl_token::state::Account::unpack(&vault_info.data.borrow())?;
+    if vault.owner != expected_vault || vault.mint != *mint_info.key {
+        msg!("Solheist vault is not a vault of this mint held by the program");
+        return Err(PledgeError::InvalidVault.into());
+    }
+    let user_token = spl_token::state::Account::unpack(&user_token_info.data.borrow())?;
+    if beneficiary.is_some_and(|beneficiary| user_token.owner != *beneficiary) || user_token.mint != *mint_info.key {
+        msg!("Reward destination is not the beneficiary's Solheist token account");
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let mint = spl_token::state::Mint::unpack(&mint_info.data.borrow())?;
+    Ok((vault_bump, mint.decimals))
+}
+
+// The claim fee goes to the treasury's associated Solheist token account,
+// passed as `fee_info`.
+fn check_fee_account<'a, 'b>(
+    program_id: &Pubkey,
+    fee_info: Option<&'b AccountInfo<'a>>,
+    mint_info: &AccountInfo,
+) -> Result<&'b AccountInfo<'a>, ProgramError> {
+    let expected = claim_fee_address(program_id, mint_info.key);
+    let Some(fee_info) = fee_info.filter(|info| *info.key == expected) else {
+        msg!("Claim fee needs the treasury's Solheist token account");
+        return Err(PledgeError::InvalidFeeAccount.into());
+    };
+    assert_writable(fee_info, "Claim fee")?;
+    if *fee_info.owner != spl_token::id() {
+        msg!("Treasury's Solheist token account does not exist");
+        return Err(PledgeError::InvalidFeeAccount.into());
+    }
+    let fee_account = spl_token::state::Account::unpack(&fee_info.data.borrow())?;
+    if fee_account.owner != find_treasury_address(program_id).0 || fee_account.mint != *mint_info.key {
+        msg!("Claim fee account is not a Solheist token account of the treasury");
+        return Err(PledgeError::InvalidFeeAccount.into());
+    }
+    Ok(fee_info)
+}
+
+// Creates `beneficiary`'s associated token account of `mint_info` at
+// `ata_info`, paid for by `payer_info`. The remaining accounts are the system
+// program, the associated token program and, unless the payer is the
+// beneficiary, the beneficiary's wallet.
+fn create_associated_token_account<'a>(
+    payer_info: &AccountInfo<'a>,
+    ata_info: &AccountInfo<'a>,
+    mint_info: &AccountInfo<'a>,
+    token_program_info: &AccountInfo<'a>,
+    beneficiary: &Pubkey,
+    account_info_iter: &mut std::slice::Iter<AccountInfo<'a>>,
+) -> ProgramResult {
+    let system_program_info = next_account_info(account_info_iter)?;
+    let ata_program_info = next_account_info(account_info_iter)?;
+    let wallet_info = if payer_info.key == beneficiary { payer_info } else { next_account_info(account_info_iter)? };
+    if wallet_info.key != beneficiary {
+        msg!("Wallet account is not the beneficiary");
+        return Err(ProgramError::InvalidArgument);
+    }
+    assert_is_program(system_program_info, &system_program::ID, "System program")?;
+    assert_is_program(ata_program_info, &spl_associated_token_account::id(), "Associated token program")?;
+    assert_is_program(token_program_info, &spl_token::id(), "Token program")?;
+    if *ata_info.key != spl_associated_token_account::get_associated_token_address(beneficiary, mint_info.key) {
+        msg!("Reward destination is not the beneficiary's associated token account");
+        return Err(ProgramError::InvalidSeeds);
+    }
+    invoke(
+        &spl_associated_token_account::instruction::create_associated_token_account(
+            payer_info.key,
+            beneficiary,
+            mint_info.key,
+            &spl_token::id(),
+        ),
+        &[
+            payer_info.clone(),
+            ata_info.clone(),
+            wallet_info.clone(),
+            mint_info.clone(),
+            system_program_info.clone(),
+            token_program_info.clone(),
+            ata_program_info.clone(),
+        ],
+    )
+}
+
+// The phase `elapsed` seconds after the sale started. A phase whose end does
+// not fit in a u64, like the open-ended u64::MAX final phase, never ends.
+fn get_sale_phase(elapsed: u64, phase_durations: &[u64; 5]) -> usize {
+    let mut phase_end: u64 = 0;
+    for (i, &duration) in phase_durations.iter().enumerate() {
+        phase_end = match phase_end.checked_add(duration) {
+            Some(end) => end,
+            None => return i,
+        };
+        if elapsed < phase_end {
+            return i;
+        }
+    }
+    phase_durations.len() - 1
+}
+
+// When `phase` ends, in seconds after the sale started, or None when that
+// does not fit in a u64 and the phase never ends.
+fn get_phase_end(phase: usize, phase_durations: &[u64; 5]) -> Option<u64> {
+    phase_durations[..=phase].iter().try_fold(0u64, |end, &duration| end.checked_add(duration))
+}
+
+// Seconds from `current_time` until `phase` ends, 0 once it has, or u64::MAX
+// for a phase that never ends.
+fn get_phase_time_remaining(phase: usize, pledge_contract: &ContractConfig, current_time: u64) -> u64 {
+    match get_phase_end(phase, &pledge_contract.phase_durations) {
+        Some(phase_end) => pledge_contract.sale_start_time.saturating_add(phase_end).saturating_sub(current_time),
+        None => u64::MAX,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;    
+use crate::{buy_pledge, UserState, ContractConfig};
+use solana_program::{pubkey::Pubkey, account_info::AccountInfo};
+
+// How long purchases on the default tier stay locked.
+const DEFAULT_LOCK: u64 = LOCK_TIERS[DEFAULT_LOCK_TIER as usize].0;
+
+// What `amount` pledge tokens earn over a full lock at 1x.
+fn full_reward(amount: u64) -> u64 {
+    (amount as u128 * REWARD_RATE_BPS as u128 / BASIS_POINTS as u128) as u64
+}
+
+// Builds a program-owned user state PDA for `wallet` holding `user_state`.
+fn user_state_account<'a>(program_id: &Pubkey, wallet: &Pubkey, user_state: UserState) -> AccountInfo<'a> {
+    let (key, bump) = find_user_state_address(program_id, wallet);
+    let beneficiary = if user_state.beneficiary == Pubkey::default() { *wallet } else { user_state.beneficiary };
+    let user_state = UserState { is_initialized: true, owner: *wallet, bump, beneficiary, ..user_state };
+    AccountInfo::new(
+        Box::leak(Box::new(key)),
+        false,
+        true,
+        Box::leak(Box::new(1_000_000)),
+        borsh::to_vec(&user_state).unwrap().leak(),
+        Box::leak(Box::new(*program_id)),
+        false,
+        0,
+    )
+}
+
+// Builds the initialized config PDA holding `pledge_contract`.
+fn config_account<'a>(program_id: &Pubkey, pledge_contract: ContractConfig) -> AccountInfo<'a> {
+    let (key, bump) = find_config_address(program_id);
+    let pledge_contract = ContractConfig { is_initialized: true, bump, ..pledge_contract };
+    let mut data = vec![0; ContractConfig::LEN];
+    pledge_contract.serialize(&mut data.as_mut_slice()).unwrap();
+    AccountInfo::new(
+        Box::leak(Box::new(key)),
+        false,
+        true,
+        Box::leak(Box::new(1_000_000)),
+        data.leak(),
+        Box::leak(Box::new(*program_id)),
+        false,
+        0,
+    )
+}
+
+// Records sol_log_data output, return data and CPIs per test thread, and
+// fails CPIs on a thread that asked for it; everything else behaves like the
+// default off-chain stubs.
+struct EventCapture;
+
+thread_local! {
+    static LOGGED_DATA: std::cell::RefCell<Vec<Vec<u8>>> = const { std::cell::RefCell::new(Vec::new()) };
+    static RETURN_DATA: std::cell::RefCell<Vec<u8>> = const { std::cell::RefCell::new(Vec::new()) };
+    static FAIL_CPI: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+    static INVOKED: std::cell::RefCell<Vec<solana_program::instruction::Instruction>> =
+        const { std::cell::RefCell::new(Vec::new()) };
+}
+
+impl solana_program::program_stubs::SyscallStubs for EventCapture {
+    fn sol_invoke_signed(
+        &self,
+        instruction: &solana_program::instruction::Instruction,
+        _account_infos: &[AccountInfo],
+        _signers_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        INVOKED.with(|invoked| invoked.borrow_mut().push(instruction.clone()));
+        if FAIL_CPI.with(|fail| fail.get()) {
+            return Err(ProgramError::InsufficientFunds);
+        }
+        Ok(())
+    }
+
+    fn sol_log_data(&self, fields: &[&[u8]]) {
+        assert_eq!(fields.first(), Some(&&[EVENT_SCHEMA_VERSION][..]));
+        LOGGED_DATA.with(|logged| logged.borrow_mut().extend(fields[1..].iter().map(|field| field.to_vec())));
+    }
+
+    fn sol_set_return_data(&self, data: &[u8]) {
+        RETURN_DATA.with(|return_data| *return_data.borrow_mut() = data.to_vec());
+    }
+}
+
+fn install_capture() {
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+    INSTALL.call_once(|| {
+        solana_program::program_stubs::set_syscall_stubs(Box::new(EventCapture));
+    });
+}
+
+// Returns the events emitted on this thread since the last call.
+fn take_events() -> Vec<PledgeEvent> {
+    install_capture();
+    LOGGED_DATA.with(|logged| logged.borrow_mut().drain(..).map(|data| PledgeEvent::try_from_slice(&data).unwrap()).collect())
+}
+
+// Makes every CPI on this thread fail, or succeed again.
+fn fail_cpis(fail: bool) {
+    install_capture();
+    FAIL_CPI.with(|cell| cell.set(fail));
+}
+
+// Returns the instructions invoked on this thread since the last call.
+fn take_cpis() -> Vec<solana_program::instruction::Instruction> {
+    install_capture();
+    INVOKED.with(|invoked| std::mem::take(&mut *invoked.borrow_mut()))
+}
+
+// Returns the return data last set on this thread.
+fn take_return_data() -> Vec<u8> {
+    install_capture();
+    RETURN_DATA.with(|return_data| std::mem::take(&mut *return_data.borrow_mut()))
+}
+
+// A user state holding a single lot of `amount` locked at `lock_start_time`.
+fn locked_state(amount: u64, lock_start_time: u64) -> UserState {
+    let mut user_state = UserState::default();
+    user_state
+        .add_lot(PledgeLot {
+            amount,
+            rate: PHASE_RATES[0],
+            lock_start_time,
+            vesting_end_time: lock_start_time + VESTING_PERIOD,
+            multiplier_bps: BASIS_POINTS,
+        })
+        .unwrap();
+    user_state
+}
+
+// An initialized SPL token account holding `amount` tokens for `owner`.
+fn token_account<'a>(owner: &Pubkey, amount: u64) -> AccountInfo<'a> {
+    token_account_at(Pubkey::new_unique(), &Pubkey::default(), owner, amount)
+}
+
+fn token_account_at<'a>(key: Pubkey, mint: &Pubkey, owner: &Pubkey, amount: u64) -> AccountInfo<'a> {
+    let mut data = vec![0; spl_token::state::Account::LEN];
+    spl_token::state::Account {
+        mint: *mint,
+        owner: *owner,
+        amount,
+        state: spl_token::state::AccountState::Initialized,
+        ..spl_token::state::Account::default()
+    }
+    .pack_into_slice(&mut data);
+    AccountInfo::new(
+        Box::leak(Box::new(key)),
+        false,
+        true,
+        Box::leak(Box::new(1_000_000)),
+        data.leak(),
+        &spl_token::ID,
+        false,
+        0,
+    )
+}
+
+fn mint_account<'a>(key: Pubkey) -> AccountInfo<'a> {
+    let mut data = vec![0; spl_token::state::Mint::LEN];
+    spl_token::state::Mint {
+        supply: TOTAL_SOLHIT_SUPPLY,
+        decimals: 6,
+        is_initialized: true,
+        ..spl_token::state::Mint::default()
+    }
+    .pack_into_slice(&mut data);
+    AccountInfo::new(
+        Box::leak(Box::new(key)),
+        false,
+        false,
+        Box::leak(Box::new(1_000_000)),
+        data.leak(),
+        &spl_token::ID,
+        false,
+        0,
+    )
+}
+
+fn token_program_account<'a>() -> AccountInfo<'a> {
+    AccountInfo::new(&spl_token::ID, false, false, Box::leak(Box::new(0)), &mut [], &solana_program::bpf_loader::ID, true, 0)
+}
+
+// Accounts for BuyPledgeWithToken paying in `mint` from a buyer account
+// holding `balance`.
+fn token_buy_accounts<'a>(
+    program_id: &Pubkey,
+    account_info: &AccountInfo<'a>,
+    wallet: &'a Pubkey,
+    config: &AccountInfo<'a>,
+    mint: Pubkey,
+    balance: u64,
+) -> Vec<AccountInfo<'a>> {
+    let (treasury, _) = find_treasury_address(program_id);
+    vec![
+        account_info.clone(),
+        wallet_account(wallet, true),
+        config.clone(),
+        token_account_at(Pubkey::new_unique(), &mint, wallet, balance),
+        token_account_at(Pubkey::new_unique(), &mint, &treasury, 0),
+        mint_account(mint),
+        token_program_account(),
+        whitelist_account(program_id, wallet),
+    ]
+}
+
+// Accounts for ClaimRewards after `account_info`, with a correctly derived vault.
+fn claim_accounts<'a>(program_id: &Pubkey, account_info: &AccountInfo<'a>, wallet: &'a Pubkey, is_signer: bool) -> Vec<AccountInfo<'a>> {
+    let (vault, _) = find_solhit_vault_address(program_id);
+    let mint = Pubkey::new_unique();
+    vec![
+        account_info.clone(),
+        wallet_account(wallet, is_signer),
+        config_account(program_id, ContractConfig::new()),
+        token_account_at(vault, &mint, &vault, TOTAL_SOLHIT_SUPPLY),
+        token_account_at(Pubkey::new_unique(), &mint, wallet, 0),
+        mint_account(mint),
+        token_program_account(),
+    ]
+}
+
+// Accounts for UnlockPledge after `account_info`, with a correctly held vault.
+fn unlock_accounts<'a>(program_id: &Pubkey, account_info: &AccountInfo<'a>, wallet: &'a Pubkey) -> Vec<AccountInfo<'a>> {
+    let (vault_authority, _) = find_pledge_vault_address(program_id);
+    vec![
+        account_info.clone(),
+        wallet_account(wallet, true),
+        config_account(program_id, ContractConfig::new()),
+        token_account(&vault_authority, TOTAL_PLEDGE_SUPPLY),
+        token_account(wallet, 0),
+        wallet_account(Box::leak(Box::new(vault_authority)), false),
+        token_program_account(),
+    ]
+}
+
+fn system_program_account<'a>() -> AccountInfo<'a> {
+    wallet_account(&solana_program::system_program::ID, false)
+}
+
+// The program-owned treasury PDA holding `lamports`.
+fn treasury_account<'a>(program_id: &Pubkey, lamports: u64) -> AccountInfo<'a> {
+    AccountInfo::new(
+        Box::leak(Box::new(find_treasury_address(program_id).0)),
+        false,
+        true,
+        Box::leak(Box::new(lamports)),
+        &mut [],
+        Box::leak(Box::new(*program_id)),
+        false,
+        0,
+    )
+}
+
+// The initialized whitelist PDA of `wallet`.
+fn whitelist_account<'a>(program_id: &Pubkey, wallet: &Pubkey) -> AccountInfo<'a> {
+    let (key, bump) = find_whitelist_address(program_id, wallet);
+    let whitelist = Whitelist { is_initialized: true, wallet: *wallet, bump };
+    AccountInfo::new(
+        Box::leak(Box::new(key)),
+        false,
+        false,
+        Box::leak(Box::new(1_000_000)),
+        borsh::to_vec(&whitelist).unwrap().leak(),
+        Box::leak(Box::new(*program_id)),
+        false,
+        0,
+    )
+}
+
+fn wallet_account(key: &Pubkey, is_signer: bool) -> AccountInfo<'_> {
+    AccountInfo::new(
+        key,
+        is_signer,
+        false,
+        Box::leak(Box::new(0)),
+        &mut [],
+        &solana_program::system_program::ID,
+        false,
+        0,
+    )
+}
+
+
+    #[test]
+fn test_buy_pledge() {
+    let program_id = Pubkey::new_unique();
+    let wallet = Pubkey::new_unique();
+    let account_info = user_state_account(&program_id, &wallet, UserState::default());
+
+    let amount = 1000;
+    let current_time = 1_000_000;
+    let result = buy_pledge(&program_id, &[account_info.clone(), wallet_account(&wallet, true), config_account(&program_id, ContractConfig::new()), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)], amount, DEFAULT_LOCK_TIER, None, false, current_time);
+    assert!(result.is_ok());
+
+    let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+    let pledge_contract = ContractConfig::new();
+    let sale_phase = get_sale_phase(current_time, &pledge_contract.phase_durations);
+    let rate = pledge_contract.phase_rates[sale_phase];
+    let expected_pledge_tokens = (amount * rate) / 100;
+
+    assert_eq!(user_state.locked_pledge_tokens, expected_pledge_tokens);
+    assert_eq!(user_state.lock_start_time, current_time);
+    assert_eq!(user_state.vesting_end_time, current_time + DEFAULT_LOCK);
+}
+#[test]
+fn test_buy_pledge_vesting_period() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+
+  let amount = 500;
+  let current_time = 1_000_000;
+  let accounts = [account_info.clone(), wallet_account(&wallet, true), config_account(&program_id, ContractConfig::new()), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)];
+
+  for (tier, (lock_duration, multiplier_bps)) in LOCK_TIERS.into_iter().enumerate() {
+    buy_pledge(&program_id, &accounts, amount, tier as u8, None, false, current_time).unwrap();
+    let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+    assert_eq!(user_state.lots[tier].vesting_end_time, current_time + lock_duration);
+    assert_eq!(user_state.lots[tier].multiplier_bps, multiplier_bps);
+  }
+  assert_eq!(
+    buy_pledge(&program_id, &accounts, amount, LOCK_TIERS.len() as u8, None, false, current_time),
+    Err(PledgeError::InvalidLockTier.into())
+  );
+}
+
+#[test]
+fn test_lock_tiers_scale_rewards() {
+  let program_id = Pubkey::new_unique();
+  let wallets = [Pubkey::new_unique(), Pubkey::new_unique()];
+  let config = config_account(&program_id, ContractConfig::new());
+  let mut rewards = vec![];
+  for (tier, wallet) in [0, 2].into_iter().zip(&wallets) {
+    let account_info = user_state_account(&program_id, wallet, UserState::default());
+    let accounts = [account_info.clone(), wallet_account(wallet, true), config.clone(), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, wallet)];
+    buy_pledge(&program_id, &accounts, 1000, tier, None, false, 0).unwrap();
+    update_reward(&program_id, &account_info, &config, VESTING_PERIOD).unwrap();
+    rewards.push(UserState::try_from_slice(&account_info.data.borrow()).unwrap().solhit_rewards);
+  }
+
+  // Fully vested, the same purchase earns 0.5x on the short tier and 2x on
+  // the long one.
+  assert_eq!(rewards, vec![full_reward(2000) / 2, full_reward(2000) * 2]);
+}
+
+#[test]
+fn test_buy_pledge_exceed_supply() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+
+  let pledge_contract = ContractConfig::new();
+  let amount = pledge_contract.total_pledge_supply + 1;
+  let current_time = 1_000_000;
+
+  let result = buy_pledge(&program_id, &[account_info.clone(), wallet_account(&wallet, true), config_account(&program_id, ContractConfig::new()), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)], amount, DEFAULT_LOCK_TIER, None, false, current_time);
+
+  assert!(result.is_err());
+}
+
+#[test]
+fn test_buy_pledge_invalid_amount() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, locked_state(200, 500_000));
+
+  let amount = 0;
+  let current_time = 1_000_000;
+
+  let result = buy_pledge(&program_id, &[account_info.clone(), wallet_account(&wallet, true), config_account(&program_id, ContractConfig::new()), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)], amount, DEFAULT_LOCK_TIER, None, false, current_time);
+
+  assert_eq!(result, Err(PledgeError::InvalidAmount.into()));
+  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.locked_pledge_tokens, 200);
+  assert_eq!(user_state.lock_start_time, 500_000);
+  assert_eq!(user_state.vesting_end_time, 500_000 + VESTING_PERIOD);
+}
+
+#[test]
+fn test_process_instruction_rejects_malformed_data() {
+  let mut account_data = vec![0u8; UserState::LEN];
+  let pubkey = Pubkey::new_unique();
+  let mut lamports = 1000;
+  let account_info = AccountInfo::new(
+    &pubkey,
+    false,
+    true,
+    &mut lamports,
+    &mut account_data,
+    &pubkey,
+    false,
+    0,
+  );
+  let accounts = [account_info];
+
+  for data in [&[][..], &[0][..], &[0, 1, 2][..], &[9][..]] {
+    let result = process_instruction(&pubkey, &accounts, data);
+    assert_eq!(result, Err(ProgramError::InvalidInstructionData));
+  }
+}
+
+// The Clock sysvar account reporting `unix_timestamp`.
+fn clock_account<'a>(unix_timestamp: i64) -> AccountInfo<'a> {
+  let mut account_info = AccountInfo::new(
+    &clock::ID,
+    false,
+    false,
+    Box::leak(Box::new(1_000_000)),
+    vec![0; Clock::size_of()].leak(),
+    &solana_program::sysvar::ID,
+    false,
+    0,
+  );
+  Clock { unix_timestamp, ..Clock::default() }.to_account_info(&mut account_info).unwrap();
+  account_info
+}
+
+#[test]
+fn test_process_instruction_uses_injected_clock() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, locked_state(1000, 0));
+  let config = config_account(&program_id, ContractConfig::new());
+  let data = PledgeInstruction::UpdateReward.pack();
+
+  let accounts = [account_info.clone(), config.clone(), clock_account(VESTING_PERIOD as i64)];
+  process_instruction(&program_id, &accounts, &data).unwrap();
+  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.solhit_rewards, full_reward(1000));
+}
+
+#[test]
+fn test_process_instruction_rejects_negative_clock() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, locked_state(1000, 0));
+  let config = config_account(&program_id, ContractConfig::new());
+  let data = PledgeInstruction::UpdateReward.pack();
+
+  let accounts = [account_info.clone(), config, clock_account(-1)];
+  assert_eq!(process_instruction(&program_id, &accounts, &data), Err(PledgeError::InvalidClock.into()));
+  assert_eq!(UserState::try_from_slice(&account_info.data.borrow()).unwrap().solhit_rewards, 0);
+}
+
+fn assert_buy_rejected_without_mutation(instruction_data: &[u8]) {
+  let original = vec![7u8; UserState::LEN];
+  let mut account_data = original.clone();
+  let pubkey = Pubkey::new_unique();
+  let mut lamports = 1000;
+  let account_info = AccountInfo::new(
+    &pubkey,
+    false,
+    true,
+    &mut lamports,
+    &mut account_data,
+    &pubkey,
+    false,
+    0,
+  );
+  let accounts = [account_info];
+
+  let result = process_instruction(&pubkey, &accounts, instruction_data);
+
+  assert_eq!(result, Err(ProgramError::InvalidInstructionData));
+  assert_eq!(*accounts[0].data.borrow(), &original[..]);
+}
+
+#[test]
+fn test_process_instruction_empty_data() {
+  assert_buy_rejected_without_mutation(&[]);
+}
+
+#[test]
+fn test_process_instruction_tag_without_amount() {
+  assert_buy_rejected_without_mutation(&[0]);
+}
+
+#[test]
+fn test_process_instruction_truncated_amount() {
+  assert_buy_rejected_without_mutation(&[0, 1, 2, 3, 4, 5]);
+}
+
+#[test]
+fn test_handlers_reject_foreign_owner() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let foreign_owner = Pubkey::new_unique();
+  let mut account_data = vec![0u8; UserState::LEN];
+  let pubkey = Pubkey::new_unique();
+  let mut lamports = 1000;
+  let account_info = AccountInfo::new(
+    &pubkey,
+    false,
+    true,
+    &mut lamports,
+    &mut account_data,
+    &foreign_owner,
+    false,
+    0,
+  );
+
+  assert_eq!(buy_pledge(&program_id, &[account_info.clone(), wallet_account(&wallet, true), config_account(&program_id, ContractConfig::new()), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)], 1000, DEFAULT_LOCK_TIER, None, false, 1_000_000), Err(ProgramError::IncorrectProgramId));
+  assert_eq!(update_reward(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), 1_000_000), Err(ProgramError::IncorrectProgramId));
+  assert_eq!(view_rewards(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), false, 1_000_000), Err(ProgramError::IncorrectProgramId));
+  assert_eq!(
+    claim_rewards(&program_id, &claim_accounts(&program_id, &account_info, &wallet, true), 0, 0),
+    Err(ProgramError::IncorrectProgramId)
+  );
+  assert!(account_info.data.borrow().iter().all(|&byte| byte == 0));
+}
+
+#[test]
+fn test_handlers_accept_program_owned_account() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+
+  assert!(buy_pledge(&program_id, &[account_info.clone(), wallet_account(&wallet, true), config_account(&program_id, ContractConfig::new()), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)], 1000, DEFAULT_LOCK_TIER, None, false, 1_000_000).is_ok());
+  assert!(update_reward(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), 1_000_001).is_ok());
+  assert!(view_rewards(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), false, 1_000_000).is_ok());
+}
+
+#[test]
+fn test_handlers_reject_read_only_account() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let mut account_info = user_state_account(&program_id, &wallet, UserState::default());
+  account_info.is_writable = false;
+
+  assert_eq!(buy_pledge(&program_id, &[account_info.clone(), wallet_account(&wallet, true), config_account(&program_id, ContractConfig::new()), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)], 1000, DEFAULT_LOCK_TIER, None, false, 1_000_000), Err(PledgeError::AccountNotWritable.into()));
+  assert_eq!(update_reward(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), 1_000_000), Err(PledgeError::AccountNotWritable.into()));
+  assert_eq!(
+    claim_rewards(&program_id, &claim_accounts(&program_id, &account_info, &wallet, true), 0, 0),
+    Err(PledgeError::AccountNotWritable.into())
+  );
+  // Reading is still allowed.
+  assert!(view_rewards(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), false, 1_000_000).is_ok());
+}
+
+#[test]
+fn test_handlers_reject_read_only_config_and_treasury() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let rent = Rent::default();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let mut config = config_account(&program_id, ContractConfig { admin, ..ContractConfig::new() });
+  config.is_writable = false;
+  let not_writable = Err(PledgeError::AccountNotWritable.into());
+
+  assert_eq!(update_reward(&program_id, &account_info, &config, 1_000_000), not_writable);
+  assert_eq!(set_paused(&program_id, &[config.clone(), wallet_account(&admin, true)], true), not_writable);
+  assert_eq!(
+    buy_pledge(&program_id, &[account_info.clone(), wallet_account(&wallet, true), config.clone(), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)], 1000, DEFAULT_LOCK_TIER, None, false, 1_000_000),
+    not_writable
+  );
+
+  let config = config_account(&program_id, ContractConfig { admin, ..ContractConfig::new() });
+  let mut treasury = treasury_account(&program_id, rent.minimum_balance(0) + 1_000);
+  treasury.is_writable = false;
+  let destination = wallet_account(&admin, true);
+  let accounts = [config.clone(), destination.clone(), treasury, destination];
+  assert_eq!(withdraw_treasury(&program_id, &accounts, 400, &rent), not_writable);
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().total_withdrawn, 0);
+}
+
+// Program accounts are never state, even where the handler only reads.
+#[test]
+fn test_handlers_reject_executable_state_accounts() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let executable = Err(PledgeError::ExecutableAccount.into());
+
+  let mut account_info = user_state_account(&program_id, &wallet, UserState::default());
+  account_info.executable = true;
+  let config = config_account(&program_id, ContractConfig { admin, ..ContractConfig::new() });
+  assert_eq!(update_reward(&program_id, &account_info, &config, 1_000_000), executable);
+  assert_eq!(view_rewards(&program_id, &account_info, &config, false, 1_000_000), executable);
+  assert_eq!(claim_rewards(&program_id, &claim_accounts(&program_id, &account_info, &wallet, true), 0, 0), executable);
+
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let mut config = config_account(&program_id, ContractConfig { admin, ..ContractConfig::new() });
+  config.executable = true;
+  assert_eq!(update_reward(&program_id, &account_info, &config, 1_000_000), executable);
+  assert_eq!(set_paused(&program_id, &[config.clone(), wallet_account(&admin, true)], true), executable);
+  assert_eq!(view_rewards(&program_id, &account_info, &config, false, 1_000_000), executable);
+}
+
+// A lookalike passed for the system or SPL token program is refused before
+// the handler reads any other account.
+#[test]
+fn test_handlers_reject_fake_program_accounts() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let rent = Rent::default();
+  let keys = [(); 4].map(|_| Pubkey::new_unique());
+  let accounts: Vec<AccountInfo> = keys.iter().map(|key| wallet_account(key, true)).collect();
+  let fake_key = Pubkey::new_unique();
+  let fake = wallet_account(&fake_key, false);
+  // The first `len` accounts followed by the lookalike.
+  let with_fake = |len: usize| [&accounts[..len], std::slice::from_ref(&fake)].concat();
+  let rejected = Err(PledgeError::InvalidProgramAccount.into());
+
+  assert_eq!(migrate_user_state(&program_id, &with_fake(2), &rent), rejected);
+  assert_eq!(resize_user_state(&program_id, &with_fake(2), UserState::LEN as u64, &rent), rejected);
+  assert_eq!(migrate_config(&program_id, &with_fake(2), &rent), rejected);
+  assert_eq!(add_to_whitelist(&program_id, &with_fake(3), &wallet, &rent), rejected);
+  assert_eq!(join_group(&program_id, &with_fake(3), &wallet, &rent), rejected);
+  assert_eq!(record_snapshot(&program_id, &with_fake(3), 0, &rent), rejected);
+  assert_eq!(emergency_sweep(&program_id, &with_fake(4)), rejected);
+}
+
+#[test]
+fn test_buy_pledge_requires_signer() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+
+  let original = account_info.data.borrow().to_vec();
+  let result = buy_pledge(&program_id, &[account_info.clone(), wallet_account(&wallet, false), config_account(&program_id, ContractConfig::new()), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)], 1000, DEFAULT_LOCK_TIER, None, false, 1_000_000);
+  assert_eq!(result, Err(ProgramError::MissingRequiredSignature));
+  assert_eq!(*account_info.data.borrow(), &original[..]);
+
+  let result = buy_pledge(&program_id, &[account_info.clone(), wallet_account(&wallet, true), config_account(&program_id, ContractConfig::new()), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)], 1000, DEFAULT_LOCK_TIER, None, false, 1_000_000);
+  assert!(result.is_ok());
+  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+  assert!(user_state.locked_pledge_tokens > 0);
+}
+
+#[test]
+fn test_claim_rewards_requires_signer() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let user_state = UserState {
+    solhit_rewards: 500,
+    ..UserState::default()
+  };
+  let account_info = user_state_account(&program_id, &wallet, user_state);
+
+  let result = claim_rewards(&program_id, &claim_accounts(&program_id, &account_info, &wallet, false), 0, 0);
+  assert_eq!(result, Err(ProgramError::MissingRequiredSignature));
+  assert_eq!(UserState::try_from_slice(&account_info.data.borrow()).unwrap().solhit_rewards, 500);
+
+  let result = claim_rewards(&program_id, &claim_accounts(&program_id, &account_info, &wallet, true), 0, 0);
+  assert!(result.is_ok());
+  assert_eq!(UserState::try_from_slice(&account_info.data.borrow()).unwrap().solhit_rewards, 0);
+}
+
+#[test]
+fn test_find_user_state_address() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+
+  let (address, bump) = find_user_state_address(&program_id, &wallet);
+
+  let expected = Pubkey::create_program_address(&[USER_STATE_SEED, wallet.as_ref(), &[bump]], &program_id).unwrap();
+  assert_eq!(address, expected);
+  assert_ne!(address, find_user_state_address(&program_id, &Pubkey::new_unique()).0);
+  assert_ne!(address, find_user_state_address(&Pubkey::new_unique(), &wallet).0);
+}
+
+#[test]
+fn test_initialize_user() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let rent = Rent::default();
+  let (address, bump) = find_user_state_address(&program_id, &wallet);
+  let mut account_data = vec![0u8; UserState::LEN];
+  let mut lamports = rent.minimum_balance(UserState::LEN);
+  let account_info = AccountInfo::new(
+    &address,
+    false,
+    true,
+    &mut lamports,
+    &mut account_data,
+    &program_id,
+    false,
+    0,
+  );
+  let system_program = wallet_account(&solana_program::system_program::ID, false);
+  let config = config_account(&program_id, ContractConfig::new());
+  let accounts = [account_info.clone(), wallet_account(&wallet, true), system_program, config.clone()];
+
+  assert!(initialize_user(&program_id, &accounts, &rent).is_ok());
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().initialized_users, 1);
+
+  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+  assert!(user_state.is_initialized);
+  assert_eq!(user_state.owner, wallet);
+  assert_eq!(user_state.bump, bump);
+
+  // A second initialization must not wipe the existing position.
+  buy_pledge(&program_id, &[account_info.clone(), wallet_account(&wallet, true), config_account(&program_id, ContractConfig::new()), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)], 1000, DEFAULT_LOCK_TIER, None, false, 1_000_000).unwrap();
+  let before = account_info.data.borrow().to_vec();
+  assert_eq!(
+    initialize_user(&program_id, &accounts, &rent),
+    Err(PledgeError::AlreadyInitialized.into())
+  );
+  assert_eq!(*account_info.data.borrow(), &before[..]);
+}
+
+#[test]
+fn test_initialize_user_requires_rent_exemption() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let rent = Rent::default();
+  let (address, _) = find_user_state_address(&program_id, &wallet);
+  let mut account_data = vec![0u8; UserState::LEN];
+  let mut lamports = rent.minimum_balance(UserState::LEN) - 1;
+  let account_info = AccountInfo::new(
+    &address,
+    false,
+    true,
+    &mut lamports,
+    &mut account_data,
+    &program_id,
+    false,
+    0,
+  );
+  let system_program = wallet_account(&solana_program::system_program::ID, false);
+
+  let result = initialize_user(
+    &program_id,
+    &[account_info.clone(), wallet_account(&wallet, true), system_program, config_account(&program_id, ContractConfig::new())],
+    &rent,
+  );
+  assert_eq!(result, Err(PledgeError::NotRentExempt.into()));
+  assert!(account_info.data.borrow().iter().all(|&byte| byte == 0));
+}
+
+#[test]
+fn test_handlers_reject_uninitialized_state() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let (address, _) = find_user_state_address(&program_id, &wallet);
+  let mut account_data = vec![0u8; UserState::LEN];
+  let mut lamports = 1000;
+  let account_info = AccountInfo::new(
+    &address,
+    false,
+    true,
+    &mut lamports,
+    &mut account_data,
+    &program_id,
+    false,
+    0,
+  );
+
+  assert_eq!(
+    buy_pledge(&program_id, &[account_info.clone(), wallet_account(&wallet, true), config_account(&program_id, ContractConfig::new()), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)], 1000, DEFAULT_LOCK_TIER, None, false, 1_000_000),
+    Err(ProgramError::UninitializedAccount)
+  );
+  assert_eq!(update_reward(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), 1_000_000), Err(ProgramError::UninitializedAccount));
+  assert_eq!(view_rewards(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), false, 1_000_000), Err(ProgramError::UninitializedAccount));
+  assert_eq!(
+    claim_rewards(&program_id, &claim_accounts(&program_id, &account_info, &wallet, true), 0, 0),
+    Err(ProgramError::UninitializedAccount)
+  );
+  assert!(account_info.data.borrow().iter().all(|&byte| byte == 0));
+}
+
+#[test]
+fn test_initialize_user_rejects_non_pda() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let mut account_data = vec![0u8; UserState::LEN];
+  let pubkey = Pubkey::new_unique();
+  let mut lamports = 0;
+  let account_info = AccountInfo::new(
+    &pubkey,
+    false,
+    true,
+    &mut lamports,
+    &mut account_data,
+    &program_id,
+    false,
+    0,
+  );
+  let system_program = wallet_account(&solana_program::system_program::ID, false);
+
+  let result = initialize_user(
+    &program_id,
+    &[account_info.clone(), wallet_account(&wallet, true), system_program, config_account(&program_id, ContractConfig::new())],
+    &Rent::default(),
+  );
+  assert_eq!(result, Err(ProgramError::InvalidSeeds));
+}
+
+#[test]
+fn test_handlers_reject_non_pda_state() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let (_, bump) = find_user_state_address(&program_id, &wallet);
+  let user_state = UserState {
+    is_initialized: true,
+    solhit_rewards: 500,
+    owner: wallet,
+    bump,
+    ..UserState::default()
+  };
+  // Valid contents, but stored at an address that is not the wallet's PDA.
+  let mut account_data = borsh::to_vec(&user_state).unwrap();
+  let pubkey = Pubkey::new_unique();
+  let mut lamports = 1000;
+  let account_info = AccountInfo::new(
+    &pubkey,
+    false,
+    true,
+    &mut lamports,
+    &mut account_data,
+    &program_id,
+    false,
+    0,
+  );
+
+  assert_eq!(
+    buy_pledge(&program_id, &[account_info.clone(), wallet_account(&wallet, true), config_account(&program_id, ContractConfig::new()), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)], 1000, DEFAULT_LOCK_TIER, None, false, 1_000_000),
+    Err(ProgramError::InvalidSeeds)
+  );
+  assert_eq!(update_reward(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), 1_000_000), Err(ProgramError::InvalidSeeds));
+  assert_eq!(
+    claim_rewards(&program_id, &claim_accounts(&program_id, &account_info, &wallet, true), 0, 0),
+    Err(ProgramError::InvalidSeeds)
+  );
+  // The views refuse it too, so no balance is read off a planted copy.
+  let config = config_account(&program_id, ContractConfig::new());
+  for soft in [false, true] {
+    assert_eq!(view_rewards(&program_id, &account_info, &config, soft, 1_000_000), Err(ProgramError::InvalidSeeds));
+    assert_eq!(preview_reward(&program_id, &[account_info.clone(), config.clone()], soft, 1_000_000), Err(ProgramError::InvalidSeeds));
+  }
+  assert_eq!(get_vesting_schedule(&program_id, &[account_info.clone(), config]), Err(ProgramError::InvalidSeeds));
+}
+
+#[test]
+fn test_handlers_reject_other_wallet() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let other_wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+
+  assert_eq!(
+    buy_pledge(&program_id, &[account_info.clone(), wallet_account(&other_wallet, true), config_account(&program_id, ContractConfig::new()), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &other_wallet)], 1000, DEFAULT_LOCK_TIER, None, false, 1_000_000),
+    Err(ProgramError::InvalidSeeds)
+  );
+  assert_eq!(
+    claim_rewards(&program_id, &claim_accounts(&program_id, &account_info, &other_wallet, true), 0, 0),
+    Err(ProgramError::InvalidSeeds)
+  );
+}
+
+#[test]
+fn test_initialize_contract() {
+  let program_id = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let rent = Rent::default();
+  let (address, bump) = find_config_address(&program_id);
+  let mut config_data = vec![0u8; ContractConfig::LEN];
+  let mut lamports = rent.minimum_balance(ContractConfig::LEN);
+  let config_info = AccountInfo::new(
+    &address,
+    false,
+    true,
+    &mut lamports,
+    &mut config_data,
+    &program_id,
+    false,
+    0,
+  );
+  let system_program = wallet_account(&solana_program::system_program::ID, false);
+  let accounts = [config_info.clone(), wallet_account(&admin, true), system_program, treasury_account(&program_id, 0)];
+
+  // A guardian needs somewhere to sweep to.
+  let (guardian, recovery) = (Pubkey::new_unique(), Pubkey::new_unique());
+  assert_eq!(
+    initialize_contract(&program_id, &accounts, 1_700_000_000, PHASE_CAPS, 0, guardian, Pubkey::default(), &rent),
+    Err(PledgeError::InvalidConfig.into())
+  );
+  assert!(initialize_contract(&program_id, &accounts, 1_700_000_000, PHASE_CAPS, 0, guardian, recovery, &rent).is_ok());
+
+  let pledge_contract = ContractConfig::unpack(&config_info.data.borrow()).unwrap();
+  assert!(pledge_contract.is_initialized);
+  assert_eq!(pledge_contract.bump, bump);
+  assert_eq!(pledge_contract.total_pledge_sold, 0);
+  assert_eq!(pledge_contract.total_pledge_supply, TOTAL_PLEDGE_SUPPLY);
+  assert_eq!(pledge_contract.sale_start_time, 1_700_000_000);
+  assert_eq!(pledge_contract.phase_caps, PHASE_CAPS);
+  assert_eq!(pledge_contract.admin, admin);
+  assert_eq!((pledge_contract.guardian, pledge_contract.recovery_destination), (guardian, recovery));
+  assert!(!pledge_contract.paused);
+
+  assert_eq!(
+    initialize_contract(&program_id, &accounts, 1_700_000_000, PHASE_CAPS, 0, Pubkey::default(), Pubkey::default(), &rent),
+    Err(PledgeError::AlreadyInitialized.into())
+  );
+}
+
+#[test]
+fn test_buy_pledge_global_supply_cap() {
+  let program_id = Pubkey::new_unique();
+  let wallet_a = Pubkey::new_unique();
+  let wallet_b = Pubkey::new_unique();
+  let user_a = user_state_account(&program_id, &wallet_a, UserState::default());
+  let user_b = user_state_account(&program_id, &wallet_b, UserState::default());
+  // Lift the phase cap so only the global supply limits the purchases.
+  let config = config_account(&program_id, ContractConfig { phase_caps: [TOTAL_PLEDGE_SUPPLY; 5], ..ContractConfig::new() });
+  let current_time = 1_000_000;
+  let rate = PHASE_RATES[get_sale_phase(current_time, &PHASE_DURATIONS)];
+
+  // Each user alone stays under the cap, but together they would exceed it.
+  let amount = TOTAL_PLEDGE_SUPPLY / 2 * 100 / rate + 1;
+  buy_pledge(&program_id, &[user_a.clone(), wallet_account(&wallet_a, true), config.clone(), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet_a)], amount, DEFAULT_LOCK_TIER, None, false, current_time).unwrap();
+  let sold = ContractConfig::unpack(&config.data.borrow()).unwrap().total_pledge_sold;
+  assert_eq!(sold, amount * rate / 100);
+
+  let result = buy_pledge(&program_id, &[user_b.clone(), wallet_account(&wallet_b, true), config.clone(), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet_b)], amount, DEFAULT_LOCK_TIER, None, false, current_time);
+  assert_eq!(result, Err(PledgeError::SupplyExhausted.into()));
+  assert_eq!(UserState::try_from_slice(&user_b.data.borrow()).unwrap().locked_pledge_tokens, 0);
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().total_pledge_sold, sold);
+}
+
+#[test]
+fn test_buy_pledge_phase_cap() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  // At a 100% first phase rate one lamport buys exactly one pledge token.
+  let config = config_account(&program_id, ContractConfig {
+    phase_rates: [100, 175, 150, 125, 100],
+    phase_caps: [1_000; 5],
+    ..ContractConfig::new()
+  });
+  let accounts = [account_info.clone(), wallet_account(&wallet, true), config.clone(), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)];
+
+  buy_pledge(&program_id, &accounts, 600, DEFAULT_LOCK_TIER, None, false, 0).unwrap();
+  buy_pledge(&program_id, &accounts, 400, DEFAULT_LOCK_TIER, None, false, 0).unwrap();
+  assert_eq!(buy_pledge(&program_id, &accounts, 1, DEFAULT_LOCK_TIER, None, false, 0), Err(PledgeError::PhaseCapExceeded.into()));
+  let pledge_contract = ContractConfig::unpack(&config.data.borrow()).unwrap();
+  assert_eq!(pledge_contract.phase_sold, [1_000, 0, 0, 0, 0]);
+
+  // The next phase has its own allocation.
+  buy_pledge(&program_id, &accounts, 100, DEFAULT_LOCK_TIER, None, false, PHASE_DURATIONS[0]).unwrap();
+  let pledge_contract = ContractConfig::unpack(&config.data.borrow()).unwrap();
+  assert_eq!(pledge_contract.phase_sold, [1_000, 175, 0, 0, 0]);
+  assert_eq!(pledge_contract.total_pledge_sold, 1_175);
+  assert_eq!(UserState::try_from_slice(&account_info.data.borrow()).unwrap().locked_pledge_tokens, 1_175);
+}
+
+#[test]
+fn test_buy_pledge_user_cap() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let config = config_account(&program_id, ContractConfig { max_per_user: 1_000, ..ContractConfig::new() });
+  let accounts = [account_info.clone(), wallet_account(&wallet, true), config.clone(), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)];
+
+  // Phase 0 sells at 200%, so 500 lamports reach the cap.
+  buy_pledge(&program_id, &accounts, 300, DEFAULT_LOCK_TIER, None, false, 0).unwrap();
+  buy_pledge(&program_id, &accounts, 200, DEFAULT_LOCK_TIER, None, false, 0).unwrap();
+  assert_eq!(buy_pledge(&program_id, &accounts, 1, DEFAULT_LOCK_TIER, None, false, 0), Err(PledgeError::UserCapExceeded.into()));
+  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.total_purchased, 1_000);
+  assert_eq!(user_state.locked_pledge_tokens, 1_000);
+}
+
+#[test]
+fn test_buy_pledge_without_user_cap() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  // A cap of 0 disables the check however much the user already bought.
+  let user_state = UserState { total_purchased: u64::MAX / 2, ..UserState::default() };
+  let account_info = user_state_account(&program_id, &wallet, user_state);
+  let config = config_account(&program_id, ContractConfig { max_per_user: 0, ..ContractConfig::new() });
+
+  assert!(buy_pledge(&program_id, &[account_info, wallet_account(&wallet, true), config, treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)], 1_000, DEFAULT_LOCK_TIER, None, false, 0).is_ok());
+}
+
+#[test]
+fn test_set_paused() {
+  install_capture();
+  let program_id = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, locked_state(1000, 0));
+  let config = config_account(&program_id, ContractConfig { admin, ..ContractConfig::new() });
+  let buy_accounts = [account_info.clone(), wallet_account(&wallet, true), config.clone(), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)];
+
+  let intruder = Pubkey::new_unique();
+  assert_eq!(
+    set_paused(&program_id, &[config.clone(), wallet_account(&intruder, true)], true),
+    Err(PledgeError::Unauthorized.into())
+  );
+  assert_eq!(
+    set_paused(&program_id, &[config.clone(), wallet_account(&admin, false)], true),
+    Err(ProgramError::MissingRequiredSignature)
+  );
+
+  set_paused(&program_id, &[config.clone(), wallet_account(&admin, true)], true).unwrap();
+  assert!(ContractConfig::unpack(&config.data.borrow()).unwrap().paused);
+  assert_eq!(buy_pledge(&program_id, &buy_accounts, 100, DEFAULT_LOCK_TIER, None, false, 0), Err(PledgeError::ContractPaused.into()));
+  assert_eq!(update_reward(&program_id, &account_info, &config, VESTING_PERIOD), Err(PledgeError::ContractPaused.into()));
+  let mut claim = claim_accounts(&program_id, &account_info, &wallet, true);
+  claim[2] = config.clone();
+  assert_eq!(claim_rewards(&program_id, &claim, 0, VESTING_PERIOD), Err(PledgeError::ContractPaused.into()));
+  // Reading rewards needs nothing from the config.
+  assert!(view_rewards(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), false, VESTING_PERIOD).is_ok());
+
+  set_paused(&program_id, &[config.clone(), wallet_account(&admin, true)], false).unwrap();
+  assert!(buy_pledge(&program_id, &buy_accounts, 100, DEFAULT_LOCK_TIER, None, false, 0).is_ok());
+}
+
+#[test]
+fn test_update_phase_config() {
+  let program_id = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let sale_start_time = 1_000;
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let config = config_account(&program_id, ContractConfig { admin, sale_start_time, ..ContractConfig::new() });
+  let admin_accounts = [config.clone(), wallet_account(&admin, true)];
+  let durations = [100, 100, 100, 100, u64::MAX];
+  let rates = [300, 250, 200, 150, 100];
+
+  assert_eq!(
+    update_phase_config(&program_id, &[config.clone(), wallet_account(&wallet, true)], durations, rates, 0),
+    Err(PledgeError::Unauthorized.into())
+  );
+  update_phase_config(&program_id, &admin_accounts, durations, rates, sale_start_time - 1).unwrap();
+  assert_eq!(
+    update_phase_config(&program_id, &admin_accounts, PHASE_DURATIONS, PHASE_RATES, sale_start_time),
+    Err(PledgeError::SaleStarted.into())
+  );
+
+  // Purchases are priced from the stored schedule.
+  let accounts = [account_info.clone(), wallet_account(&wallet, true), config.clone(), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)];
+  buy_pledge(&program_id, &accounts, 100, DEFAULT_LOCK_TIER, None, false, sale_start_time).unwrap();
+  buy_pledge(&program_id, &accounts, 100, DEFAULT_LOCK_TIER, None, false, sale_start_time + 100).unwrap();
+  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.locked_pledge_tokens, 300 + 250);
+}
+
+#[test]
+fn test_update_phase_config_rejects_invalid_schedule() {
+  let program_id = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let config = config_account(&program_id, ContractConfig { admin, sale_start_time: 1_000, ..ContractConfig::new() });
+  let accounts = [config.clone(), wallet_account(&admin, true)];
+
+  assert_eq!(
+    update_phase_config(&program_id, &accounts, PHASE_DURATIONS, [200, 0, 150, 125, 100], 0),
+    Err(PledgeError::InvalidPhaseConfig.into())
+  );
+  assert_eq!(
+    update_phase_config(&program_id, &accounts, [100, 0, 100, 100, u64::MAX], PHASE_RATES, 0),
+    Err(PledgeError::InvalidPhaseConfig.into())
+  );
+  let pledge_contract = ContractConfig::unpack(&config.data.borrow()).unwrap();
+  assert_eq!(pledge_contract.phase_rates, PHASE_RATES);
+  assert_eq!(pledge_contract.phase_durations, PHASE_DURATIONS);
+}
+
+#[test]
+fn test_config_validate() {
+  assert_eq!(ContractConfig::new().validate(), Ok(()));
+  let valid = ContractConfig::new;
+  // An unscheduled sale's team lock has no end yet.
+  let unscheduled = TeamLock { unlock_start: SALE_UNSCHEDULED, ..valid().team_lock };
+  assert_eq!(ContractConfig { team_lock: unscheduled, ..valid() }.validate(), Ok(()));
+
+  let invalid = [
+    (ContractConfig { phase_rates: [200, 0, 150, 125, 100], ..valid() }, PledgeError::InvalidPhaseConfig),
+    (ContractConfig { phase_durations: [100, 0, 100, 100, u64::MAX], ..valid() }, PledgeError::InvalidPhaseConfig),
+    (ContractConfig { phase_durations: [u64::MAX, 1, 100, 100, u64::MAX], ..valid() }, PledgeError::InvalidPhaseConfig),
+    (ContractConfig { lock_tiers: [(0, 5_000), (31_536_000, 10_000), (63_072_000, 20_000)], ..valid() }, PledgeError::InvalidLockTier),
+    (ContractConfig { lock_tiers: [(15_768_000, 5_000), (31_536_000, 0), (63_072_000, 20_000)], ..valid() }, PledgeError::InvalidLockTier),
+    (ContractConfig { reward_rate_bps: 0, ..valid() }, PledgeError::InvalidConfig),
+    (ContractConfig { compound_rate: 0, ..valid() }, PledgeError::InvalidConfig),
+    (ContractConfig { early_exit_penalty_percent: 101, ..valid() }, PledgeError::InvalidConfig),
+    (ContractConfig { referral_bonus_percent: 101, ..valid() }, PledgeError::InvalidConfig),
+    (ContractConfig { locked_solhit_tokens: TOTAL_SOLHIT_SUPPLY + 1, ..valid() }, PledgeError::InvalidConfig),
+    (ContractConfig { total_pledge_sold: TOTAL_PLEDGE_SUPPLY + 1, ..valid() }, PledgeError::InvalidConfig),
+    (
+      ContractConfig { global_solhit_claimed: TOTAL_SOLHIT_SUPPLY - LOCKED_SOLHIT_TOKENS + 1, ..valid() },
+      PledgeError::InvalidConfig,
+    ),
+    (
+      ContractConfig { team_lock: TeamLock { locked_amount: LOCKED_SOLHIT_TOKENS + 1, ..valid().team_lock }, ..valid() },
+      PledgeError::InvalidConfig,
+    ),
+    (ContractConfig { team_lock: TeamLock { unlock_duration: 0, unlock_cliff: 0, ..valid().team_lock }, ..valid() }, PledgeError::InvalidConfig),
+    (
+      ContractConfig { team_lock: TeamLock { unlock_cliff: TEAM_UNLOCK_DURATION + 1, ..valid().team_lock }, ..valid() },
+      PledgeError::InvalidConfig,
+    ),
+    (
+      ContractConfig { team_lock: TeamLock { unlock_start: u64::MAX - 1, ..valid().team_lock }, ..valid() },
+      PledgeError::InvalidConfig,
+    ),
+    (ContractConfig { team_released: LOCKED_SOLHIT_TOKENS + 1, ..valid() }, PledgeError::InvalidConfig),
+  ];
+  for (config, error) in invalid {
+    assert_eq!(config.validate(), Err(error));
+  }
+
+  // The limits themselves are allowed.
+  let config = ContractConfig {
+    early_exit_penalty_percent: 100,
+    referral_bonus_percent: 100,
+    locked_solhit_tokens: TOTAL_SOLHIT_SUPPLY,
+    total_pledge_sold: TOTAL_PLEDGE_SUPPLY,
+    team_lock: TeamLock { unlock_cliff: TEAM_UNLOCK_DURATION, ..valid().team_lock },
+    team_released: LOCKED_SOLHIT_TOKENS,
+    ..valid()
+  };
+  assert_eq!(config.validate(), Ok(()));
+}
+
+#[test]
+fn test_admin_updates_keep_config_valid() {
+  let program_id = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  // A config that went bad some other way cannot be saved by an admin update.
+  let config = config_account(&program_id, ContractConfig { admin, compound_rate: 0, ..ContractConfig::new() });
+  let before = config.data.borrow().to_vec();
+  let accounts = [config.clone(), wallet_account(&admin, true)];
+
+  assert_eq!(set_paused(&program_id, &accounts, true), Err(PledgeError::InvalidConfig.into()));
+  assert_eq!(set_purchase_cooldown(&program_id, &accounts, 60), Err(PledgeError::InvalidConfig.into()));
+  assert_eq!(set_noop_events(&program_id, &accounts, false), Err(PledgeError::InvalidConfig.into()));
+  assert_eq!(set_reward_expiry(&program_id, &accounts, 86_400, true), Err(PledgeError::InvalidConfig.into()));
+  assert_eq!(set_crank_bounty(&program_id, &accounts, 10, 1_000), Err(PledgeError::InvalidConfig.into()));
+  assert_eq!(set_group_cap_enforced(&program_id, &accounts, true), Err(PledgeError::InvalidConfig.into()));
+  assert_eq!(*config.data.borrow(), &before[..]);
+}
+
+// Accounts for ReleaseTeamTokens with a full Solheist vault, releasing to a
+// fresh team token account.
+fn release_accounts<'a>(program_id: &Pubkey, config: &AccountInfo<'a>, admin: &'a Pubkey) -> Vec<AccountInfo<'a>> {
+    let (vault, _) = find_solhit_vault_address(program_id);
+    let mint = Pubkey::new_unique();
+    vec![
+        config.clone(),
+        wallet_account(admin, true),
+        token_account_at(vault, &mint, &vault, TOTAL_SOLHIT_SUPPLY),
+        token_account_at(Pubkey::new_unique(), &mint, &Pubkey::new_unique(), 0),
+        mint_account(mint),
+        token_program_account(),
+    ]
+}
+
+#[test]
+fn test_release_team_tokens() {
+  install_capture();
+  let program_id = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let start = 1_000;
+  let config = config_account(&program_id, ContractConfig {
+    admin,
+    team_lock: TeamLock { unlock_start: start, ..ContractConfig::new().team_lock },
+    ..ContractConfig::new()
+  });
+  let accounts = release_accounts(&program_id, &config, &admin);
+  let team_released = || ContractConfig::unpack(&config.data.borrow()).unwrap().team_released;
+  take_events();
+
+  // Nothing before the cliff, and only the admin may release.
+  let cliff_end = start + TEAM_UNLOCK_CLIFF;
+  assert_eq!(release_team_tokens(&program_id, &accounts, cliff_end - 1), Err(PledgeError::NothingToRelease.into()));
+  let mut not_admin = accounts.clone();
+  not_admin[1] = wallet_account(Box::leak(Box::new(Pubkey::new_unique())), true);
+  assert_eq!(release_team_tokens(&program_id, &not_admin, cliff_end), Err(PledgeError::Unauthorized.into()));
+  assert_eq!(team_released(), 0);
+
+  // At the cliff half of the two year lock has vested and comes out at once.
+  release_team_tokens(&program_id, &accounts, cliff_end).unwrap();
+  assert_eq!(team_released(), LOCKED_SOLHIT_TOKENS / 2);
+  assert_eq!(
+    take_events(),
+    vec![PledgeEvent::TeamRelease {
+      event_seq: 1,
+      destination: *accounts[3].key,
+      amount: LOCKED_SOLHIT_TOKENS / 2,
+      total_released: LOCKED_SOLHIT_TOKENS / 2,
+    }]
+  );
+  assert_eq!(release_team_tokens(&program_id, &accounts, cliff_end), Err(PledgeError::NothingToRelease.into()));
+
+  // Then linearly, a second at a time.
+  release_team_tokens(&program_id, &accounts, start + TEAM_UNLOCK_DURATION / 4 * 3).unwrap();
+  assert_eq!(team_released(), LOCKED_SOLHIT_TOKENS / 4 * 3);
+  release_team_tokens(&program_id, &accounts, start + TEAM_UNLOCK_DURATION / 4 * 3 + 1_000).unwrap();
+  assert_eq!(team_released(), LOCKED_SOLHIT_TOKENS / 4 * 3 + LOCKED_SOLHIT_TOKENS * 1_000 / TEAM_UNLOCK_DURATION);
+  take_events();
+
+  // Never more than the lock, however late.
+  release_team_tokens(&program_id, &accounts, u64::MAX).unwrap();
+  assert_eq!(team_released(), LOCKED_SOLHIT_TOKENS);
+  let events = take_events();
+  assert!(matches!(events[..], [PledgeEvent::TeamRelease { total_released: LOCKED_SOLHIT_TOKENS, .. }]));
+  assert_eq!(release_team_tokens(&program_id, &accounts, u64::MAX), Err(PledgeError::NothingToRelease.into()));
+  assert_eq!(team_released(), LOCKED_SOLHIT_TOKENS);
+}
+
+#[test]
+fn test_fund_rewards() {
+  install_capture();
+  let program_id = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let config = config_account(&program_id, ContractConfig { admin, ..ContractConfig::new() });
+  let (vault, _) = find_solhit_vault_address(&program_id);
+  let mint = Pubkey::new_unique();
+  let accounts = [
+    config.clone(),
+    wallet_account(&admin, true),
+    token_account_at(Pubkey::new_unique(), &mint, &admin, 1_000),
+    token_account_at(vault, &mint, &vault, 0),
+    mint_account(mint),
+    token_program_account(),
+  ];
+  let rewards_funded = || ContractConfig::unpack(&config.data.borrow()).unwrap().rewards_funded;
+  take_events();
+
+  let mut not_admin = accounts.clone();
+  not_admin[1] = wallet_account(Box::leak(Box::new(Pubkey::new_unique())), true);
+  assert_eq!(fund_rewards(&program_id, &not_admin, 600), Err(PledgeError::Unauthorized.into()));
+  assert_eq!(fund_rewards(&program_id, &accounts, 0), Err(PledgeError::InvalidAmount.into()));
+  let mut wrong_vault = accounts.clone();
+  wrong_vault[3] = token_account_at(Pubkey::new_unique(), &mint, &vault, 0);
+  assert_eq!(fund_rewards(&program_id, &wrong_vault, 600), Err(PledgeError::InvalidVault.into()));
+  assert_eq!(rewards_funded(), 0);
+
+  fund_rewards(&program_id, &accounts, 600).unwrap();
+  fund_rewards(&program_id, &accounts, 400).unwrap();
+  assert_eq!(rewards_funded(), 1_000);
+  assert_eq!(
+    take_events(),
+    vec![
+      PledgeEvent::RewardsFunded { event_seq: 1, source: *accounts[2].key, amount: 600, total_funded: 600 },
+      PledgeEvent::RewardsFunded { event_seq: 2, source: *accounts[2].key, amount: 400, total_funded: 1_000 },
+    ]
+  );
+}
+
+#[test]
+fn test_emergency_sweep() {
+  install_capture();
+  let program_id = Pubkey::new_unique();
+  let (guardian, recovery) = (Pubkey::new_unique(), Pubkey::new_unique());
+  let config = config_account(
+    &program_id,
+    ContractConfig { paused: true, guardian, recovery_destination: recovery, ..ContractConfig::new() },
+  );
+  let (solhit_vault, _) = find_solhit_vault_address(&program_id);
+  let (vault_authority, _) = find_pledge_vault_address(&program_id);
+  let (solhit_mint, pledge_mint) = (Pubkey::new_unique(), Pubkey::new_unique());
+  let solhit_accounts = [
+    config.clone(),
+    wallet_account(Box::leak(Box::new(guardian)), true),
+    token_account_at(solhit_vault, &solhit_mint, &solhit_vault, 5_000),
+    token_account_at(Pubkey::new_unique(), &solhit_mint, &recovery, 0),
+    token_program_account(),
+  ];
+  let pledge_accounts = [
+    config.clone(),
+    solhit_accounts[1].clone(),
+    token_account_at(Pubkey::new_unique(), &pledge_mint, &vault_authority, 7_000),
+    token_account_at(Pubkey::new_unique(), &pledge_mint, &recovery, 0),
+    token_program_account(),
+    wallet_account(Box::leak(Box::new(vault_authority)), false),
+  ];
+  let transfers = || {
+    take_cpis()
+      .iter()
+      .map(|instruction| match spl_token::instruction::TokenInstruction::unpack(&instruction.data).unwrap() {
+        spl_token::instruction::TokenInstruction::Transfer { amount } => {
+          (instruction.accounts[0].pubkey, instruction.accounts[1].pubkey, amount)
+        }
+        other => panic!("unexpected CPI {:?}", other),
+      })
+      .collect::<Vec<_>>()
+  };
+  take_events();
+  take_cpis();
+
+  // Only the guardian, and only into the recovery destination's account of
+  // the vault's mint.
+  let mut not_guardian = solhit_accounts.clone();
+  not_guardian[1] = wallet_account(Box::leak(Box::new(Pubkey::new_unique())), true);
+  assert_eq!(emergency_sweep(&program_id, &not_guardian), Err(PledgeError::Unauthorized.into()));
+  let mut not_signed = solhit_accounts.clone();
+  not_signed[1] = wallet_account(Box::leak(Box::new(guardian)), false);
+  assert_eq!(emergency_sweep(&program_id, &not_signed), Err(ProgramError::MissingRequiredSignature));
+  let mut wrong_owner = solhit_accounts.clone();
+  wrong_owner[3] = token_account_at(Pubkey::new_unique(), &solhit_mint, &guardian, 0);
+  assert_eq!(emergency_sweep(&program_id, &wrong_owner), Err(PledgeError::InvalidRecoveryDestination.into()));
+  let mut wrong_mint = solhit_accounts.clone();
+  wrong_mint[3] = pledge_accounts[3].clone();
+  assert_eq!(emergency_sweep(&program_id, &wrong_mint), Err(PledgeError::InvalidRecoveryDestination.into()));
+  let mut wrong_vault = pledge_accounts.clone();
+  wrong_vault[2] = token_account_at(Pubkey::new_unique(), &pledge_mint, &recovery, 7_000);
+  assert_eq!(emergency_sweep(&program_id, &wrong_vault), Err(ProgramError::InvalidAccountData));
+  assert_eq!(emergency_sweep(&program_id, &pledge_accounts[..5]), Err(ProgramError::NotEnoughAccountKeys));
+  assert!(transfers().is_empty());
+
+  emergency_sweep(&program_id, &solhit_accounts).unwrap();
+  emergency_sweep(&program_id, &pledge_accounts).unwrap();
+  assert_eq!(
+    transfers(),
+    vec![
+      (solhit_vault, *solhit_accounts[3].key, 5_000),
+      (*pledge_accounts[2].key, *pledge_accounts[3].key, 7_000),
+    ]
+  );
+  assert_eq!(
+    take_events(),
+    vec![
+      PledgeEvent::Sweep { event_seq: 1, vault: solhit_vault, destination: *solhit_accounts[3].key, amount: 5_000 },
+      PledgeEvent::Sweep {
+        event_seq: 2,
+        vault: *pledge_accounts[2].key,
+        destination: *pledge_accounts[3].key,
+        amount: 7_000,
+      },
+    ]
+  );
+}
+
+#[test]
+fn test_emergency_sweep_needs_pause_and_guardian() {
+  install_capture();
+  let program_id = Pubkey::new_unique();
+  let (guardian, recovery) = (Pubkey::new_unique(), Pubkey::new_unique());
+  let (solhit_vault, _) = find_solhit_vault_address(&program_id);
+  let mint = Pubkey::new_unique();
+  let accounts = |pledge_contract: ContractConfig| {
+    [
+      config_account(&program_id, pledge_contract),
+      wallet_account(Box::leak(Box::new(guardian)), true),
+      token_account_at(solhit_vault, &mint, &solhit_vault, 5_000),
+      token_account_at(Pubkey::new_unique(), &mint, &recovery, 0),
+      token_program_account(),
+    ]
+  };
+  take_cpis();
+
+  let unpaused = ContractConfig { guardian, recovery_destination: recovery, ..ContractConfig::new() };
+  assert_eq!(emergency_sweep(&program_id, &accounts(unpaused)), Err(PledgeError::ContractNotPaused.into()));
+  // A contract without a guardian cannot be swept at all, however it is signed.
+  let default_guardian = ContractConfig { paused: true, ..ContractConfig::new() };
+  let mut accounts_without_guardian = accounts(default_guardian);
+  accounts_without_guardian[1] = wallet_account(Box::leak(Box::new(Pubkey::default())), true);
+  assert_eq!(emergency_sweep(&program_id, &accounts_without_guardian), Err(PledgeError::Unauthorized.into()));
+  assert!(take_cpis().is_empty());
+}
+
+#[test]
+fn test_quote_buy_matches_buy() {
+  install_capture();
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let config = config_account(&program_id, ContractConfig { purchase_cooldown_secs: 60, ..ContractConfig::new() });
+  let buy_accounts = [account_info.clone(), wallet_account(&wallet, true), config.clone(), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)];
+  let config_before = config.data.borrow().to_vec();
+  let now = PHASE_DURATIONS[0] + 10;
+  take_events();
+
+  quote_buy(&program_id, std::slice::from_ref(&config), 1000, now).unwrap();
+  let quote = BuyQuote::try_from_slice(&take_return_data()).unwrap();
+  assert_eq!(quote, BuyQuote { phase: 1, rate: PHASE_RATES[1], requested_tokens: 1750, pledge_tokens: 1750, paid: 1000 });
+  // Quoting writes nothing and emits nothing.
+  assert_eq!(*config.data.borrow(), &config_before[..]);
+  assert!(take_events().is_empty());
+
+  buy_pledge(&program_id, &buy_accounts, 1000, DEFAULT_LOCK_TIER, None, false, now).unwrap();
+  let events = take_events();
+  let [PledgeEvent::Purchase { lamports_paid, pledge_tokens, rate, .. }] = events[..] else { panic!("{:?}", events) };
+  assert_eq!((pledge_tokens, rate, lamports_paid), (quote.pledge_tokens, quote.rate, quote.paid));
+
+  // With the user state the quote sees the buyer's cooldown.
+  let quote_accounts = [config.clone(), account_info.clone()];
+  assert_eq!(quote_buy(&program_id, &quote_accounts, 1000, now + 59), Err(PledgeError::CooldownActive.into()));
+  quote_buy(&program_id, std::slice::from_ref(&config), 1000, now + 59).unwrap();
+  take_return_data();
+}
+
+#[test]
+fn test_quote_and_buy_convert_decimals() {
+  install_capture();
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let config = config_account(&program_id, ContractConfig { pledge_decimals: 6, ..ContractConfig::new() });
+  let buy_accounts = [account_info.clone(), wallet_account(&wallet, true), config.clone(), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)];
+  take_events();
+
+  // Half a SOL at the 200% first phase rate is one whole 6 decimal token.
+  quote_buy(&program_id, std::slice::from_ref(&config), 500_000_000, 0).unwrap();
+  let quote = BuyQuote::try_from_slice(&take_return_data()).unwrap();
+  assert_eq!(quote, BuyQuote { phase: 0, rate: PHASE_RATES[0], requested_tokens: 1_000_000, pledge_tokens: 1_000_000, paid: 500_000_000 });
+  buy_pledge(&program_id, &buy_accounts, 500_000_000, DEFAULT_LOCK_TIER, None, false, 0).unwrap();
+  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.locked_pledge_tokens, quote.pledge_tokens);
+
+  // Dust is refused by both, and the failed purchase takes nothing.
+  let too_small = Err(PledgeError::PurchaseTooSmall.into());
+  assert_eq!(quote_buy(&program_id, std::slice::from_ref(&config), 499, 0), too_small);
+  assert_eq!(buy_pledge(&program_id, &buy_accounts, 499, DEFAULT_LOCK_TIER, None, false, 0), too_small);
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().total_pledge_sold, 1_000_000);
+  take_events();
+}
+
+#[test]
+fn test_set_token_decimals() {
+  let program_id = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let config = config_account(&program_id, ContractConfig { admin, ..ContractConfig::new() });
+  let accounts = [config.clone(), wallet_account(&admin, true)];
+
+  let stranger = Pubkey::new_unique();
+  let result = set_token_decimals(&program_id, &[config.clone(), wallet_account(&stranger, true)], 9, 6);
+  assert_eq!(result, Err(PledgeError::Unauthorized.into()));
+  assert_eq!(set_token_decimals(&program_id, &accounts, 9, 20), Err(PledgeError::InvalidConfig.into()));
+  set_token_decimals(&program_id, &accounts, 9, 6).unwrap();
+  let pledge_contract = ContractConfig::unpack(&config.data.borrow()).unwrap();
+  assert_eq!((pledge_contract.payment_decimals, pledge_contract.pledge_decimals), (9, 6));
+
+  // Once anything has sold the price of what is left must not change.
+  let sold = config_account(&program_id, ContractConfig { admin, total_pledge_sold: 1, ..ContractConfig::new() });
+  assert_eq!(set_token_decimals(&program_id, &[sold, wallet_account(&admin, true)], 9, 6), Err(PledgeError::SaleStarted.into()));
+}
+
+#[test]
+fn test_get_global_stats() {
+  install_capture();
+  let program_id = Pubkey::new_unique();
+  let sale_start_time = 1_000;
+  let pledge_contract = ContractConfig {
+    sale_start_time,
+    total_pledge_sold: 5_000,
+    global_solhit_claimed: 300,
+    initialized_users: 7,
+    paused: true,
+    ..ContractConfig::new()
+  };
+  let stats_at = |config: &AccountInfo, now: u64| {
+    get_global_stats(&program_id, std::slice::from_ref(config), now).unwrap();
+    GlobalStats::try_from_slice(&take_return_data()).unwrap()
+  };
+
+  let config = config_account(&program_id, pledge_contract);
+  assert_eq!(
+    stats_at(&config, sale_start_time),
+    GlobalStats {
+      total_sold: 5_000,
+      total_claimed: 300,
+      active_phase: 0,
+      phase_time_remaining: PHASE_DURATIONS[0],
+      initialized_users: 7,
+      paused: true,
+    }
+  );
+  // Before the sale starts no phase is running, and the countdown is to the
+  // start.
+  let stats = stats_at(&config, 400);
+  assert_eq!((stats.active_phase, stats.phase_time_remaining), (PHASE_NOT_STARTED, 600));
+  let stats = stats_at(&config, 0);
+  assert_eq!((stats.active_phase, stats.phase_time_remaining), (PHASE_NOT_STARTED, sale_start_time));
+  let stats = stats_at(&config, sale_start_time + PHASE_DURATIONS[0] + 10);
+  assert_eq!((stats.active_phase, stats.phase_time_remaining), (1, PHASE_DURATIONS[1] - 10));
+  // The open-ended last phase never runs out.
+  let stats = stats_at(&config, sale_start_time + PHASE_DURATIONS[..4].iter().sum::<u64>());
+  assert_eq!((stats.active_phase, stats.phase_time_remaining), (4, u64::MAX));
+
+  // A finite last phase has nothing left once it is over.
+  let config = config_account(&program_id, ContractConfig { sale_start_time, phase_durations: [10; 5], ..ContractConfig::new() });
+  let stats = stats_at(&config, sale_start_time + 49);
+  assert_eq!((stats.active_phase, stats.phase_time_remaining), (4, 1));
+  let stats = stats_at(&config, sale_start_time + 1_000);
+  assert_eq!((stats.active_phase, stats.phase_time_remaining), (4, 0));
+
+  // An unscheduled sale has no start to count down to.
+  let config = config_account(&program_id, ContractConfig { sale_start_time: SALE_UNSCHEDULED, ..ContractConfig::new() });
+  for now in [0, 1_000, u64::MAX - 1] {
+    let stats = stats_at(&config, now);
+    assert_eq!((stats.active_phase, stats.phase_time_remaining), (PHASE_NOT_STARTED, u64::MAX));
+  }
+}
+
+#[test]
+fn test_quote_buy_partial_fill_matches_buy() {
+  install_capture();
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  // 50 pledge tokens left, at the first phase's 2 tokens per lamport.
+  let config = config_account(&program_id, ContractConfig { total_pledge_sold: TOTAL_PLEDGE_SUPPLY - 50, ..ContractConfig::new() });
+  let buy_accounts = [account_info.clone(), wallet_account(&wallet, true), config.clone(), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)];
+  take_events();
+
+  quote_buy(&program_id, &[config.clone(), account_info.clone()], 1000, 0).unwrap();
+  let quote = BuyQuote::try_from_slice(&take_return_data()).unwrap();
+  assert_eq!(quote, BuyQuote { phase: 0, rate: PHASE_RATES[0], requested_tokens: 2000, pledge_tokens: 50, paid: 25 });
+
+  buy_pledge(&program_id, &buy_accounts, 1000, DEFAULT_LOCK_TIER, None, true, 0).unwrap();
+  let events = take_events();
+  let [PledgeEvent::Purchase { lamports_paid, pledge_tokens, .. }, PledgeEvent::PartialFill { requested, .. }] = events[..] else {
+    panic!("{:?}", events)
+  };
+  assert_eq!((requested, pledge_tokens, lamports_paid), (quote.requested_tokens, quote.pledge_tokens, quote.paid));
+
+  // Once the supply is gone there is nothing to quote.
+  quote_buy(&program_id, std::slice::from_ref(&config), 1000, 0).unwrap_err();
+}
+
+#[test]
+fn test_start_sale() {
+  install_capture();
+  let program_id = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let unscheduled = ContractConfig {
+    admin,
+    sale_start_time: SALE_UNSCHEDULED,
+    team_lock: TeamLock { unlock_start: SALE_UNSCHEDULED, ..ContractConfig::new().team_lock },
+    ..ContractConfig::new()
+  };
+  let config = config_account(&program_id, unscheduled);
+  let admin_accounts = [config.clone(), wallet_account(&admin, true)];
+  let buy_accounts = [account_info.clone(), wallet_account(&wallet, true), config.clone(), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)];
+  let now = 1_000;
+
+  assert_eq!(
+    buy_pledge(&program_id, &buy_accounts, 100, DEFAULT_LOCK_TIER, None, false, now),
+    Err(PledgeError::SaleNotStarted.into())
+  );
+  assert_eq!(
+    start_sale(&program_id, &[config.clone(), wallet_account(&wallet, true)], None, now),
+    Err(PledgeError::Unauthorized.into())
+  );
+  assert_eq!(start_sale(&program_id, &admin_accounts, Some(now - 1), now), Err(PledgeError::InvalidStartTime.into()));
+  assert_eq!(
+    start_sale(&program_id, &admin_accounts, Some(SALE_UNSCHEDULED), now),
+    Err(PledgeError::InvalidStartTime.into())
+  );
+
+  // Scheduled ahead, the sale and the team lock begin together.
+  start_sale(&program_id, &admin_accounts, Some(now + 100), now).unwrap();
+  let pledge_contract = ContractConfig::unpack(&config.data.borrow()).unwrap();
+  assert_eq!(pledge_contract.sale_start_time, now + 100);
+  assert_eq!(pledge_contract.team_lock.unlock_start, now + 100);
+  assert_eq!(take_events(), vec![PledgeEvent::SaleStarted { event_seq: pledge_contract.event_seq, start_time: now + 100 }]);
+  assert_eq!(
+    buy_pledge(&program_id, &buy_accounts, 100, DEFAULT_LOCK_TIER, None, false, now + 99),
+    Err(PledgeError::SaleNotStarted.into())
+  );
+  buy_pledge(&program_id, &buy_accounts, 100, DEFAULT_LOCK_TIER, None, false, now + 100).unwrap();
+
+  // Only once, whether or not the start has come.
+  assert_eq!(start_sale(&program_id, &admin_accounts, None, now), Err(PledgeError::SaleStarted.into()));
+
+  // Without a start time the sale opens right away.
+  let config = config_account(&program_id, ContractConfig { admin, sale_start_time: SALE_UNSCHEDULED, ..ContractConfig::new() });
+  start_sale(&program_id, &[config.clone(), wallet_account(&admin, true)], None, now).unwrap();
+  let pledge_contract = ContractConfig::unpack(&config.data.borrow()).unwrap();
+  assert_eq!(pledge_contract.sale_start_time, now);
+  // A team lock already given its own start keeps it.
+  assert_eq!(pledge_contract.team_lock.unlock_start, ContractConfig::new().team_lock.unlock_start);
+}
+
+// A receipt account at the address for `index`, holding `receipt`.
+fn receipt_account<'a>(program_id: &Pubkey, wallet: &Pubkey, index: u32, is_writable: bool, owner: &Pubkey) -> AccountInfo<'a> {
+  let (key, bump) = find_receipt_address(program_id, wallet, index);
+  let receipt = Receipt { is_initialized: true, wallet: *wallet, index, bump, ..Receipt::default() };
+  AccountInfo::new(
+    Box::leak(Box::new(key)),
+    false,
+    is_writable,
+    Box::leak(Box::new(1_000_000)),
+    borsh::to_vec(&receipt).unwrap().leak(),
+    Box::leak(Box::new(*owner)),
+    false,
+    0,
+  )
+}
+
+#[test]
+fn test_buy_pledge_receipt_is_write_once() {
+  let program_id = Pubkey::new_unique();
+  let wallet: &'static Pubkey = Box::leak(Box::new(Pubkey::new_unique()));
+  let account_info = user_state_account(&program_id, wallet, UserState { purchase_count: 1, ..UserState::default() });
+  let buy = |receipt: AccountInfo<'static>| {
+    let accounts = [account_info.clone(), wallet_account(wallet, true), config_account(&program_id, ContractConfig::new()), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, wallet), receipt];
+    buy_pledge(&program_id, &accounts, 100, DEFAULT_LOCK_TIER, None, false, 0)
+  };
+
+  // The receipt for this purchase already exists, or cannot be written.
+  assert_eq!(buy(receipt_account(&program_id, wallet, 1, true, &program_id)), Err(PledgeError::AlreadyInitialized.into()));
+  assert_eq!(
+    buy(receipt_account(&program_id, wallet, 1, false, &system_program::ID)),
+    Err(PledgeError::AccountNotWritable.into())
+  );
+  // The last purchase's receipt is not this one's, so it is left alone.
+  let last = receipt_account(&program_id, wallet, 0, true, &program_id);
+  let data = last.data.borrow().to_vec();
+  assert!(buy(last.clone()).is_ok());
+  assert_eq!(*last.data.borrow(), data);
+}
+
+#[test]
+fn test_receipt_unpack() {
+  let receipt = Receipt {
+    is_initialized: true,
+    wallet: Pubkey::new_unique(),
+    index: 3,
+    bump: 254,
+    lamports_paid: 1_000,
+    pledge_tokens: 2_000,
+    rate: 200,
+    phase: 0,
+    timestamp: 1_700_000_000,
+  };
+  let data = borsh::to_vec(&receipt).unwrap();
+  assert_eq!(data.len(), Receipt::LEN);
+  assert_eq!(Receipt::unpack(&data), Ok(receipt));
+  assert_eq!(Receipt::unpack(&[0; Receipt::LEN]), Err(ProgramError::UninitializedAccount));
+  assert!(Receipt::unpack(&data[..Receipt::LEN - 1]).is_err());
+}
+
+#[test]
+fn test_finalize_sale() {
+  install_capture();
+  let program_id = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let config = config_account(&program_id, ContractConfig { admin, ..ContractConfig::new() });
+  let admin_accounts = [config.clone(), wallet_account(&admin, true)];
+  let buy_accounts = [account_info.clone(), wallet_account(&wallet, true), config.clone(), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)];
+  let sale_end_time = PHASE_DURATIONS[..4].iter().sum::<u64>();
+
+  buy_pledge(&program_id, &buy_accounts, 1000, DEFAULT_LOCK_TIER, None, false, 0).unwrap();
+  let sold = ContractConfig::unpack(&config.data.borrow()).unwrap().total_pledge_sold;
+  take_events();
+
+  // Not before the last finite phase is over, and only by the admin.
+  assert_eq!(finalize_sale(&program_id, &admin_accounts, sale_end_time - 1), Err(PledgeError::SaleInProgress.into()));
+  assert_eq!(
+    finalize_sale(&program_id, &[config.clone(), wallet_account(&wallet, true)], sale_end_time),
+    Err(PledgeError::Unauthorized.into())
+  );
+  buy_pledge(&program_id, &buy_accounts, 1000, DEFAULT_LOCK_TIER, None, false, sale_end_time).unwrap();
+  take_events();
+
+  finalize_sale(&program_id, &admin_accounts, sale_end_time).unwrap();
+  let pledge_contract = ContractConfig::unpack(&config.data.borrow()).unwrap();
+  assert!(pledge_contract.sale_finalized);
+  assert_eq!(pledge_contract.unsold_supply, TOTAL_PLEDGE_SUPPLY - pledge_contract.total_pledge_sold);
+  assert_eq!(
+    take_events(),
+    vec![PledgeEvent::SaleFinalized {
+      event_seq: pledge_contract.event_seq,
+      total_pledge_sold: pledge_contract.total_pledge_sold,
+      unsold_supply: pledge_contract.unsold_supply,
+    }]
+  );
+  assert!(pledge_contract.total_pledge_sold > sold);
+
+  // Purchasing is closed for good.
+  assert_eq!(
+    buy_pledge(&program_id, &buy_accounts, 1000, DEFAULT_LOCK_TIER, None, false, sale_end_time + 1),
+    Err(PledgeError::SaleEnded.into())
+  );
+  assert_eq!(finalize_sale(&program_id, &admin_accounts, sale_end_time + 1), Err(PledgeError::SaleEnded.into()));
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().total_pledge_sold, pledge_contract.total_pledge_sold);
+}
+
+// An empty snapshots PDA, already created.
+fn snapshots_account<'a>(program_id: &Pubkey) -> AccountInfo<'a> {
+  AccountInfo::new(
+    Box::leak(Box::new(find_snapshots_address(program_id).0)),
+    false,
+    true,
+    Box::leak(Box::new(1_000_000)),
+    vec![0; SnapshotBuffer::LEN].leak(),
+    Box::leak(Box::new(*program_id)),
     false,
     0,
+  )
+}
+
+#[test]
+fn test_record_snapshot() {
+  let program_id = Pubkey::new_unique();
+  let payer = Pubkey::new_unique();
+  let config = config_account(&program_id, ContractConfig {
+    total_pledge_sold: 2_000,
+    global_solhit_claimed: 30,
+    ..ContractConfig::new()
+  });
+  let snapshots = snapshots_account(&program_id);
+  let accounts = [config.clone(), snapshots.clone(), wallet_account(&payer, true), system_program_account()];
+  let start = PHASE_DURATIONS[0];
+
+  record_snapshot(&program_id, &accounts, start, &Rent::default()).unwrap();
+  // Once per interval.
+  assert_eq!(
+    record_snapshot(&program_id, &accounts, start + SNAPSHOT_INTERVAL - 1, &Rent::default()),
+    Err(PledgeError::SnapshotTooSoon.into())
+  );
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().last_snapshot_time, start);
+
+  get_latest_snapshot(&program_id, std::slice::from_ref(&snapshots)).unwrap();
+  assert_eq!(
+    Snapshot::try_from_slice(&take_return_data()).unwrap(),
+    Snapshot { timestamp: start, total_sold: 2_000, total_claimed: 30, active_phase: 1 }
+  );
+
+  // The 31st snapshot overwrites the first.
+  for index in 1..=MAX_SNAPSHOTS as u64 {
+    record_snapshot(&program_id, &accounts, start + index * SNAPSHOT_INTERVAL, &Rent::default()).unwrap();
+  }
+  let buffer = SnapshotBuffer::try_from_slice(&snapshots.data.borrow()).unwrap();
+  assert_eq!(buffer.count, MAX_SNAPSHOTS as u64 + 1);
+  assert_eq!(buffer.snapshots[0].timestamp, start + MAX_SNAPSHOTS as u64 * SNAPSHOT_INTERVAL);
+  assert_eq!(buffer.snapshots[1].timestamp, start + SNAPSHOT_INTERVAL);
+  get_latest_snapshot(&program_id, &[snapshots]).unwrap();
+  let latest = Snapshot::try_from_slice(&take_return_data()).unwrap();
+  assert_eq!(latest.timestamp, start + MAX_SNAPSHOTS as u64 * SNAPSHOT_INTERVAL);
+  assert_eq!(latest.active_phase, 3);
+}
+
+#[test]
+fn test_snapshot_interval() {
+  let program_id = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let payer = Pubkey::new_unique();
+  let config = config_account(&program_id, ContractConfig { admin, ..ContractConfig::new() });
+  let snapshots = snapshots_account(&program_id);
+  let accounts = [config.clone(), snapshots.clone(), wallet_account(&payer, true), system_program_account()];
+
+  // Nothing recorded yet.
+  assert_eq!(get_latest_snapshot(&program_id, std::slice::from_ref(&snapshots)), Err(ProgramError::UninitializedAccount));
+  assert_eq!(
+    set_snapshot_interval(&program_id, &[config.clone(), wallet_account(&payer, true)], 0),
+    Err(PledgeError::Unauthorized.into())
+  );
+  set_snapshot_interval(&program_id, &[config.clone(), wallet_account(&admin, true)], 60).unwrap();
+  record_snapshot(&program_id, &accounts, 100, &Rent::default()).unwrap();
+  assert_eq!(record_snapshot(&program_id, &accounts, 159, &Rent::default()), Err(PledgeError::SnapshotTooSoon.into()));
+  record_snapshot(&program_id, &accounts, 160, &Rent::default()).unwrap();
+
+  let mut wrong = accounts.clone();
+  wrong[1] = whitelist_account(&program_id, &payer);
+  assert_eq!(record_snapshot(&program_id, &wrong, 1_000, &Rent::default()), Err(ProgramError::InvalidSeeds));
+}
+
+#[test]
+fn test_admin_transfer() {
+  let program_id = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let new_admin = Pubkey::new_unique();
+  let config = config_account(&program_id, ContractConfig { admin, ..ContractConfig::new() });
+
+  assert_eq!(
+    nominate_admin(&program_id, &[config.clone(), wallet_account(&new_admin, true)], new_admin),
+    Err(PledgeError::Unauthorized.into())
+  );
+  nominate_admin(&program_id, &[config.clone(), wallet_account(&admin, true)], new_admin).unwrap();
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().pending_admin, Some(new_admin));
+
+  let stranger = Pubkey::new_unique();
+  assert_eq!(
+    accept_admin(&program_id, &[config.clone(), wallet_account(&stranger, true)]),
+    Err(PledgeError::Unauthorized.into())
+  );
+  assert_eq!(
+    accept_admin(&program_id, &[config.clone(), wallet_account(&new_admin, false)]),
+    Err(ProgramError::MissingRequiredSignature)
+  );
+
+  accept_admin(&program_id, &[config.clone(), wallet_account(&new_admin, true)]).unwrap();
+  let pledge_contract = ContractConfig::unpack(&config.data.borrow()).unwrap();
+  assert_eq!(pledge_contract.admin, new_admin);
+  assert_eq!(pledge_contract.pending_admin, None);
+  // The old admin has lost its rights.
+  assert_eq!(
+    set_paused(&program_id, &[config.clone(), wallet_account(&admin, true)], true),
+    Err(PledgeError::Unauthorized.into())
+  );
+}
+
+#[test]
+fn test_cancel_admin_nomination() {
+  let program_id = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let nominee = Pubkey::new_unique();
+  let config = config_account(&program_id, ContractConfig { admin, ..ContractConfig::new() });
+  let admin_accounts = [config.clone(), wallet_account(&admin, true)];
+
+  nominate_admin(&program_id, &admin_accounts, nominee).unwrap();
+  nominate_admin(&program_id, &admin_accounts, Pubkey::default()).unwrap();
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().pending_admin, None);
+  assert_eq!(
+    accept_admin(&program_id, &[config.clone(), wallet_account(&nominee, true)]),
+    Err(PledgeError::Unauthorized.into())
+  );
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().admin, admin);
+}
+
+#[test]
+fn test_withdraw_treasury() {
+  let program_id = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let rent = Rent::default();
+  let minimum = rent.minimum_balance(0);
+  let config = config_account(&program_id, ContractConfig { admin, ..ContractConfig::new() });
+  let treasury = treasury_account(&program_id, minimum + 1_000);
+  let destination = wallet_account(&admin, true);
+  let accounts = [config.clone(), destination.clone(), treasury.clone(), destination.clone()];
+
+  withdraw_treasury(&program_id, &accounts, 400, &rent).unwrap();
+  assert_eq!(treasury.lamports(), minimum + 600);
+  assert_eq!(destination.lamports(), 400);
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().total_withdrawn, 400);
+
+  // Only the lamports above the rent exempt minimum can leave.
+  assert_eq!(withdraw_treasury(&program_id, &accounts, 601, &rent), Err(PledgeError::InsufficientTreasury.into()));
+  withdraw_treasury(&program_id, &accounts, 600, &rent).unwrap();
+  assert_eq!(treasury.lamports(), minimum);
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().total_withdrawn, 1_000);
+}
+
+#[test]
+fn test_withdraw_treasury_requires_admin() {
+  let program_id = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let intruder = Pubkey::new_unique();
+  let rent = Rent::default();
+  let config = config_account(&program_id, ContractConfig { admin, ..ContractConfig::new() });
+  let treasury = treasury_account(&program_id, rent.minimum_balance(0) + 1_000);
+  let destination = wallet_account(&intruder, true);
+
+  assert_eq!(
+    withdraw_treasury(&program_id, &[config, destination.clone(), treasury.clone(), destination.clone()], 100, &rent),
+    Err(PledgeError::Unauthorized.into())
+  );
+  assert_eq!(treasury.lamports(), rent.minimum_balance(0) + 1_000);
+  assert_eq!(destination.lamports(), 0);
+}
+
+#[test]
+fn test_withdraw_treasury_rejects_treasury_as_destination() {
+  let program_id = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let rent = Rent::default();
+  let config = config_account(&program_id, ContractConfig { admin, ..ContractConfig::new() });
+  let treasury = treasury_account(&program_id, rent.minimum_balance(0) + 1_000);
+
+  assert_eq!(
+    withdraw_treasury(&program_id, &[config, wallet_account(&admin, true), treasury.clone(), treasury.clone()], 100, &rent),
+    Err(PledgeError::DuplicateAccount.into())
+  );
+  assert_eq!(treasury.lamports(), rent.minimum_balance(0) + 1_000);
+}
+
+#[test]
+fn test_buy_pledge_with_token() {
+  install_capture();
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let payment_mint = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let config = config_account(&program_id, ContractConfig { payment_mint, ..ContractConfig::new() });
+
+  // 5_000 base units at 6 decimals scale to 5_000_000 at 9, bought at the
+  // 200% first phase rate.
+  let accounts = token_buy_accounts(&program_id, &account_info, &wallet, &config, payment_mint, 5_000);
+  take_events();
+  buy_pledge_with_token(&program_id, &accounts, 5_000, 0).unwrap();
+
+  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.locked_pledge_tokens, 10_000_000);
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().total_pledge_sold, 10_000_000);
+  assert_eq!(
+    take_events(),
+    vec![PledgeEvent::TokenPurchase {
+      event_seq: 1,
+      user: *account_info.key,
+      wallet,
+      paid: 5_000,
+      pledge_tokens: 10_000_000,
+      rate: 200,
+      total_pledge_tokens: 10_000_000,
+    }]
+  );
+}
+
+#[test]
+fn test_buy_pledge_with_token_rejects_bad_payments() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let payment_mint = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let config = config_account(&program_id, ContractConfig { payment_mint, ..ContractConfig::new() });
+
+  let wrong_mint = token_buy_accounts(&program_id, &account_info, &wallet, &config, Pubkey::new_unique(), 1_000);
+  assert_eq!(buy_pledge_with_token(&program_id, &wrong_mint, 1_000, 0), Err(PledgeError::InvalidPaymentMint.into()));
+
+  let short = token_buy_accounts(&program_id, &account_info, &wallet, &config, payment_mint, 999);
+  assert_eq!(buy_pledge_with_token(&program_id, &short, 1_000, 0), Err(ProgramError::InsufficientFunds));
+
+  // The payment has to land in a token account held by the treasury PDA.
+  let mut foreign_treasury = token_buy_accounts(&program_id, &account_info, &wallet, &config, payment_mint, 1_000);
+  foreign_treasury[4] = token_account_at(Pubkey::new_unique(), &payment_mint, &wallet, 0);
+  assert_eq!(buy_pledge_with_token(&program_id, &foreign_treasury, 1_000, 0), Err(PledgeError::InvalidVault.into()));
+
+  // Token payments are off until the admin sets a mint.
+  let disabled = config_account(&program_id, ContractConfig::new());
+  let accounts = token_buy_accounts(&program_id, &account_info, &wallet, &disabled, payment_mint, 1_000);
+  assert_eq!(buy_pledge_with_token(&program_id, &accounts, 1_000, 0), Err(PledgeError::InvalidPaymentMint.into()));
+  assert_eq!(UserState::try_from_slice(&account_info.data.borrow()).unwrap().locked_pledge_tokens, 0);
+}
+
+#[test]
+fn test_set_payment_mint() {
+  let program_id = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let payment_mint = Pubkey::new_unique();
+  let config = config_account(&program_id, ContractConfig { admin, ..ContractConfig::new() });
+
+  assert_eq!(
+    set_payment_mint(&program_id, &[config.clone(), wallet_account(&payment_mint, true)], payment_mint),
+    Err(PledgeError::Unauthorized.into())
+  );
+  set_payment_mint(&program_id, &[config.clone(), wallet_account(&admin, true)], payment_mint).unwrap();
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().payment_mint, payment_mint);
+}
+
+#[test]
+fn test_buy_pledge_purchase_cooldown() {
+  let program_id = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let config = config_account(&program_id, ContractConfig { admin, ..ContractConfig::new() });
+  assert_eq!(
+    set_purchase_cooldown(&program_id, &[config.clone(), wallet_account(&wallet, true)], 3_600),
+    Err(PledgeError::Unauthorized.into())
+  );
+  set_purchase_cooldown(&program_id, &[config.clone(), wallet_account(&admin, true)], 3_600).unwrap();
+
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let buy = |current_time| {
+    let accounts = [account_info.clone(), wallet_account(&wallet, true), config.clone(), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)];
+    buy_pledge(&program_id, &accounts, 1000, DEFAULT_LOCK_TIER, None, false, current_time)
+  };
+  buy(10).unwrap();
+  assert_eq!(buy(10), Err(PledgeError::CooldownActive.into()));
+  assert_eq!(UserState::unpack(&account_info.data.borrow()).unwrap().purchase_count, 1);
+  buy(3_610).unwrap();
+  assert_eq!(UserState::unpack(&account_info.data.borrow()).unwrap().purchase_count, 2);
+}
+
+// Accounts for a BuyPledge by `wallet` referred by `referrer_info`.
+fn referred_buy_accounts<'a>(
+  program_id: &Pubkey,
+  account_info: &AccountInfo<'a>,
+  wallet: &'a Pubkey,
+  config: &AccountInfo<'a>,
+  referrer_info: &AccountInfo<'a>,
+) -> Vec<AccountInfo<'a>> {
+  vec![
+    account_info.clone(),
+    wallet_account(wallet, true),
+    config.clone(),
+    treasury_account(program_id, 0),
+    system_program_account(),
+    referrer_info.clone(),
+    whitelist_account(program_id, wallet),
+  ]
+}
+
+#[test]
+fn test_buy_pledge_with_referrer() {
+  install_capture();
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let referrer_info = user_state_account(&program_id, &Pubkey::new_unique(), UserState::default());
+  let config = config_account(&program_id, ContractConfig::new());
+  let accounts = referred_buy_accounts(&program_id, &account_info, &wallet, &config, &referrer_info);
+  take_events();
+
+  buy_pledge(&program_id, &accounts, 1000, DEFAULT_LOCK_TIER, Some(referrer_info.key), false, 0).unwrap();
+  let bought = 1000 * PHASE_RATES[0] / 100;
+  let bonus = bought * REFERRAL_BONUS_PERCENT / 100;
+  assert_eq!(UserState::unpack(&account_info.data.borrow()).unwrap().locked_pledge_tokens, bought);
+  let referrer_state = UserState::unpack(&referrer_info.data.borrow()).unwrap();
+  assert_eq!(referrer_state.locked_pledge_tokens, bonus);
+  assert_eq!(referrer_state.vesting_end_time, DEFAULT_LOCK);
+  let pledge_contract = ContractConfig::unpack(&config.data.borrow()).unwrap();
+  assert_eq!(pledge_contract.total_pledge_sold, bought + bonus);
+  assert_eq!(pledge_contract.phase_sold[0], bought);
+  assert_eq!(take_events()[1], PledgeEvent::ReferralBonus {
+    event_seq: 2,
+    user: *referrer_info.key,
+    wallet: referrer_state.owner,
+    pledge_tokens: bonus,
+    total_pledge_tokens: bonus,
+  });
+
+  // Without a referrer nobody else is credited.
+  let mut accounts = accounts;
+  accounts.remove(5);
+  buy_pledge(&program_id, &accounts, 1000, DEFAULT_LOCK_TIER, None, false, 0).unwrap();
+  assert_eq!(UserState::unpack(&referrer_info.data.borrow()).unwrap().locked_pledge_tokens, bonus);
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().total_pledge_sold, 2 * bought + bonus);
+}
+
+#[test]
+fn test_buy_pledge_rejects_bad_referrer() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let config = config_account(&program_id, ContractConfig::new());
+  let before = account_info.data.borrow().to_vec();
+
+  // Referring oneself through the same account passes it twice.
+  let accounts = referred_buy_accounts(&program_id, &account_info, &wallet, &config, &account_info);
+  assert_eq!(buy_pledge(&program_id, &accounts, 1000, DEFAULT_LOCK_TIER, Some(account_info.key), false, 0), Err(PledgeError::DuplicateAccount.into()));
+
+  // A referrer that never initialized its state.
+  let referrer = Pubkey::new_unique();
+  let (key, _) = find_user_state_address(&program_id, &referrer);
+  let mut data = vec![0; UserState::LEN];
+  let mut lamports = 1_000_000;
+  let uninitialized = AccountInfo::new(&key, false, true, &mut lamports, &mut data, &program_id, false, 0);
+  let accounts = referred_buy_accounts(&program_id, &account_info, &wallet, &config, &uninitialized);
+  assert_eq!(buy_pledge(&program_id, &accounts, 1000, DEFAULT_LOCK_TIER, Some(&key), false, 0), Err(ProgramError::UninitializedAccount));
+
+  // The account passed must be the referrer named in the instruction.
+  let referrer_info = user_state_account(&program_id, &referrer, UserState::default());
+  let accounts = referred_buy_accounts(&program_id, &account_info, &wallet, &config, &referrer_info);
+  buy_pledge(&program_id, &accounts, 1000, DEFAULT_LOCK_TIER, Some(&key), false, 0).unwrap();
+  assert_eq!(buy_pledge(&program_id, &accounts, 1000, DEFAULT_LOCK_TIER, Some(&wallet), false, 0), Err(ProgramError::InvalidArgument));
+  let accounts = &accounts[..5];
+  assert_eq!(buy_pledge(&program_id, accounts, 1000, DEFAULT_LOCK_TIER, Some(&key), false, 0), Err(ProgramError::NotEnoughAccountKeys));
+
+  // Only the valid referral went through.
+  assert_ne!(*account_info.data.borrow(), &before[..]);
+  assert_eq!(UserState::unpack(&account_info.data.borrow()).unwrap().purchase_count, 1);
+}
+
+#[test]
+fn test_set_referral_bonus() {
+  let program_id = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let other = Pubkey::new_unique();
+  let config = config_account(&program_id, ContractConfig { admin, ..ContractConfig::new() });
+
+  assert_eq!(set_referral_bonus(&program_id, &[config.clone(), wallet_account(&other, true)], 10), Err(PledgeError::Unauthorized.into()));
+  assert_eq!(set_referral_bonus(&program_id, &[config.clone(), wallet_account(&admin, true)], 101), Err(ProgramError::InvalidArgument));
+  set_referral_bonus(&program_id, &[config.clone(), wallet_account(&admin, true)], 10).unwrap();
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().referral_bonus_percent, 10);
+}
+
+#[test]
+fn test_buy_pledge_first_phase_requires_whitelist() {
+  let program_id = Pubkey::new_unique();
+  let wallet: &'static Pubkey = Box::leak(Box::new(Pubkey::new_unique()));
+  let other = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, wallet, UserState::default());
+  let buy = |whitelist: Option<AccountInfo<'static>>, current_time| {
+    let mut accounts = vec![
+      account_info.clone(),
+      wallet_account(wallet, true),
+      config_account(&program_id, ContractConfig::new()),
+      treasury_account(&program_id, 0),
+      system_program_account(),
+    ];
+    accounts.extend(whitelist);
+    buy_pledge(&program_id, &accounts, 1000, DEFAULT_LOCK_TIER, None, false, current_time)
+  };
+
+  assert_eq!(buy(None, 0), Err(PledgeError::NotWhitelisted.into()));
+  // Someone else's whitelist entry does not count.
+  assert_eq!(buy(Some(whitelist_account(&program_id, &other)), 0), Err(PledgeError::NotWhitelisted.into()));
+  let forged = sized_user_state_account(&Pubkey::new_unique(), wallet, UserState::default(), Whitelist::LEN);
+  assert_eq!(buy(Some(forged), 0), Err(PledgeError::NotWhitelisted.into()));
+  assert_eq!(UserState::unpack(&account_info.data.borrow()).unwrap().lot_count, 0);
+
+  buy(Some(whitelist_account(&program_id, wallet)), 0).unwrap();
+  // From the second phase on anyone may buy.
+  buy(None, PHASE_DURATIONS[0]).unwrap();
+  assert_eq!(UserState::unpack(&account_info.data.borrow()).unwrap().lot_count, 2);
+}
+
+#[test]
+fn test_whitelist_requires_admin() {
+  let program_id = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let config = config_account(&program_id, ContractConfig { admin, ..ContractConfig::new() });
+  let (key, _) = find_whitelist_address(&program_id, &wallet);
+  let mut lamports = 1_000_000;
+  let mut data = vec![0; Whitelist::LEN];
+  let whitelist_info = AccountInfo::new(&key, false, true, &mut lamports, &mut data, &program_id, false, 0);
+  let accounts = |signer| [config.clone(), signer, whitelist_info.clone(), system_program_account()];
+
+  let add = add_to_whitelist(&program_id, &accounts(wallet_account(&wallet, true)), &wallet, &Rent::default());
+  assert_eq!(add, Err(PledgeError::Unauthorized.into()));
+  let remove = remove_from_whitelist(&program_id, &accounts(wallet_account(&wallet, true)), &wallet);
+  assert_eq!(remove, Err(PledgeError::Unauthorized.into()));
+  assert!(whitelist_info.data.borrow().iter().all(|&byte| byte == 0));
+
+  add_to_whitelist(&program_id, &accounts(wallet_account(&admin, true)), &wallet, &Rent::default()).unwrap();
+  let whitelist = Whitelist::try_from_slice(&whitelist_info.data.borrow()).unwrap();
+  assert!(whitelist.is_initialized);
+  assert_eq!(whitelist.wallet, wallet);
+  let add = add_to_whitelist(&program_id, &accounts(wallet_account(&admin, true)), &wallet, &Rent::default());
+  assert_eq!(add, Err(PledgeError::AlreadyInitialized.into()));
+
+  // The whitelist account must be the one derived from the wallet.
+  let add = add_to_whitelist(&program_id, &accounts(wallet_account(&admin, true)), &admin, &Rent::default());
+  assert_eq!(add, Err(ProgramError::InvalidSeeds));
+}
+
+#[test]
+fn test_get_sale_phase_boundaries() {
+  let mut phase_start = 0;
+  for (phase, &duration) in PHASE_DURATIONS[..4].iter().enumerate() {
+    assert_eq!(get_sale_phase(phase_start, &PHASE_DURATIONS), phase);
+    assert_eq!(get_sale_phase(phase_start + duration - 1, &PHASE_DURATIONS), phase);
+    phase_start += duration;
+  }
+  assert_eq!(get_sale_phase(phase_start, &PHASE_DURATIONS), 4);
+  assert_eq!(get_sale_phase(u64::MAX - 1, &PHASE_DURATIONS), 4);
+  assert_eq!(get_sale_phase(u64::MAX, &PHASE_DURATIONS), 4);
+}
+
+#[test]
+fn test_get_sale_phase_overflowing_schedule() {
+  // The third phase already reaches past u64::MAX, so it never ends.
+  let durations = [10, u64::MAX - 15, 10, 10, u64::MAX];
+  assert_eq!(get_sale_phase(9, &durations), 0);
+  assert_eq!(get_sale_phase(u64::MAX - 6, &durations), 1);
+  assert_eq!(get_sale_phase(u64::MAX - 5, &durations), 2);
+  assert_eq!(get_sale_phase(u64::MAX, &durations), 2);
+  // Finite schedules end in the last phase once every duration has passed.
+  assert_eq!(get_sale_phase(u64::MAX, &[1, 1, 1, 1, 1]), 4);
+}
+
+#[test]
+fn test_buy_pledge_ignores_users_own_balance() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  // A large personal balance no longer limits purchases, only global sales do.
+  let account_info = user_state_account(&program_id, &wallet, locked_state(TOTAL_PLEDGE_SUPPLY, 0));
+  let config = config_account(&program_id, ContractConfig::new());
+
+  let result = buy_pledge(&program_id, &[account_info, wallet_account(&wallet, true), config, treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)], 1000, DEFAULT_LOCK_TIER, None, false, 1_000_000);
+  assert!(result.is_ok());
+}
+
+#[test]
+fn test_buy_pledge_rejects_foreign_config() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let config = config_account(&Pubkey::new_unique(), ContractConfig::new());
+
+  let result = buy_pledge(&program_id, &[account_info, wallet_account(&wallet, true), config, treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)], 1000, DEFAULT_LOCK_TIER, None, false, 1_000_000);
+  assert_eq!(result, Err(ProgramError::IncorrectProgramId));
+}
+
+fn buy_at(sale_start_time: u64, current_time: u64) -> Result<u64, ProgramError> {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let config = config_account(&program_id, ContractConfig { sale_start_time, ..ContractConfig::new() });
+
+  buy_pledge(&program_id, &[account_info.clone(), wallet_account(&wallet, true), config, treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)], 100, DEFAULT_LOCK_TIER, None, false, current_time)?;
+  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+  Ok(user_state.locked_pledge_tokens)
+}
+
+#[test]
+fn test_buy_pledge_before_sale_start() {
+  let sale_start_time = 1_700_000_000;
+
+  assert_eq!(buy_at(sale_start_time, sale_start_time - 1), Err(PledgeError::SaleNotStarted.into()));
+  assert_eq!(buy_at(sale_start_time, 0), Err(PledgeError::SaleNotStarted.into()));
+  assert_eq!(buy_at(sale_start_time, sale_start_time), Ok(200));
+}
+
+#[test]
+fn test_buy_pledge_phase_boundaries_from_sale_start() {
+  let sale_start_time = 1_700_000_000;
+  let first_boundary = sale_start_time + PHASE_DURATIONS[0];
+
+  // 100 lamports at the phase rate, so the locked amount equals the rate.
+  assert_eq!(buy_at(sale_start_time, first_boundary - 1), Ok(200));
+  assert_eq!(buy_at(sale_start_time, first_boundary), Ok(175));
+  assert_eq!(buy_at(sale_start_time, first_boundary + PHASE_DURATIONS[1] - 1), Ok(175));
+  assert_eq!(buy_at(sale_start_time, first_boundary + PHASE_DURATIONS[1]), Ok(150));
+  // Far past every finite phase, only the open-ended final rate applies.
+  assert_eq!(buy_at(sale_start_time, sale_start_time + 10 * VESTING_PERIOD), Ok(100));
+}
+
+#[test]
+fn test_buy_pledge_amount_overflow() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let config = config_account(&program_id, ContractConfig::new());
+  let accounts = [account_info.clone(), wallet_account(&wallet, true), config, treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)];
+
+  // Anything above u64::MAX / 2 at the 200% rate does not fit in a u64.
+  assert_eq!(buy_pledge(&program_id, &accounts, u64::MAX, DEFAULT_LOCK_TIER, None, false, 1_000_000), Err(PledgeError::ArithmeticOverflow.into()));
+  assert_eq!(buy_pledge(&program_id, &accounts, u64::MAX / 2 + 1, DEFAULT_LOCK_TIER, None, false, 1_000_000), Err(PledgeError::ArithmeticOverflow.into()));
+  // Fits in a u64 once scaled, but is far beyond the supply.
+  assert_eq!(buy_pledge(&program_id, &accounts, u64::MAX / 100, DEFAULT_LOCK_TIER, None, false, 1_000_000), Err(PledgeError::SupplyExhausted.into()));
+  assert_eq!(UserState::try_from_slice(&account_info.data.borrow()).unwrap().locked_pledge_tokens, 0);
+}
+
+#[test]
+fn test_buy_pledge_locked_balance_overflow() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let user_state = locked_state(u64::MAX - 1, 0);
+  let account_info = user_state_account(&program_id, &wallet, user_state);
+  let config = config_account(&program_id, ContractConfig::new());
+
+  let result = buy_pledge(&program_id, &[account_info.clone(), wallet_account(&wallet, true), config, treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)], 1000, DEFAULT_LOCK_TIER, None, false, 1_000_000);
+  assert_eq!(result, Err(PledgeError::ArithmeticOverflow.into()));
+  assert_eq!(UserState::try_from_slice(&account_info.data.borrow()).unwrap().locked_pledge_tokens, u64::MAX - 1);
+}
+
+#[test]
+fn test_buy_pledge_supply_boundary() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  // 100 tokens short of the cap; phase 0 buys at 200%, so 50 lamports fill it.
+  let config = config_account(&program_id, ContractConfig {
+    total_pledge_sold: TOTAL_PLEDGE_SUPPLY - 100,
+    ..ContractConfig::new()
+  });
+  let accounts = [account_info.clone(), wallet_account(&wallet, true), config.clone(), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)];
+
+  assert_eq!(buy_pledge(&program_id, &accounts, 51, DEFAULT_LOCK_TIER, None, false, 1_000_000), Err(PledgeError::SupplyExhausted.into()));
+  assert!(buy_pledge(&program_id, &accounts, 50, DEFAULT_LOCK_TIER, None, false, 1_000_000).is_ok());
+  let pledge_contract = ContractConfig::unpack(&config.data.borrow()).unwrap();
+  assert_eq!(pledge_contract.total_pledge_sold, TOTAL_PLEDGE_SUPPLY);
+}
+
+#[test]
+fn test_buy_pledge_partial_fill() {
+  install_capture();
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let config = config_account(&program_id, ContractConfig {
+    total_pledge_sold: TOTAL_PLEDGE_SUPPLY - 100,
+    ..ContractConfig::new()
+  });
+  let user = *account_info.key;
+  let accounts = [account_info.clone(), wallet_account(&wallet, true), config.clone(), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)];
+  take_events();
+
+  // 75 lamports ask for 150 tokens; only the last 100 are sold, for 50.
+  assert_eq!(buy_pledge(&program_id, &accounts, 75, DEFAULT_LOCK_TIER, None, false, 0), Err(PledgeError::SupplyExhausted.into()));
+  buy_pledge(&program_id, &accounts, 75, DEFAULT_LOCK_TIER, None, true, 0).unwrap();
+  assert_eq!(
+    take_events(),
+    vec![
+      PledgeEvent::Purchase {
+        event_seq: 1,
+        user,
+        wallet,
+        lamports_paid: 50,
+        pledge_tokens: 100,
+        rate: 200,
+        total_pledge_tokens: 100,
+        phase: 0,
+        phase_time_remaining: PHASE_DURATIONS[0],
+      },
+      PledgeEvent::PartialFill { event_seq: 2, user, wallet, requested: 150, filled: 100 },
+    ]
+  );
+  assert_eq!(UserState::try_from_slice(&account_info.data.borrow()).unwrap().locked_pledge_tokens, 100);
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().total_pledge_sold, TOTAL_PLEDGE_SUPPLY);
+
+  // Nothing is left to fill.
+  assert_eq!(buy_pledge(&program_id, &accounts, 75, DEFAULT_LOCK_TIER, None, true, 0), Err(PledgeError::SupplyExhausted.into()));
+}
+
+#[test]
+fn test_buy_pledge_vesting_end_overflow() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let config = config_account(&program_id, ContractConfig::new());
+
+  let result = buy_pledge(&program_id, &[account_info, wallet_account(&wallet, true), config, treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)], 1000, DEFAULT_LOCK_TIER, None, false, u64::MAX - 1);
+  assert_eq!(result, Err(PledgeError::ArithmeticOverflow.into()));
+}
+
+#[test]
+fn test_update_reward_overflow() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let user_state = locked_state(u64::MAX / 10, 0);
+  let account_info = user_state_account(&program_id, &wallet, user_state);
+  let before = account_info.data.borrow().to_vec();
+  // Rewards of 20x the principal, more than a u64 holds.
+  let config = config_account(&program_id, ContractConfig { reward_rate_bps: 20 * BASIS_POINTS, ..ContractConfig::new() });
+
+  assert_eq!(update_reward(&program_id, &account_info, &config, VESTING_PERIOD), Err(PledgeError::ArithmeticOverflow.into()));
+  assert_eq!(*account_info.data.borrow(), &before[..]);
+}
+
+#[test]
+fn test_update_reward_largest_representable_reward() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let locked_pledge_tokens = u64::MAX;
+  let user_state = locked_state(locked_pledge_tokens, 0);
+  let account_info = user_state_account(&program_id, &wallet, user_state);
+
+  update_reward(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), VESTING_PERIOD).unwrap();
+  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.solhit_rewards, full_reward(locked_pledge_tokens));
+}
+
+#[test]
+fn test_update_reward_accumulation_overflow() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let user_state = UserState {
+    solhit_rewards: u64::MAX - 1,
+    ..locked_state(1000, 0)
+  };
+  let account_info = user_state_account(&program_id, &wallet, user_state);
+
+  assert_eq!(update_reward(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), VESTING_PERIOD), Err(PledgeError::ArithmeticOverflow.into()));
+  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.solhit_rewards, u64::MAX - 1);
+}
+
+#[test]
+fn test_buy_pledge_top_up_keeps_lock_start_time() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let config = config_account(&program_id, ContractConfig::new());
+  let accounts = [account_info.clone(), wallet_account(&wallet, true), config, treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)];
+  let t0 = 1_000_000;
+
+  buy_pledge(&program_id, &accounts, 1000, DEFAULT_LOCK_TIER, None, false, t0).unwrap();
+  let first_lot = UserState::try_from_slice(&account_info.data.borrow()).unwrap().lots[0];
+  buy_pledge(&program_id, &accounts, 1000, DEFAULT_LOCK_TIER, None, false, t0 + DEFAULT_LOCK / 2).unwrap();
+
+  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.lock_start_time, t0);
+  assert_eq!(user_state.lot_count, 2);
+  let second_lot = user_state.lots[1];
+
+  // One lock period after the first purchase only that lot has vested,
+  // the second one is half way through.
+  unlock_pledge(&program_id, &unlock_accounts(&program_id, &account_info, &wallet), t0 + DEFAULT_LOCK).unwrap();
+  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.solhit_rewards, full_reward(first_lot.amount + second_lot.amount / 2));
+  assert_eq!(user_state.active_lots(), &[second_lot]);
+  assert_eq!(user_state.locked_pledge_tokens, second_lot.amount);
+  assert_eq!(user_state.lock_start_time, t0 + DEFAULT_LOCK / 2);
+}
+
+#[test]
+fn test_buy_pledge_after_unlock_restarts_lock_start_time() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let config = config_account(&program_id, ContractConfig::new());
+  let accounts = [account_info.clone(), wallet_account(&wallet, true), config, treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)];
+  let t0 = 1_000_000;
+
+  buy_pledge(&program_id, &accounts, 1000, DEFAULT_LOCK_TIER, None, false, t0).unwrap();
+  unlock_pledge(&program_id, &unlock_accounts(&program_id, &account_info, &wallet), t0 + DEFAULT_LOCK).unwrap();
+  buy_pledge(&program_id, &accounts, 1000, DEFAULT_LOCK_TIER, None, false, t0 + DEFAULT_LOCK + 10).unwrap();
+
+  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.lock_start_time, t0 + DEFAULT_LOCK + 10);
+}
+
+#[test]
+fn test_lots_vest_independently() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let config = config_account(&program_id, ContractConfig::new());
+  let accounts = [account_info.clone(), wallet_account(&wallet, true), config, treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)];
+  let t0 = 1_000_000;
+
+  // Bought in three different phases, at three different rates.
+  buy_pledge(&program_id, &accounts, 1000, DEFAULT_LOCK_TIER, None, false, t0).unwrap();
+  buy_pledge(&program_id, &accounts, 1000, DEFAULT_LOCK_TIER, None, false, t0 + PHASE_DURATIONS[0]).unwrap();
+  buy_pledge(&program_id, &accounts, 1000, DEFAULT_LOCK_TIER, None, false, t0 + 2 * PHASE_DURATIONS[0]).unwrap();
+
+  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+  let rates: Vec<u64> = user_state.active_lots().iter().map(|lot| lot.rate).collect();
+  assert_eq!(rates, vec![200, 175, 150]);
+  assert_eq!(user_state.locked_pledge_tokens, 2000 + 1750 + 1500);
+  assert_eq!(user_state.vesting_end_time, t0 + 2 * PHASE_DURATIONS[0] + DEFAULT_LOCK);
+
+  let last_lot = user_state.lots[2];
+
+  // Nothing has vested yet.
+  update_reward(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), t0 + DEFAULT_LOCK - 1).unwrap();
+  assert_eq!(UserState::try_from_slice(&account_info.data.borrow()).unwrap().lot_count, 3);
+
+  let unlock = unlock_accounts(&program_id, &account_info, &wallet);
+  let time = t0 + PHASE_DURATIONS[0] + DEFAULT_LOCK;
+  unlock_pledge(&program_id, &unlock, time).unwrap();
+  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+  let last_lot_accrued = last_lot.accrued_rewards(REWARD_RATE_BPS, 0, time).unwrap();
+  assert_eq!(user_state.solhit_rewards, full_reward(2000 + 1750) + last_lot_accrued);
+  assert_eq!(user_state.lot_count, 1);
+  assert_eq!(user_state.locked_pledge_tokens, 1500);
+
+  unlock_pledge(&program_id, &unlock, t0 + 2 * PHASE_DURATIONS[0] + DEFAULT_LOCK).unwrap();
+  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.solhit_rewards, full_reward(2000 + 1750 + 1500));
+  assert_eq!(user_state.lot_count, 0);
+  assert_eq!(user_state.locked_pledge_tokens, 0);
+  assert_eq!(user_state.vesting_end_time, 0);
+}
+
+#[test]
+fn test_buy_pledge_lot_limit() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let config = config_account(&program_id, ContractConfig::new());
+  let accounts = [account_info.clone(), wallet_account(&wallet, true), config, treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)];
+
+  for i in 0..MAX_PLEDGE_LOTS as u64 {
+    buy_pledge(&program_id, &accounts, 100, DEFAULT_LOCK_TIER, None, false, 1_000_000 + i).unwrap();
+  }
+  let before = account_info.data.borrow().to_vec();
+
+  assert_eq!(
+    buy_pledge(&program_id, &accounts, 100, DEFAULT_LOCK_TIER, None, false, 2_000_000),
+    Err(PledgeError::LotLimitReached.into())
+  );
+  assert_eq!(*account_info.data.borrow(), &before[..]);
+}
+
+#[test]
+fn test_user_state_round_trip_with_lots() {
+  let mut user_state = locked_state(1000, 5);
+  user_state.add_lot(PledgeLot { amount: 7, rate: 100, lock_start_time: 9, vesting_end_time: 10, multiplier_bps: 5_000 }).unwrap();
+
+  let data = borsh::to_vec(&user_state).unwrap();
+  assert_eq!(data.len(), UserState::LEN);
+
+  let decoded = UserState::try_from_slice(&data).unwrap();
+  assert_eq!(decoded.lot_count, 2);
+  assert_eq!(decoded.lots, user_state.lots);
+  assert_eq!(decoded.locked_pledge_tokens, 1007);
+}
+
+#[test]
+fn test_update_reward_accrues_linearly() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, locked_state(1000, 0));
+
+  update_reward(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), VESTING_PERIOD / 4).unwrap();
+  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.solhit_rewards, full_reward(1000) / 4);
+  assert_eq!(user_state.last_reward_update, VESTING_PERIOD / 4);
+  assert_eq!(user_state.locked_pledge_tokens, 1000);
+}
+
+#[test]
+fn test_update_reward_split_calls_match_single_call() {
+  let program_id = Pubkey::new_unique();
+  // An amount that does not divide evenly, so rounding would show up.
+  let split = user_state_account(&program_id, &Pubkey::new_unique(), locked_state(333, 7));
+  let single = user_state_account(&program_id, &Pubkey::new_unique(), locked_state(333, 7));
+
+  update_reward(&program_id, &split, &config_account(&program_id, ContractConfig::new()), 7 + VESTING_PERIOD / 4).unwrap();
+  update_reward(&program_id, &split, &config_account(&program_id, ContractConfig::new()), 7 + VESTING_PERIOD).unwrap();
+  update_reward(&program_id, &single, &config_account(&program_id, ContractConfig::new()), 7 + VESTING_PERIOD).unwrap();
+
+  let split = UserState::try_from_slice(&split.data.borrow()).unwrap();
+  let single = UserState::try_from_slice(&single.data.borrow()).unwrap();
+  assert_eq!(split.solhit_rewards, full_reward(333));
+  assert_eq!(split.solhit_rewards, single.solhit_rewards);
+}
+
+#[test]
+fn test_update_reward_same_timestamp_credits_nothing() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, locked_state(1000, 0));
+
+  update_reward(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), VESTING_PERIOD / 2).unwrap();
+  let first = UserState::try_from_slice(&account_info.data.borrow()).unwrap().solhit_rewards;
+  update_reward(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), VESTING_PERIOD / 2).unwrap();
+  // A slightly earlier timestamp must not credit anything either, and a much
+  // earlier one is refused.
+  let skewed = VESTING_PERIOD / 2 - MAX_CLOCK_SKEW;
+  update_reward(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), skewed).unwrap();
+  assert_eq!(
+    update_reward(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), VESTING_PERIOD / 4),
+    Err(PledgeError::ClockWentBackwards.into())
+  );
+
+  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.solhit_rewards, first);
+  assert_eq!(user_state.last_reward_update, VESTING_PERIOD / 2);
+}
+
+#[test]
+fn test_update_reward_without_accrual_writes_nothing() {
+  install_capture();
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, locked_state(1000, 0));
+  let config = config_account(&program_id, ContractConfig::new());
+  update_reward(&program_id, &account_info, &config, VESTING_PERIOD / 2).unwrap();
+  assert!(matches!(take_events()[..], [PledgeEvent::RewardUpdate { event_seq: 1, .. }]));
+
+  // Neither again at the same time nor a little earlier adds anything, so the
+  // account keeps its bytes and a NoOp stands in for the RewardUpdate.
+  let state_before = account_info.data.borrow().to_vec();
+  for now in [VESTING_PERIOD / 2, VESTING_PERIOD / 2 - MAX_CLOCK_SKEW] {
+    update_reward(&program_id, &account_info, &config, now).unwrap();
+    assert_eq!(account_info.data.borrow().to_vec(), state_before);
+  }
+  let user = *account_info.key;
+  assert_eq!(
+    take_events(),
+    vec![PledgeEvent::NoOp { event_seq: 2, user, wallet }, PledgeEvent::NoOp { event_seq: 3, user, wallet }]
+  );
+
+  // Turned off, the crank leaves the config untouched too and says nothing.
+  let mut pledge_contract = ContractConfig::unpack(&config.data.borrow()).unwrap();
+  pledge_contract.noop_events = false;
+  save_config(&config, &pledge_contract).unwrap();
+  let config_before = config.data.borrow().to_vec();
+  update_reward(&program_id, &account_info, &config, VESTING_PERIOD / 2).unwrap();
+  assert_eq!(account_info.data.borrow().to_vec(), state_before);
+  assert_eq!(config.data.borrow().to_vec(), config_before);
+  assert!(take_events().is_empty());
+
+  // Once time moves on the rewards are accrued as usual.
+  update_reward(&program_id, &account_info, &config, VESTING_PERIOD / 4 * 3).unwrap();
+  assert_ne!(account_info.data.borrow().to_vec(), state_before);
+  assert!(matches!(take_events()[..], [PledgeEvent::RewardUpdate { event_seq: 4, .. }]));
+}
+
+#[test]
+fn test_set_noop_events() {
+  let program_id = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let config = config_account(&program_id, ContractConfig { admin, ..ContractConfig::new() });
+  let accounts = [config.clone(), wallet_account(Box::leak(Box::new(admin)), true)];
+  let noop_events = || ContractConfig::unpack(&config.data.borrow()).unwrap().noop_events;
+  assert!(noop_events());
+
+  let stranger = [config.clone(), wallet_account(Box::leak(Box::new(Pubkey::new_unique())), true)];
+  assert_eq!(set_noop_events(&program_id, &stranger, false), Err(PledgeError::Unauthorized.into()));
+  set_noop_events(&program_id, &accounts, false).unwrap();
+  assert!(!noop_events());
+  set_noop_events(&program_id, &accounts, true).unwrap();
+  assert!(noop_events());
+}
+
+#[test]
+fn test_compliance_hold() {
+  let program_id = Pubkey::new_unique();
+  let admin: &'static Pubkey = Box::leak(Box::new(Pubkey::new_unique()));
+  let wallet: &'static Pubkey = Box::leak(Box::new(Pubkey::new_unique()));
+  let account_info = user_state_account(&program_id, wallet, locked_state(1000, 0));
+  let config = config_account(&program_id, ContractConfig { admin: *admin, ..ContractConfig::new() });
+  let hold = |signer: &'static Pubkey, wallet: &Pubkey, hold_until, now| {
+    let accounts = [account_info.clone(), config.clone(), wallet_account(signer, true)];
+    set_compliance_hold(&program_id, &accounts, wallet, hold_until, now)
+  };
+  let user = *account_info.key;
+  take_events();
+
+  assert_eq!(hold(wallet, wallet, u64::MAX, 10), Err(PledgeError::Unauthorized.into()));
+  assert_eq!(hold(admin, &Pubkey::new_unique(), u64::MAX, 10), Err(ProgramError::InvalidSeeds));
+  let hold_until = VESTING_PERIOD + 100;
+  hold(admin, wallet, hold_until, 10).unwrap();
+  assert_eq!(
+    take_events(),
+    vec![PledgeEvent::ComplianceHold {
+      event_seq: 1,
+      user,
+      wallet: *wallet,
+      admin: *admin,
+      previous_hold_until: 0,
+      hold_until,
+      timestamp: 10,
+    }]
+  );
+
+  // Nothing comes out while the hold lasts, but rewards keep accruing.
+  let held = Err(PledgeError::ComplianceHold.into());
+  let before = account_info.data.borrow().to_vec();
+  assert_eq!(early_exit(&program_id, &unlock_accounts(&program_id, &account_info, wallet), 20), held);
+  assert_eq!(unlock_pledge(&program_id, &unlock_accounts(&program_id, &account_info, wallet), VESTING_PERIOD), held);
+  let claim = claim_accounts(&program_id, &account_info, wallet, true);
+  assert_eq!(claim_rewards(&program_id, &claim, 0, VESTING_PERIOD), held);
+  let destination = user_state_account(&program_id, &Pubkey::new_unique(), UserState::default());
+  let transfer = [account_info.clone(), wallet_account(wallet, true), destination, config.clone()];
+  assert_eq!(transfer_position(&program_id, &transfer, VESTING_PERIOD), held);
+  assert_eq!(*account_info.data.borrow(), &before[..]);
+  update_reward(&program_id, &account_info, &config, VESTING_PERIOD).unwrap();
+  assert_eq!(UserState::unpack(&account_info.data.borrow()).unwrap().solhit_rewards, full_reward(1000));
+
+  // From hold_until on the user is free again.
+  unlock_pledge(&program_id, &unlock_accounts(&program_id, &account_info, wallet), hold_until).unwrap();
+  assert_eq!(UserState::unpack(&account_info.data.borrow()).unwrap().locked_pledge_tokens, 0);
+
+  // A hold without end lasts until lifted with 0.
+  hold(admin, wallet, u64::MAX, hold_until).unwrap();
+  assert_eq!(claim_rewards(&program_id, &claim, 0, hold_until + 1), held);
+  take_events();
+  hold(admin, wallet, 0, hold_until + 2).unwrap();
+  let [PledgeEvent::ComplianceHold { previous_hold_until, hold_until: 0, timestamp, .. }] = take_events()[..] else { panic!() };
+  assert_eq!((previous_hold_until, timestamp), (u64::MAX, hold_until + 2));
+  claim_rewards(&program_id, &claim, 0, hold_until + 3).unwrap();
+  assert_eq!(UserState::unpack(&account_info.data.borrow()).unwrap().solhit_rewards, 0);
+}
+
+// Past the vesting end, calls every period, and twice at the same time,
+// settle no more than one call at the end would: the lot's full reward plus
+// what the grace adds.
+#[test]
+fn test_update_reward_after_vesting_pays_once() {
+  let program_id = Pubkey::new_unique();
+  let grace = VESTING_PERIOD / 4;
+  let config = || config_account(&program_id, ContractConfig { post_vesting_grace_secs: grace, ..ContractConfig::new() });
+  let repeated = user_state_account(&program_id, &Pubkey::new_unique(), locked_state(1000, 0));
+  let single = user_state_account(&program_id, &Pubkey::new_unique(), locked_state(1000, 0));
+
+  for now in [VESTING_PERIOD, VESTING_PERIOD, VESTING_PERIOD + grace, 2 * VESTING_PERIOD, 2 * VESTING_PERIOD, 3 * VESTING_PERIOD] {
+    update_reward(&program_id, &repeated, &config(), now).unwrap();
+  }
+  update_reward(&program_id, &single, &config(), 3 * VESTING_PERIOD).unwrap();
+
+  let repeated = UserState::try_from_slice(&repeated.data.borrow()).unwrap();
+  let single = UserState::try_from_slice(&single.data.borrow()).unwrap();
+  assert_eq!(single.solhit_rewards, full_reward(1000) + full_reward(1000) / 4);
+  assert_eq!(repeated.solhit_rewards, single.solhit_rewards);
+  // Calls past the grace accrue nothing and leave the state as it was.
+  assert_eq!(repeated.last_reward_update, VESTING_PERIOD + grace);
+}
+
+#[test]
+fn test_handlers_reject_locked_tokens_without_vesting_end() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let corrupted = UserState { vesting_end_time: 0, ..locked_state(1000, 0) };
+  assert!(corrupted.is_vesting());
+  assert_eq!(corrupted.assert_consistent(), Err(PledgeError::StateCorrupted));
+  let mut data = vec![0; UserState::LEN];
+  assert_eq!(corrupted.pack_into_slice(&mut data), Err(PledgeError::StateCorrupted.into()));
+  let unlocked = UserState::default();
+  assert!(!unlocked.is_vesting());
+  assert_eq!(unlocked.assert_consistent(), Ok(()));
+
+  // Fresh tokens would otherwise unlock at once.
+  let account_info = user_state_account(&program_id, &wallet, corrupted);
+  let config = config_account(&program_id, ContractConfig::new());
+  let before = account_info.data.borrow().to_vec();
+  let now = VESTING_PERIOD / 2;
+  let error = Err(PledgeError::StateCorrupted.into());
+  assert_eq!(update_reward(&program_id, &account_info, &config, now), error);
+  assert_eq!(view_rewards(&program_id, &account_info, &config, false, now), error);
+  let buy_accounts = [account_info.clone(), wallet_account(&wallet, true), config.clone(), treasury_account(&program_id, 0), system_program_account()];
+  assert_eq!(buy_pledge(&program_id, &buy_accounts, 1000, DEFAULT_LOCK_TIER, None, false, now), error);
+  assert_eq!(claim_rewards(&program_id, &claim_accounts(&program_id, &account_info, &wallet, true), 0, now), error);
+  assert_eq!(unlock_pledge(&program_id, &unlock_accounts(&program_id, &account_info, &wallet), now), error);
+  assert_eq!(early_exit(&program_id, &early_exit_accounts(&program_id, &account_info, &wallet, &config), now), error);
+  let compound_accounts = [account_info.clone(), wallet_account(&wallet, true), config.clone()];
+  assert_eq!(compound_rewards(&program_id, &compound_accounts, now), error);
+  let destination = user_state_account(&program_id, &Pubkey::new_unique(), UserState::default());
+  let transfer_accounts = [account_info.clone(), wallet_account(&wallet, true), destination, config.clone()];
+  assert_eq!(transfer_position(&program_id, &transfer_accounts, now), error);
+  assert_eq!(set_beneficiary(&program_id, &[account_info.clone(), wallet_account(&wallet, true)], wallet), error);
+  assert_eq!(close_user_state(&program_id, &[account_info.clone(), wallet_account(&wallet, true), config.clone()]), error);
+  assert_eq!(*account_info.data.borrow(), &before[..]);
+}
+
+#[test]
+fn test_user_state_sanity_check() {
+  let sound = locked_state(1000, 10);
+  assert_eq!(sound.sanity_check(), Ok(()));
+  assert_eq!(UserState::default().sanity_check(), Ok(()));
+  // The reward supply is the config's to set, so no amount of rewards is corrupt.
+  assert_eq!(UserState { solhit_rewards: TOTAL_SOLHIT_SUPPLY * 2, ..sound.clone() }.sanity_check(), Ok(()));
+
+  let mut lot_backwards = sound.clone();
+  lot_backwards.lots[0].vesting_end_time = 5;
+  let corrupted = [
+    UserState { vesting_end_time: 0, ..sound.clone() },
+    UserState { vesting_end_time: 5, ..sound.clone() },
+    lot_backwards,
+    UserState { locked_pledge_tokens: 999, ..sound.clone() },
+    UserState { lot_count: 0, ..sound.clone() },
+  ];
+  for user_state in corrupted {
+    assert_eq!(user_state.sanity_check(), Err(PledgeError::StateCorrupted));
+  }
+}
+
+// Stored states that fail the sanity checks, each breaking a different
+// invariant, and bytes that are no user state at all.
+fn corrupted_user_states(program_id: &Pubkey, wallet: &Pubkey) -> Vec<(AccountInfo<'static>, ProgramError)> {
+  let sound = locked_state(1000, 0);
+  let mut lot_backwards = sound.clone();
+  lot_backwards.lots[0].lock_start_time = VESTING_PERIOD + 1;
+  let states = [
+    UserState { lock_start_time: VESTING_PERIOD + 1, ..sound.clone() },
+    lot_backwards,
+    UserState { locked_pledge_tokens: u64::MAX, ..sound.clone() },
+  ];
+  let mut accounts: Vec<_> =
+    states.into_iter().map(|user_state| (user_state_account(program_id, wallet, user_state), PledgeError::StateCorrupted.into())).collect();
+
+  let garbage = user_state_account(program_id, wallet, UserState::default());
+  garbage.data.borrow_mut()[8..].fill(0xab);
+  accounts.push((garbage, ProgramError::InvalidAccountData));
+  accounts
+}
+
+#[test]
+fn test_handlers_reject_corrupted_user_states() {
+  let program_id = Pubkey::new_unique();
+  let wallet: &'static Pubkey = Box::leak(Box::new(Pubkey::new_unique()));
+  let config = config_account(&program_id, ContractConfig::new());
+  let now = VESTING_PERIOD / 2;
+  for (account_info, error) in corrupted_user_states(&program_id, wallet) {
+    let before = account_info.data.borrow().to_vec();
+    let error = Err(error);
+    assert_eq!(update_reward(&program_id, &account_info, &config, now), error);
+    assert_eq!(view_rewards(&program_id, &account_info, &config, false, now), error);
+    let buy_accounts = [account_info.clone(), wallet_account(wallet, true), config.clone(), treasury_account(&program_id, 0), system_program_account()];
+    assert_eq!(buy_pledge(&program_id, &buy_accounts, 1000, DEFAULT_LOCK_TIER, None, false, now), error);
+    assert_eq!(claim_rewards(&program_id, &claim_accounts(&program_id, &account_info, wallet, true), 0, now), error);
+    assert_eq!(unlock_pledge(&program_id, &unlock_accounts(&program_id, &account_info, wallet), now), error);
+    assert_eq!(early_exit(&program_id, &early_exit_accounts(&program_id, &account_info, wallet, &config), now), error);
+    let compound_accounts = [account_info.clone(), wallet_account(wallet, true), config.clone()];
+    assert_eq!(compound_rewards(&program_id, &compound_accounts, now), error);
+    let destination = user_state_account(&program_id, &Pubkey::new_unique(), UserState::default());
+    let transfer_accounts = [account_info.clone(), wallet_account(wallet, true), destination, config.clone()];
+    assert_eq!(transfer_position(&program_id, &transfer_accounts, now), error);
+    assert_eq!(set_beneficiary(&program_id, &[account_info.clone(), wallet_account(wallet, true)], *wallet), error);
+    assert_eq!(close_user_state(&program_id, &[account_info.clone(), wallet_account(wallet, true), config.clone()]), error);
+    assert_eq!(*account_info.data.borrow(), &before[..]);
+  }
+}
+
+#[test]
+fn test_repair_user_state() {
+  let program_id = Pubkey::new_unique();
+  let admin: &'static Pubkey = Box::leak(Box::new(Pubkey::new_unique()));
+  let wallet: &'static Pubkey = Box::leak(Box::new(Pubkey::new_unique()));
+  let config = config_account(&program_id, ContractConfig { admin: *admin, initialized_users: 7, ..ContractConfig::new() });
+  let repair = |account_info: &AccountInfo<'static>, signer: &'static Pubkey, wallet: &Pubkey| {
+    repair_user_state(&program_id, &[account_info.clone(), config.clone(), wallet_account(signer, true)], wallet)
+  };
+
+  let mut users = 7;
+  for (account_info, error) in corrupted_user_states(&program_id, wallet) {
+    let before = account_info.data.borrow().to_vec();
+    assert_eq!(repair(&account_info, wallet, wallet), Err(PledgeError::Unauthorized.into()));
+    assert_eq!(repair(&account_info, admin, &Pubkey::new_unique()), Err(ProgramError::InvalidSeeds));
+    assert_eq!(*account_info.data.borrow(), &before[..]);
+
+    repair(&account_info, admin, wallet).unwrap();
+    assert!(account_info.data.borrow().iter().all(|&byte| byte == 0));
+    assert!(!UserState::unpack(&account_info.data.borrow()).unwrap().is_initialized);
+    // Garbage that fails to deserialize was never a counted user state.
+    if error != ProgramError::InvalidAccountData {
+      users -= 1;
+    }
+    assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().initialized_users, users);
+    // Zeroed, there is nothing left to repair.
+    assert_eq!(repair(&account_info, admin, wallet), Err(PledgeError::StateNotCorrupted.into()));
+  }
+  assert_eq!(users, 4);
+
+  // A state that loads is never wiped.
+  let sound = user_state_account(&program_id, wallet, locked_state(1000, 0));
+  let before = sound.data.borrow().to_vec();
+  assert_eq!(repair(&sound, admin, wallet), Err(PledgeError::StateNotCorrupted.into()));
+  assert_eq!(*sound.data.borrow(), &before[..]);
+}
+
+#[test]
+fn test_clock_went_backwards() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let now = VESTING_PERIOD / 2;
+  let account_info = user_state_account(&program_id, &wallet, UserState { last_activity: now, solhit_rewards: 500, ..locked_state(1000, 0) });
+  let config = config_account(&program_id, ContractConfig::new());
+  let before = account_info.data.borrow().to_vec();
+
+  // Every handler acting on the user refuses a time past the allowed skew,
+  // and leaves the user state alone.
+  let backwards = now - MAX_CLOCK_SKEW - 1;
+  let error = Err(PledgeError::ClockWentBackwards.into());
+  assert_eq!(update_reward(&program_id, &account_info, &config, backwards), error);
+  let buy_accounts = [account_info.clone(), wallet_account(&wallet, true), config.clone(), treasury_account(&program_id, 0), system_program_account()];
+  assert_eq!(buy_pledge(&program_id, &buy_accounts, 1000, DEFAULT_LOCK_TIER, None, false, backwards), error);
+  assert_eq!(claim_rewards(&program_id, &claim_accounts(&program_id, &account_info, &wallet, true), 0, backwards), error);
+  assert_eq!(unlock_pledge(&program_id, &unlock_accounts(&program_id, &account_info, &wallet), backwards), error);
+  assert_eq!(early_exit(&program_id, &early_exit_accounts(&program_id, &account_info, &wallet, &config), backwards), error);
+  let compound_accounts = [account_info.clone(), wallet_account(&wallet, true), config.clone()];
+  assert_eq!(compound_rewards(&program_id, &compound_accounts, backwards), error);
+  let destination_wallet = Pubkey::new_unique();
+  let destination = user_state_account(&program_id, &destination_wallet, UserState::default());
+  let transfer_accounts = [account_info.clone(), wallet_account(&wallet, true), destination, config.clone()];
+  assert_eq!(transfer_position(&program_id, &transfer_accounts, backwards), error);
+  assert_eq!(*account_info.data.borrow(), &before[..]);
+
+  // Right at the skew boundary the handler runs, without moving
+  // last_activity back.
+  update_reward(&program_id, &account_info, &config, now - MAX_CLOCK_SKEW).unwrap();
+  assert_eq!(UserState::unpack(&account_info.data.borrow()).unwrap().last_activity, now);
+  update_reward(&program_id, &account_info, &config, now + 10).unwrap();
+  assert_eq!(UserState::unpack(&account_info.data.borrow()).unwrap().last_activity, now + 10);
+  assert_eq!(update_reward(&program_id, &account_info, &config, now - MAX_CLOCK_SKEW), error);
+}
+
+#[test]
+fn test_transfer_position_checks_destination_clock() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let destination_wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, locked_state(1000, 0));
+  let destination = user_state_account(&program_id, &destination_wallet, UserState { last_activity: 5_000, ..UserState::default() });
+  let accounts = [account_info.clone(), wallet_account(&wallet, true), destination.clone(), config_account(&program_id, ContractConfig::new())];
+
+  assert_eq!(transfer_position(&program_id, &accounts, 5_000 - MAX_CLOCK_SKEW - 1), Err(PledgeError::ClockWentBackwards.into()));
+  transfer_position(&program_id, &accounts, 5_000 - MAX_CLOCK_SKEW).unwrap();
+  assert_eq!(UserState::unpack(&destination.data.borrow()).unwrap().last_activity, 5_000);
+  assert_eq!(UserState::unpack(&account_info.data.borrow()).unwrap().last_activity, 5_000 - MAX_CLOCK_SKEW);
+}
+
+#[test]
+fn test_update_reward_keeps_vested_principal_locked() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, locked_state(1000, 0));
+  take_events();
+
+  update_reward(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), 2 * VESTING_PERIOD).unwrap();
+  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.solhit_rewards, full_reward(1000));
+  // Principal only leaves through UnlockPledge, so no Unlock event either.
+  assert_eq!(user_state.locked_pledge_tokens, 1000);
+  assert!(matches!(take_events()[..], [PledgeEvent::RewardUpdate { .. }]));
+}
+
+#[test]
+fn test_preview_reward_matches_update_reward() {
+  install_capture();
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, locked_state(1000, 0));
+  let config = config_account(&program_id, ContractConfig::new());
+  let accounts = [account_info.clone(), config.clone()];
+  take_events();
+
+  for now in [VESTING_PERIOD / 4, VESTING_PERIOD / 2, VESTING_PERIOD / 4 * 3, VESTING_PERIOD] {
+    let state_before = account_info.data.borrow().to_vec();
+    let config_before = config.data.borrow().to_vec();
+    preview_reward(&program_id, &accounts, false, now).unwrap();
+    let preview = RewardPreview::try_from_slice(&take_return_data()).unwrap();
+    // Previewing writes nothing and emits nothing.
+    assert_eq!(account_info.data.borrow().to_vec(), state_before);
+    assert_eq!(config.data.borrow().to_vec(), config_before);
+    assert!(take_events().is_empty());
+    assert!(preview.reward_delta > 0);
+
+    let rewards_before = UserState::unpack(&state_before).unwrap().solhit_rewards;
+    update_reward(&program_id, &account_info, &config, now).unwrap();
+    let solhit_rewards = UserState::unpack(&account_info.data.borrow()).unwrap().solhit_rewards;
+    let unlockable = now >= VESTING_PERIOD;
+    assert_eq!(preview, RewardPreview { reward_delta: solhit_rewards - rewards_before, solhit_rewards, unlockable, initialized: true });
+    assert!(matches!(take_events()[..], [PledgeEvent::RewardUpdate { .. }]));
+  }
+}
+
+#[test]
+fn test_unlock_pledge_before_vesting_end() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, locked_state(1000, 0));
+  let before = account_info.data.borrow().to_vec();
+
+  let result = unlock_pledge(&program_id, &unlock_accounts(&program_id, &account_info, &wallet), VESTING_PERIOD - 1);
+  assert_eq!(result, Err(PledgeError::StillVesting.into()));
+  assert_eq!(*account_info.data.borrow(), &before[..]);
+}
+
+#[test]
+fn test_unlock_pledge_at_vesting_end() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, locked_state(1000, 0));
+  take_events();
+
+  unlock_pledge(&program_id, &unlock_accounts(&program_id, &account_info, &wallet), VESTING_PERIOD).unwrap();
+  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.locked_pledge_tokens, 0);
+  assert_eq!(user_state.lot_count, 0);
+  assert_eq!(user_state.solhit_rewards, full_reward(1000));
+  // The event carries what was unlocked, not the zero left behind.
+  assert_eq!(
+    take_events(),
+    vec![PledgeEvent::Unlock { event_seq: 1, user: *account_info.key, wallet, amount: 1000, locked_pledge_tokens: 0 }]
+  );
+
+  // Nothing is left to unlock afterwards.
+  let result = unlock_pledge(&program_id, &unlock_accounts(&program_id, &account_info, &wallet), VESTING_PERIOD);
+  assert_eq!(result, Err(PledgeError::StillVesting.into()));
+}
+
+#[test]
+fn test_unlock_pledge_after_vesting_end() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let mut user_state = locked_state(1000, 0);
+  user_state
+    .add_lot(PledgeLot {
+      amount: 500,
+      rate: 100,
+      lock_start_time: 10,
+      vesting_end_time: 10 + VESTING_PERIOD,
+      multiplier_bps: BASIS_POINTS,
+    })
+    .unwrap();
+  let account_info = user_state_account(&program_id, &wallet, user_state);
+
+  take_events();
+
+  // Only the first lot has vested, the second stays locked.
+  unlock_pledge(&program_id, &unlock_accounts(&program_id, &account_info, &wallet), VESTING_PERIOD + 5).unwrap();
+  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.locked_pledge_tokens, 500);
+  assert_eq!(user_state.lot_count, 1);
+  assert_eq!(
+    take_events(),
+    vec![PledgeEvent::Unlock { event_seq: 1, user: *account_info.key, wallet, amount: 1000, locked_pledge_tokens: 500 }]
+  );
+}
+
+#[test]
+fn test_unlock_pledge_rejects_foreign_vault() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, locked_state(1000, 0));
+
+  let mut accounts = unlock_accounts(&program_id, &account_info, &wallet);
+  accounts[3] = token_account(&Pubkey::new_unique(), TOTAL_PLEDGE_SUPPLY);
+  assert_eq!(unlock_pledge(&program_id, &accounts, VESTING_PERIOD), Err(ProgramError::InvalidAccountData));
+
+  let mut accounts = unlock_accounts(&program_id, &account_info, &wallet);
+  accounts[5] = wallet_account(Box::leak(Box::new(Pubkey::new_unique())), false);
+  assert_eq!(unlock_pledge(&program_id, &accounts, VESTING_PERIOD), Err(ProgramError::InvalidSeeds));
+  assert_eq!(UserState::try_from_slice(&account_info.data.borrow()).unwrap().locked_pledge_tokens, 1000);
+}
+
+// Accounts for EarlyExit: the UnlockPledge accounts with the given config.
+fn early_exit_accounts<'a>(
+  program_id: &Pubkey,
+  account_info: &AccountInfo<'a>,
+  wallet: &'a Pubkey,
+  config: &AccountInfo<'a>,
+) -> Vec<AccountInfo<'a>> {
+  let mut accounts = unlock_accounts(program_id, account_info, wallet);
+  accounts[2] = config.clone();
+  accounts
+}
+
+#[test]
+fn test_early_exit_half_way() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let config = config_account(&program_id, ContractConfig::new());
+  let accounts = [account_info.clone(), wallet_account(&wallet, true), config.clone(), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)];
+
+  buy_pledge(&program_id, &accounts, 1000, DEFAULT_LOCK_TIER, None, false, 0).unwrap();
+  update_reward(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), DEFAULT_LOCK / 2).unwrap();
+  assert!(UserState::try_from_slice(&account_info.data.borrow()).unwrap().solhit_rewards > 0);
+
+  let accounts = early_exit_accounts(&program_id, &account_info, &wallet, &config);
+  early_exit(&program_id, &accounts, DEFAULT_LOCK / 2).unwrap();
+
+  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.locked_pledge_tokens, 0);
+  assert_eq!(user_state.lot_count, 0);
+  assert_eq!(user_state.solhit_rewards, 0);
+  // 2000 pledge tokens bought at 200%, a quarter of which is kept.
+  let pledge_contract = ContractConfig::unpack(&config.data.borrow()).unwrap();
+  assert_eq!(pledge_contract.total_penalties, 500);
+  assert_eq!(pledge_contract.total_pledge_sold, 1500);
+}
+
+// The penalty goes back to the global supply only: the phase the exited lot
+// was bought in stays as sold, so a full phase cap stays full.
+#[test]
+fn test_early_exit_penalty_frees_supply_not_phase() {
+  let program_id = Pubkey::new_unique();
+  let config = config_account(&program_id, ContractConfig {
+    total_pledge_supply: 1000,
+    phase_caps: [1000; 5],
+    ..ContractConfig::new()
+  });
+  let buy_accounts = |account_info: &AccountInfo<'static>, wallet: &'static Pubkey| {
+    [account_info.clone(), wallet_account(wallet, true), config.clone(), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, wallet)]
+  };
+
+  // 500 lamports at 200% fill both the first phase and the supply.
+  let exiting: &'static Pubkey = Box::leak(Box::new(Pubkey::new_unique()));
+  let account_info = user_state_account(&program_id, exiting, UserState::default());
+  buy_pledge(&program_id, &buy_accounts(&account_info, exiting), 500, DEFAULT_LOCK_TIER, None, false, 0).unwrap();
+  early_exit(&program_id, &early_exit_accounts(&program_id, &account_info, exiting, &config), 1).unwrap();
+  let pledge_contract = ContractConfig::unpack(&config.data.borrow()).unwrap();
+  assert_eq!((pledge_contract.total_pledge_sold, pledge_contract.phase_sold[0]), (750, 1000));
+
+  let buyer: &'static Pubkey = Box::leak(Box::new(Pubkey::new_unique()));
+  let account_info = user_state_account(&program_id, buyer, UserState::default());
+  assert_eq!(
+    buy_pledge(&program_id, &buy_accounts(&account_info, buyer), 100, DEFAULT_LOCK_TIER, None, false, 2),
+    Err(PledgeError::PhaseCapExceeded.into())
+  );
+  // The next phase sells what the penalty freed.
+  buy_pledge(&program_id, &buy_accounts(&account_info, buyer), 100, DEFAULT_LOCK_TIER, None, false, PHASE_DURATIONS[0]).unwrap();
+  let pledge_contract = ContractConfig::unpack(&config.data.borrow()).unwrap();
+  assert_eq!(pledge_contract.total_pledge_sold, 750 + 175);
+  assert_eq!(pledge_contract.phase_sold[..2], [1000, 175]);
+}
+
+#[test]
+fn test_early_exit_zero_balance() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let config = config_account(&program_id, ContractConfig::new());
+
+  let accounts = early_exit_accounts(&program_id, &account_info, &wallet, &config);
+  assert_eq!(early_exit(&program_id, &accounts, 0), Err(PledgeError::InvalidAmount.into()));
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().total_penalties, 0);
+}
+
+#[test]
+fn test_early_exit_after_vesting() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, locked_state(1000, 0));
+  let config = config_account(&program_id, ContractConfig::new());
+  let before = account_info.data.borrow().to_vec();
+
+  let accounts = early_exit_accounts(&program_id, &account_info, &wallet, &config);
+  assert_eq!(early_exit(&program_id, &accounts, VESTING_PERIOD), Err(PledgeError::VestingEnded.into()));
+  assert_eq!(*account_info.data.borrow(), &before[..]);
+}
+
+#[test]
+fn test_early_exit_penalty_overflow() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, locked_state(1000, 0));
+  let config = config_account(&program_id, ContractConfig {
+    early_exit_penalty_percent: 101,
+    ..ContractConfig::new()
+  });
+
+  let accounts = early_exit_accounts(&program_id, &account_info, &wallet, &config);
+  assert_eq!(early_exit(&program_id, &accounts, 0), Err(PledgeError::ArithmeticOverflow.into()));
+}
+
+#[test]
+fn test_claim_rewards_rejects_mismatched_vault() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let user_state = UserState {
+    solhit_rewards: 500,
+    ..UserState::default()
+  };
+  let account_info = user_state_account(&program_id, &wallet, user_state);
+
+  // A token account at some other address.
+  let mut accounts = claim_accounts(&program_id, &account_info, &wallet, true);
+  let mint = *accounts[5].key;
+  let vault = Pubkey::new_unique();
+  accounts[3] = token_account_at(vault, &mint, &vault, TOTAL_SOLHIT_SUPPLY);
+  assert_eq!(claim_rewards(&program_id, &accounts, 0, 0), Err(PledgeError::InvalidVault.into()));
+
+  // The derived address, but for a different mint.
+  let mut accounts = claim_accounts(&program_id, &account_info, &wallet, true);
+  accounts[5] = mint_account(Pubkey::new_unique());
+  assert_eq!(claim_rewards(&program_id, &accounts, 0, 0), Err(PledgeError::InvalidVault.into()));
+
+  // Rewards paid to somebody else's token account.
+  let mut accounts = claim_accounts(&program_id, &account_info, &wallet, true);
+  accounts[4] = token_account_at(Pubkey::new_unique(), &mint, &Pubkey::new_unique(), 0);
+  assert_eq!(claim_rewards(&program_id, &accounts, 0, 0), Err(ProgramError::InvalidAccountData));
+
+  assert_eq!(UserState::try_from_slice(&account_info.data.borrow()).unwrap().solhit_rewards, 500);
+}
+
+#[test]
+fn test_claim_rewards_derived_vault() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let user_state = UserState {
+    solhit_rewards: 500,
+    ..UserState::default()
+  };
+  let account_info = user_state_account(&program_id, &wallet, user_state);
+  let (vault, bump) = find_solhit_vault_address(&program_id);
+  assert_eq!(Pubkey::create_program_address(&[SOLHIT_VAULT_SEED, &[bump]], &program_id), Ok(vault));
+
+  let result = claim_rewards(&program_id, &claim_accounts(&program_id, &account_info, &wallet, true), 0, 0);
+  assert!(result.is_ok());
+  assert_eq!(UserState::try_from_slice(&account_info.data.borrow()).unwrap().solhit_rewards, 0);
+}
+
+#[test]
+fn test_claim_rewards_rejects_aliased_accounts() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState { solhit_rewards: 500, ..UserState::default() });
+
+  // The vault standing in for the user state, the config or the destination.
+  for index in [0, 2, 4] {
+    let mut accounts = claim_accounts(&program_id, &account_info, &wallet, true);
+    accounts[index] = accounts[3].clone();
+    assert_eq!(claim_rewards(&program_id, &accounts, 0, 0), Err(PledgeError::DuplicateAccount.into()));
+  }
+  // The user state passed again as the config.
+  let mut accounts = claim_accounts(&program_id, &account_info, &wallet, true);
+  accounts[2] = account_info.clone();
+  assert_eq!(claim_rewards(&program_id, &accounts, 0, 0), Err(PledgeError::DuplicateAccount.into()));
+
+  assert_eq!(UserState::try_from_slice(&account_info.data.borrow()).unwrap().solhit_rewards, 500);
+}
+
+#[test]
+fn test_accrue_rewards() {
+  let mut user_state = locked_state(1000, 100);
+
+  user_state.accrue_rewards(REWARD_RATE_BPS, 0, 100 + VESTING_PERIOD / 2).unwrap();
+  assert_eq!(user_state.solhit_rewards, full_reward(1000) / 2);
+  user_state.accrue_rewards(REWARD_RATE_BPS, 0, 100 + 2 * VESTING_PERIOD).unwrap();
+  assert_eq!(user_state.solhit_rewards, full_reward(1000));
+  assert_eq!(user_state.last_reward_update, 100 + 2 * VESTING_PERIOD);
+}
+
+#[test]
+fn test_accrue_rewards_post_vesting_grace() {
+  let vesting_end = 100 + VESTING_PERIOD;
+  let grace = VESTING_PERIOD / 2;
+
+  // Without a grace period nothing accrues past the vesting end.
+  let mut user_state = locked_state(1000, 100);
+  user_state.accrue_rewards(REWARD_RATE_BPS, 0, vesting_end + VESTING_PERIOD).unwrap();
+  assert_eq!(user_state.solhit_rewards, full_reward(1000));
+
+  // With one, the lot keeps its pace until the grace period runs out, right
+  // at the boundary and not a second later.
+  let lot = user_state.lots[0];
+  assert_eq!(lot.accrued_rewards(REWARD_RATE_BPS, grace, vesting_end + grace - 1), Ok(full_reward(1000) * 3 / 2 - 1));
+  assert_eq!(lot.accrued_rewards(REWARD_RATE_BPS, grace, vesting_end + grace), Ok(full_reward(1000) * 3 / 2));
+  assert_eq!(lot.accrued_rewards(REWARD_RATE_BPS, grace, vesting_end + grace + 1), Ok(full_reward(1000) * 3 / 2));
+  assert_eq!(lot.accrued_rewards(REWARD_RATE_BPS, grace, u64::MAX), Ok(full_reward(1000) * 3 / 2));
+
+  // Calls straddling the vesting end and the end of the grace period add up
+  // to what a single call credits.
+  let mut split = locked_state(1000, 100);
+  for time in [vesting_end - 10, vesting_end + grace / 2, vesting_end + grace, vesting_end + grace + 1, vesting_end + 2 * grace] {
+    split.accrue_rewards(REWARD_RATE_BPS, grace, time).unwrap();
+  }
+  let mut single = locked_state(1000, 100);
+  single.accrue_rewards(REWARD_RATE_BPS, grace, vesting_end + 2 * grace).unwrap();
+  assert_eq!(split.solhit_rewards, full_reward(1000) * 3 / 2);
+  assert_eq!(split.solhit_rewards, single.solhit_rewards);
+
+  // u64::MAX never stops the lot.
+  let mut user_state = locked_state(1000, 100);
+  user_state.accrue_rewards(REWARD_RATE_BPS, u64::MAX, vesting_end + 3 * VESTING_PERIOD).unwrap();
+  assert_eq!(user_state.solhit_rewards, full_reward(1000) * 4);
+}
+
+#[test]
+fn test_set_post_vesting_grace() {
+  let program_id = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, locked_state(1000, 0));
+  let config = config_account(&program_id, ContractConfig { admin, ..ContractConfig::new() });
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().post_vesting_grace_secs, 0);
+
+  assert_eq!(
+    set_post_vesting_grace(&program_id, &[config.clone(), wallet_account(&wallet, true)], 1_000),
+    Err(PledgeError::Unauthorized.into())
+  );
+  set_post_vesting_grace(&program_id, &[config.clone(), wallet_account(&admin, true)], VESTING_PERIOD).unwrap();
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().post_vesting_grace_secs, VESTING_PERIOD);
+
+  update_reward(&program_id, &account_info, &config, 3 * VESTING_PERIOD).unwrap();
+  assert_eq!(UserState::unpack(&account_info.data.borrow()).unwrap().solhit_rewards, full_reward(1000) * 2);
+}
+
+#[test]
+fn test_config_version_mismatch() {
+  let program_id = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, locked_state(1000, 0));
+  let config = config_account(&program_id, ContractConfig { admin, config_version: 1, ..ContractConfig::new() });
+
+  let mismatch = Err(PledgeError::ConfigVersionMismatch.into());
+  assert_eq!(update_reward(&program_id, &account_info, &config, VESTING_PERIOD), mismatch);
+  assert_eq!(set_paused(&program_id, &[config.clone(), wallet_account(&admin, true)], true), mismatch);
+  let newer = config_account(&program_id, ContractConfig { admin, config_version: CONFIG_VERSION + 1, ..ContractConfig::new() });
+  assert_eq!(migrate_config(&program_id, &[newer, wallet_account(&admin, true), system_program_account()], &Rent::default()), mismatch);
+
+  // Already LEN bytes long, so the upgrade needs no realloc.
+  let accounts = [config.clone(), wallet_account(&admin, true), system_program_account()];
+  assert_eq!(
+    migrate_config(&program_id, &[config.clone(), wallet_account(&wallet, true), system_program_account()], &Rent::default()),
+    Err(PledgeError::Unauthorized.into())
+  );
+  migrate_config(&program_id, &accounts, &Rent::default()).unwrap();
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().config_version, CONFIG_VERSION);
+  update_reward(&program_id, &account_info, &config, VESTING_PERIOD).unwrap();
+  // Running it again changes nothing.
+  let data = config.data.borrow().to_vec();
+  migrate_config(&program_id, &accounts, &Rent::default()).unwrap();
+  assert_eq!(config.data.borrow().to_vec(), data);
+}
+
+// A config migrated from before it counted users stops counting them, rather
+// than starting from 0 with users it never saw.
+#[test]
+fn test_migrated_config_leaves_users_uncounted() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let config = config_account(&program_id, ContractConfig { admin: wallet, config_version: 7, ..ContractConfig::new() });
+  migrate_config(&program_id, &[config.clone(), wallet_account(&wallet, true), system_program_account()], &Rent::default()).unwrap();
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().initialized_users, USERS_UNCOUNTED);
+
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  close_user_state(&program_id, &[account_info, wallet_account(&wallet, true), config.clone()]).unwrap();
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().initialized_users, USERS_UNCOUNTED);
+
+  // A counting config never goes below 0.
+  let mut pledge_contract = ContractConfig::new();
+  assert_eq!(pledge_contract.remove_user(), Err(PledgeError::ArithmeticOverflow));
+  pledge_contract.add_user().unwrap();
+  pledge_contract.remove_user().unwrap();
+  assert_eq!(pledge_contract.initialized_users, 0);
+}
+
+#[test]
+fn test_claim_rewards_without_update_reward() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let config = config_account(&program_id, ContractConfig::new());
+  buy_pledge(&program_id, &[account_info.clone(), wallet_account(&wallet, true), config, treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)], 1000, DEFAULT_LOCK_TIER, None, false, 0).unwrap();
+
+  claim_rewards(&program_id, &claim_accounts(&program_id, &account_info, &wallet, true), 0, VESTING_PERIOD).unwrap();
+  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.solhit_rewards, 0);
+  assert_eq!(user_state.last_reward_update, VESTING_PERIOD);
+
+  // Nothing earned twice: a later update has nothing left to credit.
+  update_reward(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), 2 * VESTING_PERIOD).unwrap();
+  assert_eq!(UserState::try_from_slice(&account_info.data.borrow()).unwrap().solhit_rewards, 0);
+}
+
+#[test]
+fn test_claim_rewards_partial() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let user_state = UserState {
+    solhit_rewards: 500,
+    ..UserState::default()
+  };
+  let account_info = user_state_account(&program_id, &wallet, user_state);
+  let accounts = claim_accounts(&program_id, &account_info, &wallet, true);
+
+  claim_rewards(&program_id, &accounts, 200, 0).unwrap();
+  assert_eq!(UserState::try_from_slice(&account_info.data.borrow()).unwrap().solhit_rewards, 300);
+
+  // The remainder is still claimable.
+  claim_rewards(&program_id, &accounts, 300, 0).unwrap();
+  assert_eq!(UserState::try_from_slice(&account_info.data.borrow()).unwrap().solhit_rewards, 0);
+}
+
+#[test]
+fn test_claim_rewards_over_claim() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let user_state = UserState {
+    solhit_rewards: 500,
+    ..UserState::default()
+  };
+  let account_info = user_state_account(&program_id, &wallet, user_state);
+  let accounts = claim_accounts(&program_id, &account_info, &wallet, true);
+
+  assert_eq!(claim_rewards(&program_id, &accounts, 501, 0), Err(PledgeError::InsufficientRewards.into()));
+  assert_eq!(UserState::try_from_slice(&account_info.data.borrow()).unwrap().solhit_rewards, 500);
+
+  claim_rewards(&program_id, &accounts, 500, 0).unwrap();
+  assert_eq!(UserState::try_from_slice(&account_info.data.borrow()).unwrap().solhit_rewards, 0);
+}
+
+#[test]
+fn test_claim_rewards_tracks_claimed_totals() {
+  let program_id = Pubkey::new_unique();
+  let first_wallet = Pubkey::new_unique();
+  let second_wallet = Pubkey::new_unique();
+  let first = user_state_account(&program_id, &first_wallet, UserState { solhit_rewards: 500, ..UserState::default() });
+  let second = user_state_account(&program_id, &second_wallet, UserState { solhit_rewards: 800, ..UserState::default() });
+  // Only 1000 Solheist left for payouts.
+  let config = config_account(&program_id, ContractConfig {
+    global_solhit_claimed: TOTAL_SOLHIT_SUPPLY - LOCKED_SOLHIT_TOKENS - 1000,
+    ..ContractConfig::new()
+  });
+  let mut first_accounts = claim_accounts(&program_id, &first, &first_wallet, true);
+  first_accounts[2] = config.clone();
+  let mut second_accounts = claim_accounts(&program_id, &second, &second_wallet, true);
+  second_accounts[2] = config.clone();
+
+  claim_rewards(&program_id, &first_accounts, 200, 0).unwrap();
+  claim_rewards(&program_id, &second_accounts, 500, 0).unwrap();
+  claim_rewards(&program_id, &first_accounts, 300, 0).unwrap();
+
+  let first_state = UserState::try_from_slice(&first.data.borrow()).unwrap();
+  assert_eq!(first_state.total_claimed, 500);
+  assert_eq!(first_state.solhit_rewards, 0);
+  let pledge_contract = ContractConfig::unpack(&config.data.borrow()).unwrap();
+  assert_eq!(pledge_contract.global_solhit_claimed, TOTAL_SOLHIT_SUPPLY - LOCKED_SOLHIT_TOKENS);
+
+  // The cap is reached, the second user's remaining rewards cannot be paid.
+  assert_eq!(claim_rewards(&program_id, &second_accounts, 0, 0), Err(PledgeError::RewardsExhausted.into()));
+  let second_state = UserState::try_from_slice(&second.data.borrow()).unwrap();
+  assert_eq!(second_state.total_claimed, 500);
+  assert_eq!(second_state.solhit_rewards, 300);
+}
+
+#[test]
+fn test_claim_rewards_keeps_unreleased_team_tokens() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState { solhit_rewards: 500, ..UserState::default() });
+  let mut accounts = claim_accounts(&program_id, &account_info, &wallet, true);
+  // The vault holds the whole team lock and only 100 Solheist more.
+  let (vault, _) = find_solhit_vault_address(&program_id);
+  accounts[3] = token_account_at(vault, accounts[5].key, &vault, LOCKED_SOLHIT_TOKENS + 100);
+
+  assert_eq!(claim_rewards(&program_id, &accounts, 101, 0), Err(PledgeError::InsufficientVaultBalance.into()));
+  claim_rewards(&program_id, &accounts, 100, 0).unwrap();
+  assert_eq!(claim_rewards(&program_id, &accounts, 400, 0), Err(PledgeError::InsufficientVaultBalance.into()));
+
+  // Team tokens already released are no longer set aside.
+  accounts[2] = config_account(&program_id, ContractConfig { team_released: 300, ..ContractConfig::new() });
+  claim_rewards(&program_id, &accounts, 400, 0).unwrap();
+  assert_eq!(UserState::unpack(&account_info.data.borrow()).unwrap().total_claimed, 500);
+}
+
+#[test]
+fn test_claim_rewards_writes_before_transfer() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState { solhit_rewards: 500, ..UserState::default() });
+  let accounts = claim_accounts(&program_id, &account_info, &wallet, true);
+  take_events();
+
+  // The claim is already recorded when the transfer runs. Off-chain nothing
+  // rolls the accounts back, but on-chain the failed transfer reverts them.
+  fail_cpis(true);
+  let result = claim_rewards(&program_id, &accounts, 0, 0);
+  fail_cpis(false);
+  assert_eq!(result, Err(ProgramError::InsufficientFunds));
+  let user_state = UserState::unpack(&account_info.data.borrow()).unwrap();
+  assert_eq!((user_state.solhit_rewards, user_state.total_claimed), (0, 500));
+  let pledge_contract = ContractConfig::unpack(&accounts[2].data.borrow()).unwrap();
+  assert_eq!((pledge_contract.global_solhit_claimed, pledge_contract.event_seq), (500, 1));
+  assert!(take_events().is_empty());
+}
+
+#[test]
+fn test_claim_rewards_nothing_to_claim() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+
+  let result = claim_rewards(&program_id, &claim_accounts(&program_id, &account_info, &wallet, true), 0, 0);
+  assert_eq!(result, Err(ProgramError::Custom(PledgeError::NothingToClaim as u32)));
+}
+
+#[test]
+fn test_claim_rewards_emits_claimed_amount() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let user_state = UserState {
+    solhit_rewards: 500,
+    ..UserState::default()
+  };
+  let account_info = user_state_account(&program_id, &wallet, user_state);
+  take_events();
+
+  claim_rewards(&program_id, &claim_accounts(&program_id, &account_info, &wallet, true), 0, 0).unwrap();
+  assert_eq!(
+    take_events(),
+    vec![PledgeEvent::RewardClaim { event_seq: 1, user: *account_info.key, wallet, claimed: 500, remaining: 0, fee: 0 }]
+  );
+  assert_eq!(UserState::try_from_slice(&account_info.data.borrow()).unwrap().solhit_rewards, 0);
+}
+
+// ClaimRewards accounts for a user with `rewards` to claim under a claim fee
+// of `claim_fee_bps`, followed by the treasury's Solheist token account.
+fn claim_fee_accounts<'a>(
+  program_id: &Pubkey,
+  account_info: &AccountInfo<'a>,
+  wallet: &'a Pubkey,
+  claim_fee_bps: u16,
+) -> Vec<AccountInfo<'a>> {
+  let mut accounts = claim_accounts(program_id, account_info, wallet, true);
+  accounts[2] = config_account(program_id, ContractConfig { claim_fee_bps, ..ContractConfig::new() });
+  let mint = *accounts[5].key;
+  let (treasury, _) = find_treasury_address(program_id);
+  accounts.push(token_account_at(claim_fee_address(program_id, &mint), &mint, &treasury, 0));
+  accounts
+}
+
+// The destination and amount of each token transfer invoked since the last call.
+fn take_transfers() -> Vec<(Pubkey, u64)> {
+  take_cpis()
+    .iter()
+    .map(|instruction| match spl_token::instruction::TokenInstruction::unpack(&instruction.data).unwrap() {
+      spl_token::instruction::TokenInstruction::TransferChecked { amount, .. } => (instruction.accounts[2].pubkey, amount),
+      other => panic!("unexpected CPI {:?}", other),
+    })
+    .collect()
+}
+
+#[test]
+fn test_claim_rewards_splits_claim_fee() {
+  let program_id = Pubkey::new_unique();
+  let wallet: &'static Pubkey = Box::leak(Box::new(Pubkey::new_unique()));
+  let claim = |claim_fee_bps: u16, rewards: u64| {
+    let account_info = user_state_account(&program_id, wallet, UserState { solhit_rewards: rewards, ..UserState::default() });
+    let accounts = claim_fee_accounts(&program_id, &account_info, wallet, claim_fee_bps);
+    take_events();
+    take_cpis();
+    claim_rewards(&program_id, &accounts, 0, 0).unwrap();
+    let [PledgeEvent::RewardClaim { claimed, fee, .. }] = take_events()[..] else { panic!("expected a RewardClaim") };
+    assert_eq!(claimed, rewards);
+    (*accounts[4].key, *accounts[7].key, fee, take_transfers())
+  };
+
+  // No fee: a single transfer of the whole claim.
+  let (user_token, _, fee, transfers) = claim(0, 10_000);
+  assert_eq!(fee, 0);
+  assert_eq!(transfers, vec![(user_token, 10_000)]);
+
+  // 100 bps: 1% to the treasury in a second transfer.
+  let (user_token, fee_account, fee, transfers) = claim(100, 10_000);
+  assert_eq!(fee, 100);
+  assert_eq!(transfers, vec![(user_token, 9_900), (fee_account, 100)]);
+
+  // The fee rounds down, never costing the user more than 1%: 199 pays 1,
+  // and anything under 100 pays nothing and makes a single transfer.
+  let (user_token, fee_account, fee, transfers) = claim(100, 199);
+  assert_eq!(fee, 1);
+  assert_eq!(transfers, vec![(user_token, 198), (fee_account, 1)]);
+  for rewards in [1, 99] {
+    let (user_token, _, fee, transfers) = claim(100, rewards);
+    assert_eq!(fee, 0);
+    assert_eq!(transfers, vec![(user_token, rewards)]);
+  }
+}
+
+#[test]
+fn test_claim_rewards_checks_fee_account() {
+  let program_id = Pubkey::new_unique();
+  let wallet: &'static Pubkey = Box::leak(Box::new(Pubkey::new_unique()));
+  let invalid_fee_account = Err(ProgramError::Custom(PledgeError::InvalidFeeAccount as u32));
+  let claim = |change: &dyn Fn(&mut Vec<AccountInfo<'static>>)| {
+    let user_state = UserState { solhit_rewards: 10_000, ..UserState::default() };
+    let account_info = user_state_account(&program_id, wallet, user_state);
+    let mut accounts = claim_fee_accounts(&program_id, &account_info, wallet, 100);
+    change(&mut accounts);
+    let result = claim_rewards(&program_id, &accounts, 0, 0);
+    let rewards = UserState::try_from_slice(&account_info.data.borrow()).unwrap().solhit_rewards;
+    (result, rewards)
+  };
+
+  // Left out, or replaced by another of the treasury's token accounts.
+  assert_eq!(claim(&|accounts| drop(accounts.pop())), (invalid_fee_account.clone(), 10_000));
+  let (treasury, _) = find_treasury_address(&program_id);
+  let other = |accounts: &mut Vec<AccountInfo<'static>>| {
+    let mint = *accounts[5].key;
+    accounts[7] = token_account_at(Pubkey::new_unique(), &mint, &treasury, 0);
+  };
+  assert_eq!(claim(&other), (invalid_fee_account.clone(), 10_000));
+  // Nor is it looked for anywhere but in its own place, ahead of the
+  // accounts creating the beneficiary's token account.
+  let moved_back = |accounts: &mut Vec<AccountInfo<'static>>| {
+    accounts.splice(7..7, [system_program_account(), wallet_account(&spl_associated_token_account::ID, false)]);
+  };
+  assert_eq!(claim(&moved_back), (invalid_fee_account.clone(), 10_000));
+
+  // At the right address but held by someone else, of another mint, not
+  // yet created, or read-only.
+  let held_by_wallet = |accounts: &mut Vec<AccountInfo<'static>>| {
+    let mint = *accounts[5].key;
+    accounts[7] = token_account_at(*accounts[7].key, &mint, wallet, 0);
+  };
+  assert_eq!(claim(&held_by_wallet), (invalid_fee_account.clone(), 10_000));
+  let other_mint = |accounts: &mut Vec<AccountInfo<'static>>| {
+    accounts[7] = token_account_at(*accounts[7].key, &Pubkey::new_unique(), &treasury, 0);
+  };
+  assert_eq!(claim(&other_mint), (invalid_fee_account.clone(), 10_000));
+  let not_created = |accounts: &mut Vec<AccountInfo<'static>>| {
+    accounts[7] = wallet_account(accounts[7].key, false);
+    accounts[7].is_writable = true;
+  };
+  assert_eq!(claim(&not_created), (invalid_fee_account, 10_000));
+  let read_only = |accounts: &mut Vec<AccountInfo<'static>>| accounts[7].is_writable = false;
+  assert_eq!(claim(&read_only), (Err(ProgramError::Custom(PledgeError::AccountNotWritable as u32)), 10_000));
+
+  assert_eq!(claim(&|_| ()), (Ok(()), 0));
+}
+
+#[test]
+fn test_set_claim_fee() {
+  let program_id = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let config = config_account(&program_id, ContractConfig { admin, ..ContractConfig::new() });
+  let stranger = Pubkey::new_unique();
+
+  let result = set_claim_fee(&program_id, &[config.clone(), wallet_account(&stranger, true)], 100);
+  assert_eq!(result, Err(ProgramError::Custom(PledgeError::Unauthorized as u32)));
+  set_claim_fee(&program_id, &[config.clone(), wallet_account(&admin, true)], 100).unwrap();
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().claim_fee_bps, 100);
+
+  let result = set_claim_fee(&program_id, &[config.clone(), wallet_account(&admin, true)], 10_001);
+  assert_eq!(result, Err(ProgramError::Custom(PledgeError::InvalidConfig as u32)));
+  set_claim_fee(&program_id, &[config.clone(), wallet_account(&admin, true)], 10_000).unwrap();
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().claim_fee_bps, 10_000);
+}
+
+#[test]
+fn test_set_beneficiary() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let cold_wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState { solhit_rewards: 500, ..UserState::default() });
+  assert_eq!(UserState::unpack(&account_info.data.borrow()).unwrap().beneficiary, wallet);
+
+  // Rewards go to the wallet's own token account until the owner changes it.
+  let mut accounts = claim_accounts(&program_id, &account_info, &wallet, true);
+  claim_rewards(&program_id, &accounts, 100, 0).unwrap();
+
+  set_beneficiary(&program_id, &[account_info.clone(), wallet_account(&wallet, true)], cold_wallet).unwrap();
+  assert_eq!(UserState::unpack(&account_info.data.borrow()).unwrap().beneficiary, cold_wallet);
+  assert_eq!(claim_rewards(&program_id, &accounts, 100, 0), Err(ProgramError::InvalidAccountData));
+
+  let mint = *accounts[5].key;
+  accounts[4] = token_account_at(Pubkey::new_unique(), &mint, &cold_wallet, 0);
+  claim_rewards(&program_id, &accounts, 100, 0).unwrap();
+  assert_eq!(UserState::unpack(&account_info.data.borrow()).unwrap().solhit_rewards, 300);
+}
+
+#[test]
+fn test_set_beneficiary_requires_owner() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let attacker = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+
+  let result = set_beneficiary(&program_id, &[account_info.clone(), wallet_account(&attacker, true)], attacker);
+  assert_eq!(result, Err(ProgramError::InvalidSeeds));
+  let result = set_beneficiary(&program_id, &[account_info.clone(), wallet_account(&wallet, false)], attacker);
+  assert_eq!(result, Err(ProgramError::MissingRequiredSignature));
+  assert_eq!(UserState::unpack(&account_info.data.borrow()).unwrap().beneficiary, wallet);
+}
+
+#[test]
+fn test_delegate_claims_rewards() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let bot = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState { solhit_rewards: 500, ..UserState::default() });
+  let mut accounts = claim_accounts(&program_id, &account_info, &wallet, true);
+  accounts[1] = wallet_account(&bot, true);
+
+  assert_eq!(claim_rewards(&program_id, &accounts, 100, 0), Err(ProgramError::InvalidSeeds));
+  set_delegate(&program_id, &[account_info.clone(), wallet_account(&wallet, true)], Some(bot)).unwrap();
+  assert_eq!(UserState::unpack(&account_info.data.borrow()).unwrap().delegate, Some(bot));
+
+  // The delegate claims, but the rewards still go to the wallet's account.
+  claim_rewards(&program_id, &accounts, 100, 0).unwrap();
+  let user_state = UserState::unpack(&account_info.data.borrow()).unwrap();
+  assert_eq!((user_state.solhit_rewards, user_state.total_claimed), (400, 100));
+  accounts[4] = token_account_at(Pubkey::new_unique(), accounts[5].key, &bot, 0);
+  assert_eq!(claim_rewards(&program_id, &accounts, 100, 0), Err(ProgramError::InvalidAccountData));
+}
+
+#[test]
+fn test_delegate_cannot_manage_position() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let bot = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState { delegate: Some(bot), ..locked_state(1000, 0) });
+  let config = config_account(&program_id, ContractConfig::new());
+  let before = account_info.data.borrow().to_vec();
+
+  let as_bot = [account_info.clone(), wallet_account(&bot, true)];
+  assert_eq!(set_beneficiary(&program_id, &as_bot, bot), Err(ProgramError::InvalidSeeds));
+  assert_eq!(set_delegate(&program_id, &as_bot, Some(Pubkey::new_unique())), Err(ProgramError::InvalidSeeds));
+  assert_eq!(set_delegate(&program_id, &as_bot, None), Err(ProgramError::InvalidSeeds));
+  let mut accounts = early_exit_accounts(&program_id, &account_info, &wallet, &config);
+  accounts[1] = wallet_account(&bot, true);
+  assert_eq!(early_exit(&program_id, &accounts, VESTING_PERIOD / 2), Err(ProgramError::InvalidSeeds));
+  let destination = user_state_account(&program_id, &bot, UserState::default());
+  let accounts = [account_info.clone(), wallet_account(&bot, true), destination, config];
+  assert_eq!(transfer_position(&program_id, &accounts, 0), Err(ProgramError::InvalidSeeds));
+  assert_eq!(*account_info.data.borrow(), &before[..]);
+}
+
+#[test]
+fn test_revoked_delegate_cannot_claim() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let bot = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState { solhit_rewards: 500, delegate: Some(bot), ..UserState::default() });
+  let mut accounts = claim_accounts(&program_id, &account_info, &wallet, true);
+  accounts[1] = wallet_account(&bot, true);
+  claim_rewards(&program_id, &accounts, 100, 0).unwrap();
+
+  set_delegate(&program_id, &[account_info.clone(), wallet_account(&wallet, true)], None).unwrap();
+  assert_eq!(UserState::unpack(&account_info.data.borrow()).unwrap().delegate, None);
+  assert_eq!(claim_rewards(&program_id, &accounts, 100, 0), Err(ProgramError::InvalidSeeds));
+  // The owner can still claim.
+  claim_rewards(&program_id, &claim_accounts(&program_id, &account_info, &wallet, true), 100, 0).unwrap();
+  assert_eq!(UserState::unpack(&account_info.data.borrow()).unwrap().solhit_rewards, 300);
+}
+
+#[test]
+fn test_compound_rewards() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, locked_state(1000, 0));
+  let config = config_account(&program_id, ContractConfig { total_pledge_sold: 1000, ..ContractConfig::new() });
+  let accounts = [account_info.clone(), wallet_account(&wallet, true), config.clone()];
+  take_events();
+
+  // Half way through, half the full reward is locked as new pledge tokens.
+  compound_rewards(&program_id, &accounts, VESTING_PERIOD / 2).unwrap();
+  let rewards = full_reward(1000) / 2;
+  let compounded = rewards * COMPOUND_RATE / 100;
+  let user_state = UserState::unpack(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.solhit_rewards, 0);
+  assert_eq!(user_state.locked_pledge_tokens, 1000 + compounded);
+  assert_eq!(user_state.vesting_end_time, VESTING_PERIOD + COMPOUND_VESTING_BOOST);
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().total_pledge_sold, 1000 + compounded);
+  assert_eq!(
+    take_events(),
+    vec![PledgeEvent::Compound {
+      event_seq: 1,
+      user: *account_info.key,
+      wallet,
+      solhit_rewards: rewards,
+      pledge_tokens: compounded,
+      locked_before: 1000,
+      locked_after: 1000 + compounded,
+    }]
   );
 
-  let amount = 500;
-  let current_time = 1_000_000;
+  // Nothing new has accrued yet.
+  assert_eq!(
+    compound_rewards(&program_id, &accounts, VESTING_PERIOD / 2),
+    Err(PledgeError::NothingToCompound.into())
+  );
 
-  let _result = buy_pledge(&account_info, amount, current_time);
+  // Compounding again takes the rest of the first lot and what the
+  // compounded lot has earned since, and pushes the vesting end back again.
+  let compounded_lot = user_state.lots[1];
+  compound_rewards(&program_id, &accounts, VESTING_PERIOD).unwrap();
+  let second = rewards + compounded_lot.accrued_rewards(REWARD_RATE_BPS, 0, VESTING_PERIOD).unwrap();
+  let user_state = UserState::unpack(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.lot_count, 3);
+  assert_eq!(user_state.locked_pledge_tokens, 1000 + compounded + second * COMPOUND_RATE / 100);
+  assert_eq!(user_state.vesting_end_time, VESTING_PERIOD + 2 * COMPOUND_VESTING_BOOST);
+  assert_eq!(user_state.solhit_rewards, 0);
+}
 
-  let user_state = UserState::try_from_slice(&account_info.data.borrow()).unwrap();
-  let pledge_contract = PledgeContract::new();
+#[test]
+fn test_compound_rewards_respects_supply() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState { solhit_rewards: 500, ..locked_state(1000, 0) });
+  let before = account_info.data.borrow().to_vec();
+  let pledge_contract = ContractConfig { total_pledge_supply: 1499, total_pledge_sold: 1000, ..ContractConfig::new() };
+  let accounts = [account_info.clone(), wallet_account(&wallet, true), config_account(&program_id, pledge_contract)];
+
+  assert_eq!(compound_rewards(&program_id, &accounts, 0), Err(PledgeError::SupplyExhausted.into()));
+  assert_eq!(*account_info.data.borrow(), &before[..]);
 
-  assert_eq!(user_state.vesting_end_time, current_time + pledge_contract.vesting_period);
+  let accounts = [account_info.clone(), wallet_account(&wallet, false), accounts[2].clone()];
+  assert_eq!(compound_rewards(&program_id, &accounts, 0), Err(ProgramError::MissingRequiredSignature));
 }
 
 #[test]
-fn test_buy_pledge_exceed_supply() {
-  let mut account_data = vec![0u8; std::mem::size_of::<UserState>()];
-  let pubkey = Pubkey::new_unique();
-  let mut lamports = 1000;
-  let account_info = AccountInfo::new(
-    &pubkey,
+fn test_transfer_position_into_empty_destination() {
+  install_capture();
+  let program_id = Pubkey::new_unique();
+  let (wallet, new_wallet) = (Pubkey::new_unique(), Pubkey::new_unique());
+  let account_info = user_state_account(&program_id, &wallet, UserState { total_purchased: 1000, purchase_count: 1, ..locked_state(1000, 0) });
+  let destination = user_state_account(&program_id, &new_wallet, UserState::default());
+  let config = config_account(&program_id, ContractConfig::new());
+  take_events();
+
+  transfer_position(&program_id, &[account_info.clone(), wallet_account(&wallet, true), destination.clone(), config], VESTING_PERIOD / 2).unwrap();
+  let rewards = full_reward(1000) / 2;
+  let moved = UserState::unpack(&destination.data.borrow()).unwrap();
+  assert_eq!((moved.locked_pledge_tokens, moved.solhit_rewards), (1000, rewards));
+  assert_eq!((moved.lock_start_time, moved.vesting_end_time), (0, VESTING_PERIOD));
+  assert_eq!(moved.owner, new_wallet);
+  // The source keeps its history but nothing else.
+  let source = UserState::unpack(&account_info.data.borrow()).unwrap();
+  assert_eq!((source.lot_count, source.locked_pledge_tokens, source.solhit_rewards), (0, 0, 0));
+  assert_eq!((source.total_purchased, source.purchase_count), (1000, 1));
+  assert_eq!(
+    take_events(),
+    vec![PledgeEvent::PositionTransfer {
+      event_seq: 1,
+      user: *account_info.key,
+      wallet,
+      destination: *destination.key,
+      pledge_tokens: 1000,
+      solhit_rewards: rewards,
+    }]
+  );
+}
+
+#[test]
+fn test_transfer_position_merges_into_existing_position() {
+  let program_id = Pubkey::new_unique();
+  let (wallet, new_wallet) = (Pubkey::new_unique(), Pubkey::new_unique());
+  let account_info = user_state_account(&program_id, &wallet, locked_state(1000, 0));
+  let destination = user_state_account(&program_id, &new_wallet, UserState { solhit_rewards: 7, ..locked_state(500, 100) });
+  let config = config_account(&program_id, ContractConfig::new());
+  let accounts = [account_info.clone(), wallet_account(&wallet, true), destination.clone(), config];
+  let now = VESTING_PERIOD / 2;
+
+  transfer_position(&program_id, &accounts, now).unwrap();
+  let merged = UserState::unpack(&destination.data.borrow()).unwrap();
+  // The earlier lock start comes from the source, the later vesting end from
+  // the destination; both lots keep vesting on their own schedule.
+  assert_eq!(merged.locked_pledge_tokens, 1500);
+  assert_eq!((merged.lock_start_time, merged.vesting_end_time), (0, 100 + VESTING_PERIOD));
+  assert_eq!(merged.lot_count, 2);
+  let expected = 7 + locked_state(500, 100).lots[0].accrued_rewards(REWARD_RATE_BPS, 0, now).unwrap() + full_reward(1000) / 2;
+  assert_eq!(merged.solhit_rewards, expected);
+  assert_eq!(merged.last_reward_update, now);
+
+  // Rewards carry on from the transfer without counting anything twice.
+  update_reward(&program_id, &destination, &accounts[3], VESTING_PERIOD + 100).unwrap();
+  let merged = UserState::unpack(&destination.data.borrow()).unwrap();
+  assert_eq!(merged.solhit_rewards, 7 + full_reward(500) + full_reward(1000));
+
+  // An emptied source has nothing left to move.
+  assert_eq!(transfer_position(&program_id, &accounts, VESTING_PERIOD + 100), Err(PledgeError::NothingToTransfer.into()));
+}
+
+#[test]
+fn test_transfer_position_requires_source_wallet() {
+  let program_id = Pubkey::new_unique();
+  let wallet: &'static Pubkey = Box::leak(Box::new(Pubkey::new_unique()));
+  let new_wallet: &'static Pubkey = Box::leak(Box::new(Pubkey::new_unique()));
+  let account_info = user_state_account(&program_id, wallet, locked_state(1000, 0));
+  let destination = user_state_account(&program_id, new_wallet, UserState::default());
+  let config = config_account(&program_id, ContractConfig::new());
+  let before = account_info.data.borrow().to_vec();
+
+  let accounts = |signer: &'static Pubkey, is_signer: bool, destination: &AccountInfo<'static>| {
+    vec![account_info.clone(), wallet_account(signer, is_signer), destination.clone(), config.clone()]
+  };
+  assert_eq!(transfer_position(&program_id, &accounts(wallet, false, &destination), 0), Err(ProgramError::MissingRequiredSignature));
+  // The destination's wallet cannot pull the position over either.
+  assert_eq!(transfer_position(&program_id, &accounts(new_wallet, true, &destination), 0), Err(ProgramError::InvalidSeeds));
+  // Only into an initialized user state, and not into itself.
+  let uninitialized = AccountInfo::new(
+    destination.key,
     false,
     true,
-    &mut lamports,
-    &mut account_data,
-    &pubkey,
+    Box::leak(Box::new(1_000_000)),
+    vec![0; UserState::LEN].leak(),
+    Box::leak(Box::new(program_id)),
     false,
     0,
   );
+  assert_eq!(transfer_position(&program_id, &accounts(wallet, true, &uninitialized), 0), Err(ProgramError::UninitializedAccount));
+  assert_eq!(transfer_position(&program_id, &accounts(wallet, true, &account_info), 0), Err(PledgeError::DuplicateAccount.into()));
+  assert_eq!(*account_info.data.borrow(), &before[..]);
+}
 
-  let pledge_contract = PledgeContract::new();
-  let amount = pledge_contract.total_pledge_supply + 1;
-  let current_time = 1_000_000;
+#[test]
+fn test_buy_pledge_emits_purchase() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let config = config_account(&program_id, ContractConfig::new());
+  take_events();
+
+  let user = *account_info.key;
+  buy_pledge(&program_id, &[account_info, wallet_account(&wallet, true), config, treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)], 100, DEFAULT_LOCK_TIER, None, false, 0).unwrap();
+  assert_eq!(
+    take_events(),
+    vec![PledgeEvent::Purchase {
+      event_seq: 1,
+      user,
+      wallet,
+      lamports_paid: 100,
+      pledge_tokens: 200,
+      rate: 200,
+      total_pledge_tokens: 200,
+      phase: 0,
+      phase_time_remaining: PHASE_DURATIONS[0],
+    }]
+  );
+}
 
-  let result = buy_pledge(&account_info, amount, current_time);
+#[test]
+fn test_purchase_event_phase() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let config = config_account(&program_id, ContractConfig::new());
+  let accounts = [account_info, wallet_account(&wallet, true), config, treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)];
+  take_events();
 
-  assert!(result.is_err());
+  let purchase_phase = |now| {
+    buy_pledge(&program_id, &accounts, 100, DEFAULT_LOCK_TIER, None, false, now).unwrap();
+    let events = take_events();
+    let [PledgeEvent::Purchase { phase, phase_time_remaining, rate, .. }] = events[..] else { panic!("{:?}", events) };
+    (phase, phase_time_remaining, rate)
+  };
+  assert_eq!(purchase_phase(10), (0, PHASE_DURATIONS[0] - 10, PHASE_RATES[0]));
+  // The last second of a phase still sells at its rate; the next one starts
+  // the following phase with its whole length ahead.
+  assert_eq!(purchase_phase(PHASE_DURATIONS[0] - 1), (0, 1, PHASE_RATES[0]));
+  assert_eq!(purchase_phase(PHASE_DURATIONS[0]), (1, PHASE_DURATIONS[1], PHASE_RATES[1]));
+  // The open-ended final phase never runs out.
+  let final_phase: u64 = PHASE_DURATIONS[..4].iter().sum();
+  assert_eq!(purchase_phase(final_phase + 1_000), (4, u64::MAX, PHASE_RATES[4]));
 }
 
 #[test]
-fn test_buy_pledge_invalid_amount() {
-  let mut account_data = vec![0u8; std::mem::size_of::<UserState>()];
-  let pubkey = Pubkey::new_unique();
-  let mut lamports = 1000;
-  let account_info = AccountInfo::new(
-    &pubkey,
+fn test_events_carry_user_and_sequence() {
+  install_capture();
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let config = config_account(&program_id, ContractConfig::new());
+  let user = *account_info.key;
+  take_events();
+
+  let accounts = [account_info.clone(), wallet_account(&wallet, true), config.clone(), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)];
+  buy_pledge(&program_id, &accounts, 1000, DEFAULT_LOCK_TIER, None, false, 0).unwrap();
+  update_reward(&program_id, &account_info, &config, DEFAULT_LOCK / 2).unwrap();
+  let mut accounts = claim_accounts(&program_id, &account_info, &wallet, true);
+  accounts[2] = config.clone();
+  claim_rewards(&program_id, &accounts, 0, DEFAULT_LOCK / 2).unwrap();
+  let mut accounts = unlock_accounts(&program_id, &account_info, &wallet);
+  accounts[2] = config.clone();
+  unlock_pledge(&program_id, &accounts, DEFAULT_LOCK).unwrap();
+
+  // Every payload leads with the sequence number, the user state and the
+  // wallet, numbered in the order the instructions ran.
+  let payloads = LOGGED_DATA.with(|logged| logged.borrow().clone());
+  assert_eq!(payloads.iter().map(|data| data[0]).collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+  for (index, data) in payloads.iter().enumerate() {
+    assert_eq!(data[1..9], (index as u64 + 1).to_le_bytes());
+    assert_eq!(data[9..41], user.to_bytes());
+    assert_eq!(data[41..73], wallet.to_bytes());
+  }
+  assert_eq!(take_events().len(), 4);
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().event_seq, 4);
+
+  // A failed instruction emits nothing and leaves the counter alone.
+  assert!(unlock_pledge(&program_id, &accounts, DEFAULT_LOCK).is_err());
+  assert!(take_events().is_empty());
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().event_seq, 4);
+}
+
+#[test]
+fn test_user_state_pack_into_slice() {
+  let user_state = locked_state(1000, 5);
+  let expected = borsh::to_vec(&user_state).unwrap();
+
+  let mut too_small = vec![0; UserState::LEN - 1];
+  assert_eq!(user_state.pack_into_slice(&mut too_small), Err(ProgramError::AccountDataTooSmall));
+  assert!(too_small.iter().all(|&byte| byte == 0));
+
+  let mut exact = vec![0; UserState::LEN];
+  user_state.pack_into_slice(&mut exact).unwrap();
+  assert_eq!(exact, expected);
+
+  // Bytes past LEN are left alone.
+  let mut oversized = vec![0xff; UserState::LEN + 32];
+  user_state.pack_into_slice(&mut oversized).unwrap();
+  assert_eq!(&oversized[..UserState::LEN], &expected[..]);
+  assert!(oversized[UserState::LEN..].iter().all(|&byte| byte == 0xff));
+}
+
+#[test]
+fn test_initialize_user_rejects_small_account() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let (key, _) = find_user_state_address(&program_id, &wallet);
+  let mut lamports = 1_000_000_000;
+  let mut data = vec![0; UserState::LEN - 1];
+  let account_info = AccountInfo::new(&key, false, true, &mut lamports, &mut data, &program_id, false, 0);
+  let system_program = wallet_account(&solana_program::system_program::ID, false);
+
+  let result = initialize_user(
+    &program_id,
+    &[account_info, wallet_account(&wallet, true), system_program, config_account(&program_id, ContractConfig::new())],
+    &Rent::default(),
+  );
+  assert_eq!(result, Err(ProgramError::AccountDataTooSmall));
+}
+
+// The version 1 encoding of `user_state`, written out field by field: no
+// version byte, so the initialized flag comes first.
+fn v1_bytes(user_state: &UserState) -> Vec<u8> {
+  let mut data = vec![user_state.is_initialized as u8];
+  for value in [
+    user_state.locked_pledge_tokens,
+    user_state.solhit_rewards,
+    user_state.lock_start_time,
+    user_state.vesting_end_time,
+    user_state.last_reward_update,
+    user_state.total_claimed,
+    user_state.total_purchased,
+  ] {
+    data.extend_from_slice(&value.to_le_bytes());
+  }
+  data.extend_from_slice(user_state.owner.as_ref());
+  data.push(user_state.bump);
+  data.push(user_state.lot_count);
+  for lot in &user_state.lots {
+    for value in [lot.amount, lot.rate, lot.lock_start_time, lot.vesting_end_time] {
+      data.extend_from_slice(&value.to_le_bytes());
+    }
+  }
+  assert_eq!(data.len(), UserState::LEN_V1);
+  data
+}
+
+#[test]
+fn test_user_state_reads_version_1() {
+  let user_state = UserState { solhit_rewards: 77, total_claimed: 5, ..locked_state(1000, 10) };
+  let v1 = v1_bytes(&user_state);
+
+  let read = UserState::unpack(&v1).unwrap();
+  assert_eq!(borsh::to_vec(&read).unwrap(), borsh::to_vec(&user_state).unwrap());
+  assert!(UserState::needs_migration(&v1));
+
+  // Every write is in the latest version.
+  let latest = borsh::to_vec(&read).unwrap();
+  assert_eq!(latest.len(), UserState::LEN);
+  assert_eq!(latest[..8], USER_STATE_DISCRIMINATOR);
+  assert_eq!(latest[8], USER_STATE_VERSION);
+  assert!(!UserState::needs_migration(&latest));
+
+  assert_eq!(UserState::unpack(&v1[..UserState::LEN_V1 - 1]).err(), Some(ProgramError::AccountDataTooSmall));
+  let mut unknown = latest.clone();
+  unknown[8] = USER_STATE_VERSION + 1;
+  assert_eq!(UserState::unpack(&unknown).err(), Some(ProgramError::InvalidAccountData));
+  unknown[..8].copy_from_slice(&CONFIG_DISCRIMINATOR);
+  assert_eq!(UserState::unpack(&unknown).err(), Some(PledgeError::InvalidAccountType.into()));
+}
+
+#[test]
+fn test_migrate_user_state() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let (_, bump) = find_user_state_address(&program_id, &wallet);
+  let user_state = UserState {
+    is_initialized: true,
+    owner: wallet,
+    bump,
+    solhit_rewards: 77,
+    beneficiary: wallet,
+    ..locked_state(1000, 10)
+  };
+  // Already big enough for the new layout, so the rewrite needs no realloc;
+  // tests/migrate_user_state.rs covers growing the account.
+  let account_info = sized_user_state_account(&program_id, &wallet, UserState::default(), UserState::LEN);
+  {
+    let mut data = account_info.data.borrow_mut();
+    data.fill(0);
+    data[..UserState::LEN_V1].copy_from_slice(&v1_bytes(&user_state));
+  }
+
+  // Version 1 accounts stay readable before the migration.
+  view_rewards(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), false, 10).unwrap();
+
+  let payer = Pubkey::new_unique();
+  let accounts = [account_info.clone(), wallet_account(&payer, true), system_program_account()];
+  migrate_user_state(&program_id, &accounts, &Rent::default()).unwrap();
+  let data = account_info.data.borrow().to_vec();
+  assert_eq!(data, borsh::to_vec(&user_state).unwrap());
+
+  // Running it again leaves the account as it is.
+  migrate_user_state(&program_id, &accounts, &Rent::default()).unwrap();
+  assert_eq!(*account_info.data.borrow(), &data[..]);
+}
+
+#[test]
+fn test_user_state_required_len() {
+  assert_eq!(UserState::required_len(USER_STATE_VERSION), Some(UserState::LEN));
+  assert_eq!(UserState::required_len(6), Some(UserState::LEN_V6));
+  assert_eq!(UserState::required_len(1), Some(UserState::LEN_V1));
+  assert_eq!(UserState::required_len(0), Some(UserState::LEN_V1));
+  assert_eq!(UserState::required_len(USER_STATE_VERSION + 1), None);
+  // Every version only adds to the one before.
+  let lens: Vec<_> = (1..=USER_STATE_VERSION).map(|version| UserState::required_len(version).unwrap()).collect();
+  assert!(lens.windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+#[test]
+fn test_resize_user_state_rejects_shrinking() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = sized_user_state_account(&program_id, &wallet, locked_state(1000, 10), UserState::LEN + 32);
+  let accounts = [account_info.clone(), wallet_account(&wallet, true), system_program_account()];
+  let rent = Rent::default();
+
+  let shrink = resize_user_state(&program_id, &accounts, UserState::LEN as u64, &rent);
+  assert_eq!(shrink, Err(PledgeError::AccountShrinkNotSupported.into()));
+  assert_eq!(account_info.data_len(), UserState::LEN + 32);
+  // Its current size needs no realloc and is fine, but only for the owner.
+  resize_user_state(&program_id, &accounts, UserState::LEN as u64 + 32, &rent).unwrap();
+  let mut as_other = accounts.clone();
+  as_other[1] = wallet_account(Box::leak(Box::new(Pubkey::new_unique())), true);
+  assert_eq!(resize_user_state(&program_id, &as_other, UserState::LEN as u64 + 32, &rent), Err(ProgramError::InvalidSeeds));
+}
+
+#[test]
+fn test_user_state_reads_version_2() {
+  let user_state = UserState { solhit_rewards: 77, total_purchased: 1000, ..locked_state(1000, 10) };
+  let mut v2 = vec![2];
+  v2.extend_from_slice(&v1_bytes(&user_state));
+  assert_eq!(v2.len(), UserState::LEN_V2);
+  assert!(UserState::needs_migration(&v2));
+
+  // Version 2 predates the activity fields, which read as zero.
+  let read = UserState::unpack(&v2).unwrap();
+  assert_eq!(read.purchase_count, 0);
+  assert_eq!(read.last_activity, 0);
+  assert_eq!(borsh::to_vec(&read).unwrap(), borsh::to_vec(&user_state).unwrap());
+  assert_eq!(UserState::try_from_slice(&v2).unwrap().total_purchased, 1000);
+
+  assert_eq!(UserState::unpack(&v2[..UserState::LEN_V2 - 1]).err(), Some(ProgramError::AccountDataTooSmall));
+}
+
+#[test]
+fn test_user_state_reads_version_3() {
+  let mut user_state = UserState { purchase_count: 2, last_activity: 40, ..locked_state(1000, 10) };
+  user_state
+    .add_lot(PledgeLot { amount: 300, rate: 150, lock_start_time: 20, vesting_end_time: 30, multiplier_bps: BASIS_POINTS })
+    .unwrap();
+  let v1 = v1_bytes(&user_state);
+  let mut v3 = vec![3];
+  v3.extend_from_slice(&v1[..1 + 8 * 7]);
+  v3.extend_from_slice(&user_state.purchase_count.to_le_bytes());
+  v3.extend_from_slice(&user_state.last_activity.to_le_bytes());
+  v3.extend_from_slice(&v1[1 + 8 * 7..]);
+  assert_eq!(v3.len(), UserState::LEN_V3);
+  assert!(UserState::needs_migration(&v3));
+
+  // Version 3 lots predate the tiers and keep accruing at 1x.
+  let read = UserState::unpack(&v3).unwrap();
+  assert_eq!(read.lots, user_state.lots);
+  assert_eq!(borsh::to_vec(&read).unwrap(), borsh::to_vec(&user_state).unwrap());
+  assert_eq!(UserState::try_from_slice(&v3).unwrap().lots, user_state.lots);
+
+  assert_eq!(UserState::unpack(&v3[..UserState::LEN_V3 - 1]).err(), Some(ProgramError::AccountDataTooSmall));
+}
+
+#[test]
+fn test_user_state_reads_version_4() {
+  let wallet = Pubkey::new_unique();
+  let user_state = UserState { owner: wallet, beneficiary: wallet, ..locked_state(1000, 10) };
+  let mut v4 = borsh::to_vec(&user_state).unwrap()[8..].to_vec();
+  v4.truncate(UserState::LEN_V4);
+  v4[0] = 4;
+  assert!(UserState::needs_migration(&v4));
+
+  // Version 4 predates beneficiaries, so rewards keep going to the owner.
+  let read = UserState::unpack(&v4).unwrap();
+  assert_eq!(read.beneficiary, wallet);
+  assert_eq!(borsh::to_vec(&read).unwrap(), borsh::to_vec(&user_state).unwrap());
+  assert_eq!(UserState::try_from_slice(&v4).unwrap().beneficiary, wallet);
+
+  assert_eq!(UserState::unpack(&v4[..UserState::LEN_V4 - 1]).err(), Some(ProgramError::AccountDataTooSmall));
+}
+
+#[test]
+fn test_user_state_reads_version_5() {
+  let wallet = Pubkey::new_unique();
+  let cold_wallet = Pubkey::new_unique();
+  let user_state = UserState { owner: wallet, beneficiary: cold_wallet, ..locked_state(1000, 10) };
+  let mut v5 = borsh::to_vec(&user_state).unwrap()[8..].to_vec();
+  v5.truncate(UserState::LEN_V5);
+  v5[0] = 5;
+  assert!(UserState::needs_migration(&v5));
+
+  // Version 5 keeps its beneficiary and predates delegates.
+  let read = UserState::unpack(&v5).unwrap();
+  assert_eq!((read.beneficiary, read.delegate), (cold_wallet, None));
+  assert_eq!(borsh::to_vec(&read).unwrap(), borsh::to_vec(&user_state).unwrap());
+  let read = UserState::try_from_slice(&v5).unwrap();
+  assert_eq!((read.beneficiary, read.delegate), (cold_wallet, None));
+
+  assert_eq!(UserState::unpack(&v5[..UserState::LEN_V5 - 1]).err(), Some(ProgramError::AccountDataTooSmall));
+}
+
+#[test]
+fn test_user_state_reads_version_6() {
+  let wallet = Pubkey::new_unique();
+  let bot = Pubkey::new_unique();
+  let user_state = UserState { owner: wallet, beneficiary: wallet, delegate: Some(bot), ..locked_state(1000, 10) };
+  let mut v6 = borsh::to_vec(&user_state).unwrap()[8..].to_vec();
+  v6.truncate(UserState::LEN_V6);
+  v6[0] = 6;
+  assert!(UserState::needs_migration(&v6));
+
+  // Version 6 keeps its delegate and predates buy groups.
+  let read = UserState::unpack(&v6).unwrap();
+  assert_eq!((read.delegate, read.group), (Some(bot), None));
+  assert_eq!(borsh::to_vec(&read).unwrap(), borsh::to_vec(&user_state).unwrap());
+  let read = UserState::try_from_slice(&v6).unwrap();
+  assert_eq!((read.delegate, read.group), (Some(bot), None));
+
+  assert_eq!(UserState::unpack(&v6[..UserState::LEN_V6 - 1]).err(), Some(ProgramError::AccountDataTooSmall));
+}
+
+#[test]
+fn test_user_state_reads_version_7() {
+  let wallet = Pubkey::new_unique();
+  let group = Pubkey::new_unique();
+  let user_state = UserState { owner: wallet, beneficiary: wallet, group: Some(group), ..locked_state(1000, 10) };
+  let mut v7 = borsh::to_vec(&user_state).unwrap()[8..UserState::LEN_V8].to_vec();
+  v7[0] = 7;
+  assert_eq!(v7.len(), UserState::LEN_V7);
+  assert!(UserState::needs_migration(&v7));
+
+  // Version 7 is version 8 without the discriminator.
+  let read = UserState::unpack(&v7).unwrap();
+  assert_eq!(borsh::to_vec(&read).unwrap(), borsh::to_vec(&user_state).unwrap());
+  assert_eq!(UserState::try_from_slice(&v7).unwrap().group, Some(group));
+
+  assert_eq!(UserState::unpack(&v7[..UserState::LEN_V7 - 1]).err(), Some(ProgramError::AccountDataTooSmall));
+}
+
+#[test]
+fn test_user_state_reads_version_8() {
+  let wallet = Pubkey::new_unique();
+  let user_state = UserState { owner: wallet, beneficiary: wallet, ..locked_state(1000, 10) };
+  let mut v8 = borsh::to_vec(&user_state).unwrap()[..UserState::LEN_V8].to_vec();
+  v8[8] = 8;
+  assert!(UserState::needs_migration(&v8));
+  assert!(!UserState::needs_migration(&borsh::to_vec(&user_state).unwrap()));
+
+  // Version 8 is the latest layout without hold_until, so it reads as no hold.
+  let read = UserState::unpack(&v8).unwrap();
+  assert_eq!(read.hold_until, 0);
+  assert_eq!(borsh::to_vec(&read).unwrap(), borsh::to_vec(&user_state).unwrap());
+  assert_eq!(UserState::try_from_slice(&v8).unwrap().locked_pledge_tokens, 1000);
+
+  assert_eq!(UserState::unpack(&v8[..UserState::LEN_V8 - 1]).err(), Some(ProgramError::AccountDataTooSmall));
+}
+
+#[test]
+fn test_user_state_deserialize_reader() {
+  use std::io::{Cursor, ErrorKind, Read};
+
+  let user_state = UserState { hold_until: 5, ..locked_state(1000, 10) };
+  let data = borsh::to_vec(&user_state).unwrap();
+  // Whatever follows the state is left in the reader, even without an end.
+  let mut oversized = Cursor::new([&data[..], &[0xff; 64]].concat());
+  let read = UserState::deserialize_reader(&mut oversized).unwrap();
+  assert_eq!(borsh::to_vec(&read).unwrap(), data);
+  assert_eq!(oversized.position(), UserState::LEN as u64);
+  let read = UserState::deserialize_reader(&mut data.as_slice().chain(std::io::repeat(0xff))).unwrap();
+  assert_eq!(read.hold_until, 5);
+
+  // An older layout stops at its own length.
+  let mut v7 = data[8..UserState::LEN_V8].to_vec();
+  v7[0] = 7;
+  let mut reader = Cursor::new([&v7[..], &[0xff; 64]].concat());
+  assert_eq!(UserState::deserialize_reader(&mut reader).unwrap().locked_pledge_tokens, 1000);
+  assert_eq!(reader.position(), UserState::LEN_V7 as u64);
+
+  let error = |mut bytes: &[u8]| UserState::deserialize_reader(&mut bytes).err().map(|error| error.kind());
+  assert_eq!(error(&data[..UserState::LEN - 1]), Some(ErrorKind::UnexpectedEof));
+  assert_eq!(error(&v7[..8]), Some(ErrorKind::UnexpectedEof));
+  assert_eq!(error(&[]), Some(ErrorKind::UnexpectedEof));
+  let mut unknown = data.clone();
+  unknown[8] = USER_STATE_VERSION + 1;
+  assert_eq!(error(&unknown), Some(ErrorKind::InvalidData));
+  assert_eq!(error(&borsh::to_vec(&ContractConfig::new()).unwrap()), Some(ErrorKind::InvalidData));
+}
+
+#[test]
+fn test_discriminators() {
+  assert_eq!(USER_STATE_DISCRIMINATOR, solana_program::hash::hash(b"pledge:user").to_bytes()[..8]);
+  assert_eq!(CONFIG_DISCRIMINATOR, solana_program::hash::hash(b"pledge:config").to_bytes()[..8]);
+  // Neither may start like an account written before them.
+  assert!(CONFIG_DISCRIMINATOR[0] >= USER_STATE_DISCRIMINATOR_VERSION);
+  assert!(USER_STATE_DISCRIMINATOR[0] > 1);
+
+  let user_state = borsh::to_vec(&locked_state(1000, 10)).unwrap();
+  let config = borsh::to_vec(&ContractConfig::new()).unwrap();
+  assert_eq!(config[..8], CONFIG_DISCRIMINATOR);
+  assert_eq!(ContractConfig::unpack(&user_state).err(), Some(PledgeError::InvalidAccountType.into()));
+  assert_eq!(UserState::unpack(&config).err(), Some(PledgeError::InvalidAccountType.into()));
+}
+
+#[test]
+fn test_handlers_reject_swapped_config_and_user_state() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let admin = Pubkey::new_unique();
+  let user_state = user_state_account(&program_id, &wallet, locked_state(1000, 10));
+  let config = config_account(&program_id, ContractConfig { admin, ..ContractConfig::new() });
+  let wrong_type = Err(PledgeError::InvalidAccountType.into());
+
+  assert_eq!(update_reward(&program_id, &config, &user_state, 1_000_000), wrong_type);
+  assert_eq!(update_reward(&program_id, &user_state, &user_state_account(&program_id, &admin, UserState::default()), 1_000_000), wrong_type);
+
+  let mut accounts = claim_accounts(&program_id, &user_state, &wallet, true);
+  accounts.swap(0, 2);
+  assert_eq!(claim_rewards(&program_id, &accounts, 0, 0), wrong_type);
+
+  assert_eq!(set_beneficiary(&program_id, &[config.clone(), wallet_account(&wallet, true)], wallet), wrong_type);
+  assert_eq!(set_paused(&program_id, &[user_state.clone(), wallet_account(&admin, true)], true), wrong_type);
+  assert_eq!(close_user_state(&program_id, &[config.clone(), wallet_account(&wallet, true), user_state.clone()]), wrong_type);
+}
+
+// A program-owned user state PDA for `wallet` whose data is `len` bytes long,
+// holding `user_state` in the first UserState::LEN of them where it fits.
+fn sized_user_state_account<'a>(program_id: &Pubkey, wallet: &Pubkey, user_state: UserState, len: usize) -> AccountInfo<'a> {
+  let account_info = user_state_account(program_id, wallet, user_state);
+  let mut data = account_info.data.borrow().to_vec();
+  data.resize(len, 0);
+  AccountInfo::new(
+    Box::leak(Box::new(*account_info.key)),
     false,
     true,
-    &mut lamports,
-    &mut account_data,
-    &pubkey,
+    Box::leak(Box::new(1_000_000)),
+    data.leak(),
+    Box::leak(Box::new(*program_id)),
     false,
     0,
+  )
+}
+
+#[test]
+fn test_handlers_with_account_sizes() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+
+  let undersized = sized_user_state_account(&program_id, &wallet, UserState::default(), UserState::LEN - 1);
+  let config = config_account(&program_id, ContractConfig::new());
+  let result = buy_pledge(&program_id, &[undersized.clone(), wallet_account(&wallet, true), config, treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)], 1000, DEFAULT_LOCK_TIER, None, false, 0);
+  assert_eq!(result, Err(ProgramError::AccountDataTooSmall));
+  assert_eq!(update_reward(&program_id, &undersized, &config_account(&program_id, ContractConfig::new()), 0), Err(ProgramError::AccountDataTooSmall));
+  assert_eq!(view_rewards(&program_id, &undersized, &config_account(&program_id, ContractConfig::new()), false, 0), Err(ProgramError::AccountDataTooSmall));
+
+  for len in [UserState::LEN, UserState::LEN + 32] {
+    let account_info = sized_user_state_account(&program_id, &wallet, UserState::default(), len);
+    let config = config_account(&program_id, ContractConfig::new());
+    buy_pledge(&program_id, &[account_info.clone(), wallet_account(&wallet, true), config, treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)], 1000, DEFAULT_LOCK_TIER, None, false, 0).unwrap();
+    update_reward(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), VESTING_PERIOD).unwrap();
+    view_rewards(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), false, VESTING_PERIOD).unwrap();
+
+    let data = account_info.data.borrow();
+    assert_eq!(data.len(), len);
+    let user_state = UserState::unpack(&data).unwrap();
+    assert_eq!(user_state.solhit_rewards, full_reward(2000));
+    assert!(data[UserState::LEN..].iter().all(|&byte| byte == 0));
+  }
+}
+
+#[test]
+fn test_handlers_reject_short_accounts() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let rent = Rent::default();
+  let too_small = Err(ProgramError::AccountDataTooSmall);
+
+  for len in [0, 31, 40] {
+    let short = sized_user_state_account(&program_id, &wallet, locked_state(1000, 0), len);
+    let config = config_account(&program_id, ContractConfig::new());
+    let pair = [short.clone(), wallet_account(&wallet, true)];
+    let with_system = [short.clone(), wallet_account(&wallet, true), system_program_account()];
+    let with_config = [short.clone(), wallet_account(&wallet, true), config.clone()];
+
+    let initialize_accounts = [short.clone(), wallet_account(&wallet, true), system_program_account(), config.clone()];
+    assert_eq!(initialize_user(&program_id, &initialize_accounts, &rent), too_small);
+    assert_eq!(migrate_user_state(&program_id, &with_system, &rent), too_small);
+    assert_eq!(close_user_state(&program_id, &with_config), too_small);
+    assert_eq!(set_beneficiary(&program_id, &pair, Pubkey::new_unique()), too_small);
+    let buy_accounts = [short.clone(), wallet_account(&wallet, true), config.clone(), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)];
+    assert_eq!(buy_pledge(&program_id, &buy_accounts, 1000, DEFAULT_LOCK_TIER, None, false, 0), too_small);
+    assert_eq!(buy_pledge_with_token(&program_id, &claim_accounts(&program_id, &short, &wallet, true), 1000, 0), too_small);
+    assert_eq!(update_reward(&program_id, &short, &config, 0), too_small);
+    assert_eq!(view_rewards(&program_id, &short, &config_account(&program_id, ContractConfig::new()), false, 0), too_small);
+    assert_eq!(get_vesting_schedule(&program_id, &[short.clone(), config.clone()]), too_small);
+    assert_eq!(claim_rewards(&program_id, &claim_accounts(&program_id, &short, &wallet, true), 0, 0), too_small);
+    assert_eq!(compound_rewards(&program_id, &with_config, 0), too_small);
+    assert_eq!(unlock_pledge(&program_id, &unlock_accounts(&program_id, &short, &wallet), DEFAULT_LOCK), too_small);
+    assert_eq!(early_exit(&program_id, &unlock_accounts(&program_id, &short, &wallet), 0), too_small);
+
+    // A short referrer is refused too, before the buyer is touched.
+    let buyer = Pubkey::new_unique();
+    let account_info = user_state_account(&program_id, &buyer, UserState::default());
+    let before = account_info.data.borrow().to_vec();
+    let accounts = referred_buy_accounts(&program_id, &account_info, &buyer, &config, &short);
+    assert_eq!(buy_pledge(&program_id, &accounts, 1000, DEFAULT_LOCK_TIER, Some(short.key), false, 0), too_small);
+    assert_eq!(*account_info.data.borrow(), &before[..]);
+  }
+}
+
+#[test]
+fn test_initialize_user_accepts_padded_account() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let (key, _) = find_user_state_address(&program_id, &wallet);
+  let mut lamports = 1_000_000_000;
+  let mut data = vec![0; UserState::LEN + 32];
+  let account_info = AccountInfo::new(&key, false, true, &mut lamports, &mut data, &program_id, false, 0);
+  let system_program = wallet_account(&solana_program::system_program::ID, false);
+
+  initialize_user(
+    &program_id,
+    &[account_info.clone(), wallet_account(&wallet, true), system_program, config_account(&program_id, ContractConfig::new())],
+    &Rent::default(),
+  )
+  .unwrap();
+  let user_state = UserState::unpack(&account_info.data.borrow()).unwrap();
+  assert!(user_state.is_initialized);
+  assert_eq!(user_state.owner, wallet);
+}
+
+#[test]
+fn test_user_activity_counters() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let buy = |amount, time| {
+    let config = config_account(&program_id, ContractConfig::new());
+    let accounts = [account_info.clone(), wallet_account(&wallet, true), config, treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)];
+    buy_pledge(&program_id, &accounts, amount, DEFAULT_LOCK_TIER, None, false, time)
+  };
+
+  buy(1000, 10).unwrap();
+  buy(500, 20).unwrap();
+  let user_state = UserState::unpack(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.purchase_count, 2);
+  assert_eq!(user_state.total_purchased, 1500 * PHASE_RATES[0] / 100);
+  assert_eq!(user_state.last_activity, 20);
+
+  // A rejected purchase is not activity.
+  assert!(buy(0, 30).is_err());
+  assert_eq!(UserState::unpack(&account_info.data.borrow()).unwrap().last_activity, 20);
+
+  update_reward(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), VESTING_PERIOD / 2).unwrap();
+  assert_eq!(UserState::unpack(&account_info.data.borrow()).unwrap().last_activity, VESTING_PERIOD / 2);
+
+  claim_rewards(&program_id, &claim_accounts(&program_id, &account_info, &wallet, true), 0, VESTING_PERIOD).unwrap();
+  let user_state = UserState::unpack(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.last_activity, VESTING_PERIOD);
+  // Neither updating nor claiming counts as a purchase.
+  assert_eq!(user_state.purchase_count, 2);
+  assert_eq!(user_state.total_purchased, 1500 * PHASE_RATES[0] / 100);
+
+  take_return_data();
+  view_rewards(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), false, VESTING_PERIOD + 1).unwrap();
+  let view = RewardsView::try_from_slice(&take_return_data()).unwrap();
+  assert_eq!((view.total_purchased, view.purchase_count, view.last_activity), (1500 * PHASE_RATES[0] / 100, 2, VESTING_PERIOD));
+}
+
+#[test]
+fn test_close_user_state_with_outstanding_balance() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+
+  for user_state in [locked_state(1000, 0), UserState { solhit_rewards: 1, ..UserState::default() }] {
+    let account_info = user_state_account(&program_id, &wallet, user_state);
+    let before = account_info.data.borrow().to_vec();
+    let accounts = [account_info.clone(), wallet_account(&wallet, true), config_account(&program_id, ContractConfig::new())];
+    assert_eq!(close_user_state(&program_id, &accounts), Err(PledgeError::OutstandingBalance.into()));
+    assert_eq!(*account_info.data.borrow(), &before[..]);
+    assert_eq!(account_info.lamports(), 1_000_000);
+  }
+
+  // Only the wallet that owns the state may close it.
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let other = Pubkey::new_unique();
+  let config = config_account(&program_id, ContractConfig::new());
+  assert_eq!(close_user_state(&program_id, &[account_info.clone(), wallet_account(&wallet, false), config.clone()]), Err(ProgramError::MissingRequiredSignature));
+  assert!(close_user_state(&program_id, &[account_info.clone(), wallet_account(&other, true), config.clone()]).is_err());
+  assert_eq!(account_info.lamports(), 1_000_000);
+}
+
+// A user state that has bought stays open once it is emptied, so closing and
+// reinitializing it cannot reset its purchases and buy past max_per_user.
+#[test]
+fn test_close_user_state_after_purchases() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let config = config_account(&program_id, ContractConfig { max_per_user: 2000, initialized_users: 1, ..ContractConfig::new() });
+  let account_info = user_state_account(&program_id, &wallet, UserState::default());
+  let accounts = [account_info.clone(), wallet_account(&wallet, true), config.clone(), treasury_account(&program_id, 0), system_program_account(), whitelist_account(&program_id, &wallet)];
+  buy_pledge(&program_id, &accounts, 1000, DEFAULT_LOCK_TIER, None, false, 0).unwrap();
+
+  // Everything bought has since been unlocked and the rewards claimed.
+  let mut user_state = UserState::unpack(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.total_purchased, 2000);
+  user_state.remove_vesting_lots(0).unwrap();
+  user_state.pack_into_slice(&mut account_info.data.borrow_mut()).unwrap();
+  let before = account_info.data.borrow().to_vec();
+  let close_accounts = [account_info.clone(), wallet_account(&wallet, true), config.clone()];
+  assert_eq!(close_user_state(&program_id, &close_accounts), Err(PledgeError::UserStateInUse.into()));
+  assert_eq!(*account_info.data.borrow(), &before[..]);
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().initialized_users, 1);
+  assert_eq!(
+    buy_pledge(&program_id, &accounts, 1, DEFAULT_LOCK_TIER, None, false, DEFAULT_LOCK),
+    Err(PledgeError::UserCapExceeded.into())
   );
 
-  let amount = 0;
-  let current_time = 1_000_000;
+  // A group member stays in the group's count, so it stays open too.
+  let account_info = user_state_account(&program_id, &wallet, UserState { group: Some(Pubkey::new_unique()), ..UserState::default() });
+  let close_accounts = [account_info.clone(), wallet_account(&wallet, true), config.clone()];
+  assert_eq!(close_user_state(&program_id, &close_accounts), Err(PledgeError::UserStateInUse.into()));
+}
 
-  let result = buy_pledge(&account_info, amount, current_time);
+// A user state of `wallet` at an address of its own rather than the wallet's
+// PDA, as created before user states had to be PDAs.
+fn legacy_user_state_account<'a>(program_id: &Pubkey, wallet: &Pubkey, user_state: UserState) -> AccountInfo<'a> {
+  let mut account_info = user_state_account(program_id, wallet, user_state);
+  account_info.key = Box::leak(Box::new(Pubkey::new_unique()));
+  account_info
+}
 
-  assert!(result.is_ok());
+#[test]
+fn test_merge_user_states() {
+  let program_id = Pubkey::new_unique();
+  let wallet: &'static Pubkey = Box::leak(Box::new(Pubkey::new_unique()));
+  let account_info = user_state_account(&program_id, wallet, UserState { total_purchased: 1000, purchase_count: 1, ..locked_state(1000, 100) });
+  let duplicate = UserState { total_purchased: 500, purchase_count: 1, solhit_rewards: 40, ..locked_state(500, 50) };
+  let duplicate_info = legacy_user_state_account(&program_id, wallet, duplicate);
+  let config = config_account(&program_id, ContractConfig { initialized_users: 2, ..ContractConfig::new() });
+  let wallet_info = wallet_account(wallet, true);
+  let accounts = [account_info.clone(), duplicate_info.clone(), wallet_info.clone(), config.clone()];
+
+  merge_user_states(&program_id, &accounts, 100).unwrap();
+  let user_state = UserState::unpack(&account_info.data.borrow()).unwrap();
+  assert_eq!(user_state.locked_pledge_tokens, 1500);
+  assert_eq!((user_state.lock_start_time, user_state.vesting_end_time), (50, 100 + VESTING_PERIOD));
+  assert_eq!(user_state.solhit_rewards, 40 + full_reward(500) * 50 / VESTING_PERIOD);
+  assert_eq!((user_state.total_purchased, user_state.purchase_count), (1500, 2));
+  // The duplicate is closed, its rent back with the wallet.
+  assert!(duplicate_info.data.borrow().iter().all(|&byte| byte == 0));
+  assert_eq!((duplicate_info.lamports(), wallet_info.lamports()), (0, 1_000_000));
+  assert_eq!(*duplicate_info.owner, system_program::ID);
+  // InitializeUser never counted the pre-PDA duplicate.
+  assert_eq!(ContractConfig::unpack(&config.data.borrow()).unwrap().initialized_users, 2);
+
+  // Closed, it cannot be merged again.
+  assert_eq!(merge_user_states(&program_id, &accounts, 200), Err(ProgramError::IncorrectProgramId));
+}
+
+#[test]
+fn test_merge_user_states_rejects_other_owners() {
+  let program_id = Pubkey::new_unique();
+  let wallet: &'static Pubkey = Box::leak(Box::new(Pubkey::new_unique()));
+  let other_wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, wallet, locked_state(1000, 0));
+  let config = config_account(&program_id, ContractConfig::new());
+  let merge = |duplicate_info: &AccountInfo<'static>, signer: &'static Pubkey| {
+    let before = (account_info.data.borrow().to_vec(), duplicate_info.data.borrow().to_vec());
+    let accounts = [account_info.clone(), duplicate_info.clone(), wallet_account(signer, true), config.clone()];
+    let result = merge_user_states(&program_id, &accounts, 100);
+    assert_eq!((account_info.data.borrow().to_vec(), duplicate_info.data.borrow().to_vec()), before);
+    result
+  };
+
+  // Another wallet's state, at a stray address or at its own PDA.
+  let unauthorized = Err(ProgramError::InvalidSeeds);
+  assert_eq!(merge(&legacy_user_state_account(&program_id, &other_wallet, locked_state(500, 0)), wallet), unauthorized);
+  assert_eq!(merge(&user_state_account(&program_id, &other_wallet, locked_state(500, 0)), wallet), unauthorized);
+  // Nor may someone else merge the wallet's own states.
+  let duplicate_info = legacy_user_state_account(&program_id, wallet, locked_state(500, 0));
+  let stranger: &'static Pubkey = Box::leak(Box::new(other_wallet));
+  assert_eq!(merge(&duplicate_info, stranger), unauthorized);
+  // A zeroed account, or the PDA itself, is no duplicate.
+  let zeroed = legacy_user_state_account(&program_id, wallet, UserState::default());
+  zeroed.data.borrow_mut().fill(0);
+  assert_eq!(merge(&zeroed, wallet), Err(ProgramError::UninitializedAccount));
+  assert_eq!(merge(&account_info, wallet), Err(PledgeError::DuplicateAccount.into()));
+}
+
+// Locked user states for `count` fresh wallets.
+fn batch_user_states<'a>(program_id: &Pubkey, count: usize) -> Vec<AccountInfo<'a>> {
+  (0..count)
+    .map(|_| user_state_account(program_id, &Pubkey::new_unique(), locked_state(1000, 0)))
+    .collect()
+}
+
+#[test]
+fn test_update_reward_batch() {
+  install_capture();
+  let program_id = Pubkey::new_unique();
+  let users = batch_user_states(&program_id, 5);
+  let mut accounts = vec![config_account(&program_id, ContractConfig::new())];
+  accounts.extend(users.iter().cloned());
+  take_events();
+
+  update_reward_batch(&program_id, &accounts, VESTING_PERIOD / 2).unwrap();
+  for account_info in &users {
+    let user_state = UserState::unpack(&account_info.data.borrow()).unwrap();
+    assert_eq!(user_state.solhit_rewards, full_reward(1000) / 2);
+    assert_eq!(user_state.last_reward_update, VESTING_PERIOD / 2);
+  }
+  assert_eq!(take_events(), vec![PledgeEvent::RewardBatch { event_seq: 1, processed: 5, skipped: 0 }]);
+}
+
+#[test]
+fn test_update_reward_batch_skips_invalid_accounts() {
+  install_capture();
+  let program_id = Pubkey::new_unique();
+  let users = batch_user_states(&program_id, 2);
+  let foreign = user_state_account(&Pubkey::new_unique(), &Pubkey::new_unique(), locked_state(1000, 0));
+  let undersized = sized_user_state_account(&program_id, &Pubkey::new_unique(), locked_state(1000, 0), UserState::LEN - 1);
+  let accounts = [
+    config_account(&program_id, ContractConfig::new()),
+    users[0].clone(),
+    foreign.clone(),
+    undersized.clone(),
+    users[1].clone(),
+  ];
+  let foreign_before = foreign.data.borrow().to_vec();
+  let undersized_before = undersized.data.borrow().to_vec();
+  take_events();
+
+  update_reward_batch(&program_id, &accounts, VESTING_PERIOD / 2).unwrap();
+  for account_info in &users {
+    assert_eq!(UserState::unpack(&account_info.data.borrow()).unwrap().solhit_rewards, full_reward(1000) / 2);
+  }
+  assert_eq!(*foreign.data.borrow(), &foreign_before[..]);
+  assert_eq!(*undersized.data.borrow(), &undersized_before[..]);
+  assert_eq!(take_events(), vec![PledgeEvent::RewardBatch { event_seq: 1, processed: 2, skipped: 2 }]);
+}
+
+#[test]
+fn test_update_reward_batch_rejects_duplicates() {
+  let program_id = Pubkey::new_unique();
+  let users = batch_user_states(&program_id, 2);
+  let config = config_account(&program_id, ContractConfig::new());
+  let before = users[0].data.borrow().to_vec();
+
+  // A user state listed twice would be credited twice.
+  let accounts = [config.clone(), users[0].clone(), users[1].clone(), users[0].clone()];
+  assert_eq!(update_reward_batch(&program_id, &accounts, VESTING_PERIOD / 2), Err(PledgeError::DuplicateAccount.into()));
+  let accounts = [config.clone(), users[0].clone(), config.clone()];
+  assert_eq!(update_reward_batch(&program_id, &accounts, VESTING_PERIOD / 2), Err(PledgeError::DuplicateAccount.into()));
+  assert_eq!(*users[0].data.borrow(), &before[..]);
+}
+
+#[test]
+fn test_duplicate_accounts_rejected() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let account_info = user_state_account(&program_id, &wallet, locked_state(1000, 0));
+  let config = config_account(&program_id, ContractConfig::new());
+
+  assert_eq!(update_reward(&program_id, &account_info, &account_info, 0), Err(PledgeError::DuplicateAccount.into()));
+  assert_eq!(update_reward(&program_id, &config, &config, 0), Err(PledgeError::DuplicateAccount.into()));
+
+  let mut accounts = unlock_accounts(&program_id, &account_info, &wallet);
+  accounts[4] = accounts[3].clone();
+  assert_eq!(unlock_pledge(&program_id, &accounts, DEFAULT_LOCK), Err(PledgeError::DuplicateAccount.into()));
+  assert_eq!(early_exit(&program_id, &accounts, 0), Err(PledgeError::DuplicateAccount.into()));
+}
+
+#[test]
+fn test_update_reward_batch_limits() {
+  install_capture();
+  let program_id = Pubkey::new_unique();
+  take_events();
+
+  // An empty batch is a no-op that still reports.
+  update_reward_batch(&program_id, &[config_account(&program_id, ContractConfig::new())], 0).unwrap();
+  assert_eq!(take_events(), vec![PledgeEvent::RewardBatch { event_seq: 1, processed: 0, skipped: 0 }]);
+  assert_eq!(update_reward_batch(&program_id, &[], 0), Err(ProgramError::NotEnoughAccountKeys));
+
+  // An injected clock is not part of the batch.
+  let mut accounts = vec![config_account(&program_id, ContractConfig::new())];
+  accounts.extend(batch_user_states(&program_id, MAX_REWARD_BATCH));
+  accounts.push(clock_account(0));
+  update_reward_batch(&program_id, &accounts, 0).unwrap();
+  assert_eq!(take_events(), vec![PledgeEvent::RewardBatch { event_seq: 1, processed: MAX_REWARD_BATCH as u32, skipped: 0 }]);
+
+  accounts.extend(batch_user_states(&program_id, 1));
+  assert_eq!(update_reward_batch(&program_id, &accounts, 0), Err(PledgeError::BatchTooLarge.into()));
+
+  let paused = config_account(&program_id, ContractConfig { paused: true, ..ContractConfig::new() });
+  accounts[0] = paused;
+  accounts.truncate(2);
+  assert_eq!(update_reward_batch(&program_id, &accounts, 0), Err(PledgeError::ContractPaused.into()));
+}
+
+#[test]
+fn test_get_vesting_schedule_return_data() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let user_state = locked_state(1000, 0);
+  let account_info = user_state_account(&program_id, &wallet, user_state.clone());
+  let config = config_account(&program_id, ContractConfig::new());
+  let before = account_info.data.borrow().to_vec();
+  take_return_data();
+
+  get_vesting_schedule(&program_id, &[account_info.clone(), config.clone()]).unwrap();
+  let schedule = Vec::<VestingCheckpoint>::try_from_slice(&take_return_data()).unwrap();
+  assert_eq!(schedule, logic::vesting_schedule(&user_state, &ContractConfig::new()).unwrap());
+  let last = schedule.last().unwrap();
+  assert_eq!(*last, VestingCheckpoint { timestamp: VESTING_PERIOD, unlocked_amount: 1000, accrued_rewards: full_reward(1000) });
+  assert!(borsh::to_vec(&schedule).unwrap().len() <= solana_program::program::MAX_RETURN_DATA);
+  // Nothing is written back.
+  assert_eq!(*account_info.data.borrow(), &before[..]);
+
+  // Another program's account is rejected.
+  let foreign = user_state_account(&Pubkey::new_unique(), &wallet, locked_state(1000, 0));
+  assert_eq!(get_vesting_schedule(&program_id, &[foreign, config]), Err(ProgramError::IncorrectProgramId));
+}
+
+#[test]
+fn test_view_rewards_return_data() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let user_state = UserState {
+    solhit_rewards: 7,
+    total_purchased: 1000,
+    purchase_count: 1,
+    last_activity: 5,
+    ..locked_state(1000, 0)
+  };
+  let account_info = user_state_account(&program_id, &wallet, user_state);
+  let before = account_info.data.borrow().to_vec();
+  take_return_data();
+
+  view_rewards(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), false, VESTING_PERIOD / 4).unwrap();
+  let view = RewardsView::try_from_slice(&take_return_data()).unwrap();
+  assert_eq!(view, RewardsView {
+    pending_rewards: 7 + full_reward(1000) / 4,
+    locked_pledge_tokens: 1000,
+    vesting_end_time: VESTING_PERIOD,
+    seconds_remaining: VESTING_PERIOD - VESTING_PERIOD / 4,
+    total_purchased: 1000,
+    purchase_count: 1,
+    last_activity: 5,
+    initialized: true,
+  });
+  // Viewing accrues nothing into the account.
+  assert_eq!(*account_info.data.borrow(), &before[..]);
+
+  // Past the end of vesting the remaining time saturates at zero.
+  view_rewards(&program_id, &account_info, &config_account(&program_id, ContractConfig::new()), false, 2 * VESTING_PERIOD).unwrap();
+  let view = RewardsView::try_from_slice(&take_return_data()).unwrap();
+  assert_eq!(view.seconds_remaining, 0);
+  assert_eq!(view.pending_rewards, 7 + full_reward(1000));
 }
 
-}
\ No newline at end of file
+// A user state never created, or zeroed by RepairUserState, fails every view
+// with UninitializedAccount, unless ViewRewards or PreviewReward asked for a
+// soft answer, which reads `initialized: false` rather than a zero balance.
+#[test]
+fn test_views_of_uninitialized_state() {
+  let program_id = Pubkey::new_unique();
+  let wallet = Pubkey::new_unique();
+  let config = config_account(&program_id, ContractConfig::new());
+  let (address, _) = find_user_state_address(&program_id, &wallet);
+  let never_created = AccountInfo::new(&address, false, false, Box::leak(Box::new(0)), &mut [], &system_program::ID, false, 0);
+  let zeroed = user_state_account(&program_id, &wallet, UserState::default());
+  zeroed.data.borrow_mut().fill(0);
+  let uninitialized = Err(ProgramError::UninitializedAccount);
+
+  for account_info in [never_created, zeroed] {
+    let accounts = [account_info.clone(), config.clone()];
+    take_return_data();
+    assert_eq!(view_rewards(&program_id, &account_info, &config, false, 1_000), uninitialized);
+    assert_eq!(preview_reward(&program_id, &accounts, false, 1_000), uninitialized);
+    assert_eq!(get_vesting_schedule(&program_id, &accounts), uninitialized);
+    assert!(take_return_data().is_empty());
+
+    view_rewards(&program_id, &account_info, &config, true, 1_000).unwrap();
+    let view = RewardsView::try_from_slice(&take_return_data()).unwrap();
+    assert_eq!(view, RewardsView { initialized: false, ..RewardsView::default() });
+    preview_reward(&program_id, &accounts, true, 1_000).unwrap();
+    let preview = RewardPreview::try_from_slice(&take_return_data()).unwrap();
+    assert_eq!(preview, RewardPreview { initialized: false, ..RewardPreview::default() });
+  }
+
+  // An initialized user state reads the same either way.
+  let account_info = user_state_account(&program_id, &wallet, locked_state(1000, 0));
+  let accounts = [account_info.clone(), config.clone()];
+  for soft in [false, true] {
+    view_rewards(&program_id, &account_info, &config, soft, VESTING_PERIOD / 2).unwrap();
+    let view = RewardsView::try_from_slice(&take_return_data()).unwrap();
+    assert!(view.initialized);
+    assert_eq!(view.locked_pledge_tokens, 1000);
+    preview_reward(&program_id, &accounts, soft, VESTING_PERIOD / 2).unwrap();
+    assert!(RewardPreview::try_from_slice(&take_return_data()).unwrap().initialized);
+  }
+
+  // Soft or not, an account of another kind is still refused.
+  let foreign = config_account(&Pubkey::new_unique(), ContractConfig::new());
+  assert_eq!(view_rewards(&program_id, &foreign, &config, true, 1_000), Err(ProgramError::IncorrectProgramId));
+}
+}
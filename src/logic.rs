@@ -0,0 +1,1088 @@
+// Pledge rules as plain functions over the state structs. Nothing here touches
+// accounts or sysvars, so the handlers do the account plumbing, pass in the
+// current time and persist the result, and off-chain code can run the same
+// rules directly.
+use solana_program::msg;
+
+use crate::{
+    get_phase_time_remaining, get_sale_phase, math, BuyGroup, ContractConfig, PledgeError, PledgeLot, UserState,
+    VestingCheckpoint, BASIS_POINTS, MAX_CLOCK_SKEW, MAX_VESTING_CHECKPOINTS, SECONDS_PER_MONTH,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuyOutcome {
+    pub rate: u64,
+    pub pledge_tokens: u64,
+    // The pledge tokens `amount` asked for and the part of it charged; both
+    // differ from pledge_tokens and `amount` only on a partial fill.
+    pub requested_tokens: u64,
+    pub paid: u64,
+    // The config's total_pledge_sold once this purchase is counted.
+    pub total_pledge_sold: u64,
+    // The sale phase bought in and its phase_sold entry after the purchase,
+    // and the seconds left in that phase, u64::MAX for one that never ends.
+    pub phase: usize,
+    pub phase_sold: u64,
+    pub phase_time_remaining: u64,
+    // The lock length and reward multiplier of the chosen tier.
+    pub lock_duration: u64,
+    pub multiplier_bps: u64,
+}
+
+// Records a purchase of `amount` at `now` as a new lot locked for lock tier
+// `tier`. With `allow_partial` a purchase larger than the remaining supply
+// buys exactly what remains, for a proportionally smaller payment, instead of
+// failing. The user state is only changed when the purchase succeeds; the
+// caller stores the sold counters and charges outcome.paid.
+pub fn apply_buy(
+    user_state: &mut UserState,
+    config: &ContractConfig,
+    amount: u64,
+    tier: u8,
+    allow_partial: bool,
+    now: u64,
+) -> Result<BuyOutcome, PledgeError> {
+    check_clock(user_state, now)?;
+    if amount == 0 {
+        msg!("Purchase amount must be greater than zero");
+        return Err(PledgeError::InvalidAmount);
+    }
+    if config.sale_finalized {
+        msg!("The sale has been finalized");
+        return Err(PledgeError::SaleEnded);
+    }
+
+    let &(lock_duration, multiplier_bps) = config.lock_tiers.get(tier as usize).ok_or_else(|| {
+        msg!("Unknown lock tier {}", tier);
+        PledgeError::InvalidLockTier
+    })?;
+
+    if now < config.sale_start_time {
+        msg!("Sale starts at {}", config.sale_start_time);
+        return Err(PledgeError::SaleNotStarted);
+    }
+
+    // Timed from the newest open lot rather than last_activity, which anyone
+    // can refresh through the permissionless UpdateReward crank.
+    if config.purchase_cooldown_secs != 0 {
+        let last_purchase = user_state.active_lots().iter().map(|lot| lot.lock_start_time).max();
+        if let Some(last_purchase) = last_purchase {
+            let ready_at = last_purchase.saturating_add(config.purchase_cooldown_secs);
+            if now < ready_at {
+                msg!("Next purchase allowed in {} seconds", ready_at - now);
+                return Err(PledgeError::CooldownActive);
+            }
+        }
+    }
+
+    let sale_phase = get_sale_phase(now - config.sale_start_time, &config.phase_durations);
+    let rate = config.phase_rates[sale_phase];
+
+    let (payment_decimals, pledge_decimals) = (config.payment_decimals, config.pledge_decimals);
+    let requested_tokens = match math::lamports_to_pledge_base_units(amount, rate, payment_decimals, pledge_decimals) {
+        Err(PledgeError::PurchaseTooSmall) => {
+            msg!("Payment of {} buys less than a pledge token base unit", amount);
+            return Err(PledgeError::PurchaseTooSmall);
+        }
+        result => result?,
+    };
+    let remaining = config.total_pledge_supply.saturating_sub(config.total_pledge_sold);
+    let (pledge_tokens, paid) = if allow_partial && requested_tokens > remaining && remaining > 0 {
+        // Rounded up, so the remainder never sells below the phase rate.
+        (remaining, math::pledge_base_units_to_lamports(remaining, rate, payment_decimals, pledge_decimals)?)
+    } else {
+        (requested_tokens, amount)
+    };
+
+    // The cap is global: every user's purchases count against the same supply.
+    let total_pledge_sold = config
+        .total_pledge_sold
+        .checked_add(pledge_tokens)
+        .ok_or(PledgeError::ArithmeticOverflow)?;
+    if total_pledge_sold > config.total_pledge_supply {
+        msg!("Purchase exceeds the remaining pledge supply");
+        return Err(PledgeError::SupplyExhausted);
+    }
+
+    let phase_sold = config.phase_sold[sale_phase]
+        .checked_add(pledge_tokens)
+        .ok_or(PledgeError::ArithmeticOverflow)?;
+    if phase_sold > config.phase_caps[sale_phase] {
+        msg!("Purchase exceeds the allocation of sale phase {}", sale_phase);
+        return Err(PledgeError::PhaseCapExceeded);
+    }
+
+    // Counts everything the user ever bought, so unlocking frees no headroom.
+    let total_purchased = user_state
+        .total_purchased
+        .checked_add(pledge_tokens)
+        .ok_or(PledgeError::ArithmeticOverflow)?;
+    if config.max_per_user != 0 && total_purchased > config.max_per_user {
+        msg!("Purchase exceeds the per-user cap of {}", config.max_per_user);
+        return Err(PledgeError::UserCapExceeded);
+    }
+
+    let purchase_count = user_state.purchase_count.checked_add(1).ok_or(PledgeError::ArithmeticOverflow)?;
+    let vesting_end_time = now.checked_add(lock_duration).ok_or(PledgeError::ArithmeticOverflow)?;
+    user_state.add_lot(PledgeLot {
+        amount: pledge_tokens,
+        rate,
+        lock_start_time: now,
+        vesting_end_time,
+        multiplier_bps,
+    })?;
+    user_state.total_purchased = total_purchased;
+    user_state.purchase_count = purchase_count;
+    record_activity(user_state, now)?;
+
+    Ok(BuyOutcome {
+        rate,
+        pledge_tokens,
+        requested_tokens,
+        paid,
+        total_pledge_sold,
+        phase: sale_phase,
+        phase_sold,
+        phase_time_remaining: get_phase_time_remaining(sale_phase, config, now),
+        lock_duration,
+        multiplier_bps,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReferralOutcome {
+    pub bonus: u64,
+    // The config's total_pledge_sold once the bonus is counted too.
+    pub total_pledge_sold: u64,
+}
+
+// Credits the referrer of `outcome`'s purchase with its bonus share, as a lot
+// on the purchase's tier vesting alongside it. The bonus comes out of the
+// global supply but not out of any phase or per-user allowance.
+pub fn apply_referral(
+    referrer: &mut UserState,
+    config: &ContractConfig,
+    outcome: &BuyOutcome,
+    now: u64,
+) -> Result<ReferralOutcome, PledgeError> {
+    let bonus = math::apply_percent(outcome.pledge_tokens, config.referral_bonus_percent)?;
+    if bonus == 0 {
+        return Ok(ReferralOutcome { bonus, total_pledge_sold: outcome.total_pledge_sold });
+    }
+
+    let total_pledge_sold = outcome
+        .total_pledge_sold
+        .checked_add(bonus)
+        .ok_or(PledgeError::ArithmeticOverflow)?;
+    if total_pledge_sold > config.total_pledge_supply {
+        msg!("Referral bonus exceeds the remaining pledge supply");
+        return Err(PledgeError::SupplyExhausted);
+    }
+
+    let vesting_end_time = now.checked_add(outcome.lock_duration).ok_or(PledgeError::ArithmeticOverflow)?;
+    referrer.add_lot(PledgeLot {
+        amount: bonus,
+        rate: outcome.rate,
+        lock_start_time: now,
+        vesting_end_time,
+        multiplier_bps: outcome.multiplier_bps,
+    })?;
+
+    Ok(ReferralOutcome { bonus, total_pledge_sold })
+}
+
+// Counts `outcome`'s purchase towards the buyer's group. When the config
+// enforces group caps the group's combined purchases, like a single user's,
+// may not exceed max_per_user.
+pub fn apply_group_buy(group: &mut BuyGroup, config: &ContractConfig, outcome: &BuyOutcome) -> Result<(), PledgeError> {
+    let total_purchased = group
+        .total_purchased
+        .checked_add(outcome.pledge_tokens)
+        .ok_or(PledgeError::ArithmeticOverflow)?;
+    if config.enforce_group_cap && config.max_per_user != 0 && total_purchased > config.max_per_user {
+        msg!("Purchase exceeds the group cap of {}", config.max_per_user);
+        return Err(PledgeError::GroupCapExceeded);
+    }
+    group.total_purchased = total_purchased;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompoundOutcome {
+    pub solhit_rewards: u64,
+    pub pledge_tokens: u64,
+    // The config's total_pledge_sold once the compounded tokens are counted.
+    pub total_pledge_sold: u64,
+}
+
+// Converts all of the user's accrued rewards into a new 1x lot of pledge
+// tokens at the config's compound rate. The lot vests compound_vesting_boost
+// after the user's latest lot, or after `now` when nothing is left vesting,
+// so the user's vesting_end_time moves back by the boost. Like a referral
+// bonus it comes out of the global supply but not out of any phase or
+// per-user allowance. The caller accrues up to `now` first.
+pub fn apply_compound(
+    user_state: &mut UserState,
+    config: &ContractConfig,
+    now: u64,
+) -> Result<CompoundOutcome, PledgeError> {
+    check_clock(user_state, now)?;
+    let solhit_rewards = user_state.solhit_rewards;
+    let pledge_tokens = math::apply_percent(solhit_rewards, config.compound_rate)?;
+    if pledge_tokens == 0 {
+        msg!("No rewards to compound");
+        return Err(PledgeError::NothingToCompound);
+    }
+
+    let total_pledge_sold = config
+        .total_pledge_sold
+        .checked_add(pledge_tokens)
+        .ok_or(PledgeError::ArithmeticOverflow)?;
+    if total_pledge_sold > config.total_pledge_supply {
+        msg!("Compounding exceeds the remaining pledge supply");
+        return Err(PledgeError::SupplyExhausted);
+    }
+
+    let vesting_end_time = user_state
+        .vesting_end_time
+        .max(now)
+        .checked_add(config.compound_vesting_boost)
+        .ok_or(PledgeError::ArithmeticOverflow)?;
+    user_state.add_lot(PledgeLot {
+        amount: pledge_tokens,
+        rate: config.compound_rate,
+        lock_start_time: now,
+        vesting_end_time,
+        multiplier_bps: BASIS_POINTS,
+    })?;
+    user_state.solhit_rewards = 0;
+    record_activity(user_state, now)?;
+
+    Ok(CompoundOutcome { solhit_rewards, pledge_tokens, total_pledge_sold })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferOutcome {
+    // What moved: the source's locked pledge tokens and its rewards accrued
+    // up to the transfer.
+    pub pledge_tokens: u64,
+    pub solhit_rewards: u64,
+}
+
+// Moves the whole position of `source` into `destination`. Both are accrued
+// up to `now` first, so neither earns for the same interval twice. The
+// source's lots move over as they are, each keeping its own lock start,
+// vesting end, rate and multiplier, which merges the summary fields: the
+// destination's locked_pledge_tokens is the sum of both, its lock_start_time
+// the earlier and its vesting_end_time the later of the two. The rewards are
+// added to the destination's. The source is left without lots or rewards but
+// keeps its history (total_claimed, total_purchased, purchase_count). Nothing
+// changes unless the whole transfer succeeds.
+pub fn apply_transfer(
+    source: &mut UserState,
+    destination: &mut UserState,
+    config: &ContractConfig,
+    now: u64,
+) -> Result<TransferOutcome, PledgeError> {
+    let mut from = source.clone();
+    let mut to = destination.clone();
+    record_activity(&mut from, now)?;
+    record_activity(&mut to, now)?;
+    accrue(&mut from, config, now)?;
+    accrue(&mut to, config, now)?;
+    if from.lot_count == 0 && from.solhit_rewards == 0 {
+        msg!("Source has no position to transfer");
+        return Err(PledgeError::NothingToTransfer);
+    }
+
+    let outcome = TransferOutcome { pledge_tokens: from.locked_pledge_tokens, solhit_rewards: from.solhit_rewards };
+    for lot in from.remove_lots(|_| true)? {
+        to.add_lot(lot)?;
+    }
+    to.solhit_rewards = to
+        .solhit_rewards
+        .checked_add(from.solhit_rewards)
+        .ok_or(PledgeError::ArithmeticOverflow)?;
+    from.solhit_rewards = 0;
+
+    *source = from;
+    *destination = to;
+    Ok(outcome)
+}
+
+// Folds `duplicate`, a second user state of the same owner left over from
+// before user states were PDAs, into `user_state`. Its position moves over
+// as in apply_transfer, and its history (total_claimed, total_purchased,
+// purchase_count) adds to the user's. The duplicate's beneficiary, delegate
+// and group are dropped. Nothing changes unless the whole merge succeeds.
+pub fn apply_merge(
+    user_state: &mut UserState,
+    duplicate: &UserState,
+    config: &ContractConfig,
+    now: u64,
+) -> Result<(), PledgeError> {
+    let mut merged = user_state.clone();
+    let mut duplicate = duplicate.clone();
+    record_activity(&mut merged, now)?;
+    if duplicate.lot_count != 0 || duplicate.solhit_rewards != 0 {
+        apply_transfer(&mut duplicate, &mut merged, config, now)?;
+    }
+    merged.total_claimed = merged
+        .total_claimed
+        .checked_add(duplicate.total_claimed)
+        .ok_or(PledgeError::ArithmeticOverflow)?;
+    merged.total_purchased = merged
+        .total_purchased
+        .checked_add(duplicate.total_purchased)
+        .ok_or(PledgeError::ArithmeticOverflow)?;
+    merged.purchase_count = merged
+        .purchase_count
+        .checked_add(duplicate.purchase_count)
+        .ok_or(PledgeError::ArithmeticOverflow)?;
+
+    *user_state = merged;
+    Ok(())
+}
+
+// A phase schedule is usable when every phase has a positive rate and length
+// and the finite phases end at a representable time. The last phase may run
+// forever with a u64::MAX duration.
+pub fn validate_phase_config(durations: &[u64; 5], rates: &[u64; 5]) -> Result<(), PledgeError> {
+    if rates.contains(&0) {
+        msg!("Phase rates must be greater than zero");
+        return Err(PledgeError::InvalidPhaseConfig);
+    }
+    if durations.contains(&0) {
+        msg!("Phase durations must be greater than zero");
+        return Err(PledgeError::InvalidPhaseConfig);
+    }
+    let finite = &durations[..durations.len() - 1];
+    if finite.iter().try_fold(0u64, |end, &duration| end.checked_add(duration)).is_none() {
+        msg!("Phase durations overflow");
+        return Err(PledgeError::InvalidPhaseConfig);
+    }
+    Ok(())
+}
+
+// When the last phase with a finite duration ends, from which the sale may be
+// finalized. An open-ended last phase would never end, so it does not count.
+pub fn sale_end_time(config: &ContractConfig) -> Result<u64, PledgeError> {
+    config
+        .phase_durations
+        .iter()
+        .filter(|&&duration| duration != u64::MAX)
+        .try_fold(config.sale_start_time, |end, &duration| end.checked_add(duration))
+        .ok_or(PledgeError::ArithmeticOverflow)
+}
+
+// Converts a token payment of `amount` base units of a mint with `decimals`
+// decimals to the `payment_decimals` scale that apply_buy prices. Precision
+// below that scale is dropped.
+pub fn scale_payment(amount: u64, decimals: u8, payment_decimals: u8) -> Result<u64, PledgeError> {
+    if decimals <= payment_decimals {
+        let factor = 10u64.checked_pow((payment_decimals - decimals) as u32).ok_or(PledgeError::ArithmeticOverflow)?;
+        math::mul_div_floor(amount, factor, 1)
+    } else {
+        // Any factor past a u64 is more than every amount, which scales to 0.
+        let factor = 10u64.checked_pow((decimals - payment_decimals) as u32);
+        factor.map_or(Ok(0), |factor| math::mul_div_floor(amount, 1, factor))
+    }
+}
+
+// Fails when `now` is more than MAX_CLOCK_SKEW seconds behind the user's
+// last_activity: a clock running backwards would otherwise pass unnoticed
+// through the saturating time arithmetic of accrual and vesting.
+pub fn check_clock(user_state: &UserState, now: u64) -> Result<(), PledgeError> {
+    if now.saturating_add(MAX_CLOCK_SKEW) < user_state.last_activity {
+        msg!("Clock reads {}, before the last activity at {}", now, user_state.last_activity);
+        return Err(PledgeError::ClockWentBackwards);
+    }
+    Ok(())
+}
+
+// Fails while the admin holds the user, until hold_until.
+pub fn check_compliance_hold(user_state: &UserState, now: u64) -> Result<(), PledgeError> {
+    if now < user_state.hold_until {
+        msg!("User is on a compliance hold until {}", user_state.hold_until);
+        return Err(PledgeError::ComplianceHold);
+    }
+    Ok(())
+}
+
+// Checks the clock and records activity at `now`. A time within the allowed
+// skew of last_activity leaves it where it is, so it never moves back.
+pub fn record_activity(user_state: &mut UserState, now: u64) -> Result<(), PledgeError> {
+    check_clock(user_state, now)?;
+    user_state.last_activity = user_state.last_activity.max(now);
+    Ok(())
+}
+
+// Credits the rewards earned up to `now` at the config's reward rate.
+pub fn accrue(user_state: &mut UserState, config: &ContractConfig, now: u64) -> Result<(), PledgeError> {
+    user_state.accrue_rewards(config.reward_rate_bps, config.post_vesting_grace_secs, now)
+}
+
+// Whether accruing `before` into `after` changed nothing worth storing: no
+// rewards were added and no lot reached its vesting end in between. Only
+// the timestamps moved.
+pub fn is_noop_accrual(before: &UserState, after: &UserState) -> bool {
+    after.solhit_rewards == before.solhit_rewards
+        && !before.active_lots().iter().any(|lot| {
+            lot.vesting_end_time > before.last_reward_update && lot.vesting_end_time <= after.last_reward_update
+        })
+}
+
+// Checkpoints from the user's earliest lock start to its latest vesting end,
+// a month apart, or further apart when that would take more than
+// MAX_VESTING_CHECKPOINTS; the last one is the vesting end itself. Each
+// checkpoint's rewards are what accrue would leave in solhit_rewards at its
+// time, so the timetable follows UpdateReward exactly.
+pub fn vesting_schedule(user_state: &UserState, config: &ContractConfig) -> Result<Vec<VestingCheckpoint>, PledgeError> {
+    if user_state.lot_count == 0 {
+        return Ok(Vec::new());
+    }
+    let start = user_state.lock_start_time;
+    let end = user_state.vesting_end_time;
+    let span = end.saturating_sub(start);
+    let steps = span.div_ceil(SECONDS_PER_MONTH).min(MAX_VESTING_CHECKPOINTS as u64 - 1);
+    let interval = if steps == 0 { 0 } else { span.div_ceil(steps) };
+
+    let mut schedule = Vec::with_capacity(steps as usize + 1);
+    for step in 0..=steps {
+        let timestamp = start.saturating_add(step.saturating_mul(interval)).min(end);
+        let mut unlocked_amount: u64 = 0;
+        for lot in user_state.active_lots().iter().filter(|lot| lot.vesting_end_time <= timestamp) {
+            unlocked_amount = unlocked_amount.checked_add(lot.amount).ok_or(PledgeError::ArithmeticOverflow)?;
+        }
+        let mut accrued = user_state.clone();
+        accrue(&mut accrued, config, timestamp)?;
+        schedule.push(VestingCheckpoint { timestamp, unlocked_amount, accrued_rewards: accrued.solhit_rewards });
+    }
+    Ok(schedule)
+}
+
+// Team tokens vested by `now` that have not been released yet. Fails before
+// the cliff and once everything vested so far is out.
+pub fn releasable_team_tokens(config: &ContractConfig, now: u64) -> Result<u64, PledgeError> {
+    let team_lock = &config.team_lock;
+    let cliff_end = team_lock.unlock_start.saturating_add(team_lock.unlock_cliff);
+    if now < cliff_end {
+        msg!("Team tokens are locked until {}", cliff_end);
+        return Err(PledgeError::NothingToRelease);
+    }
+    let releasable = team_lock.vested(now)?.saturating_sub(config.team_released);
+    if releasable == 0 {
+        msg!("All {} vested team tokens are released", config.team_released);
+        return Err(PledgeError::NothingToRelease);
+    }
+    Ok(releasable)
+}
+
+// Takes everything the user has earned by `now` out of solhit_rewards and
+// returns it, once reward_expiry_secs have passed since the user's vesting
+// end or, with nothing locked, since anything last acted on the user. A
+// reward_expiry_secs of 0 means rewards never expire.
+pub fn expire_rewards(user_state: &mut UserState, config: &ContractConfig, now: u64) -> Result<u64, PledgeError> {
+    let since = if user_state.is_vesting() { user_state.vesting_end_time } else { user_state.last_activity };
+    let expires_at = since.saturating_add(config.reward_expiry_secs);
+    if config.reward_expiry_secs == 0 || now < expires_at {
+        msg!("Rewards expire at {}, now is {}", expires_at, now);
+        return Err(PledgeError::NotExpired);
+    }
+    record_activity(user_state, now)?;
+    accrue(user_state, config, now)?;
+    Ok(std::mem::take(&mut user_state.solhit_rewards))
+}
+
+// How much a claim of `amount` pays out, 0 meaning everything accrued.
+pub fn compute_claim(user_state: &UserState, amount: u64) -> Result<u64, PledgeError> {
+    let claimed = if amount == 0 { user_state.solhit_rewards } else { amount };
+    if claimed > user_state.solhit_rewards {
+        msg!("Cannot claim {} rewards, only {} available", claimed, user_state.solhit_rewards);
+        return Err(PledgeError::InsufficientRewards);
+    }
+    if claimed == 0 {
+        msg!("No rewards to claim");
+        return Err(PledgeError::NothingToClaim);
+    }
+    Ok(claimed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        COMPOUND_VESTING_BOOST, DEFAULT_LOCK_TIER, LOCKED_SOLHIT_TOKENS, LOCK_TIERS, MAX_PLEDGE_LOTS, PAYMENT_DECIMALS,
+        PHASE_DURATIONS, PHASE_RATES, REWARD_RATE_BPS, TOTAL_PLEDGE_SUPPLY, TOTAL_SOLHIT_SUPPLY, VESTING_PERIOD,
+    };
+
+    // Small xorshift generator, enough to drive randomized cases reproducibly.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, bound: u64) -> u64 {
+            self.next() % bound
+        }
+    }
+
+    // What `amount` pledge tokens earn over a full lock at 1x.
+    fn full_reward(amount: u64) -> u64 {
+        amount * REWARD_RATE_BPS / BASIS_POINTS
+    }
+
+    // Selling the whole pledge supply at the most generous lock tier still
+    // pays out no more Solheist than is left unlocked.
+    #[test]
+    fn test_rewards_fit_solhit_supply() {
+        let multiplier_bps = LOCK_TIERS.iter().map(|&(_, multiplier_bps)| multiplier_bps).max().unwrap();
+        let lot = PledgeLot {
+            amount: TOTAL_PLEDGE_SUPPLY,
+            rate: PHASE_RATES[0],
+            lock_start_time: 0,
+            vesting_end_time: VESTING_PERIOD,
+            multiplier_bps,
+        };
+        let total_rewards = lot.accrued_rewards(REWARD_RATE_BPS, 0, VESTING_PERIOD).unwrap();
+        assert!(total_rewards <= TOTAL_SOLHIT_SUPPLY - LOCKED_SOLHIT_TOKENS);
+        assert_eq!(lot.accrued_rewards(REWARD_RATE_BPS, 0, u64::MAX).unwrap(), total_rewards);
+    }
+
+    #[test]
+    fn test_apply_buy() {
+        let mut user_state = UserState::default();
+        let config = ContractConfig::new();
+
+        let outcome = apply_buy(&mut user_state, &config, 100, DEFAULT_LOCK_TIER, false, 0).unwrap();
+        let (lock_duration, multiplier_bps) = LOCK_TIERS[DEFAULT_LOCK_TIER as usize];
+        assert_eq!(
+            outcome,
+            BuyOutcome {
+                rate: 200,
+                pledge_tokens: 200,
+                requested_tokens: 200,
+                paid: 100,
+                total_pledge_sold: 200,
+                phase: 0,
+                phase_sold: 200,
+                phase_time_remaining: PHASE_DURATIONS[0],
+                lock_duration,
+                multiplier_bps,
+            }
+        );
+        assert_eq!(user_state.locked_pledge_tokens, 200);
+        assert_eq!(user_state.vesting_end_time, lock_duration);
+    }
+
+    #[test]
+    fn test_apply_buy_lock_tiers() {
+        let config = ContractConfig::new();
+        let mut short = UserState::default();
+        let mut long = UserState::default();
+        apply_buy(&mut short, &config, 100, 0, false, 0).unwrap();
+        apply_buy(&mut long, &config, 100, 2, false, 0).unwrap();
+        assert_eq!(short.lots[0].vesting_end_time, LOCK_TIERS[0].0);
+        assert_eq!(long.lots[0].vesting_end_time, LOCK_TIERS[2].0);
+
+        // Both accrue at the same pace per second, but the 2x lot keeps going
+        // four times as long and ends up with four times the rewards.
+        accrue(&mut short, &config, LOCK_TIERS[0].0 / 2).unwrap();
+        accrue(&mut long, &config, LOCK_TIERS[0].0 / 2).unwrap();
+        assert_eq!(short.solhit_rewards, full_reward(200) / 4);
+        assert_eq!(long.solhit_rewards, short.solhit_rewards);
+        accrue(&mut short, &config, LOCK_TIERS[2].0).unwrap();
+        accrue(&mut long, &config, LOCK_TIERS[2].0).unwrap();
+        assert_eq!(short.solhit_rewards, full_reward(200) / 2);
+        assert_eq!(long.solhit_rewards, full_reward(200) * 2);
+
+        let mut user_state = UserState::default();
+        assert_eq!(apply_buy(&mut user_state, &config, 100, 3, false, 0), Err(PledgeError::InvalidLockTier));
+        assert_eq!(apply_buy(&mut user_state, &config, 100, u8::MAX, false, 0), Err(PledgeError::InvalidLockTier));
+        assert_eq!(user_state.lot_count, 0);
+    }
+
+    // A second purchase a year into a two-year lock vests on its own
+    // schedule and leaves the first lot's end where it was.
+    #[test]
+    fn test_apply_buy_repeat_keeps_earlier_vesting() {
+        let config = ContractConfig::new();
+        let mut user_state = UserState::default();
+        let (two_years, _) = LOCK_TIERS[2];
+        apply_buy(&mut user_state, &config, 100, 2, false, 0).unwrap();
+        apply_buy(&mut user_state, &config, 100, 2, false, two_years / 2).unwrap();
+        assert_eq!(user_state.lots[0].vesting_end_time, two_years);
+        assert_eq!(user_state.lots[1].vesting_end_time, two_years / 2 * 3);
+        assert_eq!(user_state.vesting_end_time, two_years / 2 * 3);
+    }
+
+    #[test]
+    fn test_apply_referral() {
+        let config = ContractConfig::new();
+        let mut buyer = UserState::default();
+        let mut referrer = UserState::default();
+
+        let outcome = apply_buy(&mut buyer, &config, 1_000, DEFAULT_LOCK_TIER, false, 0).unwrap();
+        let referral = apply_referral(&mut referrer, &config, &outcome, 0).unwrap();
+        assert_eq!(referral, ReferralOutcome { bonus: 100, total_pledge_sold: 2_100 });
+        assert_eq!(referrer.locked_pledge_tokens, 100);
+        assert_eq!(referrer.vesting_end_time, buyer.vesting_end_time);
+        assert_eq!(referrer.lots[0].multiplier_bps, buyer.lots[0].multiplier_bps);
+        // A bonus is not a purchase.
+        assert_eq!((referrer.total_purchased, referrer.purchase_count), (0, 0));
+
+        // The bonus must fit the remaining supply as well.
+        let config = ContractConfig { total_pledge_supply: 2_099, ..config };
+        assert_eq!(apply_referral(&mut referrer, &config, &outcome, 0), Err(PledgeError::SupplyExhausted));
+        assert_eq!(referrer.lot_count, 1);
+
+        // Too small a purchase earns no bonus and opens no lot.
+        let outcome = apply_buy(&mut buyer, &config, 4, DEFAULT_LOCK_TIER, false, 0).unwrap();
+        assert_eq!(apply_referral(&mut referrer, &config, &outcome, 0).unwrap().bonus, 0);
+        assert_eq!(referrer.lot_count, 1);
+    }
+
+    #[test]
+    fn test_apply_compound() {
+        let config = ContractConfig::new();
+        let mut user_state = UserState::default();
+        apply_buy(&mut user_state, &config, 100, DEFAULT_LOCK_TIER, false, 0).unwrap();
+        let vesting_end_time = user_state.vesting_end_time;
+        let config = ContractConfig { total_pledge_sold: 200, ..config };
+        assert_eq!(apply_compound(&mut user_state, &config, 10), Err(PledgeError::NothingToCompound));
+
+        user_state.solhit_rewards = 500;
+        let outcome = apply_compound(&mut user_state, &config, 10).unwrap();
+        assert_eq!(outcome, CompoundOutcome { solhit_rewards: 500, pledge_tokens: 500, total_pledge_sold: 700 });
+        assert_eq!(user_state.solhit_rewards, 0);
+        assert_eq!(user_state.locked_pledge_tokens, 700);
+        assert_eq!(user_state.vesting_end_time, vesting_end_time + COMPOUND_VESTING_BOOST);
+        assert_eq!(user_state.lots[1].lock_start_time, 10);
+
+        // With everything vested the boost counts from now.
+        let mut vested = UserState { solhit_rewards: 50, ..UserState::default() };
+        apply_compound(&mut vested, &config, 1_000).unwrap();
+        assert_eq!(vested.vesting_end_time, 1_000 + COMPOUND_VESTING_BOOST);
+
+        // Past the supply the state is left as it was.
+        user_state.solhit_rewards = 500;
+        let config = ContractConfig { total_pledge_supply: 1_199, total_pledge_sold: 700, ..config };
+        assert_eq!(apply_compound(&mut user_state, &config, 20), Err(PledgeError::SupplyExhausted));
+        assert_eq!((user_state.solhit_rewards, user_state.lot_count), (500, 2));
+    }
+
+    #[test]
+    fn test_vesting_schedule() {
+        let config = ContractConfig::new();
+        let mut user_state = UserState::default();
+        assert_eq!(vesting_schedule(&user_state, &config).unwrap(), Vec::new());
+
+        apply_buy(&mut user_state, &config, 100, 0, false, 0).unwrap();
+        apply_buy(&mut user_state, &config, 300, DEFAULT_LOCK_TIER, false, 0).unwrap();
+        let schedule = vesting_schedule(&user_state, &config).unwrap();
+        assert_eq!(schedule.len(), 13);
+        assert_eq!(schedule[0], VestingCheckpoint { timestamp: 0, unlocked_amount: 0, accrued_rewards: 0 });
+        assert_eq!(schedule[6].unlocked_amount, user_state.lots[0].amount);
+        assert_eq!(schedule[12].timestamp, user_state.vesting_end_time);
+        assert_eq!(schedule[12].unlocked_amount, user_state.locked_pledge_tokens);
+        for checkpoint in &schedule {
+            let mut accrued = user_state.clone();
+            accrue(&mut accrued, &config, checkpoint.timestamp).unwrap();
+            assert_eq!(checkpoint.accrued_rewards, accrued.solhit_rewards);
+        }
+        // Building the schedule leaves the state alone.
+        assert_eq!(user_state.solhit_rewards, 0);
+
+        // Long locks spread the checkpoints out instead of adding more.
+        apply_buy(&mut user_state, &config, 100, 2, false, 0).unwrap();
+        user_state.vesting_end_time = 100 * SECONDS_PER_MONTH;
+        let schedule = vesting_schedule(&user_state, &config).unwrap();
+        assert_eq!(schedule.len(), MAX_VESTING_CHECKPOINTS);
+        assert_eq!(schedule.last().unwrap().timestamp, 100 * SECONDS_PER_MONTH);
+    }
+
+    #[test]
+    fn test_apply_buy_cooldown() {
+        let config = ContractConfig { purchase_cooldown_secs: 60, ..ContractConfig::new() };
+        let mut user_state = UserState::default();
+
+        apply_buy(&mut user_state, &config, 100, DEFAULT_LOCK_TIER, false, 1_000).unwrap();
+        assert_eq!(apply_buy(&mut user_state, &config, 100, DEFAULT_LOCK_TIER, false, 1_000), Err(PledgeError::CooldownActive));
+        assert_eq!(apply_buy(&mut user_state, &config, 100, DEFAULT_LOCK_TIER, false, 1_059), Err(PledgeError::CooldownActive));
+        assert_eq!(user_state.lot_count, 1);
+        apply_buy(&mut user_state, &config, 100, DEFAULT_LOCK_TIER, false, 1_060).unwrap();
+
+        // Other activity does not restart the cooldown.
+        user_state.last_activity = 1_100;
+        apply_buy(&mut user_state, &config, 100, DEFAULT_LOCK_TIER, false, 1_120).unwrap();
+        assert_eq!(user_state.lot_count, 3);
+
+        // Without a cooldown purchases may follow each other immediately.
+        let config = ContractConfig::new();
+        apply_buy(&mut user_state, &config, 100, DEFAULT_LOCK_TIER, false, 1_120).unwrap();
+    }
+
+    #[test]
+    fn test_apply_buy_errors_leave_state_untouched() {
+        let config = ContractConfig { sale_start_time: 1_000, ..ContractConfig::new() };
+        let mut user_state = UserState::default();
+
+        assert_eq!(apply_buy(&mut user_state, &config, 0, DEFAULT_LOCK_TIER, false, 1_000), Err(PledgeError::InvalidAmount));
+        assert_eq!(apply_buy(&mut user_state, &config, 100, DEFAULT_LOCK_TIER, false, 999), Err(PledgeError::SaleNotStarted));
+        assert_eq!(
+            apply_buy(&mut user_state, &config, u64::MAX, DEFAULT_LOCK_TIER, false, 1_000),
+            Err(PledgeError::ArithmeticOverflow)
+        );
+        assert_eq!(
+            apply_buy(&mut user_state, &config, TOTAL_PLEDGE_SUPPLY, DEFAULT_LOCK_TIER, false, 1_000),
+            Err(PledgeError::SupplyExhausted)
+        );
+        let finalized = ContractConfig { sale_finalized: true, ..config };
+        assert_eq!(apply_buy(&mut user_state, &finalized, 100, DEFAULT_LOCK_TIER, false, 1_000), Err(PledgeError::SaleEnded));
+        assert_eq!(user_state.lot_count, 0);
+        assert_eq!(user_state.locked_pledge_tokens, 0);
+    }
+
+    #[test]
+    fn test_apply_buy_phase_cap() {
+        let mut config = ContractConfig { phase_caps: [1_000; 5], ..ContractConfig::new() };
+        let mut user_state = UserState::default();
+
+        // Phase 0 sells at 200%, so 500 lamports fill its cap exactly.
+        let outcome = apply_buy(&mut user_state, &config, 500, DEFAULT_LOCK_TIER, false, 0).unwrap();
+        assert_eq!(outcome.phase_sold, 1_000);
+        config.phase_sold[0] = outcome.phase_sold;
+        config.total_pledge_sold = outcome.total_pledge_sold;
+        assert_eq!(apply_buy(&mut user_state, &config, 1, DEFAULT_LOCK_TIER, false, 0), Err(PledgeError::PhaseCapExceeded));
+        assert_eq!(user_state.lot_count, 1);
+
+        let outcome = apply_buy(&mut user_state, &config, 100, DEFAULT_LOCK_TIER, false, PHASE_DURATIONS[0]).unwrap();
+        assert_eq!((outcome.phase, outcome.phase_sold, outcome.total_pledge_sold), (1, 175, 1_175));
+    }
+
+    #[test]
+    fn test_apply_buy_converts_decimals() {
+        // One SOL, at 9 decimals, buys two whole 6 decimal tokens in the 200%
+        // first phase rather than two billion base units.
+        let config = ContractConfig { pledge_decimals: 6, ..ContractConfig::new() };
+        let mut user_state = UserState::default();
+        let outcome = apply_buy(&mut user_state, &config, 1_000_000_000, DEFAULT_LOCK_TIER, false, 0).unwrap();
+        assert_eq!((outcome.requested_tokens, outcome.pledge_tokens, outcome.paid), (2_000_000, 2_000_000, 1_000_000_000));
+        assert_eq!(user_state.locked_pledge_tokens, 2_000_000);
+
+        // Dust worth less than a base unit buys nothing and opens no lot.
+        assert_eq!(apply_buy(&mut user_state, &config, 499, DEFAULT_LOCK_TIER, false, 0), Err(PledgeError::PurchaseTooSmall));
+        assert_eq!(user_state.lot_count, 1);
+        apply_buy(&mut user_state, &config, 500, DEFAULT_LOCK_TIER, false, 0).unwrap();
+        assert_eq!(user_state.locked_pledge_tokens, 2_000_001);
+
+        // A partial fill charges the lamports of what is left, rounded up.
+        let config = ContractConfig { total_pledge_sold: TOTAL_PLEDGE_SUPPLY - 3, ..config };
+        let outcome = apply_buy(&mut UserState::default(), &config, 1_000_000, DEFAULT_LOCK_TIER, true, 0).unwrap();
+        assert_eq!((outcome.requested_tokens, outcome.pledge_tokens, outcome.paid), (2_000, 3, 1_500));
+
+        // With the decimals the other way round each lamport buys thousands.
+        let config = ContractConfig { payment_decimals: 6, ..ContractConfig::new() };
+        let outcome = apply_buy(&mut UserState::default(), &config, 1, DEFAULT_LOCK_TIER, false, 0).unwrap();
+        assert_eq!(outcome.pledge_tokens, 2_000);
+    }
+
+    #[test]
+    fn test_apply_buy_partial_fill() {
+        let mut config = ContractConfig { total_pledge_sold: TOTAL_PLEDGE_SUPPLY - 101, ..ContractConfig::new() };
+        let mut user_state = UserState::default();
+
+        // Without the flag the whole purchase has to fit.
+        assert_eq!(apply_buy(&mut user_state, &config, 100, DEFAULT_LOCK_TIER, false, 0), Err(PledgeError::SupplyExhausted));
+        assert_eq!(user_state.lot_count, 0);
+
+        // 101 tokens at 200% cost 50.5 lamports, rounded up.
+        let outcome = apply_buy(&mut user_state, &config, 100, DEFAULT_LOCK_TIER, true, 0).unwrap();
+        assert_eq!((outcome.requested_tokens, outcome.pledge_tokens, outcome.paid), (200, 101, 51));
+        assert_eq!(outcome.total_pledge_sold, TOTAL_PLEDGE_SUPPLY);
+        assert_eq!(user_state.locked_pledge_tokens, 101);
+
+        // A purchase that fits is not cut down.
+        config.total_pledge_sold = 0;
+        let outcome = apply_buy(&mut user_state, &config, 100, DEFAULT_LOCK_TIER, true, 0).unwrap();
+        assert_eq!((outcome.requested_tokens, outcome.pledge_tokens, outcome.paid), (200, 200, 100));
+
+        config.total_pledge_sold = TOTAL_PLEDGE_SUPPLY;
+        assert_eq!(apply_buy(&mut user_state, &config, 100, DEFAULT_LOCK_TIER, true, 0), Err(PledgeError::SupplyExhausted));
+    }
+
+    #[test]
+    fn test_apply_buy_user_cap() {
+        let config = ContractConfig { max_per_user: 1_000, ..ContractConfig::new() };
+        let mut user_state = UserState::default();
+
+        apply_buy(&mut user_state, &config, 300, DEFAULT_LOCK_TIER, false, 0).unwrap();
+        apply_buy(&mut user_state, &config, 200, DEFAULT_LOCK_TIER, false, 0).unwrap();
+        assert_eq!(user_state.total_purchased, 1_000);
+        assert_eq!(apply_buy(&mut user_state, &config, 1, DEFAULT_LOCK_TIER, false, 0), Err(PledgeError::UserCapExceeded));
+
+        // Unlocked lots still count against the cap.
+        user_state.remove_vested_lots(VESTING_PERIOD).unwrap();
+        assert_eq!(user_state.locked_pledge_tokens, 0);
+        assert_eq!(
+            apply_buy(&mut user_state, &config, 1, DEFAULT_LOCK_TIER, false, VESTING_PERIOD),
+            Err(PledgeError::UserCapExceeded)
+        );
+        assert_eq!(user_state.total_purchased, 1_000);
+    }
+
+    #[test]
+    fn test_apply_group_buy() {
+        let mut config = ContractConfig { max_per_user: 1_000, ..ContractConfig::new() };
+        let mut group = BuyGroup { total_purchased: 600, ..BuyGroup::default() };
+        let outcome = apply_buy(&mut UserState::default(), &config, 300, DEFAULT_LOCK_TIER, false, 0).unwrap();
+
+        // Without enforcement the group only keeps count.
+        apply_group_buy(&mut group, &config, &outcome).unwrap();
+        assert_eq!(group.total_purchased, 600 + outcome.pledge_tokens);
+
+        config.enforce_group_cap = true;
+        group.total_purchased = 1_000 - outcome.pledge_tokens;
+        apply_group_buy(&mut group, &config, &outcome).unwrap();
+        assert_eq!(group.total_purchased, 1_000);
+        assert_eq!(apply_group_buy(&mut group, &config, &outcome), Err(PledgeError::GroupCapExceeded));
+        assert_eq!(group.total_purchased, 1_000);
+
+        // 0 still means no cap.
+        config.max_per_user = 0;
+        apply_group_buy(&mut group, &config, &outcome).unwrap();
+    }
+
+    #[test]
+    fn test_validate_phase_config() {
+        assert_eq!(validate_phase_config(&PHASE_DURATIONS, &PHASE_RATES), Ok(()));
+        assert_eq!(validate_phase_config(&[1, 1, 1, 1, 1], &[1; 5]), Ok(()));
+        assert_eq!(validate_phase_config(&PHASE_DURATIONS, &[200, 175, 150, 125, 0]), Err(PledgeError::InvalidPhaseConfig));
+        assert_eq!(validate_phase_config(&[0, 1, 1, 1, u64::MAX], &PHASE_RATES), Err(PledgeError::InvalidPhaseConfig));
+        assert_eq!(
+            validate_phase_config(&[u64::MAX, 1, 1, 1, u64::MAX], &PHASE_RATES),
+            Err(PledgeError::InvalidPhaseConfig)
+        );
+    }
+
+    #[test]
+    fn test_sale_end_time() {
+        let config = ContractConfig { sale_start_time: 1_000, ..ContractConfig::new() };
+        assert_eq!(sale_end_time(&config), Ok(1_000 + PHASE_DURATIONS[..4].iter().sum::<u64>()));
+
+        // A schedule whose last phase is finite ends with it.
+        let config = ContractConfig { phase_durations: [10, 20, 30, 40, 50], ..config };
+        assert_eq!(sale_end_time(&config), Ok(1_150));
+        let config = ContractConfig { sale_start_time: u64::MAX - 100, ..config };
+        assert_eq!(sale_end_time(&config), Err(PledgeError::ArithmeticOverflow));
+    }
+
+    #[test]
+    fn test_scale_payment() {
+        // One whole token at 6 decimals is worth one SOL in lamports.
+        assert_eq!(scale_payment(1_000_000, 6, PAYMENT_DECIMALS), Ok(1_000_000_000));
+        assert_eq!(scale_payment(7, PAYMENT_DECIMALS, PAYMENT_DECIMALS), Ok(7));
+        assert_eq!(scale_payment(1_999, 12, PAYMENT_DECIMALS), Ok(1));
+        assert_eq!(scale_payment(999, 12, PAYMENT_DECIMALS), Ok(0));
+        assert_eq!(scale_payment(u64::MAX, 0, PAYMENT_DECIMALS), Err(PledgeError::ArithmeticOverflow));
+        // Payments priced at 6 decimals keep a 6 decimal token as it is.
+        assert_eq!(scale_payment(1_000_000, 6, 6), Ok(1_000_000));
+        assert_eq!(scale_payment(1_999, 9, 6), Ok(1));
+    }
+
+    #[test]
+    fn test_compute_claim() {
+        let user_state = UserState { solhit_rewards: 500, ..UserState::default() };
+        assert_eq!(compute_claim(&user_state, 0), Ok(500));
+        assert_eq!(compute_claim(&user_state, 200), Ok(200));
+        assert_eq!(compute_claim(&user_state, 501), Err(PledgeError::InsufficientRewards));
+        assert_eq!(compute_claim(&UserState::default(), 0), Err(PledgeError::NothingToClaim));
+    }
+
+    #[test]
+    fn test_expire_rewards() {
+        let config = ContractConfig { reward_expiry_secs: 100, ..ContractConfig::new() };
+        let mut bought = UserState::default();
+        apply_buy(&mut bought, &config, 100, DEFAULT_LOCK_TIER, false, 0).unwrap();
+        let expires_at = bought.vesting_end_time + 100;
+
+        let mut user_state = bought.clone();
+        assert_eq!(expire_rewards(&mut user_state, &config, expires_at - 1), Err(PledgeError::NotExpired));
+        assert_eq!((user_state.solhit_rewards, user_state.last_activity), (bought.solhit_rewards, bought.last_activity));
+
+        let amount = expire_rewards(&mut user_state, &config, expires_at).unwrap();
+        assert!(amount > 0);
+        assert_eq!(user_state.solhit_rewards, 0);
+        assert_eq!(user_state.last_activity, expires_at);
+
+        // Nothing accrues after the vesting end, so waiting longer expires no more.
+        let mut user_state = bought.clone();
+        assert_eq!(expire_rewards(&mut user_state, &config, expires_at * 10), Ok(amount));
+
+        // With nothing locked the clock starts at the last activity.
+        let mut unlocked = UserState { solhit_rewards: 7, last_activity: 50, ..UserState::default() };
+        assert_eq!(expire_rewards(&mut unlocked, &config, 149), Err(PledgeError::NotExpired));
+        assert_eq!(expire_rewards(&mut unlocked, &config, 150), Ok(7));
+
+        let disabled = ContractConfig { reward_expiry_secs: 0, ..config };
+        assert_eq!(expire_rewards(&mut bought, &disabled, u64::MAX), Err(PledgeError::NotExpired));
+    }
+
+    // However purchases are spread over time, the sold total matches the sum
+    // of the lots and never passes the supply.
+    #[test]
+    fn test_apply_transfer_lot_limit() {
+        let config = ContractConfig::new();
+        let mut source = UserState::default();
+        let mut destination = UserState::default();
+        apply_buy(&mut source, &config, 100, DEFAULT_LOCK_TIER, false, 0).unwrap();
+        for _ in 0..MAX_PLEDGE_LOTS {
+            apply_buy(&mut destination, &config, 100, DEFAULT_LOCK_TIER, false, 0).unwrap();
+        }
+        let (source_before, destination_before) = (source.clone(), destination.clone());
+
+        // The merged lots would not fit, so neither side changes, accrual included.
+        assert_eq!(
+            apply_transfer(&mut source, &mut destination, &config, VESTING_PERIOD),
+            Err(PledgeError::LotLimitReached)
+        );
+        assert_eq!(borsh::to_vec(&source).unwrap(), borsh::to_vec(&source_before).unwrap());
+        assert_eq!(borsh::to_vec(&destination).unwrap(), borsh::to_vec(&destination_before).unwrap());
+    }
+
+    #[test]
+    fn test_apply_merge() {
+        let config = ContractConfig::new();
+        let mut user_state = UserState::default();
+        let mut duplicate = UserState::default();
+        apply_buy(&mut user_state, &config, 100, DEFAULT_LOCK_TIER, false, 1_000).unwrap();
+        apply_buy(&mut duplicate, &config, 300, 0, false, 0).unwrap();
+        apply_buy(&mut duplicate, &config, 50, 2, false, 500).unwrap();
+        duplicate.total_claimed = 7;
+        duplicate.solhit_rewards = 11;
+
+        let now = 2_000;
+        let mut expected_rewards = duplicate.clone();
+        accrue(&mut expected_rewards, &config, now).unwrap();
+        let mut own_rewards = user_state.clone();
+        accrue(&mut own_rewards, &config, now).unwrap();
+        apply_merge(&mut user_state, &duplicate, &config, now).unwrap();
+
+        assert_eq!(user_state.lot_count, 3);
+        assert_eq!(user_state.locked_pledge_tokens, 200 + 600 + 100);
+        assert_eq!(user_state.lock_start_time, 0);
+        assert_eq!(user_state.vesting_end_time, 500 + LOCK_TIERS[2].0);
+        assert_eq!(user_state.solhit_rewards, own_rewards.solhit_rewards + expected_rewards.solhit_rewards);
+        assert_eq!((user_state.total_purchased, user_state.purchase_count, user_state.total_claimed), (900, 3, 7));
+        assert_eq!((user_state.last_reward_update, user_state.last_activity), (now, now));
+
+        // An empty duplicate only adds its history.
+        let empty = UserState { total_claimed: 3, purchase_count: 2, ..UserState::default() };
+        let before = user_state.clone();
+        apply_merge(&mut user_state, &empty, &config, now).unwrap();
+        assert_eq!(user_state.locked_pledge_tokens, before.locked_pledge_tokens);
+        assert_eq!((user_state.total_claimed, user_state.purchase_count), (10, 5));
+
+        // Counters that would overflow leave the user as it was.
+        let before = user_state.clone();
+        let overflowing = UserState { total_purchased: u64::MAX, ..duplicate.clone() };
+        assert_eq!(apply_merge(&mut user_state, &overflowing, &config, now), Err(PledgeError::ArithmeticOverflow));
+        assert_eq!(borsh::to_vec(&user_state).unwrap(), borsh::to_vec(&before).unwrap());
+    }
+
+    #[test]
+    fn test_check_compliance_hold() {
+        let user_state = UserState { hold_until: 100, ..UserState::default() };
+        assert_eq!(check_compliance_hold(&user_state, 99), Err(PledgeError::ComplianceHold));
+        assert_eq!(check_compliance_hold(&user_state, 100), Ok(()));
+        assert_eq!(check_compliance_hold(&UserState::default(), 0), Ok(()));
+    }
+
+    #[test]
+    fn test_is_noop_accrual() {
+        let config = ContractConfig::new();
+        let mut user_state = UserState::default();
+        apply_buy(&mut user_state, &config, 100_000, DEFAULT_LOCK_TIER, false, 0).unwrap();
+        let mut accrued = user_state.clone();
+        accrue(&mut accrued, &config, VESTING_PERIOD / 2).unwrap();
+        assert!(!is_noop_accrual(&user_state, &accrued));
+        let mut again = accrued.clone();
+        accrue(&mut again, &config, VESTING_PERIOD / 2).unwrap();
+        assert!(is_noop_accrual(&accrued, &again));
+
+        // A lot reaching its vesting end counts even when it adds no rewards.
+        let unrewarded = ContractConfig { reward_rate_bps: 0, ..ContractConfig::new() };
+        let vesting_end = user_state.vesting_end_time;
+        let mut before = user_state.clone();
+        accrue(&mut before, &unrewarded, vesting_end - 1).unwrap();
+        let mut after = before.clone();
+        accrue(&mut after, &unrewarded, vesting_end).unwrap();
+        assert_eq!(after.solhit_rewards, before.solhit_rewards);
+        assert!(!is_noop_accrual(&before, &after));
+        let mut later = after.clone();
+        accrue(&mut later, &unrewarded, vesting_end + 1).unwrap();
+        assert!(is_noop_accrual(&after, &later));
+    }
+
+    #[test]
+    fn test_random_buys_respect_supply() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..200 {
+            let mut config = ContractConfig {
+                total_pledge_supply: rng.below(1_000_000) + 1,
+                ..ContractConfig::new()
+            };
+            let mut user_state = UserState::default();
+            let mut now = 0;
+            for _ in 0..MAX_PLEDGE_LOTS {
+                now += rng.below(2 * PHASE_DURATIONS[0]);
+                match apply_buy(&mut user_state, &config, rng.below(200_000) + 1, DEFAULT_LOCK_TIER, false, now) {
+                    Ok(outcome) => {
+                        config.total_pledge_sold = outcome.total_pledge_sold;
+                        config.phase_sold[outcome.phase] = outcome.phase_sold;
+                    }
+                    Err(error) => assert_eq!(error, PledgeError::SupplyExhausted),
+                }
+                assert!(config.total_pledge_sold <= config.total_pledge_supply);
+                assert_eq!(config.phase_sold.iter().sum::<u64>(), config.total_pledge_sold);
+                assert_eq!(config.total_pledge_sold, user_state.locked_pledge_tokens);
+            }
+            // Rates only go down as the sale moves through its phases.
+            let rates: Vec<u64> = user_state.active_lots().iter().map(|lot| lot.rate).collect();
+            assert!(rates.windows(2).all(|pair| pair[0] >= pair[1]));
+        }
+    }
+
+    // Splitting accrual over any sequence of increasing timestamps credits the
+    // same total as one accrual at the end, and never more than the full reward.
+    #[test]
+    fn test_random_accrual_is_order_independent() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let config = ContractConfig::new();
+        for _ in 0..200 {
+            let mut user_state = UserState::default();
+            let mut now = 0;
+            for _ in 0..rng.below(5) + 1 {
+                now += rng.below(VESTING_PERIOD / 2);
+                apply_buy(&mut user_state, &config, rng.below(10_000) + 1, DEFAULT_LOCK_TIER, false, now).unwrap();
+            }
+            let mut single = UserState { lots: user_state.lots, lot_count: user_state.lot_count, ..UserState::default() };
+            let end = now + VESTING_PERIOD;
+
+            let mut time = 0;
+            while time < end {
+                time = (time + rng.below(VESTING_PERIOD / 3) + 1).min(end);
+                accrue(&mut user_state, &config, time).unwrap();
+                let full: u64 = user_state.active_lots().iter().map(|lot| full_reward(lot.amount)).sum();
+                assert!(user_state.solhit_rewards <= full);
+            }
+            accrue(&mut single, &config, end).unwrap();
+            assert_eq!(user_state.solhit_rewards, single.solhit_rewards);
+        }
+    }
+}
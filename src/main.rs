@@ -1,3 +1,380 @@
+// pledge-cli: operator commands against a deployed pledge program, built with
+// the `cli` feature. The RPC URL and the keypair come from the Solana CLI
+// config, the default one unless --config names another, and that keypair
+// signs and pays for every transaction. Output is one line per result, or
+// JSON with --json.
+use pledge::{
+    client, find_config_address, find_user_state_address, ContractConfig, UserState, MAX_REWARD_BATCH, PHASE_CAPS,
+    SALE_UNSCHEDULED,
+};
+use serde::Serialize;
+use solana_cli_config::Config;
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{
+    commitment_config::CommitmentConfig,
+    instruction::Instruction,
+    pubkey::Pubkey,
+    signature::{read_keypair_file, Keypair, Signature, Signer},
+    transaction::Transaction,
+};
+use std::{env, error::Error, fmt, process, str::FromStr};
+
+const USAGE: &str = "\
+usage: pledge-cli --program-id <PUBKEY> [--config <PATH>] [--url <URL>] [--keypair <PATH>] [--json] <COMMAND>
+
+commands:
+  init-contract [--sale-start <UNIX_TIME>] [--max-per-user <AMOUNT>]
+                [--guardian <PUBKEY> --recovery-destination <PUBKEY>]
+  start-sale [--start-time <UNIX_TIME>]
+  show-config
+  show-user <WALLET>
+  crank <WALLET>...
+  pause
+  unpause";
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    // Without --sale-start the sale stays closed until start-sale.
+    InitContract { sale_start_time: u64, max_per_user: u64, guardian: Option<(Pubkey, Pubkey)> },
+    StartSale { start_time: Option<u64> },
+    ShowConfig,
+    ShowUser { wallet: Pubkey },
+    Crank { wallets: Vec<Pubkey> },
+    SetPaused { paused: bool },
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    program_id: Pubkey,
+    config_file: Option<String>,
+    url: Option<String>,
+    keypair: Option<String>,
+    json: bool,
+    command: Command,
+}
+
+// The options that take a value. Any of them may come anywhere on the line;
+// those of a command are refused with any other command.
+const VALUE_OPTIONS: [&str; 9] = [
+    "--program-id",
+    "--config",
+    "--url",
+    "--keypair",
+    "--sale-start",
+    "--max-per-user",
+    "--guardian",
+    "--recovery-destination",
+    "--start-time",
+];
+
+fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Options, String> {
+    let mut args = args.into_iter();
+    let mut values: Vec<(&str, String)> = Vec::new();
+    let mut operands = Vec::new();
+    let mut json = false;
+    while let Some(arg) = args.next() {
+        if arg == "--json" {
+            json = true;
+        } else if let Some(&name) = VALUE_OPTIONS.iter().find(|&&name| name == arg) {
+            let value = args.next().ok_or_else(|| format!("{name} needs a value"))?;
+            if values.iter().any(|&(seen, _)| seen == name) {
+                return Err(format!("{name} given twice"));
+            }
+            values.push((name, value));
+        } else if arg.starts_with("--") {
+            return Err(format!("unknown option {arg}"));
+        } else {
+            operands.push(arg);
+        }
+    }
+
+    let mut take = |name: &str| values.iter().position(|&(seen, _)| seen == name).map(|index| values.remove(index).1);
+    let program_id = pubkey(&take("--program-id").ok_or("--program-id is required")?)?;
+    let config_file = take("--config");
+    let url = take("--url");
+    let keypair = take("--keypair");
+
+    let (name, operands) = operands.split_first().ok_or("no command given")?;
+    let command = match name.as_str() {
+        "init-contract" => {
+            let sale_start_time = take("--sale-start").map_or(Ok(SALE_UNSCHEDULED), |value| number("--sale-start", &value))?;
+            let max_per_user = take("--max-per-user").map_or(Ok(0), |value| number("--max-per-user", &value))?;
+            let guardian = match (take("--guardian"), take("--recovery-destination")) {
+                (Some(guardian), Some(recovery_destination)) => Some((pubkey(&guardian)?, pubkey(&recovery_destination)?)),
+                (None, None) => None,
+                _ => return Err("--guardian and --recovery-destination go together".into()),
+            };
+            Command::InitContract { sale_start_time, max_per_user, guardian }
+        }
+        "start-sale" => Command::StartSale {
+            start_time: take("--start-time").map(|value| number("--start-time", &value)).transpose()?,
+        },
+        "show-config" => Command::ShowConfig,
+        "show-user" => match operands {
+            [wallet] => Command::ShowUser { wallet: pubkey(wallet)? },
+            _ => return Err("show-user takes one wallet".into()),
+        },
+        "crank" if operands.is_empty() => return Err("crank needs at least one wallet".into()),
+        "crank" => Command::Crank { wallets: operands.iter().map(|wallet| pubkey(wallet)).collect::<Result<_, _>>()? },
+        "pause" => Command::SetPaused { paused: true },
+        "unpause" => Command::SetPaused { paused: false },
+        _ => return Err(format!("unknown command {name}")),
+    };
+    if !operands.is_empty() && !matches!(command, Command::ShowUser { .. } | Command::Crank { .. }) {
+        return Err(format!("{name} takes no arguments"));
+    }
+    if let Some((option, _)) = values.first() {
+        return Err(format!("{option} does not apply to {name}"));
+    }
+    Ok(Options { program_id, config_file, url, keypair, json, command })
+}
+
+fn pubkey(value: &str) -> Result<Pubkey, String> {
+    Pubkey::from_str(value).map_err(|_| format!("invalid pubkey {value}"))
+}
+
+fn number(option: &str, value: &str) -> Result<u64, String> {
+    value.parse().map_err(|_| format!("{option} needs a whole number, not {value}"))
+}
+
+// The account at `address` decoded from its data, or an error naming it.
+fn decode_config(address: &Pubkey, data: &[u8]) -> Result<ContractConfig, String> {
+    ContractConfig::try_from_account_data(data).map_err(|error| format!("{address} is not a pledge config: {error}"))
+}
+
+fn decode_user_state(address: &Pubkey, data: &[u8]) -> Result<UserState, String> {
+    UserState::try_from_account_data(data).map_err(|error| format!("{address} is not a pledge user state: {error}"))
+}
+
+// The Display summary, or every field as JSON.
+fn render<T: fmt::Display + Serialize>(value: &T, json: bool) -> Result<String, serde_json::Error> {
+    if json {
+        serde_json::to_string_pretty(value)
+    } else {
+        Ok(value.to_string())
+    }
+}
+
+fn render_signatures(signatures: &[Signature], json: bool) -> String {
+    if json {
+        let signatures: Vec<String> = signatures.iter().map(Signature::to_string).collect();
+        serde_json::json!({ "signatures": signatures }).to_string()
+    } else {
+        signatures.iter().map(Signature::to_string).collect::<Vec<_>>().join("\n")
+    }
+}
+
+// The Solana CLI config at `path`, or at the default location, falling back
+// to the CLI's own defaults when there is no file there.
+fn cli_config(path: Option<&str>) -> Result<Config, String> {
+    match path {
+        Some(path) => Config::load(path).map_err(|error| format!("{path}: {error}")),
+        None => Ok(solana_cli_config::CONFIG_FILE.as_deref().and_then(|path| Config::load(path).ok()).unwrap_or_default()),
+    }
+}
+
+fn send(rpc: &RpcClient, payer: &Keypair, instruction: Instruction) -> Result<Signature, Box<dyn Error>> {
+    let blockhash = rpc.get_latest_blockhash()?;
+    let transaction = Transaction::new_signed_with_payer(&[instruction], Some(&payer.pubkey()), &[payer], blockhash);
+    Ok(rpc.send_and_confirm_transaction(&transaction)?)
+}
+
+fn run(options: Options) -> Result<String, Box<dyn Error>> {
+    let config = cli_config(options.config_file.as_deref())?;
+    let url = options.url.unwrap_or(config.json_rpc_url);
+    let rpc = RpcClient::new_with_commitment(url, CommitmentConfig::confirmed());
+    let program_id = &options.program_id;
+    let signer = || {
+        let path = options.keypair.as_deref().unwrap_or(&config.keypair_path);
+        read_keypair_file(path).map_err(|error| format!("{path}: {error}"))
+    };
+
+    let signatures = match options.command {
+        Command::ShowConfig => {
+            let address = find_config_address(program_id).0;
+            let config = decode_config(&address, &rpc.get_account_data(&address)?)?;
+            return Ok(render(&config, options.json)?);
+        }
+        Command::ShowUser { wallet } => {
+            let address = find_user_state_address(program_id, &wallet).0;
+            let user_state = decode_user_state(&address, &rpc.get_account_data(&address)?)?;
+            return Ok(render(&user_state, options.json)?);
+        }
+        Command::InitContract { sale_start_time, max_per_user, guardian } => {
+            let admin = signer()?;
+            let (guardian, recovery_destination) = guardian.unwrap_or_default();
+            let initialize = client::initialize_contract_with_guardian_ix(
+                program_id,
+                &admin.pubkey(),
+                sale_start_time,
+                PHASE_CAPS,
+                max_per_user,
+                guardian,
+                recovery_destination,
+            );
+            vec![send(&rpc, &admin, initialize)?]
+        }
+        Command::StartSale { start_time } => {
+            let admin = signer()?;
+            vec![send(&rpc, &admin, client::start_sale_ix(program_id, &admin.pubkey(), start_time))?]
+        }
+        Command::SetPaused { paused } => {
+            let admin = signer()?;
+            vec![send(&rpc, &admin, client::set_paused_ix(program_id, &admin.pubkey(), paused))?]
+        }
+        // One UpdateRewardBatch per MAX_REWARD_BATCH wallets, paid for by the
+        // keypair.
+        Command::Crank { wallets } => {
+            let payer = signer()?;
+            wallets
+                .chunks(MAX_REWARD_BATCH)
+                .map(|batch| send(&rpc, &payer, client::update_reward_batch_ix(program_id, batch)))
+                .collect::<Result<_, _>>()?
+        }
+    };
+    Ok(render_signatures(&signatures, options.json))
+}
+
 fn main() {
-    println!("Hello, world!");
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("error: {error}\n\n{USAGE}");
+            process::exit(2);
+        }
+    };
+    match run(options) {
+        Ok(output) => println!("{output}"),
+        Err(error) => {
+            eprintln!("error: {error}");
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<Options, String> {
+        parse_args(line.split_whitespace().map(String::from))
+    }
+
+    fn command(line: &str) -> Command {
+        parse(&format!("--program-id {} {line}", Pubkey::default())).unwrap().command
+    }
+
+    fn parse_error(line: &str) -> String {
+        parse(&format!("--program-id {} {line}", Pubkey::default())).unwrap_err()
+    }
+
+    #[test]
+    fn test_parse_global_options() {
+        let program_id = Pubkey::new_unique();
+        let options = parse(&format!("show-config --json --url http://localhost:8899 --program-id {program_id}")).unwrap();
+        assert_eq!(
+            options,
+            Options {
+                program_id,
+                config_file: None,
+                url: Some("http://localhost:8899".into()),
+                keypair: None,
+                json: true,
+                command: Command::ShowConfig,
+            }
+        );
+        let options = parse(&format!("--config cli.yml --keypair id.json --program-id {program_id} pause")).unwrap();
+        assert_eq!(options.config_file.as_deref(), Some("cli.yml"));
+        assert_eq!(options.keypair.as_deref(), Some("id.json"));
+        assert!(!options.json);
+
+        assert_eq!(parse("show-config").unwrap_err(), "--program-id is required");
+        assert_eq!(parse("--program-id nope show-config").unwrap_err(), "invalid pubkey nope");
+        assert_eq!(parse_error(""), "no command given");
+        assert_eq!(parse_error("show-config --url"), "--url needs a value");
+        assert_eq!(parse_error("show-config --url a --url b"), "--url given twice");
+        assert_eq!(parse_error("show-config --verbose"), "unknown option --verbose");
+        assert_eq!(parse_error("withdraw"), "unknown command withdraw");
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        let (guardian, recovery_destination) = (Pubkey::new_unique(), Pubkey::new_unique());
+        assert_eq!(
+            command("init-contract"),
+            Command::InitContract { sale_start_time: SALE_UNSCHEDULED, max_per_user: 0, guardian: None }
+        );
+        assert_eq!(
+            command(&format!(
+                "init-contract --sale-start 1700000000 --max-per-user 500 --guardian {guardian} --recovery-destination {recovery_destination}"
+            )),
+            Command::InitContract {
+                sale_start_time: 1_700_000_000,
+                max_per_user: 500,
+                guardian: Some((guardian, recovery_destination)),
+            }
+        );
+        assert_eq!(command("start-sale"), Command::StartSale { start_time: None });
+        assert_eq!(command("start-sale --start-time 1700000000"), Command::StartSale { start_time: Some(1_700_000_000) });
+        assert_eq!(command("show-config"), Command::ShowConfig);
+        assert_eq!(command(&format!("show-user {guardian}")), Command::ShowUser { wallet: guardian });
+        assert_eq!(command("pause"), Command::SetPaused { paused: true });
+        assert_eq!(command("unpause"), Command::SetPaused { paused: false });
+
+        let wallets: Vec<Pubkey> = (0..3).map(|_| Pubkey::new_unique()).collect();
+        let line = wallets.iter().map(Pubkey::to_string).collect::<Vec<_>>().join(" ");
+        assert_eq!(command(&format!("crank {line}")), Command::Crank { wallets });
+    }
+
+    #[test]
+    fn test_parse_command_errors() {
+        let wallet = Pubkey::new_unique();
+        assert_eq!(parse_error("init-contract --sale-start soon"), "--sale-start needs a whole number, not soon");
+        assert_eq!(
+            parse_error(&format!("init-contract --guardian {wallet}")),
+            "--guardian and --recovery-destination go together"
+        );
+        assert_eq!(parse_error("show-config --start-time 5"), "--start-time does not apply to show-config");
+        assert_eq!(parse_error("pause --max-per-user 5"), "--max-per-user does not apply to pause");
+        assert_eq!(parse_error(&format!("pause {wallet}")), "pause takes no arguments");
+        assert_eq!(parse_error("show-user"), "show-user takes one wallet");
+        assert_eq!(parse_error(&format!("show-user {wallet} {wallet}")), "show-user takes one wallet");
+        assert_eq!(parse_error("crank"), "crank needs at least one wallet");
+        assert_eq!(parse_error(&format!("crank {wallet} nope")), "invalid pubkey nope");
+    }
+
+    #[test]
+    fn test_decode_accounts() {
+        let address = Pubkey::new_unique();
+        let config = ContractConfig { admin: Pubkey::new_unique(), paused: true, ..ContractConfig::new() };
+        let config_data = borsh::to_vec(&config).unwrap();
+        let decoded = decode_config(&address, &config_data).unwrap();
+        assert_eq!(decoded.admin, config.admin);
+        assert!(render(&decoded, false).unwrap().ends_with(", paused"));
+        let json: serde_json::Value = serde_json::from_str(&render(&decoded, true).unwrap()).unwrap();
+        assert_eq!(json["paused"], true);
+        assert_eq!(json["admin"], serde_json::to_value(config.admin).unwrap());
+
+        let owner = Pubkey::new_unique();
+        let user_state = UserState { is_initialized: true, owner, solhit_rewards: 42, ..UserState::default() };
+        let mut user_data = vec![0; UserState::LEN + 16];
+        user_state.pack_into_slice(&mut user_data).unwrap();
+        let decoded = decode_user_state(&address, &user_data).unwrap();
+        assert_eq!(render(&decoded, false).unwrap(), user_state.to_string());
+        let json: serde_json::Value = serde_json::from_str(&render(&decoded, true).unwrap()).unwrap();
+        assert_eq!(json["solhit_rewards"], 42);
+
+        // Each refuses the other's account.
+        let error = decode_user_state(&address, &config_data).err().unwrap();
+        assert!(error.starts_with(&format!("{address} is not a pledge user state")), "{error}");
+        assert!(decode_config(&address, &user_data).is_err());
+    }
+
+    #[test]
+    fn test_render_signatures() {
+        let signatures = [Signature::from([1; 64]), Signature::from([2; 64])];
+        assert_eq!(render_signatures(&signatures, false), format!("{}\n{}", signatures[0], signatures[1]));
+        let json: serde_json::Value = serde_json::from_str(&render_signatures(&signatures, true)).unwrap();
+        assert_eq!(json["signatures"][1], signatures[1].to_string());
+    }
 }
@@ -0,0 +1,206 @@
+// Fixed-point helpers for every token, reward and penalty amount. Products
+// are taken in u128, so no intermediate step can overflow or truncate; only
+// the final division rounds, and each helper says which way. Results that do
+// not fit a u64, and division by zero, are ArithmeticOverflow.
+use crate::{PledgeError, BASIS_POINTS};
+
+// floor(a * b / d).
+pub fn mul_div_floor(a: u64, b: u64, d: u64) -> Result<u64, PledgeError> {
+    if d == 0 {
+        return Err(PledgeError::ArithmeticOverflow);
+    }
+    u64::try_from(a as u128 * b as u128 / d as u128).map_err(|_| PledgeError::ArithmeticOverflow)
+}
+
+// ceil(a * b / d), for amounts owed to the program.
+pub fn mul_div_ceil(a: u64, b: u64, d: u64) -> Result<u64, PledgeError> {
+    if d == 0 {
+        return Err(PledgeError::ArithmeticOverflow);
+    }
+    u64::try_from((a as u128 * b as u128).div_ceil(d as u128)).map_err(|_| PledgeError::ArithmeticOverflow)
+}
+
+// `bps` basis points of `amount`, rounded down.
+pub fn apply_bps(amount: u64, bps: u16) -> Result<u64, PledgeError> {
+    mul_div_floor(amount, bps as u64, BASIS_POINTS)
+}
+
+// `percent` percent of `amount`, rounded down. Percentages above 100 scale
+// the amount up.
+pub fn apply_percent(amount: u64, percent: u64) -> Result<u64, PledgeError> {
+    mul_div_floor(amount, percent, 100)
+}
+
+// Pledge base units bought with `lamports` at `rate` pledge tokens per 100
+// payment tokens, each side counted in base units of its own decimals,
+// rounded down. A payment too small to buy a single base unit is
+// PurchaseTooSmall.
+pub fn lamports_to_pledge_base_units(
+    lamports: u64,
+    rate: u64,
+    payment_decimals: u8,
+    pledge_decimals: u8,
+) -> Result<u64, PledgeError> {
+    let (scale_up, scale_down) = decimal_scale(payment_decimals, pledge_decimals)?;
+    let scaled = (lamports as u128 * rate as u128).checked_mul(scale_up).ok_or(PledgeError::ArithmeticOverflow)?;
+    let pledge_tokens = u64::try_from(scaled / (100 * scale_down)).map_err(|_| PledgeError::ArithmeticOverflow)?;
+    if pledge_tokens == 0 {
+        return Err(PledgeError::PurchaseTooSmall);
+    }
+    Ok(pledge_tokens)
+}
+
+// The lamports that buy `pledge_tokens` base units at `rate`, the inverse of
+// lamports_to_pledge_base_units rounded up, so nothing sells below the rate.
+pub fn pledge_base_units_to_lamports(
+    pledge_tokens: u64,
+    rate: u64,
+    payment_decimals: u8,
+    pledge_decimals: u8,
+) -> Result<u64, PledgeError> {
+    let (scale_up, scale_down) = decimal_scale(payment_decimals, pledge_decimals)?;
+    let divisor = (rate as u128).checked_mul(scale_up).filter(|&divisor| divisor != 0);
+    let scaled = (pledge_tokens as u128 * 100).checked_mul(scale_down);
+    let (Some(divisor), Some(scaled)) = (divisor, scaled) else {
+        return Err(PledgeError::ArithmeticOverflow);
+    };
+    u64::try_from(scaled.div_ceil(divisor)).map_err(|_| PledgeError::ArithmeticOverflow)
+}
+
+// 10^pledge_decimals / 10^payment_decimals as a multiplier and a divisor,
+// one of which is 1.
+fn decimal_scale(payment_decimals: u8, pledge_decimals: u8) -> Result<(u128, u128), PledgeError> {
+    let power = |exponent: u8| 10u128.checked_pow(exponent as u32).ok_or(PledgeError::ArithmeticOverflow);
+    if pledge_decimals >= payment_decimals {
+        Ok((power(pledge_decimals - payment_decimals)?, 1))
+    } else {
+        Ok((1, power(payment_decimals - pledge_decimals)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        // Mostly small values, with the edges of the range mixed in.
+        fn operand(&mut self) -> u64 {
+            match self.next() % 4 {
+                0 => self.next() % 1_000,
+                1 => u64::MAX - self.next() % 1_000,
+                _ => self.next() >> (self.next() % 64),
+            }
+        }
+    }
+
+    #[test]
+    fn test_mul_div_floor() {
+        assert_eq!(mul_div_floor(100, 200, 100), Ok(200));
+        assert_eq!(mul_div_floor(7, 1, 2), Ok(3));
+        assert_eq!(mul_div_floor(0, u64::MAX, 1), Ok(0));
+        // The product overflows a u64 but the quotient does not.
+        assert_eq!(mul_div_floor(u64::MAX, u64::MAX, u64::MAX), Ok(u64::MAX));
+        assert_eq!(mul_div_floor(u64::MAX, 3, 4), Ok(u64::MAX / 4 * 3 + 2));
+        assert_eq!(mul_div_floor(u64::MAX, 2, 1), Err(PledgeError::ArithmeticOverflow));
+        assert_eq!(mul_div_floor(1, 1, 0), Err(PledgeError::ArithmeticOverflow));
+        assert_eq!(mul_div_floor(0, 0, 0), Err(PledgeError::ArithmeticOverflow));
+    }
+
+    #[test]
+    fn test_mul_div_ceil() {
+        assert_eq!(mul_div_ceil(100, 200, 100), Ok(200));
+        assert_eq!(mul_div_ceil(7, 1, 2), Ok(4));
+        assert_eq!(mul_div_ceil(1, 1, u64::MAX), Ok(1));
+        assert_eq!(mul_div_ceil(0, 5, 3), Ok(0));
+        assert_eq!(mul_div_ceil(u64::MAX, u64::MAX, u64::MAX), Ok(u64::MAX));
+        assert_eq!(mul_div_ceil(u64::MAX, u64::MAX, u64::MAX - 1), Err(PledgeError::ArithmeticOverflow));
+        assert_eq!(mul_div_ceil(1, 1, 0), Err(PledgeError::ArithmeticOverflow));
+    }
+
+    #[test]
+    fn test_apply_bps_and_percent() {
+        assert_eq!(apply_bps(10_000, 500), Ok(500));
+        assert_eq!(apply_bps(19, 500), Ok(0));
+        assert_eq!(apply_bps(1_000_000, u16::MAX), Ok(6_553_500));
+        assert_eq!(apply_bps(u64::MAX, u16::MAX), Err(PledgeError::ArithmeticOverflow));
+        assert_eq!(apply_bps(u64::MAX, 10_000), Ok(u64::MAX));
+        assert_eq!(apply_percent(1_000, 10), Ok(100));
+        assert_eq!(apply_percent(199, 50), Ok(99));
+        assert_eq!(apply_percent(u64::MAX, 100), Ok(u64::MAX));
+        assert_eq!(apply_percent(u64::MAX, 101), Err(PledgeError::ArithmeticOverflow));
+    }
+
+    #[test]
+    fn test_lamports_to_pledge_base_units() {
+        // Matching decimals keep base units one for one at the rate.
+        assert_eq!(lamports_to_pledge_base_units(100, 200, 9, 9), Ok(200));
+        assert_eq!(lamports_to_pledge_base_units(1, 50, 9, 9), Err(PledgeError::PurchaseTooSmall));
+        // One SOL at 9 decimals buys two whole tokens of 6 at 200%.
+        assert_eq!(lamports_to_pledge_base_units(1_000_000_000, 200, 9, 6), Ok(2_000_000));
+        assert_eq!(lamports_to_pledge_base_units(1_499, 200, 9, 6), Ok(2));
+        // Dust below a thousandth of a token's worth buys nothing.
+        assert_eq!(lamports_to_pledge_base_units(499, 200, 9, 6), Err(PledgeError::PurchaseTooSmall));
+        assert_eq!(lamports_to_pledge_base_units(0, 200, 9, 6), Err(PledgeError::PurchaseTooSmall));
+        // The other way round a lamport buys a thousand base units.
+        assert_eq!(lamports_to_pledge_base_units(1, 100, 6, 9), Ok(1_000));
+        // u64::MAX lamports at 6 decimals cannot be counted at 9 ...
+        assert_eq!(lamports_to_pledge_base_units(u64::MAX, 100, 6, 9), Err(PledgeError::ArithmeticOverflow));
+        // ... but the u128 product still converts when it scales down.
+        assert_eq!(lamports_to_pledge_base_units(u64::MAX, 200, 9, 6), Ok(u64::MAX / 1_000 * 2 + 1));
+        assert_eq!(lamports_to_pledge_base_units(1, 1, 0, u8::MAX), Err(PledgeError::ArithmeticOverflow));
+    }
+
+    #[test]
+    fn test_pledge_base_units_to_lamports() {
+        assert_eq!(pledge_base_units_to_lamports(200, 200, 9, 9), Ok(100));
+        assert_eq!(pledge_base_units_to_lamports(201, 200, 9, 9), Ok(101));
+        assert_eq!(pledge_base_units_to_lamports(2_000_000, 200, 9, 6), Ok(1_000_000_000));
+        assert_eq!(pledge_base_units_to_lamports(1, 200, 9, 6), Ok(500));
+        assert_eq!(pledge_base_units_to_lamports(1_001, 100, 6, 9), Ok(2));
+        assert_eq!(pledge_base_units_to_lamports(1, 0, 9, 9), Err(PledgeError::ArithmeticOverflow));
+        // Rounding up never buys fewer base units than were priced.
+        for (payment_decimals, pledge_decimals) in [(9, 9), (9, 6), (6, 9), (0, 18)] {
+            for pledge_tokens in [1, 7, 999, 1_000_001] {
+                let lamports = pledge_base_units_to_lamports(pledge_tokens, 150, payment_decimals, pledge_decimals).unwrap();
+                let bought = lamports_to_pledge_base_units(lamports, 150, payment_decimals, pledge_decimals).unwrap();
+                assert!(bought >= pledge_tokens);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rounding_direction() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..10_000 {
+            let (a, b, d) = (rng.operand(), rng.operand(), rng.operand().max(1));
+            let exact = a as u128 * b as u128;
+            match (mul_div_floor(a, b, d), mul_div_ceil(a, b, d)) {
+                (Ok(floor), Ok(ceil)) => {
+                    // Never more than the true quotient, and less only by the
+                    // remainder; ceil is the same bound from above.
+                    assert!(floor as u128 * d as u128 <= exact);
+                    assert!((floor as u128 + 1) * d as u128 > exact);
+                    assert!(ceil as u128 * d as u128 >= exact);
+                    assert_eq!(ceil - floor, u64::from(!exact.is_multiple_of(d as u128)));
+                }
+                (Ok(floor), Err(error)) => {
+                    assert_eq!(floor, u64::MAX);
+                    assert_eq!(error, PledgeError::ArithmeticOverflow);
+                }
+                (Err(error), _) => {
+                    assert_eq!(error, PledgeError::ArithmeticOverflow);
+                    assert!(exact / d as u128 > u64::MAX as u128);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,101 @@
+// Checks on the flags and balance of accounts a handler is about to write.
+// Handlers run them before reading any state, so a read-only or executable
+// account fails up front with an error naming it, instead of deep in the
+// runtime once the handler has done its work. `name` is what the log calls
+// the account.
+use crate::PledgeError;
+use solana_program::{account_info::AccountInfo, entrypoint::ProgramResult, msg, pubkey::Pubkey, rent::Rent};
+
+pub fn assert_writable(account_info: &AccountInfo, name: &str) -> ProgramResult {
+    if !account_info.is_writable {
+        msg!("{} account must be writable", name);
+        return Err(PledgeError::AccountNotWritable.into());
+    }
+    Ok(())
+}
+
+// Program accounts never hold pledge state, whoever owns them.
+pub fn assert_not_executable(account_info: &AccountInfo, name: &str) -> ProgramResult {
+    if account_info.executable {
+        msg!("{} account is an executable program", name);
+        return Err(PledgeError::ExecutableAccount.into());
+    }
+    Ok(())
+}
+
+pub fn assert_rent_exempt(account_info: &AccountInfo, rent: &Rent, name: &str) -> ProgramResult {
+    if !rent.is_exempt(account_info.lamports(), account_info.data_len()) {
+        msg!("{} account is not rent exempt", name);
+        return Err(PledgeError::NotRentExempt.into());
+    }
+    Ok(())
+}
+
+// A program account passed for a CPI must be the program the handler means
+// to call. The instruction built for the CPI takes its program id from this
+// account, so a lookalike would run in the real program's place.
+pub fn assert_is_program(account_info: &AccountInfo, expected: &Pubkey, name: &str) -> ProgramResult {
+    if account_info.key != expected {
+        msg!("{} account {} is not {}", name, account_info.key, expected);
+        return Err(PledgeError::InvalidProgramAccount.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_program::{program_error::ProgramError, pubkey::Pubkey};
+
+    fn account<'a>(is_writable: bool, executable: bool, lamports: u64, len: usize) -> AccountInfo<'a> {
+        AccountInfo::new(
+            Box::leak(Box::new(Pubkey::new_unique())),
+            false,
+            is_writable,
+            Box::leak(Box::new(lamports)),
+            vec![0; len].leak(),
+            Box::leak(Box::new(Pubkey::new_unique())),
+            executable,
+            0,
+        )
+    }
+
+    #[test]
+    fn test_assert_writable() {
+        assert_eq!(assert_writable(&account(true, false, 0, 0), "Test"), Ok(()));
+        assert_eq!(
+            assert_writable(&account(false, false, 0, 0), "Test"),
+            Err(ProgramError::from(PledgeError::AccountNotWritable))
+        );
+    }
+
+    #[test]
+    fn test_assert_not_executable() {
+        assert_eq!(assert_not_executable(&account(true, false, 0, 0), "Test"), Ok(()));
+        assert_eq!(
+            assert_not_executable(&account(false, true, 0, 0), "Test"),
+            Err(ProgramError::from(PledgeError::ExecutableAccount))
+        );
+    }
+
+    #[test]
+    fn test_assert_rent_exempt() {
+        let rent = Rent::default();
+        let minimum = rent.minimum_balance(100);
+        assert_eq!(assert_rent_exempt(&account(true, false, minimum, 100), &rent, "Test"), Ok(()));
+        assert_eq!(
+            assert_rent_exempt(&account(true, false, minimum - 1, 100), &rent, "Test"),
+            Err(ProgramError::from(PledgeError::NotRentExempt))
+        );
+    }
+
+    #[test]
+    fn test_assert_is_program() {
+        let account = account(false, true, 0, 0);
+        assert_eq!(assert_is_program(&account, account.key, "Test"), Ok(()));
+        assert_eq!(
+            assert_is_program(&account, &spl_token::id(), "Test"),
+            Err(ProgramError::from(PledgeError::InvalidProgramAccount))
+        );
+    }
+}
@@ -0,0 +1,289 @@
+use bytemuck::{Pod, Zeroable};
+use solana_program::{msg, program_error::ProgramError, pubkey::Pubkey};
+
+use crate::{PledgeError, PledgeLot, UserState, MAX_PLEDGE_LOTS, USER_STATE_DISCRIMINATOR, USER_STATE_VERSION};
+
+// A little-endian u64 with alignment 1. Account data is only aligned at its
+// start and the version byte and initialized flag come first, so native u64
+// fields would be misaligned; byte arrays keep the layout free of padding.
+#[repr(transparent)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Pod, Zeroable)]
+pub struct PodU64(pub [u8; 8]);
+
+impl PodU64 {
+    pub fn get(self) -> u64 {
+        u64::from_le_bytes(self.0)
+    }
+
+    pub fn set(&mut self, value: u64) {
+        self.0 = value.to_le_bytes();
+    }
+}
+
+impl From<u64> for PodU64 {
+    fn from(value: u64) -> Self {
+        Self(value.to_le_bytes())
+    }
+}
+
+#[repr(transparent)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Pod, Zeroable)]
+pub struct PodU32(pub [u8; 4]);
+
+impl PodU32 {
+    pub fn get(self) -> u32 {
+        u32::from_le_bytes(self.0)
+    }
+
+    pub fn set(&mut self, value: u32) {
+        self.0 = value.to_le_bytes();
+    }
+}
+
+impl From<u32> for PodU32 {
+    fn from(value: u32) -> Self {
+        Self(value.to_le_bytes())
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Pod, Zeroable)]
+pub struct PodPledgeLot {
+    pub amount: PodU64,
+    pub rate: PodU64,
+    pub lock_start_time: PodU64,
+    pub vesting_end_time: PodU64,
+    pub multiplier_bps: PodU64,
+}
+
+// The latest UserState account layout, byte for byte what the Borsh encoding
+// writes, viewed in place over the account data.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+pub struct UserStateData {
+    pub discriminator: [u8; 8],
+    pub version: u8,
+    pub is_initialized: u8,
+    pub locked_pledge_tokens: PodU64,
+    pub solhit_rewards: PodU64,
+    pub lock_start_time: PodU64,
+    pub vesting_end_time: PodU64,
+    pub last_reward_update: PodU64,
+    pub total_claimed: PodU64,
+    pub total_purchased: PodU64,
+    pub purchase_count: PodU32,
+    pub last_activity: PodU64,
+    pub owner: Pubkey,
+    pub bump: u8,
+    pub lot_count: u8,
+    pub lots: [PodPledgeLot; MAX_PLEDGE_LOTS],
+    pub beneficiary: Pubkey,
+    pub has_delegate: u8,
+    pub delegate: Pubkey,
+    pub has_group: u8,
+    pub group: Pubkey,
+    pub hold_until: PodU64,
+}
+
+const _: () = assert!(std::mem::size_of::<PodPledgeLot>() == PledgeLot::LEN);
+const _: () = assert!(std::mem::size_of::<UserStateData>() == UserState::LEN);
+const _: () = assert!(std::mem::align_of::<UserStateData>() == 1);
+
+impl UserStateData {
+    // Views the first UserState::LEN bytes of `src`, which must hold the
+    // latest version.
+    pub fn load(src: &[u8]) -> Result<&Self, ProgramError> {
+        let data: &Self = bytemuck::from_bytes(Self::prefix(src)?);
+        data.check()?;
+        Ok(data)
+    }
+
+    // Like load, but for mutating the fields in place.
+    pub fn load_mut(dst: &mut [u8]) -> Result<&mut Self, ProgramError> {
+        let len = Self::prefix(dst)?.len();
+        let data: &mut Self = bytemuck::from_bytes_mut(&mut dst[..len]);
+        data.check()?;
+        Ok(data)
+    }
+
+    // Views the first UserState::LEN bytes of `dst` without checking what
+    // they hold, for overwriting them.
+    pub fn load_mut_unchecked(dst: &mut [u8]) -> Result<&mut Self, ProgramError> {
+        let len = Self::prefix(dst)?.len();
+        Ok(bytemuck::from_bytes_mut(&mut dst[..len]))
+    }
+
+    fn prefix(src: &[u8]) -> Result<&[u8], ProgramError> {
+        src.get(..UserState::LEN).ok_or_else(|| {
+            msg!("User state account is too small: {} < {}", src.len(), UserState::LEN);
+            ProgramError::AccountDataTooSmall
+        })
+    }
+
+    fn check(&self) -> Result<(), ProgramError> {
+        if self.discriminator != USER_STATE_DISCRIMINATOR {
+            msg!("Account is not a user state");
+            return Err(PledgeError::InvalidAccountType.into());
+        }
+        if self.version != USER_STATE_VERSION {
+            msg!("Unexpected user state version {}", self.version);
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if self.is_initialized > 1 || self.has_delegate > 1 || self.has_group > 1 || self.lot_count as usize > MAX_PLEDGE_LOTS {
+            msg!("User state account data is corrupt");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(())
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.is_initialized != 0
+    }
+}
+
+impl From<&PledgeLot> for PodPledgeLot {
+    fn from(lot: &PledgeLot) -> Self {
+        Self {
+            amount: lot.amount.into(),
+            rate: lot.rate.into(),
+            lock_start_time: lot.lock_start_time.into(),
+            vesting_end_time: lot.vesting_end_time.into(),
+            multiplier_bps: lot.multiplier_bps.into(),
+        }
+    }
+}
+
+impl From<&PodPledgeLot> for PledgeLot {
+    fn from(lot: &PodPledgeLot) -> Self {
+        Self {
+            amount: lot.amount.get(),
+            rate: lot.rate.get(),
+            lock_start_time: lot.lock_start_time.get(),
+            vesting_end_time: lot.vesting_end_time.get(),
+            multiplier_bps: lot.multiplier_bps.get(),
+        }
+    }
+}
+
+impl From<&UserState> for UserStateData {
+    fn from(state: &UserState) -> Self {
+        Self {
+            discriminator: USER_STATE_DISCRIMINATOR,
+            version: USER_STATE_VERSION,
+            is_initialized: state.is_initialized as u8,
+            locked_pledge_tokens: state.locked_pledge_tokens.into(),
+            solhit_rewards: state.solhit_rewards.into(),
+            lock_start_time: state.lock_start_time.into(),
+            vesting_end_time: state.vesting_end_time.into(),
+            last_reward_update: state.last_reward_update.into(),
+            total_claimed: state.total_claimed.into(),
+            total_purchased: state.total_purchased.into(),
+            purchase_count: state.purchase_count.into(),
+            last_activity: state.last_activity.into(),
+            owner: state.owner,
+            bump: state.bump,
+            lot_count: state.lot_count,
+            lots: state.lots.each_ref().map(PodPledgeLot::from),
+            beneficiary: state.beneficiary,
+            has_delegate: state.delegate.is_some() as u8,
+            delegate: state.delegate.unwrap_or_default(),
+            has_group: state.group.is_some() as u8,
+            group: state.group.unwrap_or_default(),
+            hold_until: state.hold_until.into(),
+        }
+    }
+}
+
+impl From<&UserStateData> for UserState {
+    fn from(data: &UserStateData) -> Self {
+        Self {
+            is_initialized: data.is_initialized(),
+            locked_pledge_tokens: data.locked_pledge_tokens.get(),
+            solhit_rewards: data.solhit_rewards.get(),
+            lock_start_time: data.lock_start_time.get(),
+            vesting_end_time: data.vesting_end_time.get(),
+            last_reward_update: data.last_reward_update.get(),
+            total_claimed: data.total_claimed.get(),
+            total_purchased: data.total_purchased.get(),
+            purchase_count: data.purchase_count.get(),
+            last_activity: data.last_activity.get(),
+            owner: data.owner,
+            bump: data.bump,
+            lot_count: data.lot_count,
+            lots: data.lots.each_ref().map(PledgeLot::from),
+            beneficiary: data.beneficiary,
+            delegate: (data.has_delegate != 0).then_some(data.delegate),
+            group: (data.has_group != 0).then_some(data.group),
+            hold_until: data.hold_until.get(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sample_state() -> UserState {
+    let mut user_state = UserState {
+      is_initialized: true,
+      locked_pledge_tokens: 1_500,
+      solhit_rewards: 42,
+      lock_start_time: 10,
+      vesting_end_time: u64::MAX,
+      last_reward_update: 20,
+      total_claimed: 7,
+      total_purchased: 1_500,
+      purchase_count: 1,
+      last_activity: 20,
+      owner: Pubkey::new_unique(),
+      bump: 254,
+      delegate: Some(Pubkey::new_unique()),
+      group: Some(Pubkey::new_unique()),
+      hold_until: 1_000,
+      ..UserState::default()
+    };
+    user_state
+      .add_lot(PledgeLot { amount: 1_500, rate: 200, lock_start_time: 10, vesting_end_time: u64::MAX, multiplier_bps: 10_000 })
+      .unwrap();
+    user_state
+  }
+
+  #[test]
+  fn test_layout_matches_borsh_encoding() {
+    let user_state = sample_state();
+    let data = UserStateData::from(&user_state);
+    assert_eq!(bytemuck::bytes_of(&data), borsh::to_vec(&user_state).unwrap().as_slice());
+  }
+
+  #[test]
+  fn test_load_mut_writes_in_place() {
+    let mut buffer = borsh::to_vec(&sample_state()).unwrap();
+    buffer.push(0xff);
+    let data = UserStateData::load_mut(&mut buffer).unwrap();
+    data.solhit_rewards.set(99);
+    data.lots[0].amount.set(1);
+
+    let user_state = UserState::unpack(&buffer).unwrap();
+    assert_eq!(user_state.solhit_rewards, 99);
+    assert_eq!(user_state.lots[0].amount, 1);
+    assert_eq!(*buffer.last().unwrap(), 0xff);
+  }
+
+  #[test]
+  fn test_load_rejects_bad_data() {
+    let mut buffer = borsh::to_vec(&sample_state()).unwrap();
+    assert_eq!(UserStateData::load(&buffer[..UserState::LEN - 1]).err(), Some(ProgramError::AccountDataTooSmall));
+
+    buffer[0] ^= 1;
+    assert_eq!(UserStateData::load(&buffer).err(), Some(PledgeError::InvalidAccountType.into()));
+
+    buffer[0] ^= 1;
+    buffer[8] = USER_STATE_VERSION + 1;
+    assert_eq!(UserStateData::load(&buffer).err(), Some(ProgramError::InvalidAccountData));
+
+    buffer[8] = USER_STATE_VERSION;
+    let lot_count = 8 + UserState::LEN_V4 - PledgeLot::LEN * MAX_PLEDGE_LOTS - 1;
+    buffer[lot_count] = MAX_PLEDGE_LOTS as u8 + 1;
+    assert_eq!(UserStateData::load(&buffer).err(), Some(ProgramError::InvalidAccountData));
+  }
+}
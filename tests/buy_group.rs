@@ -0,0 +1,152 @@
+mod common;
+
+use borsh::BorshDeserialize;
+use common::Harness;
+use pledge::{
+    find_group_address, find_user_state_address, find_whitelist_address, BuyGroup, PledgeError, PledgeInstruction,
+    DEFAULT_LOCK_TIER, PHASE_CAPS, PHASE_RATES,
+};
+use solana_program::{
+    instruction::{AccountMeta, Instruction, InstructionError},
+    pubkey::Pubkey,
+    system_program,
+};
+use solana_program_test::tokio;
+use solana_sdk::{
+    signature::{Keypair, Signer},
+    transaction::TransactionError,
+};
+
+// Lamports one purchase pays in these tests, and the pledge tokens that buys
+// in the last, open-ended sale phase.
+const AMOUNT: u64 = 1_000;
+const TOKENS: u64 = AMOUNT * PHASE_RATES[4] / 100;
+// Each wallet, and with enforcement each group, may buy three purchases' worth.
+const CAP: u64 = 3 * TOKENS;
+
+// A sale past its whitelisted phase with a cap of CAP, enforced on groups.
+async fn setup() -> Harness {
+    let mut harness = Harness::start().await;
+    let initialize = Instruction::new_with_bytes(
+        harness.program_id,
+        &PledgeInstruction::InitializeContract {
+            sale_start_time: 0,
+            phase_caps: PHASE_CAPS,
+            max_per_user: CAP,
+            guardian: Pubkey::default(),
+            recovery_destination: Pubkey::default(),
+        }
+        .pack(),
+        harness.initialize_contract(0).accounts,
+    );
+    harness.send_as_admin(initialize).await.unwrap();
+    harness.send_as_admin(set_group_cap_enforced(&harness, true)).await.unwrap();
+    assert!(harness.config().await.enforce_group_cap);
+    harness
+}
+
+fn set_group_cap_enforced(harness: &Harness, enforced: bool) -> Instruction {
+    Instruction::new_with_bytes(
+        harness.program_id,
+        &PledgeInstruction::SetGroupCapEnforced { enforced }.pack(),
+        vec![AccountMeta::new(harness.config_address(), false), AccountMeta::new_readonly(harness.admin.pubkey(), true)],
+    )
+}
+
+async fn new_user(harness: &mut Harness) -> Keypair {
+    let wallet = harness.add_wallet();
+    let user_state = find_user_state_address(&harness.program_id, &wallet.pubkey()).0;
+    let mut initialize = harness.initialize_user();
+    initialize.accounts[..2].clone_from_slice(&[AccountMeta::new(user_state, false), AccountMeta::new(wallet.pubkey(), true)]);
+    harness.send(initialize, &[&wallet]).await.unwrap();
+    wallet
+}
+
+async fn join(harness: &mut Harness, wallet: &Keypair, group_id: Pubkey) -> Result<(), TransactionError> {
+    let join = Instruction::new_with_bytes(
+        harness.program_id,
+        &PledgeInstruction::JoinGroup { group_id }.pack(),
+        vec![
+            AccountMeta::new(find_user_state_address(&harness.program_id, &wallet.pubkey()).0, false),
+            AccountMeta::new(wallet.pubkey(), true),
+            AccountMeta::new(find_group_address(&harness.program_id, &group_id).0, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+        ],
+    );
+    harness.try_send(join, &[wallet]).await
+}
+
+// Buys on the default tier, passing the group of `group_id` and the whitelist
+// PDA after the fixed accounts.
+async fn buy(harness: &mut Harness, wallet: &Keypair, amount: u64, group_id: Option<Pubkey>) -> Result<(), TransactionError> {
+    let program_id = harness.program_id;
+    let mut buy = harness.buy(amount, DEFAULT_LOCK_TIER);
+    buy.accounts[..2].clone_from_slice(&[
+        AccountMeta::new(find_user_state_address(&program_id, &wallet.pubkey()).0, false),
+        AccountMeta::new(wallet.pubkey(), true),
+    ]);
+    if let Some(group_id) = group_id {
+        buy.accounts.push(AccountMeta::new(find_group_address(&program_id, &group_id).0, false));
+    }
+    buy.accounts.push(AccountMeta::new_readonly(find_whitelist_address(&program_id, &wallet.pubkey()).0, false));
+    harness.try_send(buy, &[wallet]).await
+}
+
+async fn group(harness: &mut Harness, group_id: Pubkey) -> BuyGroup {
+    let address = find_group_address(&harness.program_id, &group_id).0;
+    let account = harness.context.banks_client.get_account(address).await.unwrap().unwrap();
+    BuyGroup::try_from_slice(&account.data).unwrap()
+}
+
+fn custom(error: PledgeError) -> TransactionError {
+    TransactionError::InstructionError(0, InstructionError::Custom(error as u32))
+}
+
+#[tokio::test]
+async fn test_group_members_share_the_cap() {
+    let mut harness = setup().await;
+    let group_id = Pubkey::new_unique();
+    let first = new_user(&mut harness).await;
+    let second = new_user(&mut harness).await;
+    join(&mut harness, &first, group_id).await.unwrap();
+    join(&mut harness, &second, group_id).await.unwrap();
+    assert_eq!(join(&mut harness, &second, Pubkey::new_unique()).await, Err(custom(PledgeError::AlreadyInGroup)));
+
+    // Each wallet stays under its own cap, but together they reach it.
+    buy(&mut harness, &first, 2 * AMOUNT, Some(group_id)).await.unwrap();
+    buy(&mut harness, &second, AMOUNT, Some(group_id)).await.unwrap();
+    let group = group(&mut harness, group_id).await;
+    assert_eq!((group.member_count, group.total_purchased), (2, CAP));
+
+    assert_eq!(buy(&mut harness, &second, AMOUNT + 1, Some(group_id)).await, Err(custom(PledgeError::GroupCapExceeded)));
+    assert_eq!(buy(&mut harness, &first, AMOUNT / 2, Some(group_id)).await, Err(custom(PledgeError::GroupCapExceeded)));
+    // Leaving the group account out does not get around it.
+    assert!(buy(&mut harness, &first, AMOUNT / 3, None).await.is_err());
+
+    // A wallet outside the group still has its whole allowance.
+    let wallet = harness.wallet.insecure_clone();
+    harness.send_as_wallet(harness.initialize_user()).await.unwrap();
+    buy(&mut harness, &wallet, 3 * AMOUNT, None).await.unwrap();
+    assert_eq!(harness.user_state().await.total_purchased, CAP);
+}
+
+#[tokio::test]
+async fn test_joining_a_capped_group_blocks_further_buys() {
+    let mut harness = setup().await;
+    let group_id = Pubkey::new_unique();
+    let first = new_user(&mut harness).await;
+    let second = new_user(&mut harness).await;
+    buy(&mut harness, &first, 3 * AMOUNT, None).await.unwrap();
+    buy(&mut harness, &second, AMOUNT, None).await.unwrap();
+
+    // What each bought before joining counts, even past the cap.
+    join(&mut harness, &first, group_id).await.unwrap();
+    join(&mut harness, &second, group_id).await.unwrap();
+    assert_eq!(group(&mut harness, group_id).await.total_purchased, CAP + TOKENS);
+    assert_eq!(buy(&mut harness, &second, AMOUNT, Some(group_id)).await, Err(custom(PledgeError::GroupCapExceeded)));
+
+    // Without enforcement only each wallet's own cap applies.
+    harness.send_as_admin(set_group_cap_enforced(&harness, false)).await.unwrap();
+    buy(&mut harness, &second, 2 * AMOUNT, Some(group_id)).await.unwrap();
+    assert_eq!(group(&mut harness, group_id).await.total_purchased, 2 * CAP);
+}
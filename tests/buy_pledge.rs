@@ -0,0 +1,220 @@
+mod common;
+
+use borsh::BorshDeserialize;
+use common::{mint_account, token_account, token_balance};
+use pledge::{
+    find_config_address, find_treasury_address, find_user_state_address, process_instruction, ContractConfig,
+    PledgeInstruction, UserState, DEFAULT_LOCK_TIER, PHASE_RATES,
+};
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+    system_program,
+};
+use solana_program_test::{processor, tokio, ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    account::Account,
+    signature::{Keypair, Signer},
+    transaction::Transaction,
+};
+
+const WALLET_LAMPORTS: u64 = 1_000_000_000;
+const TREASURY_LAMPORTS: u64 = 1_000_000_000;
+const BUYER_TOKENS: u64 = 10_000;
+
+// A running test validator with an initialized config, a treasury holding
+// TREASURY_LAMPORTS and a user holding WALLET_LAMPORTS and BUYER_TOKENS of
+// the payment mint, plus an empty account of some other mint.
+struct BuySetup {
+    context: ProgramTestContext,
+    program_id: Pubkey,
+    wallet: Keypair,
+    config: Pubkey,
+    treasury: Pubkey,
+    user_state: Pubkey,
+    payment_mint: Pubkey,
+    buyer_token: Pubkey,
+    treasury_token: Pubkey,
+    other_mint: Pubkey,
+}
+
+async fn setup() -> BuySetup {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new("pledge", program_id, processor!(process_instruction));
+
+    let wallet = Keypair::new();
+    program_test.add_account(
+        wallet.pubkey(),
+        Account { lamports: WALLET_LAMPORTS, data: vec![], owner: system_program::ID, executable: false, rent_epoch: 0 },
+    );
+    let (treasury, _) = find_treasury_address(&program_id);
+    program_test.add_account(
+        treasury,
+        Account { lamports: TREASURY_LAMPORTS, data: vec![], owner: program_id, executable: false, rent_epoch: 0 },
+    );
+
+    let payment_mint = Pubkey::new_unique();
+    program_test.add_account(payment_mint, mint_account(BUYER_TOKENS));
+    let buyer_token = Pubkey::new_unique();
+    program_test.add_account(buyer_token, token_account(&payment_mint, &wallet.pubkey(), BUYER_TOKENS));
+    let treasury_token = Pubkey::new_unique();
+    program_test.add_account(treasury_token, token_account(&payment_mint, &treasury, 0));
+    let other_mint = Pubkey::new_unique();
+    program_test.add_account(other_mint, mint_account(0));
+
+    let (user_state, bump) = find_user_state_address(&program_id, &wallet.pubkey());
+    let state =
+        UserState { is_initialized: true, owner: wallet.pubkey(), bump, beneficiary: wallet.pubkey(), ..UserState::default() };
+    program_test.add_account(
+        user_state,
+        Account {
+            lamports: 1_000_000_000,
+            data: borsh::to_vec(&state).unwrap(),
+            owner: program_id,
+            executable: false,
+            rent_epoch: 0,
+        },
+    );
+
+    let (config, bump) = find_config_address(&program_id);
+    let pledge_contract = ContractConfig { is_initialized: true, bump, payment_mint, ..ContractConfig::new() };
+    let mut config_data = vec![0; ContractConfig::LEN];
+    borsh::to_writer(config_data.as_mut_slice(), &pledge_contract).unwrap();
+    program_test.add_account(
+        config,
+        Account { lamports: 1_000_000_000, data: config_data, owner: program_id, executable: false, rent_epoch: 0 },
+    );
+
+    let context = program_test.start_with_context().await;
+    BuySetup {
+        context,
+        program_id,
+        wallet,
+        config,
+        treasury,
+        user_state,
+        payment_mint,
+        buyer_token,
+        treasury_token,
+        other_mint,
+    }
+}
+
+impl BuySetup {
+    fn buy(&self, amount: u64) -> Transaction {
+        let instruction = Instruction::new_with_bytes(
+            self.program_id,
+            &PledgeInstruction::BuyPledge { amount, tier: DEFAULT_LOCK_TIER, referrer: None, allow_partial: false }.pack(),
+            vec![
+                AccountMeta::new(self.user_state, false),
+                AccountMeta::new(self.wallet.pubkey(), true),
+                AccountMeta::new(self.config, false),
+                AccountMeta::new(self.treasury, false),
+                AccountMeta::new_readonly(system_program::ID, false),
+            ],
+        );
+        self.transaction(instruction)
+    }
+
+    fn buy_with_token(&self, amount: u64, mint: Pubkey) -> Transaction {
+        let instruction = Instruction::new_with_bytes(
+            self.program_id,
+            &PledgeInstruction::BuyPledgeWithToken { amount }.pack(),
+            vec![
+                AccountMeta::new(self.user_state, false),
+                AccountMeta::new_readonly(self.wallet.pubkey(), true),
+                AccountMeta::new(self.config, false),
+                AccountMeta::new(self.buyer_token, false),
+                AccountMeta::new(self.treasury_token, false),
+                AccountMeta::new_readonly(mint, false),
+                AccountMeta::new_readonly(spl_token::id(), false),
+            ],
+        );
+        self.transaction(instruction)
+    }
+
+    // The wallet also pays the fee, so a SOL purchase costs it the payment
+    // plus the fee.
+    fn transaction(&self, instruction: Instruction) -> Transaction {
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&self.wallet.pubkey()));
+        transaction.sign(&[&self.wallet], self.context.last_blockhash);
+        transaction
+    }
+
+    async fn lamports(&mut self, address: Pubkey) -> u64 {
+        self.context.banks_client.get_balance(address).await.unwrap()
+    }
+
+    async fn user_state(&mut self) -> UserState {
+        let account = self.context.banks_client.get_account(self.user_state).await.unwrap().unwrap();
+        UserState::try_from_slice(&account.data).unwrap()
+    }
+}
+
+#[tokio::test]
+async fn test_buy_pledge_pays_the_treasury() {
+    let mut setup = setup().await;
+    let amount = 10_000_000;
+
+    let transaction = setup.buy(amount);
+    let fee = setup
+        .context
+        .banks_client
+        .get_fee_for_message(transaction.message().clone())
+        .await
+        .unwrap()
+        .unwrap();
+    setup.context.banks_client.process_transaction(transaction).await.unwrap();
+
+    assert_eq!(setup.lamports(setup.treasury).await, TREASURY_LAMPORTS + amount);
+    let wallet = setup.wallet.pubkey();
+    assert_eq!(setup.lamports(wallet).await, WALLET_LAMPORTS - amount - fee);
+    // The test clock is far past a sale starting at 0, so the final rate applies.
+    assert_eq!(setup.user_state().await.locked_pledge_tokens, amount * PHASE_RATES[4] / 100);
+}
+
+#[tokio::test]
+async fn test_buy_pledge_without_enough_lamports_fails() {
+    let mut setup = setup().await;
+
+    let transaction = setup.buy(WALLET_LAMPORTS);
+    assert!(setup.context.banks_client.process_transaction(transaction).await.is_err());
+
+    assert_eq!(setup.lamports(setup.treasury).await, TREASURY_LAMPORTS);
+    assert_eq!(setup.user_state().await.locked_pledge_tokens, 0);
+}
+
+#[tokio::test]
+async fn test_buy_pledge_with_token_pays_the_treasury() {
+    let mut setup = setup().await;
+
+    let transaction = setup.buy_with_token(4_000, setup.payment_mint);
+    setup.context.banks_client.process_transaction(transaction).await.unwrap();
+
+    assert_eq!(token_balance(&mut setup.context.banks_client, setup.buyer_token).await, BUYER_TOKENS - 4_000);
+    assert_eq!(token_balance(&mut setup.context.banks_client, setup.treasury_token).await, 4_000);
+    // 4_000 units at 6 decimals scale to 4_000_000 and buy at the final rate.
+    assert_eq!(setup.user_state().await.locked_pledge_tokens, 4_000_000 * PHASE_RATES[4] / 100);
+}
+
+#[tokio::test]
+async fn test_buy_pledge_with_token_rejects_wrong_mint() {
+    let mut setup = setup().await;
+
+    let transaction = setup.buy_with_token(4_000, setup.other_mint);
+    assert!(setup.context.banks_client.process_transaction(transaction).await.is_err());
+
+    assert_eq!(token_balance(&mut setup.context.banks_client, setup.buyer_token).await, BUYER_TOKENS);
+    assert_eq!(setup.user_state().await.locked_pledge_tokens, 0);
+}
+
+#[tokio::test]
+async fn test_buy_pledge_with_token_without_enough_tokens_fails() {
+    let mut setup = setup().await;
+
+    let transaction = setup.buy_with_token(BUYER_TOKENS + 1, setup.payment_mint);
+    assert!(setup.context.banks_client.process_transaction(transaction).await.is_err());
+
+    assert_eq!(token_balance(&mut setup.context.banks_client, setup.treasury_token).await, 0);
+    assert_eq!(setup.user_state().await.locked_pledge_tokens, 0);
+}
@@ -0,0 +1,116 @@
+// The claim fee: claim_fee_bps of each claim, rounded down, paid from the
+// Solheist vault into the treasury's associated token account.
+mod common;
+
+use common::{token_account, Harness};
+use pledge::{claim_fee_address, PledgeError, PledgeInstruction, DEFAULT_LOCK_TIER, LOCK_TIERS};
+use solana_program::{
+    instruction::{AccountMeta, Instruction, InstructionError},
+    pubkey::Pubkey,
+    system_program,
+};
+use solana_program_test::tokio;
+use solana_sdk::{signature::Signer, transaction::TransactionError};
+
+fn set_claim_fee(harness: &Harness, bps: u16) -> Instruction {
+    Instruction::new_with_bytes(
+        harness.program_id,
+        &PledgeInstruction::SetClaimFee { bps }.pack(),
+        vec![AccountMeta::new(harness.config_address(), false), AccountMeta::new_readonly(harness.admin.pubkey(), true)],
+    )
+}
+
+fn fee_address(harness: &Harness) -> Pubkey {
+    claim_fee_address(&harness.program_id, &harness.solhit_mint)
+}
+
+// Claims `amount`, passing `fee_account` in its place after the usual
+// accounts.
+fn claim_with_fee(harness: &Harness, amount: u64, fee_account: Pubkey) -> Instruction {
+    let mut claim = harness.claim(amount);
+    claim.accounts.push(AccountMeta::new(fee_account, false));
+    claim
+}
+
+fn error(error: PledgeError) -> TransactionError {
+    TransactionError::InstructionError(0, InstructionError::Custom(error as u32))
+}
+
+// A user halfway through the default lock with its rewards accrued, and the
+// treasury's Solheist token account created empty.
+async fn setup() -> Harness {
+    let mut harness = Harness::start().await;
+    harness.send_as_admin(harness.initialize_contract(0)).await.unwrap();
+    harness.send_as_wallet(harness.initialize_user()).await.unwrap();
+    harness.send_as_wallet(harness.buy(1_000_000, DEFAULT_LOCK_TIER)).await.unwrap();
+    let (lock, _) = LOCK_TIERS[DEFAULT_LOCK_TIER as usize];
+    harness.advance_clock(lock / 2).await;
+    harness.send(harness.update_reward(), &[]).await.unwrap();
+    assert!(harness.user_state().await.solhit_rewards > 20_000);
+
+    let treasury = harness.treasury_address();
+    let fee_account = token_account(&harness.solhit_mint, &treasury, 0);
+    harness.context.set_account(&fee_address(&harness), &fee_account.into());
+    harness
+}
+
+#[tokio::test]
+async fn test_claim_fee_goes_to_treasury() {
+    let mut harness = setup().await;
+    let fee_account = fee_address(&harness);
+
+    // Without a fee the claim needs no fee account and pays out in full.
+    assert_eq!(harness.config().await.claim_fee_bps, 0);
+    harness.send_as_wallet(harness.claim(5_000)).await.unwrap();
+    assert_eq!(harness.token_balance(harness.solhit_token).await, 5_000);
+    assert_eq!(harness.token_balance(fee_account).await, 0);
+
+    harness.send_as_admin(set_claim_fee(&harness, 100)).await.unwrap();
+    assert_eq!(harness.config().await.claim_fee_bps, 100);
+    let rewards = harness.user_state().await.solhit_rewards;
+    harness.send_as_wallet(claim_with_fee(&harness, 10_000, fee_account)).await.unwrap();
+    assert_eq!(harness.token_balance(harness.solhit_token).await, 5_000 + 9_900);
+    assert_eq!(harness.token_balance(fee_account).await, 100);
+    assert_eq!(harness.user_state().await.solhit_rewards, rewards - 10_000);
+
+    // The fee rounds down: 1% of 199 is 1, and of 99 nothing at all, so that
+    // claim makes the single transfer, though it still passes the fee account.
+    harness.send_as_wallet(claim_with_fee(&harness, 199, fee_account)).await.unwrap();
+    assert_eq!(harness.token_balance(harness.solhit_token).await, 5_000 + 9_900 + 198);
+    assert_eq!(harness.token_balance(fee_account).await, 101);
+    harness.send_as_wallet(claim_with_fee(&harness, 99, fee_account)).await.unwrap();
+    assert_eq!(harness.token_balance(harness.solhit_token).await, 5_000 + 9_900 + 198 + 99);
+    assert_eq!(harness.token_balance(fee_account).await, 101);
+    assert_eq!(harness.user_state().await.solhit_rewards, rewards - 10_000 - 199 - 99);
+}
+
+#[tokio::test]
+async fn test_claim_fee_requires_treasury_token_account() {
+    let mut harness = setup().await;
+    let wallet = harness.wallet.insecure_clone();
+    let admin = harness.admin.insecure_clone();
+    harness.send_as_admin(set_claim_fee(&harness, 100)).await.unwrap();
+
+    let missing = harness.try_send(harness.claim(10_000), &[&wallet]).await;
+    assert_eq!(missing, Err(error(PledgeError::InvalidFeeAccount)));
+
+    // Another Solheist token account of the treasury will not do either.
+    let other = Pubkey::new_unique();
+    let treasury = harness.treasury_address();
+    harness.context.set_account(&other, &token_account(&harness.solhit_mint, &treasury, 0).into());
+    let wrong = harness.try_send(claim_with_fee(&harness, 10_001, other), &[&wallet]).await;
+    assert_eq!(wrong, Err(error(PledgeError::InvalidFeeAccount)));
+    // Nor will the right one anywhere but in its place, ahead of the accounts
+    // creating an associated token account.
+    let mut moved_back = harness.claim(10_001);
+    moved_back.accounts.push(AccountMeta::new_readonly(system_program::ID, false));
+    moved_back.accounts.push(AccountMeta::new_readonly(spl_associated_token_account::id(), false));
+    moved_back.accounts.push(AccountMeta::new(fee_address(&harness), false));
+    assert_eq!(harness.try_send(moved_back, &[&wallet]).await, Err(error(PledgeError::InvalidFeeAccount)));
+    assert_eq!(harness.token_balance(harness.solhit_token).await, 0);
+
+    // Nor may the fee exceed the whole claim.
+    let too_high = harness.try_send(set_claim_fee(&harness, 10_001), &[&admin]).await;
+    assert_eq!(too_high, Err(error(PledgeError::InvalidConfig)));
+    assert_eq!(harness.config().await.claim_fee_bps, 100);
+}
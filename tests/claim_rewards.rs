@@ -0,0 +1,388 @@
+mod common;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use common::{token_account, token_balance};
+use pledge::{
+    find_config_address, find_solhit_vault_address, find_treasury_address, find_user_state_address, process_instruction,
+    ContractConfig, PledgeError,
+    PledgeInstruction, UserState, BASIS_POINTS, DEFAULT_LOCK_TIER, LOCKED_SOLHIT_TOKENS, REWARD_RATE_BPS, VESTING_PERIOD,
+};
+use solana_program::{
+    clock::Clock,
+    instruction::{AccountMeta, Instruction, InstructionError},
+    program_pack::Pack,
+    pubkey::Pubkey,
+    system_program,
+};
+use solana_program_test::{processor, tokio, ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    account::Account,
+    signature::{Keypair, Signer},
+    transaction::{Transaction, TransactionError},
+};
+use spl_associated_token_account::get_associated_token_address;
+
+// The team's locked tranche, which claims leave alone, and a million more.
+const VAULT_BALANCE: u64 = LOCKED_SOLHIT_TOKENS + 1_000_000;
+
+// What the admin's Solheist token account starts out with.
+const ADMIN_BALANCE: u64 = 1_000;
+
+// A running test validator with a funded Solheist vault, an initialized
+// config and a user holding `solhit_rewards` unclaimed rewards.
+struct ClaimSetup {
+    context: ProgramTestContext,
+    wallet: Keypair,
+    admin: Keypair,
+    admin_token: Pubkey,
+    config: Pubkey,
+    treasury: Pubkey,
+    user_state: Pubkey,
+    vault: Pubkey,
+    user_token: Pubkey,
+    claim: Instruction,
+}
+
+async fn setup(solhit_rewards: u64) -> ClaimSetup {
+    setup_with_vault(solhit_rewards, VAULT_BALANCE).await
+}
+
+// Like setup, with `vault_balance` Solheist in the vault.
+async fn setup_with_vault(solhit_rewards: u64, vault_balance: u64) -> ClaimSetup {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new("pledge", program_id, processor!(process_instruction));
+
+    let wallet = Keypair::new();
+    program_test.add_account(
+        wallet.pubkey(),
+        Account { lamports: 1_000_000_000, data: vec![], owner: system_program::ID, executable: false, rent_epoch: 0 },
+    );
+    let (treasury, _) = find_treasury_address(&program_id);
+    program_test.add_account(
+        treasury,
+        Account { lamports: 1_000_000_000, data: vec![], owner: program_id, executable: false, rent_epoch: 0 },
+    );
+    let mint = Pubkey::new_unique();
+    let mut mint_data = vec![0; spl_token::state::Mint::LEN];
+    spl_token::state::Mint {
+        supply: vault_balance + ADMIN_BALANCE,
+        decimals: 6,
+        is_initialized: true,
+        ..spl_token::state::Mint::default()
+    }
+    .pack_into_slice(&mut mint_data);
+    program_test.add_account(
+        mint,
+        Account { lamports: 1_000_000_000, data: mint_data, owner: spl_token::id(), executable: false, rent_epoch: 0 },
+    );
+
+    let (vault, _) = find_solhit_vault_address(&program_id);
+    program_test.add_account(vault, token_account(&mint, &vault, vault_balance));
+    let user_token = Pubkey::new_unique();
+    program_test.add_account(user_token, token_account(&mint, &wallet.pubkey(), 0));
+    let admin = Keypair::new();
+    let admin_token = Pubkey::new_unique();
+    program_test.add_account(admin_token, token_account(&mint, &admin.pubkey(), ADMIN_BALANCE));
+
+    let (user_state, bump) = find_user_state_address(&program_id, &wallet.pubkey());
+    let state = UserState {
+        is_initialized: true,
+        solhit_rewards,
+        owner: wallet.pubkey(),
+        bump,
+        beneficiary: wallet.pubkey(),
+        ..UserState::default()
+    };
+    program_test.add_account(
+        user_state,
+        Account {
+            lamports: 1_000_000_000,
+            data: borsh::to_vec(&state).unwrap(),
+            owner: program_id,
+            executable: false,
+            rent_epoch: 0,
+        },
+    );
+
+    let (config, bump) = find_config_address(&program_id);
+    // Counting the user state above, as InitializeUser would have.
+    let pledge_contract =
+        ContractConfig { is_initialized: true, bump, admin: admin.pubkey(), initialized_users: 1, ..ContractConfig::new() };
+    let mut config_data = vec![0; ContractConfig::LEN];
+    pledge_contract.serialize(&mut config_data.as_mut_slice()).unwrap();
+    program_test.add_account(
+        config,
+        Account {
+            lamports: 1_000_000_000,
+            data: config_data,
+            owner: program_id,
+            executable: false,
+            rent_epoch: 0,
+        },
+    );
+
+    let claim = Instruction::new_with_bytes(
+        program_id,
+        &PledgeInstruction::ClaimRewards { amount: 0 }.pack(),
+        vec![
+            AccountMeta::new(user_state, false),
+            AccountMeta::new_readonly(wallet.pubkey(), true),
+            AccountMeta::new(config, false),
+            AccountMeta::new(vault, false),
+            AccountMeta::new(user_token, false),
+            AccountMeta::new_readonly(mint, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ],
+    );
+
+    let context = program_test.start_with_context().await;
+    ClaimSetup { context, wallet, admin, admin_token, config, treasury, user_state, vault, user_token, claim }
+}
+
+impl ClaimSetup {
+    fn transaction(&self, instruction: Instruction) -> Transaction {
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&self.context.payer.pubkey()));
+        transaction.sign(&[&self.context.payer, &self.wallet], self.context.last_blockhash);
+        transaction
+    }
+
+    fn buy(&self, amount: u64) -> Instruction {
+        Instruction::new_with_bytes(
+            self.claim.program_id,
+            &PledgeInstruction::BuyPledge { amount, tier: DEFAULT_LOCK_TIER, referrer: None, allow_partial: false }.pack(),
+            vec![
+                AccountMeta::new(self.user_state, false),
+                AccountMeta::new(self.wallet.pubkey(), true),
+                AccountMeta::new(self.config, false),
+                AccountMeta::new(self.treasury, false),
+                AccountMeta::new_readonly(system_program::ID, false),
+            ],
+        )
+    }
+
+    fn close(&self) -> Instruction {
+        Instruction::new_with_bytes(
+            self.claim.program_id,
+            &PledgeInstruction::CloseUserState.pack(),
+            vec![
+                AccountMeta::new(self.user_state, false),
+                AccountMeta::new(self.wallet.pubkey(), true),
+                AccountMeta::new(self.config, false),
+            ],
+        )
+    }
+
+    // The claim, paying into `destination` and creating it as the wallet's
+    // associated token account if it is empty.
+    fn claim_to_ata(&self, destination: Pubkey) -> Instruction {
+        let mut claim = self.claim.clone();
+        claim.accounts[1] = AccountMeta::new(self.wallet.pubkey(), true);
+        claim.accounts[4] = AccountMeta::new(destination, false);
+        claim.accounts.push(AccountMeta::new_readonly(system_program::ID, false));
+        claim.accounts.push(AccountMeta::new_readonly(spl_associated_token_account::id(), false));
+        claim
+    }
+
+    fn associated_token_address(&self) -> Pubkey {
+        get_associated_token_address(&self.wallet.pubkey(), &self.claim.accounts[5].pubkey)
+    }
+
+    fn claim_amount(&self, amount: u64) -> Instruction {
+        Instruction { data: PledgeInstruction::ClaimRewards { amount }.pack(), ..self.claim.clone() }
+    }
+
+    // FundRewards from the admin's token account, signed by the admin.
+    fn fund(&self, amount: u64) -> Transaction {
+        let fund = Instruction::new_with_bytes(
+            self.claim.program_id,
+            &PledgeInstruction::FundRewards { amount }.pack(),
+            vec![
+                AccountMeta::new(self.config, false),
+                AccountMeta::new_readonly(self.admin.pubkey(), true),
+                AccountMeta::new(self.admin_token, false),
+                AccountMeta::new(self.vault, false),
+                AccountMeta::new_readonly(self.claim.accounts[5].pubkey, false),
+                AccountMeta::new_readonly(spl_token::id(), false),
+            ],
+        );
+        let mut transaction = Transaction::new_with_payer(&[fund], Some(&self.context.payer.pubkey()));
+        transaction.sign(&[&self.context.payer, &self.admin], self.context.last_blockhash);
+        transaction
+    }
+
+    async fn config(&mut self) -> ContractConfig {
+        let account = self.context.banks_client.get_account(self.config).await.unwrap().unwrap();
+        ContractConfig::unpack(&account.data).unwrap()
+    }
+
+    async fn user_state(&mut self) -> UserState {
+        let account = self.context.banks_client.get_account(self.user_state).await.unwrap().unwrap();
+        UserState::try_from_slice(&account.data).unwrap()
+    }
+
+    async fn solhit_rewards(&mut self) -> u64 {
+        self.user_state().await.solhit_rewards
+    }
+
+    async fn advance_clock(&mut self, seconds: u64) {
+        let mut clock: Clock = self.context.banks_client.get_sysvar().await.unwrap();
+        clock.unix_timestamp += seconds as i64;
+        self.context.set_sysvar(&clock);
+    }
+}
+
+#[tokio::test]
+async fn test_claim_rewards_transfers_solhit_tokens() {
+    let mut setup = setup(500).await;
+
+    let transaction = setup.transaction(setup.claim.clone());
+    setup.context.banks_client.process_transaction(transaction).await.unwrap();
+
+    assert_eq!(token_balance(&mut setup.context.banks_client, setup.vault).await, VAULT_BALANCE - 500);
+    assert_eq!(token_balance(&mut setup.context.banks_client, setup.user_token).await, 500);
+    assert_eq!(setup.solhit_rewards().await, 0);
+}
+
+#[tokio::test]
+async fn test_claim_rewards_into_existing_associated_token_account() {
+    let mut setup = setup(500).await;
+    let ata = setup.associated_token_address();
+    let mint = setup.claim.accounts[5].pubkey;
+    setup.context.set_account(&ata, &token_account(&mint, &setup.wallet.pubkey(), 0).into());
+
+    let transaction = setup.transaction(setup.claim_to_ata(ata));
+    setup.context.banks_client.process_transaction(transaction).await.unwrap();
+
+    assert_eq!(token_balance(&mut setup.context.banks_client, ata).await, 500);
+    assert_eq!(setup.solhit_rewards().await, 0);
+}
+
+#[tokio::test]
+async fn test_claim_rewards_creates_associated_token_account() {
+    let mut setup = setup(500).await;
+    let ata = setup.associated_token_address();
+    let wallet = setup.wallet.pubkey();
+    let wallet_lamports = setup.context.banks_client.get_balance(wallet).await.unwrap();
+
+    let transaction = setup.transaction(setup.claim_to_ata(ata));
+    setup.context.banks_client.process_transaction(transaction).await.unwrap();
+
+    assert_eq!(token_balance(&mut setup.context.banks_client, ata).await, 500);
+    assert_eq!(setup.solhit_rewards().await, 0);
+    // The wallet paid the new account's rent.
+    let ata_lamports = setup.context.banks_client.get_balance(ata).await.unwrap();
+    assert!(ata_lamports > 0);
+    assert_eq!(setup.context.banks_client.get_balance(wallet).await.unwrap(), wallet_lamports - ata_lamports);
+}
+
+#[tokio::test]
+async fn test_claim_rewards_into_non_canonical_account_fails() {
+    let mut setup = setup(500).await;
+    let destination = Pubkey::new_unique();
+
+    let transaction = setup.transaction(setup.claim_to_ata(destination));
+    let error = setup.context.banks_client.process_transaction(transaction).await.unwrap_err().unwrap();
+    assert_eq!(error, TransactionError::InstructionError(0, InstructionError::InvalidSeeds));
+
+    assert!(setup.context.banks_client.get_account(destination).await.unwrap().is_none());
+    assert_eq!(setup.solhit_rewards().await, 500);
+}
+
+#[tokio::test]
+async fn test_claim_rewards_accrues_without_update_reward() {
+    let mut setup = setup(0).await;
+
+    let transaction = setup.transaction(setup.buy(1000));
+    setup.context.banks_client.process_transaction(transaction).await.unwrap();
+    let locked_pledge_tokens = setup.user_state().await.locked_pledge_tokens;
+    assert!(locked_pledge_tokens > 0);
+
+    setup.advance_clock(VESTING_PERIOD).await;
+    let transaction = setup.transaction(setup.claim.clone());
+    setup.context.banks_client.process_transaction(transaction).await.unwrap();
+
+    let full_reward = locked_pledge_tokens * REWARD_RATE_BPS / BASIS_POINTS;
+    assert_eq!(token_balance(&mut setup.context.banks_client, setup.user_token).await, full_reward);
+    assert_eq!(token_balance(&mut setup.context.banks_client, setup.vault).await, VAULT_BALANCE - full_reward);
+    assert_eq!(setup.solhit_rewards().await, 0);
+}
+
+#[tokio::test]
+async fn test_failed_transfer_leaves_claim_unrecorded() {
+    let mut setup = setup(500).await;
+    let mut frozen = token_account(&Pubkey::default(), &setup.wallet.pubkey(), 0);
+    let mint = setup.claim.accounts[5].pubkey;
+    let mut user_token = spl_token::state::Account::unpack(&frozen.data).unwrap();
+    user_token.mint = mint;
+    user_token.state = spl_token::state::AccountState::Frozen;
+    user_token.pack_into_slice(&mut frozen.data);
+    setup.context.set_account(&setup.user_token, &frozen.into());
+
+    // The state is written before the transfer; the failed transfer rolls it
+    // back with the rest of the transaction.
+    let transaction = setup.transaction(setup.claim.clone());
+    assert!(setup.context.banks_client.process_transaction(transaction).await.is_err());
+
+    let user_state = setup.user_state().await;
+    assert_eq!((user_state.solhit_rewards, user_state.total_claimed), (500, 0));
+    let pledge_contract = setup.config().await;
+    assert_eq!((pledge_contract.global_solhit_claimed, pledge_contract.event_seq), (0, 0));
+    assert_eq!(token_balance(&mut setup.context.banks_client, setup.vault).await, VAULT_BALANCE);
+}
+
+#[tokio::test]
+async fn test_fund_rewards_then_claim_down_to_zero() {
+    // The vault holds only the team's tranche, which claims may not touch.
+    let mut setup = setup_with_vault(500, LOCKED_SOLHIT_TOKENS).await;
+    let underfunded = TransactionError::InstructionError(
+        0,
+        InstructionError::Custom(PledgeError::InsufficientVaultBalance as u32),
+    );
+    let transaction = setup.transaction(setup.claim.clone());
+    let error = setup.context.banks_client.process_transaction(transaction).await.unwrap_err().unwrap();
+    assert_eq!(error, underfunded);
+
+    let transaction = setup.fund(300);
+    setup.context.banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(token_balance(&mut setup.context.banks_client, setup.vault).await, LOCKED_SOLHIT_TOKENS + 300);
+    assert_eq!(token_balance(&mut setup.context.banks_client, setup.admin_token).await, ADMIN_BALANCE - 300);
+    assert_eq!(setup.config().await.rewards_funded, 300);
+
+    let transaction = setup.transaction(setup.claim_amount(300));
+    setup.context.banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(token_balance(&mut setup.context.banks_client, setup.user_token).await, 300);
+    assert_eq!(token_balance(&mut setup.context.banks_client, setup.vault).await, LOCKED_SOLHIT_TOKENS);
+
+    // The funding is used up, so the rest of the rewards wait for more.
+    let transaction = setup.transaction(setup.claim_amount(200));
+    let error = setup.context.banks_client.process_transaction(transaction).await.unwrap_err().unwrap();
+    assert_eq!(error, underfunded);
+    assert_eq!(setup.solhit_rewards().await, 200);
+}
+
+#[tokio::test]
+async fn test_close_user_state_with_pending_rewards_fails() {
+    let mut setup = setup(500).await;
+
+    let transaction = setup.transaction(setup.close());
+    assert!(setup.context.banks_client.process_transaction(transaction).await.is_err());
+
+    assert_eq!(setup.solhit_rewards().await, 500);
+}
+
+#[tokio::test]
+async fn test_close_user_state_after_full_claim() {
+    let mut setup = setup(500).await;
+    let transaction = setup.transaction(setup.claim.clone());
+    setup.context.banks_client.process_transaction(transaction).await.unwrap();
+
+    let wallet = setup.wallet.pubkey();
+    let wallet_lamports = setup.context.banks_client.get_balance(wallet).await.unwrap();
+    let state_lamports = setup.context.banks_client.get_balance(setup.user_state).await.unwrap();
+    let transaction = setup.transaction(setup.close());
+    setup.context.banks_client.process_transaction(transaction).await.unwrap();
+
+    // The context payer pays the fee, so the wallet receives the rent in full.
+    assert_eq!(setup.context.banks_client.get_balance(wallet).await.unwrap(), wallet_lamports + state_lamports);
+    assert!(setup.context.banks_client.get_account(setup.user_state).await.unwrap().is_none());
+}
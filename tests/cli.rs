@@ -0,0 +1,105 @@
+// pledge-cli run against a local test validator, since program-test has no
+// RPC endpoint. Skipped unless PLEDGE_CLI_TEST_URL names the validator and
+// PLEDGE_CLI_TEST_PROGRAM_ID a pledge program freshly deployed to it, whose
+// config the test initializes, e.g.:
+//
+//   solana-test-validator --bpf-program <PROGRAM_ID> target/deploy/pledge.so
+//   PLEDGE_CLI_TEST_URL=http://127.0.0.1:8899 PLEDGE_CLI_TEST_PROGRAM_ID=<PROGRAM_ID> cargo test --features cli --test cli
+use pledge::{client, SALE_UNSCHEDULED};
+use serde_json::Value;
+use solana_cli_config::Config;
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{
+    commitment_config::CommitmentConfig,
+    native_token::LAMPORTS_PER_SOL,
+    signature::{write_keypair_file, Keypair, Signer},
+    transaction::Transaction,
+};
+use std::{env, path::{Path, PathBuf}, process::Command, thread, time::Duration};
+
+struct Validator {
+    url: String,
+    program_id: String,
+}
+
+fn validator() -> Option<Validator> {
+    match (env::var("PLEDGE_CLI_TEST_URL"), env::var("PLEDGE_CLI_TEST_PROGRAM_ID")) {
+        (Ok(url), Ok(program_id)) => Some(Validator { url, program_id }),
+        _ => {
+            eprintln!("PLEDGE_CLI_TEST_URL and PLEDGE_CLI_TEST_PROGRAM_ID are not set, skipping");
+            None
+        }
+    }
+}
+
+// A funded keypair and a Solana CLI config pointing at the validator with
+// it, both written to a fresh temporary directory.
+fn setup(validator: &Validator, rpc: &RpcClient) -> (Keypair, PathBuf) {
+    let dir = env::temp_dir().join(format!("pledge-cli-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let keypair = Keypair::new();
+    let keypair_path = dir.join("id.json");
+    write_keypair_file(&keypair, &keypair_path).unwrap();
+    let config = Config {
+        json_rpc_url: validator.url.clone(),
+        keypair_path: keypair_path.to_str().unwrap().into(),
+        ..Config::default()
+    };
+    let config_path = dir.join("config.yml");
+    config.save(config_path.to_str().unwrap()).unwrap();
+
+    let airdrop = rpc.request_airdrop(&keypair.pubkey(), 10 * LAMPORTS_PER_SOL).unwrap();
+    for _ in 0..60 {
+        if rpc.confirm_transaction(&airdrop).unwrap() {
+            return (keypair, config_path);
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+    panic!("airdrop not confirmed");
+}
+
+// Runs pledge-cli with --json and the given arguments, returning its output.
+fn pledge_cli(validator: &Validator, config_path: &Path, args: &[&str]) -> Value {
+    let output = Command::new(env!("CARGO_BIN_EXE_pledge-cli"))
+        .args(["--config", config_path.to_str().unwrap(), "--program-id", &validator.program_id, "--json"])
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "pledge-cli {args:?}: {}", String::from_utf8_lossy(&output.stderr));
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+#[test]
+fn test_cli_against_validator() {
+    let Some(validator) = validator() else { return };
+    let rpc = RpcClient::new_with_commitment(validator.url.clone(), CommitmentConfig::confirmed());
+    let (keypair, config_path) = setup(&validator, &rpc);
+    let wallet = keypair.pubkey().to_string();
+
+    let initialized = pledge_cli(&validator, &config_path, &["init-contract"]);
+    assert_eq!(initialized["signatures"].as_array().unwrap().len(), 1);
+    let config = pledge_cli(&validator, &config_path, &["show-config"]);
+    assert_eq!(config["admin"], serde_json::to_value(keypair.pubkey()).unwrap());
+    assert_eq!(config["sale_start_time"], SALE_UNSCHEDULED);
+    assert_eq!(config["paused"], false);
+
+    pledge_cli(&validator, &config_path, &["pause"]);
+    assert_eq!(pledge_cli(&validator, &config_path, &["show-config"])["paused"], true);
+    pledge_cli(&validator, &config_path, &["unpause"]);
+    pledge_cli(&validator, &config_path, &["start-sale"]);
+    let config = pledge_cli(&validator, &config_path, &["show-config"]);
+    assert_eq!(config["paused"], false);
+    assert!(config["sale_start_time"].as_u64().unwrap() < SALE_UNSCHEDULED);
+
+    // The keypair's own user state, cranked and shown.
+    let program_id = validator.program_id.parse().unwrap();
+    let initialize_user = client::initialize_user_ix(&program_id, &keypair.pubkey());
+    let blockhash = rpc.get_latest_blockhash().unwrap();
+    let transaction = Transaction::new_signed_with_payer(&[initialize_user], Some(&keypair.pubkey()), &[&keypair], blockhash);
+    rpc.send_and_confirm_transaction(&transaction).unwrap();
+    let cranked = pledge_cli(&validator, &config_path, &["crank", &wallet]);
+    assert_eq!(cranked["signatures"].as_array().unwrap().len(), 1);
+    let user_state = pledge_cli(&validator, &config_path, &["show-user", &wallet]);
+    assert_eq!(user_state["owner"], serde_json::to_value(keypair.pubkey()).unwrap());
+    assert_eq!(user_state["locked_pledge_tokens"], 0);
+}
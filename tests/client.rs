@@ -0,0 +1,398 @@
+mod common;
+
+use borsh::BorshDeserialize;
+use common::Harness;
+use pledge::{
+    client, find_solhit_vault_address, BuyQuote, ContractConfig, PledgeError, Receipt, RewardPreview, Snapshot, UserState,
+    VestingCheckpoint, CONFIG_VERSION, DEFAULT_LOCK_TIER, LOCKED_SOLHIT_TOKENS, LOCK_TIERS, PHASE_CAPS, PHASE_RATES,
+    SNAPSHOT_INTERVAL, TEAM_UNLOCK_CLIFF, TEAM_UNLOCK_DURATION,
+};
+use solana_program::{program_pack::Pack, pubkey::Pubkey};
+use solana_program_test::tokio;
+use solana_sdk::signature::{Keypair, Signer};
+use spl_associated_token_account::get_associated_token_address;
+
+// A whole pledge, from setting up the contract to unlocking, with every
+// instruction built by the client module.
+#[tokio::test]
+async fn test_client_pledge_lifecycle() {
+    let mut harness = Harness::start().await;
+    let program_id = harness.program_id;
+    let admin = harness.admin.insecure_clone();
+    let wallet = harness.wallet.insecure_clone();
+    let start = harness.now().await;
+
+    // Selling the first phase from now, which is reserved for the whitelist.
+    let initialize = client::initialize_contract_ix(&program_id, &admin.pubkey(), start, PHASE_CAPS, 0);
+    harness.send(initialize, &[&admin]).await.unwrap();
+    harness.send(client::add_to_whitelist_ix(&program_id, &admin.pubkey(), wallet.pubkey()), &[&admin]).await.unwrap();
+    harness.send(client::initialize_user_ix(&program_id, &wallet.pubkey()), &[&wallet]).await.unwrap();
+
+    // Small enough that the rewards fit in the claimable Solheist supply.
+    let amount = 100_000;
+    let buy = client::buy_pledge_ix(&program_id, &wallet.pubkey(), amount, DEFAULT_LOCK_TIER, None, false, None);
+    harness.send(buy, &[&wallet]).await.unwrap();
+    let user_state = harness.user_state().await;
+    let bought = amount * PHASE_RATES[0] / 100;
+    assert_eq!(user_state.locked_pledge_tokens, bought);
+    assert_eq!(harness.config().await.total_pledge_sold, bought);
+
+    // Half way through the lock there are rewards to crank and claim.
+    let lock = LOCK_TIERS[DEFAULT_LOCK_TIER as usize].0;
+    harness.advance_clock(lock / 2).await;
+    let return_data = harness.return_data(client::preview_reward_ix(&program_id, &wallet.pubkey())).await;
+    let preview = RewardPreview::try_from_slice(&return_data).unwrap();
+    harness.send(client::update_reward_ix(&program_id, &wallet.pubkey()), &[]).await.unwrap();
+    let rewards = harness.user_state().await.solhit_rewards;
+    assert!(rewards > 0);
+    assert_eq!(preview, RewardPreview { reward_delta: rewards, solhit_rewards: rewards, unlockable: false, initialized: true });
+
+    let claim = client::claim_rewards_ix(&program_id, &wallet.pubkey(), &harness.solhit_token, &harness.solhit_mint, 0);
+    harness.send(claim, &[&wallet]).await.unwrap();
+    assert_eq!(harness.token_balance(harness.solhit_token).await, rewards);
+    assert_eq!(harness.user_state().await.solhit_rewards, 0);
+
+    // The schedule comes back as return data.
+    let return_data = harness.return_data(client::get_vesting_schedule_ix(&program_id, &wallet.pubkey())).await;
+    let schedule = Vec::<VestingCheckpoint>::try_from_slice(&return_data).unwrap();
+    assert_eq!(schedule.last().unwrap().timestamp, start + lock);
+    assert_eq!(schedule.last().unwrap().unlocked_amount, bought);
+
+    harness.advance_clock(lock - lock / 2).await;
+    let unlock = client::unlock_pledge_ix(&program_id, &wallet.pubkey(), &harness.pledge_vault, &harness.pledge_token);
+    harness.send(unlock, &[&wallet]).await.unwrap();
+    assert_eq!(harness.token_balance(harness.pledge_token).await, bought);
+    assert_eq!(harness.user_state().await.locked_pledge_tokens, 0);
+    // BuyPledge, UpdateReward, ClaimRewards and UnlockPledge each emitted one event.
+    assert_eq!(harness.config().await.event_seq, 4);
+}
+
+#[tokio::test]
+async fn test_client_admin_instructions() {
+    let mut harness = Harness::start().await;
+    let program_id = harness.program_id;
+    let admin = harness.admin.insecure_clone();
+    let wallet = harness.wallet.insecure_clone();
+
+    // A sale already past its whitelisted phase.
+    let initialize = client::initialize_contract_ix(&program_id, &admin.pubkey(), 0, PHASE_CAPS, 0);
+    harness.send(initialize, &[&admin]).await.unwrap();
+    harness.send(client::initialize_user_ix(&program_id, &wallet.pubkey()), &[&wallet]).await.unwrap();
+
+    harness.send(client::set_paused_ix(&program_id, &admin.pubkey(), true), &[&admin]).await.unwrap();
+    assert!(harness.config().await.paused);
+    let buy = client::buy_pledge_ix(&program_id, &wallet.pubkey(), 1_000, DEFAULT_LOCK_TIER, None, false, None);
+    assert!(harness.send(buy, &[&wallet]).await.is_err());
+    harness.send(client::set_paused_ix(&program_id, &admin.pubkey(), false), &[&admin]).await.unwrap();
+
+    harness.send(client::set_purchase_cooldown_ix(&program_id, &admin.pubkey(), 60), &[&admin]).await.unwrap();
+    harness.send(client::set_referral_bonus_ix(&program_id, &admin.pubkey(), 7), &[&admin]).await.unwrap();
+    let config = harness.config().await;
+    assert_eq!((config.purchase_cooldown_secs, config.referral_bonus_percent), (60, 7));
+    // A config created by this program is already current.
+    harness.send(client::migrate_config_ix(&program_id, &admin.pubkey()), &[&admin]).await.unwrap();
+    assert_eq!(harness.config().await.config_version, CONFIG_VERSION);
+
+    // The finite phases are long over, so the sale can be closed.
+    harness.send(client::finalize_sale_ix(&program_id, &admin.pubkey()), &[&admin]).await.unwrap();
+    assert!(harness.config().await.sale_finalized);
+    let buy = client::buy_pledge_ix(&program_id, &wallet.pubkey(), 2_000, DEFAULT_LOCK_TIER, None, false, None);
+    assert!(harness.send(buy, &[&wallet]).await.is_err());
+
+    // Handing the contract over to the wallet.
+    harness.send(client::nominate_admin_ix(&program_id, &admin.pubkey(), wallet.pubkey()), &[&admin]).await.unwrap();
+    harness.send(client::accept_admin_ix(&program_id, &wallet.pubkey()), &[&wallet]).await.unwrap();
+    assert_eq!(harness.config().await.admin, wallet.pubkey());
+    assert!(harness.send(client::set_referral_bonus_ix(&program_id, &admin.pubkey(), 8), &[&admin]).await.is_err());
+
+    harness.send(client::close_user_state_ix(&program_id, &wallet.pubkey()), &[&wallet]).await.unwrap();
+    let user_state = harness.user_state_address();
+    assert!(harness.context.banks_client.get_account(user_state).await.unwrap().is_none());
+}
+
+// Snapshots create their PDA on first use and come back as return data.
+#[tokio::test]
+async fn test_client_snapshots() {
+    let mut harness = Harness::start().await;
+    let program_id = harness.program_id;
+    let admin = harness.admin.insecure_clone();
+    let wallet = harness.wallet.insecure_clone();
+
+    let initialize = client::initialize_contract_ix(&program_id, &admin.pubkey(), 0, PHASE_CAPS, 0);
+    harness.send(initialize, &[&admin]).await.unwrap();
+    harness.send(client::record_snapshot_ix(&program_id, &wallet.pubkey()), &[&wallet]).await.unwrap();
+    let now = harness.now().await;
+    assert_eq!(harness.config().await.last_snapshot_time, now);
+
+    harness.advance_clock(SNAPSHOT_INTERVAL - 60).await;
+    let record = client::record_snapshot_ix(&program_id, &wallet.pubkey());
+    assert!(harness.send(record, &[&wallet]).await.is_err());
+
+    let return_data = harness.return_data(client::get_latest_snapshot_ix(&program_id)).await;
+    let snapshot = Snapshot::try_from_slice(&return_data).unwrap();
+    assert_eq!(snapshot, Snapshot { timestamp: now, total_sold: 0, total_claimed: 0, active_phase: 4 });
+}
+
+// The team lock starts with the sale and releases into any Solheist account.
+#[tokio::test]
+async fn test_client_release_team_tokens() {
+    let mut harness = Harness::start().await;
+    let program_id = harness.program_id;
+    let admin = harness.admin.insecure_clone();
+    let team_token = harness.solhit_token;
+    let solhit_vault = harness.solhit_vault;
+
+    let sale_start_time = harness.now().await;
+    let initialize = client::initialize_contract_ix(&program_id, &admin.pubkey(), sale_start_time, PHASE_CAPS, 0);
+    harness.send(initialize, &[&admin]).await.unwrap();
+    let release = client::release_team_tokens_ix(&program_id, &admin.pubkey(), &team_token, &harness.solhit_mint);
+    assert!(harness.send(release.clone(), &[&admin]).await.is_err());
+
+    let vault_balance = harness.token_balance(solhit_vault).await;
+    harness.advance_clock(TEAM_UNLOCK_CLIFF).await;
+    harness.send(release.clone(), &[&admin]).await.unwrap();
+    assert_eq!(harness.token_balance(team_token).await, LOCKED_SOLHIT_TOKENS / 2);
+    assert_eq!(harness.token_balance(solhit_vault).await, vault_balance - LOCKED_SOLHIT_TOKENS / 2);
+
+    harness.advance_clock(TEAM_UNLOCK_DURATION).await;
+    harness.send(release.clone(), &[&admin]).await.unwrap();
+    assert_eq!(harness.token_balance(team_token).await, LOCKED_SOLHIT_TOKENS);
+    assert_eq!(harness.config().await.team_released, LOCKED_SOLHIT_TOKENS);
+    harness.advance_clock(60).await;
+    assert!(harness.send(release, &[&admin]).await.is_err());
+}
+
+// A quote simulated right before a purchase prices it exactly.
+#[tokio::test]
+async fn test_client_quote_buy() {
+    let mut harness = Harness::start().await;
+    let program_id = harness.program_id;
+    let admin = harness.admin.insecure_clone();
+    let wallet = harness.wallet.insecure_clone();
+
+    let initialize = client::initialize_contract_ix(&program_id, &admin.pubkey(), 0, PHASE_CAPS, 0);
+    harness.send(initialize, &[&admin]).await.unwrap();
+    harness.send(client::initialize_user_ix(&program_id, &wallet.pubkey()), &[&wallet]).await.unwrap();
+
+    let return_data = harness.return_data(client::quote_buy_ix(&program_id, Some(&wallet.pubkey()), 100_000)).await;
+    let quote = BuyQuote::try_from_slice(&return_data).unwrap();
+    assert_eq!(quote.rate, PHASE_RATES[4]);
+    assert_eq!(quote.pledge_tokens, quote.requested_tokens);
+
+    let lamports = harness.lamports(wallet.pubkey()).await;
+    let buy = client::buy_pledge_ix(&program_id, &wallet.pubkey(), 100_000, DEFAULT_LOCK_TIER, None, false, None);
+    harness.send(buy, &[&wallet]).await.unwrap();
+    assert_eq!(harness.user_state().await.locked_pledge_tokens, quote.pledge_tokens);
+    assert_eq!(lamports - harness.lamports(wallet.pubkey()).await, quote.paid);
+}
+
+// Rewards for a beneficiary without a Solheist token account create its
+// associated token account, at the wallet's expense.
+#[tokio::test]
+async fn test_client_claim_rewards_to_ata() {
+    let mut harness = Harness::start().await;
+    let program_id = harness.program_id;
+    let admin = harness.admin.insecure_clone();
+    let wallet = harness.wallet.insecure_clone();
+    let start = harness.now().await;
+    let initialize = client::initialize_contract_ix(&program_id, &admin.pubkey(), start, PHASE_CAPS, 0);
+    harness.send(initialize, &[&admin]).await.unwrap();
+    harness.send(client::add_to_whitelist_ix(&program_id, &admin.pubkey(), wallet.pubkey()), &[&admin]).await.unwrap();
+    harness.send(client::initialize_user_ix(&program_id, &wallet.pubkey()), &[&wallet]).await.unwrap();
+    let buy = client::buy_pledge_ix(&program_id, &wallet.pubkey(), 100_000, DEFAULT_LOCK_TIER, None, false, None);
+    harness.send(buy, &[&wallet]).await.unwrap();
+    let beneficiary = Pubkey::new_unique();
+    harness.send(client::set_beneficiary_ix(&program_id, &wallet.pubkey(), beneficiary), &[&wallet]).await.unwrap();
+
+    harness.advance_clock(LOCK_TIERS[DEFAULT_LOCK_TIER as usize].0 / 2).await;
+    harness.send(client::update_reward_ix(&program_id, &wallet.pubkey()), &[]).await.unwrap();
+    let rewards = harness.user_state().await.solhit_rewards;
+    assert!(rewards > 0);
+
+    let wallet_lamports = harness.lamports(wallet.pubkey()).await;
+    let claim = client::claim_rewards_to_ata_ix(&program_id, &wallet.pubkey(), &beneficiary, &harness.solhit_mint, 0);
+    harness.send(claim, &[&wallet]).await.unwrap();
+    let ata = get_associated_token_address(&beneficiary, &harness.solhit_mint);
+    assert_eq!(harness.token_balance(ata).await, rewards);
+    assert_eq!(harness.lamports(wallet.pubkey()).await, wallet_lamports - harness.lamports(ata).await);
+}
+
+// Under a claim fee the treasury's share goes to its associated token
+// account, passed ahead of those creating the wallet's own.
+#[tokio::test]
+async fn test_client_claim_fee() {
+    let mut harness = Harness::start().await;
+    let program_id = harness.program_id;
+    let admin = harness.admin.insecure_clone();
+    let wallet = harness.wallet.insecure_clone();
+    let initialize = client::initialize_contract_ix(&program_id, &admin.pubkey(), 0, PHASE_CAPS, 0);
+    harness.send(initialize, &[&admin]).await.unwrap();
+    harness.send(client::initialize_user_ix(&program_id, &wallet.pubkey()), &[&wallet]).await.unwrap();
+    let buy = client::buy_pledge_ix(&program_id, &wallet.pubkey(), 100_000, DEFAULT_LOCK_TIER, None, false, None);
+    harness.send(buy, &[&wallet]).await.unwrap();
+    harness.send(client::set_claim_fee_ix(&program_id, &admin.pubkey(), 250), &[&admin]).await.unwrap();
+    assert_eq!(harness.config().await.claim_fee_bps, 250);
+
+    harness.advance_clock(LOCK_TIERS[DEFAULT_LOCK_TIER as usize].0 / 2).await;
+    harness.send(client::update_reward_ix(&program_id, &wallet.pubkey()), &[]).await.unwrap();
+    let rewards = harness.user_state().await.solhit_rewards;
+    assert!(rewards > 0);
+    let fee_account = pledge::claim_fee_address(&program_id, &harness.solhit_mint);
+    let treasury = harness.treasury_address();
+    harness.context.set_account(&fee_account, &common::token_account(&harness.solhit_mint, &treasury, 0).into());
+
+    let claim = client::claim_rewards_to_ata_ix(&program_id, &wallet.pubkey(), &wallet.pubkey(), &harness.solhit_mint, 0);
+    harness.send(client::with_claim_fee(claim, &harness.solhit_mint), &[&wallet]).await.unwrap();
+    let fee = rewards * 250 / 10_000;
+    let ata = get_associated_token_address(&wallet.pubkey(), &harness.solhit_mint);
+    assert_eq!(harness.token_balance(ata).await, rewards - fee);
+    assert_eq!(harness.token_balance(fee_account).await, fee);
+}
+
+// A delegate claiming under a claim fee into the wallet's associated token
+// account, which does not exist yet, pays for creating it.
+#[tokio::test]
+async fn test_client_delegate_claim_fee_to_ata() {
+    let mut harness = Harness::start().await;
+    let program_id = harness.program_id;
+    let admin = harness.admin.insecure_clone();
+    let wallet = harness.wallet.insecure_clone();
+    let initialize = client::initialize_contract_ix(&program_id, &admin.pubkey(), 0, PHASE_CAPS, 0);
+    harness.send(initialize, &[&admin]).await.unwrap();
+    harness.send(client::initialize_user_ix(&program_id, &wallet.pubkey()), &[&wallet]).await.unwrap();
+    let buy = client::buy_pledge_ix(&program_id, &wallet.pubkey(), 100_000, DEFAULT_LOCK_TIER, None, false, None);
+    harness.send(buy, &[&wallet]).await.unwrap();
+    harness.send(client::set_claim_fee_ix(&program_id, &admin.pubkey(), 250), &[&admin]).await.unwrap();
+
+    harness.advance_clock(LOCK_TIERS[DEFAULT_LOCK_TIER as usize].0 / 2).await;
+    harness.send(client::update_reward_ix(&program_id, &wallet.pubkey()), &[]).await.unwrap();
+    let rewards = harness.user_state().await.solhit_rewards;
+    assert!(rewards > 0);
+    let fee_account = pledge::claim_fee_address(&program_id, &harness.solhit_mint);
+    let treasury = harness.treasury_address();
+    harness.context.set_account(&fee_account, &common::token_account(&harness.solhit_mint, &treasury, 0).into());
+    let delegate = harness.add_wallet();
+    harness.send(client::set_delegate_ix(&program_id, &wallet.pubkey(), delegate.pubkey()), &[&wallet]).await.unwrap();
+
+    let delegate_lamports = harness.lamports(delegate.pubkey()).await;
+    let claim =
+        client::delegate_claim_rewards_to_ata_ix(&program_id, &wallet.pubkey(), &delegate.pubkey(), &wallet.pubkey(), &harness.solhit_mint, 0);
+    harness.send(client::with_claim_fee(claim, &harness.solhit_mint), &[&delegate]).await.unwrap();
+    let fee = rewards * 250 / 10_000;
+    let ata = get_associated_token_address(&wallet.pubkey(), &harness.solhit_mint);
+    assert_eq!(harness.token_balance(ata).await, rewards - fee);
+    assert_eq!(harness.token_balance(fee_account).await, fee);
+    assert_eq!(harness.lamports(delegate.pubkey()).await, delegate_lamports - harness.lamports(ata).await);
+}
+
+// The guardian set at initialization sweeping both vaults once the admin has
+// paused the contract.
+#[tokio::test]
+async fn test_client_emergency_sweep() {
+    let mut harness = Harness::start().await;
+    let program_id = harness.program_id;
+    let admin = harness.admin.insecure_clone();
+    let (guardian, recovery) = (Keypair::new(), Pubkey::new_unique());
+    let initialize = client::initialize_contract_with_guardian_ix(
+        &program_id,
+        &admin.pubkey(),
+        0,
+        PHASE_CAPS,
+        0,
+        guardian.pubkey(),
+        recovery,
+    );
+    harness.send(initialize, &[&admin]).await.unwrap();
+    harness.send(client::set_paused_ix(&program_id, &admin.pubkey(), true), &[&admin]).await.unwrap();
+
+    let vault = harness.context.banks_client.get_account(harness.pledge_vault).await.unwrap().unwrap();
+    let pledge_mint = spl_token::state::Account::unpack(&vault.data).unwrap().mint;
+    let (solhit_vault, _) = find_solhit_vault_address(&program_id);
+    for (vault, mint) in [(harness.pledge_vault, pledge_mint), (solhit_vault, harness.solhit_mint)] {
+        let recovery_token = Pubkey::new_unique();
+        harness.context.set_account(&recovery_token, &common::token_account(&mint, &recovery, 0).into());
+        let sweep = client::emergency_sweep_ix(&program_id, &guardian.pubkey(), &vault, &recovery_token);
+        harness.send(sweep, &[&guardian]).await.unwrap();
+        assert_eq!(harness.token_balance(vault).await, 0);
+        assert_eq!(harness.token_balance(recovery_token).await, common::VAULT_BALANCE);
+    }
+}
+
+// Accounts fetched as raw bytes decode to what the program stored, whatever
+// follows them and in the older layout too.
+#[tokio::test]
+async fn test_client_decodes_accounts() {
+    let mut harness = Harness::start().await;
+    let program_id = harness.program_id;
+    let admin = harness.admin.insecure_clone();
+    let wallet = harness.wallet.insecure_clone();
+    let initialize = client::initialize_contract_ix(&program_id, &admin.pubkey(), 0, PHASE_CAPS, 0);
+    harness.send(initialize, &[&admin]).await.unwrap();
+    harness.send(client::initialize_user_ix(&program_id, &wallet.pubkey()), &[&wallet]).await.unwrap();
+    let buy = client::buy_pledge_ix(&program_id, &wallet.pubkey(), 100_000, DEFAULT_LOCK_TIER, None, false, None);
+    harness.send(buy, &[&wallet]).await.unwrap();
+
+    let user_data = harness.context.banks_client.get_account(harness.user_state_address()).await.unwrap().unwrap().data;
+    let config_data = harness.context.banks_client.get_account(harness.config_address()).await.unwrap().unwrap().data;
+    let user_state = UserState::try_from_account_data(&user_data).unwrap();
+    let config = ContractConfig::try_from_account_data(&config_data).unwrap();
+    assert_eq!(borsh::to_vec(&user_state).unwrap(), user_data);
+    // Without a pending admin the config leaves the room for one unused.
+    let encoded_config = borsh::to_vec(&config).unwrap();
+    assert_eq!(encoded_config, config_data[..encoded_config.len()]);
+    assert!(user_state.locked_pledge_tokens > 0);
+    assert_eq!(config.total_pledge_sold, user_state.locked_pledge_tokens);
+
+    let mut padded = user_data.clone();
+    padded.extend([0; 64]);
+    assert_eq!(borsh::to_vec(&UserState::try_from_account_data(&padded).unwrap()).unwrap(), user_data);
+    // Version 7 is the same layout without the discriminator.
+    let mut v7 = user_data[8..].to_vec();
+    v7[0] = 7;
+    assert_eq!(borsh::to_vec(&UserState::try_from_account_data(&v7).unwrap()).unwrap(), user_data);
+
+    assert_eq!(UserState::try_from_account_data(&config_data).err(), Some(PledgeError::InvalidAccountType));
+    assert_eq!(ContractConfig::try_from_account_data(&user_data).err(), Some(PledgeError::InvalidAccountType));
+    assert_eq!(UserState::try_from_account_data(&user_data[..40]).err(), Some(PledgeError::InvalidAccountType));
+
+    assert!(user_state.to_string().starts_with(&format!("user {}: {} pledge tokens", wallet.pubkey(), user_state.locked_pledge_tokens)));
+    assert!(config.to_string().starts_with(&format!("config v{}: {} of", CONFIG_VERSION, config.total_pledge_sold)));
+
+    #[cfg(feature = "serde")]
+    {
+        let json = serde_json::to_value(&user_state).unwrap();
+        assert_eq!(json["locked_pledge_tokens"], user_state.locked_pledge_tokens);
+        let from_json: UserState = serde_json::from_value(json).unwrap();
+        assert_eq!(borsh::to_vec(&from_json).unwrap(), user_data);
+        let json = serde_json::to_value(&config).unwrap();
+        assert_eq!(json["total_pledge_sold"], config.total_pledge_sold);
+        let from_json: ContractConfig = serde_json::from_value(json).unwrap();
+        assert_eq!(borsh::to_vec(&from_json).unwrap(), encoded_config);
+    }
+}
+
+// Receipts asked for with with_receipt, found again by enumerating the
+// wallet's purchases.
+#[tokio::test]
+async fn test_client_enumerates_receipts() {
+    let mut harness = Harness::start().await;
+    let program_id = harness.program_id;
+    let admin = harness.admin.insecure_clone();
+    let wallet = harness.wallet.insecure_clone();
+    let initialize = client::initialize_contract_ix(&program_id, &admin.pubkey(), 0, PHASE_CAPS, 0);
+    harness.send(initialize, &[&admin]).await.unwrap();
+    harness.send(client::initialize_user_ix(&program_id, &wallet.pubkey()), &[&wallet]).await.unwrap();
+    for (purchase_count, amount) in [(0, 100_000), (1, 200_000)] {
+        let buy = client::buy_pledge_ix(&program_id, &wallet.pubkey(), amount, DEFAULT_LOCK_TIER, None, false, None);
+        harness.send(client::with_receipt(buy, &wallet.pubkey(), purchase_count), &[&wallet]).await.unwrap();
+    }
+
+    let purchase_count = harness.user_state().await.purchase_count;
+    let addresses = client::receipt_addresses(&program_id, &wallet.pubkey(), purchase_count);
+    let mut paid = vec![];
+    for address in addresses {
+        let data = harness.context.banks_client.get_account(address).await.unwrap().unwrap().data;
+        paid.push(Receipt::unpack(&data).unwrap().lamports_paid);
+    }
+    assert_eq!(paid, [100_000, 200_000]);
+}
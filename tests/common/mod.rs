@@ -0,0 +1,297 @@
+// Fixtures shared by the integration tests. Each test binary compiles its own
+// copy of this module and uses only part of it.
+#![allow(dead_code)]
+
+use borsh::BorshDeserialize;
+use pledge::{
+    find_config_address, find_pledge_vault_address, find_solhit_vault_address, find_treasury_address,
+    find_user_state_address, process_instruction, ContractConfig, PledgeInstruction, UserState,
+};
+use solana_program::{
+    clock::Clock,
+    instruction::{AccountMeta, Instruction},
+    program_pack::Pack,
+    pubkey::Pubkey,
+    system_program,
+};
+use solana_program_test::{processor, BanksClient, ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    account::Account,
+    signature::{Keypair, Signer},
+    transaction::{Transaction, TransactionError},
+};
+
+pub const WALLET_LAMPORTS: u64 = 10_000_000_000;
+pub const VAULT_BALANCE: u64 = 1_000_000_000_000;
+
+pub fn token_account(mint: &Pubkey, owner: &Pubkey, amount: u64) -> Account {
+    let mut data = vec![0; spl_token::state::Account::LEN];
+    spl_token::state::Account {
+        mint: *mint,
+        owner: *owner,
+        amount,
+        state: spl_token::state::AccountState::Initialized,
+        ..spl_token::state::Account::default()
+    }
+    .pack_into_slice(&mut data);
+    Account { lamports: 1_000_000_000, data, owner: spl_token::id(), executable: false, rent_epoch: 0 }
+}
+
+pub fn mint_account(supply: u64) -> Account {
+    let mut data = vec![0; spl_token::state::Mint::LEN];
+    spl_token::state::Mint { supply, decimals: 6, is_initialized: true, ..spl_token::state::Mint::default() }
+        .pack_into_slice(&mut data);
+    Account { lamports: 1_000_000_000, data, owner: spl_token::id(), executable: false, rent_epoch: 0 }
+}
+
+pub fn wallet_account(lamports: u64) -> Account {
+    Account { lamports, data: vec![], owner: system_program::ID, executable: false, rent_epoch: 0 }
+}
+
+pub async fn token_balance(banks_client: &mut BanksClient, address: Pubkey) -> u64 {
+    let account = banks_client.get_account(address).await.unwrap().unwrap();
+    spl_token::state::Account::unpack(&account.data).unwrap().amount
+}
+
+// A running test validator with the program deployed but not initialized,
+// an admin and a user wallet each holding WALLET_LAMPORTS, Solheist and
+// pledge vaults holding VAULT_BALANCE, and empty token accounts of both
+// mints for the user. Instructions are paid for by the context payer, so the
+// wallets' balances move only by what the program moves.
+pub struct Harness {
+    pub context: ProgramTestContext,
+    pub program_id: Pubkey,
+    pub admin: Keypair,
+    pub wallet: Keypair,
+    pub solhit_mint: Pubkey,
+    pub solhit_vault: Pubkey,
+    pub solhit_token: Pubkey,
+    pub pledge_vault: Pubkey,
+    pub pledge_token: Pubkey,
+}
+
+impl Harness {
+    pub async fn start() -> Self {
+        let program_id = Pubkey::new_unique();
+        let mut program_test = ProgramTest::new("pledge", program_id, processor!(process_instruction));
+
+        let admin = Keypair::new();
+        program_test.add_account(admin.pubkey(), wallet_account(WALLET_LAMPORTS));
+        let wallet = Keypair::new();
+        program_test.add_account(wallet.pubkey(), wallet_account(WALLET_LAMPORTS));
+
+        let solhit_mint = Pubkey::new_unique();
+        program_test.add_account(solhit_mint, mint_account(VAULT_BALANCE));
+        let (solhit_vault, _) = find_solhit_vault_address(&program_id);
+        program_test.add_account(solhit_vault, token_account(&solhit_mint, &solhit_vault, VAULT_BALANCE));
+        let solhit_token = Pubkey::new_unique();
+        program_test.add_account(solhit_token, token_account(&solhit_mint, &wallet.pubkey(), 0));
+
+        let pledge_mint = Pubkey::new_unique();
+        program_test.add_account(pledge_mint, mint_account(VAULT_BALANCE));
+        let (vault_authority, _) = find_pledge_vault_address(&program_id);
+        let pledge_vault = Pubkey::new_unique();
+        program_test.add_account(pledge_vault, token_account(&pledge_mint, &vault_authority, VAULT_BALANCE));
+        let pledge_token = Pubkey::new_unique();
+        program_test.add_account(pledge_token, token_account(&pledge_mint, &wallet.pubkey(), 0));
+
+        Self {
+            context: program_test.start_with_context().await,
+            program_id,
+            admin,
+            wallet,
+            solhit_mint,
+            solhit_vault,
+            solhit_token,
+            pledge_vault,
+            pledge_token,
+        }
+    }
+
+    pub fn config_address(&self) -> Pubkey {
+        find_config_address(&self.program_id).0
+    }
+
+    pub fn treasury_address(&self) -> Pubkey {
+        find_treasury_address(&self.program_id).0
+    }
+
+    pub fn user_state_address(&self) -> Pubkey {
+        find_user_state_address(&self.program_id, &self.wallet.pubkey()).0
+    }
+
+    // Sends `instruction` in a transaction of its own, signed by `signers` as
+    // well as the payer.
+    pub async fn send(&mut self, instruction: Instruction, signers: &[&Keypair]) -> Result<(), ()> {
+        self.try_send(instruction, signers).await.map_err(|_| ())
+    }
+
+    // Like send, but keeps the error.
+    pub async fn try_send(&mut self, instruction: Instruction, signers: &[&Keypair]) -> Result<(), TransactionError> {
+        let blockhash = self.context.banks_client.get_latest_blockhash().await.unwrap();
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&self.context.payer.pubkey()));
+        let mut keypairs = vec![&self.context.payer];
+        keypairs.extend_from_slice(signers);
+        transaction.sign(&keypairs, blockhash);
+        self.context.banks_client.process_transaction(transaction).await.map_err(|error| error.unwrap())
+    }
+
+    // A new wallet holding WALLET_LAMPORTS.
+    pub fn add_wallet(&mut self) -> Keypair {
+        let wallet = Keypair::new();
+        self.context.set_account(&wallet.pubkey(), &wallet_account(WALLET_LAMPORTS).into());
+        wallet
+    }
+
+    // Simulates `instruction`, paid for by the payer, and returns the return
+    // data it sets.
+    pub async fn return_data(&mut self, instruction: Instruction) -> Vec<u8> {
+        let blockhash = self.context.banks_client.get_latest_blockhash().await.unwrap();
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&self.context.payer.pubkey()));
+        transaction.sign(&[&self.context.payer], blockhash);
+        let simulation = self.context.banks_client.simulate_transaction(transaction).await.unwrap();
+        simulation.simulation_details.unwrap().return_data.unwrap().data
+    }
+
+    // Simulates `instruction`, signed by `signers` as well as the payer, and
+    // returns its log messages, whether or not it succeeds.
+    pub async fn logs(&mut self, instruction: Instruction, signers: &[&Keypair]) -> Vec<String> {
+        let blockhash = self.context.banks_client.get_latest_blockhash().await.unwrap();
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&self.context.payer.pubkey()));
+        let mut keypairs = vec![&self.context.payer];
+        keypairs.extend_from_slice(signers);
+        transaction.sign(&keypairs, blockhash);
+        let simulation = self.context.banks_client.simulate_transaction(transaction).await.unwrap();
+        simulation.simulation_details.unwrap().logs
+    }
+
+    pub async fn send_as_admin(&mut self, instruction: Instruction) -> Result<(), ()> {
+        let admin = self.admin.insecure_clone();
+        self.send(instruction, &[&admin]).await
+    }
+
+    pub async fn send_as_wallet(&mut self, instruction: Instruction) -> Result<(), ()> {
+        let wallet = self.wallet.insecure_clone();
+        self.send(instruction, &[&wallet]).await
+    }
+
+    pub async fn now(&mut self) -> u64 {
+        let clock: Clock = self.context.banks_client.get_sysvar().await.unwrap();
+        clock.unix_timestamp as u64
+    }
+
+    // Moves to a later slot, for a fresh blockhash, and sets the clock
+    // `seconds` ahead.
+    pub async fn advance_clock(&mut self, seconds: u64) {
+        let mut clock: Clock = self.context.banks_client.get_sysvar().await.unwrap();
+        self.context.warp_to_slot(clock.slot + 100).unwrap();
+        clock.slot += 100;
+        clock.unix_timestamp += seconds as i64;
+        self.context.set_sysvar(&clock);
+    }
+
+    pub async fn lamports(&mut self, address: Pubkey) -> u64 {
+        self.context.banks_client.get_balance(address).await.unwrap()
+    }
+
+    pub async fn token_balance(&mut self, address: Pubkey) -> u64 {
+        token_balance(&mut self.context.banks_client, address).await
+    }
+
+    pub async fn user_state(&mut self) -> UserState {
+        let account = self.context.banks_client.get_account(self.user_state_address()).await.unwrap().unwrap();
+        UserState::try_from_slice(&account.data).unwrap()
+    }
+
+    pub async fn config(&mut self) -> ContractConfig {
+        let account = self.context.banks_client.get_account(self.config_address()).await.unwrap().unwrap();
+        ContractConfig::unpack(&account.data).unwrap()
+    }
+
+    pub fn initialize_contract(&self, sale_start_time: u64) -> Instruction {
+        Instruction::new_with_bytes(
+            self.program_id,
+            &PledgeInstruction::InitializeContract {
+                sale_start_time,
+                phase_caps: pledge::PHASE_CAPS,
+                max_per_user: 0,
+                guardian: Pubkey::default(),
+                recovery_destination: Pubkey::default(),
+            }
+            .pack(),
+            vec![
+                AccountMeta::new(self.config_address(), false),
+                AccountMeta::new(self.admin.pubkey(), true),
+                AccountMeta::new_readonly(system_program::ID, false),
+                AccountMeta::new(self.treasury_address(), false),
+            ],
+        )
+    }
+
+    pub fn initialize_user(&self) -> Instruction {
+        Instruction::new_with_bytes(
+            self.program_id,
+            &PledgeInstruction::InitializeUser.pack(),
+            vec![
+                AccountMeta::new(self.user_state_address(), false),
+                AccountMeta::new(self.wallet.pubkey(), true),
+                AccountMeta::new_readonly(system_program::ID, false),
+                AccountMeta::new(self.config_address(), false),
+            ],
+        )
+    }
+
+    pub fn buy(&self, amount: u64, tier: u8) -> Instruction {
+        Instruction::new_with_bytes(
+            self.program_id,
+            &PledgeInstruction::BuyPledge { amount, tier, referrer: None, allow_partial: false }.pack(),
+            vec![
+                AccountMeta::new(self.user_state_address(), false),
+                AccountMeta::new(self.wallet.pubkey(), true),
+                AccountMeta::new(self.config_address(), false),
+                AccountMeta::new(self.treasury_address(), false),
+                AccountMeta::new_readonly(system_program::ID, false),
+            ],
+        )
+    }
+
+    pub fn update_reward(&self) -> Instruction {
+        Instruction::new_with_bytes(
+            self.program_id,
+            &PledgeInstruction::UpdateReward.pack(),
+            vec![AccountMeta::new(self.user_state_address(), false), AccountMeta::new(self.config_address(), false)],
+        )
+    }
+
+    pub fn claim(&self, amount: u64) -> Instruction {
+        Instruction::new_with_bytes(
+            self.program_id,
+            &PledgeInstruction::ClaimRewards { amount }.pack(),
+            vec![
+                AccountMeta::new(self.user_state_address(), false),
+                AccountMeta::new_readonly(self.wallet.pubkey(), true),
+                AccountMeta::new(self.config_address(), false),
+                AccountMeta::new(self.solhit_vault, false),
+                AccountMeta::new(self.solhit_token, false),
+                AccountMeta::new_readonly(self.solhit_mint, false),
+                AccountMeta::new_readonly(spl_token::id(), false),
+            ],
+        )
+    }
+
+    pub fn unlock(&self) -> Instruction {
+        Instruction::new_with_bytes(
+            self.program_id,
+            &PledgeInstruction::UnlockPledge.pack(),
+            vec![
+                AccountMeta::new(self.user_state_address(), false),
+                AccountMeta::new_readonly(self.wallet.pubkey(), true),
+                AccountMeta::new(self.config_address(), false),
+                AccountMeta::new(self.pledge_vault, false),
+                AccountMeta::new(self.pledge_token, false),
+                AccountMeta::new_readonly(find_pledge_vault_address(&self.program_id).0, false),
+                AccountMeta::new_readonly(spl_token::id(), false),
+            ],
+        )
+    }
+}
@@ -0,0 +1,78 @@
+// A compliance hold the admin puts on a wallet: its unlock fails until the
+// hold expires, and nobody else may set one.
+mod common;
+
+use common::Harness;
+use pledge::{PledgeError, PledgeInstruction, DEFAULT_LOCK_TIER, LOCK_TIERS};
+use solana_program::instruction::{AccountMeta, Instruction, InstructionError};
+use solana_program_test::tokio;
+use solana_sdk::{
+    signature::{Keypair, Signer},
+    transaction::TransactionError,
+};
+
+// SetComplianceHold over the harness wallet's state, signed by `signer`.
+fn set_hold(harness: &Harness, signer: &Keypair, hold_until: u64) -> Instruction {
+    Instruction::new_with_bytes(
+        harness.program_id,
+        &PledgeInstruction::SetComplianceHold { wallet: harness.wallet.pubkey(), hold_until }.pack(),
+        vec![
+            AccountMeta::new(harness.user_state_address(), false),
+            AccountMeta::new(harness.config_address(), false),
+            AccountMeta::new_readonly(signer.pubkey(), true),
+        ],
+    )
+}
+
+fn error(error: PledgeError) -> TransactionError {
+    TransactionError::InstructionError(0, InstructionError::Custom(error as u32))
+}
+
+// A user whose single purchase has just finished vesting.
+async fn setup() -> Harness {
+    let mut harness = Harness::start().await;
+    harness.send_as_admin(harness.initialize_contract(0)).await.unwrap();
+    harness.send_as_wallet(harness.initialize_user()).await.unwrap();
+    harness.send_as_wallet(harness.buy(1_000_000, DEFAULT_LOCK_TIER)).await.unwrap();
+    let (lock, _) = LOCK_TIERS[DEFAULT_LOCK_TIER as usize];
+    harness.advance_clock(lock).await;
+    harness
+}
+
+#[tokio::test]
+async fn test_hold_blocks_unlock_until_it_expires() {
+    let mut harness = setup().await;
+    let admin = harness.admin.insecure_clone();
+    let wallet = harness.wallet.insecure_clone();
+    let hold_until = harness.now().await + 86_400;
+    harness.try_send(set_hold(&harness, &admin, hold_until), &[&admin]).await.unwrap();
+    assert_eq!(harness.user_state().await.hold_until, hold_until);
+    assert_eq!(harness.config().await.event_seq, 2);
+
+    let locked = harness.user_state().await.locked_pledge_tokens;
+    assert_eq!(harness.try_send(harness.unlock(), &[&wallet]).await, Err(error(PledgeError::ComplianceHold)));
+    // Rewards go on accruing through the hold.
+    let rewards = harness.user_state().await.solhit_rewards;
+    harness.send(harness.update_reward(), &[]).await.unwrap();
+    assert!(harness.user_state().await.solhit_rewards > rewards);
+
+    harness.advance_clock(86_400).await;
+    harness.try_send(harness.unlock(), &[&wallet]).await.unwrap();
+    assert_eq!(harness.token_balance(harness.pledge_token).await, locked);
+    assert_eq!(harness.user_state().await.locked_pledge_tokens, 0);
+}
+
+#[tokio::test]
+async fn test_hold_only_set_by_admin() {
+    let mut harness = setup().await;
+    let wallet = harness.wallet.insecure_clone();
+    let result = harness.try_send(set_hold(&harness, &wallet, u64::MAX), &[&wallet]).await;
+    assert_eq!(result, Err(error(PledgeError::Unauthorized)));
+    assert_eq!(harness.user_state().await.hold_until, 0);
+
+    // Lifted with 0, a hold no longer stands in the way.
+    let admin = harness.admin.insecure_clone();
+    harness.try_send(set_hold(&harness, &admin, u64::MAX), &[&admin]).await.unwrap();
+    harness.try_send(set_hold(&harness, &admin, 0), &[&admin]).await.unwrap();
+    harness.try_send(harness.unlock(), &[&wallet]).await.unwrap();
+}
@@ -0,0 +1,69 @@
+// Compute unit budgets for the hot instructions. Native processors are not
+// metered, so these run against the SBF build of the program:
+//
+//     cargo build-sbf && SBF_OUT_DIR=target/deploy cargo test --test compute_units -- --ignored
+use pledge::{
+    find_config_address, find_user_state_address, ContractConfig, PledgeInstruction, PledgeLot, UserState,
+};
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+};
+use solana_program_test::{tokio, ProgramTest};
+use solana_sdk::{account::Account, signature::Signer, transaction::Transaction};
+
+// UpdateReward on a user holding every lot slot used to spend most of its
+// budget decoding and re-encoding the user state with Borsh. Reading it in
+// place keeps it well under this.
+const UPDATE_REWARD_BUDGET: u64 = 12_000;
+
+#[tokio::test]
+#[ignore = "needs the SBF build, see the top of the file"]
+async fn test_update_reward_compute_units() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new("pledge", program_id, None);
+    program_test.prefer_bpf(true);
+
+    let wallet = Pubkey::new_unique();
+    let (user_state, bump) = find_user_state_address(&program_id, &wallet);
+    let mut state = UserState { is_initialized: true, owner: wallet, bump, beneficiary: wallet, ..UserState::default() };
+    for i in 0..pledge::MAX_PLEDGE_LOTS as u64 {
+        let lot = PledgeLot {
+            amount: 1_000 + i,
+            rate: 200,
+            lock_start_time: i,
+            vesting_end_time: u64::MAX,
+            multiplier_bps: pledge::BASIS_POINTS,
+        };
+        state.add_lot(lot).unwrap();
+    }
+    let mut data = vec![0; UserState::LEN];
+    state.pack_into_slice(&mut data).unwrap();
+    program_test.add_account(
+        user_state,
+        Account { lamports: 1_000_000_000, data, owner: program_id, executable: false, rent_epoch: 0 },
+    );
+
+    let (config, bump) = find_config_address(&program_id);
+    let pledge_contract = ContractConfig { is_initialized: true, bump, ..ContractConfig::new() };
+    let mut config_data = vec![0; ContractConfig::LEN];
+    borsh::to_writer(config_data.as_mut_slice(), &pledge_contract).unwrap();
+    program_test.add_account(
+        config,
+        Account { lamports: 1_000_000_000, data: config_data, owner: program_id, executable: false, rent_epoch: 0 },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let instruction = Instruction::new_with_bytes(
+        program_id,
+        &PledgeInstruction::UpdateReward.pack(),
+        vec![AccountMeta::new(user_state, false), AccountMeta::new(config, false)],
+    );
+    let mut transaction = Transaction::new_with_payer(&[instruction], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+
+    let simulation = banks_client.simulate_transaction(transaction).await.unwrap();
+    assert_eq!(simulation.result, Some(Ok(())));
+    let units_consumed = simulation.simulation_details.unwrap().units_consumed;
+    assert!(units_consumed <= UPDATE_REWARD_BUDGET, "UpdateReward used {} compute units", units_consumed);
+}
@@ -0,0 +1,95 @@
+// The bounty UpdateRewardBatch pays whoever runs it, out of the Solheist
+// vault, for every user state whose rewards the crank moved forward.
+mod common;
+
+use common::{Harness, VAULT_BALANCE};
+use pledge::{find_solhit_vault_address, PledgeInstruction, DEFAULT_LOCK_TIER, LOCK_TIERS};
+use solana_program::instruction::{AccountMeta, Instruction};
+use solana_program_test::tokio;
+use solana_sdk::signature::Signer;
+
+const CRANK_FEE: u64 = 5;
+
+// A user with a locked purchase and a bounty of CRANK_FEE per account out of
+// `crank_budget`.
+async fn setup(crank_budget: u64) -> Harness {
+    let mut harness = Harness::start().await;
+    harness.send_as_admin(harness.initialize_contract(0)).await.unwrap();
+    harness.send_as_wallet(harness.initialize_user()).await.unwrap();
+    harness.send_as_wallet(harness.buy(100_000, DEFAULT_LOCK_TIER)).await.unwrap();
+    let set_crank_bounty = Instruction::new_with_bytes(
+        harness.program_id,
+        &PledgeInstruction::SetCrankBounty { crank_fee: CRANK_FEE, crank_budget }.pack(),
+        vec![AccountMeta::new(harness.config_address(), false), AccountMeta::new_readonly(harness.admin.pubkey(), true)],
+    );
+    harness.send_as_admin(set_crank_bounty).await.unwrap();
+    harness
+}
+
+// UpdateRewardBatch over the user, paying the bounty into the user's own
+// Solheist token account.
+fn crank(harness: &Harness) -> Instruction {
+    Instruction::new_with_bytes(
+        harness.program_id,
+        &PledgeInstruction::UpdateRewardBatch.pack(),
+        vec![
+            AccountMeta::new(harness.config_address(), false),
+            AccountMeta::new(harness.user_state_address(), false),
+            AccountMeta::new(find_solhit_vault_address(&harness.program_id).0, false),
+            AccountMeta::new(harness.solhit_token, false),
+            AccountMeta::new_readonly(harness.solhit_mint, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ],
+    )
+}
+
+// Cranks `seconds` after the last crank and returns the bounty paid and the
+// rewards the user gained.
+async fn crank_after(harness: &mut Harness, seconds: u64) -> (u64, u64) {
+    harness.advance_clock(seconds).await;
+    let (tokens, rewards) = (harness.token_balance(harness.solhit_token).await, harness.user_state().await.solhit_rewards);
+    harness.send(crank(harness), &[]).await.unwrap();
+    (
+        harness.token_balance(harness.solhit_token).await - tokens,
+        harness.user_state().await.solhit_rewards - rewards,
+    )
+}
+
+#[tokio::test]
+async fn test_crank_with_changes_pays() {
+    let mut harness = setup(1_000).await;
+    let (lock, _) = LOCK_TIERS[DEFAULT_LOCK_TIER as usize];
+
+    let (bounty, accrued) = crank_after(&mut harness, lock / 4).await;
+    assert!(accrued > 0);
+    assert_eq!(bounty, CRANK_FEE);
+    assert_eq!(harness.token_balance(harness.solhit_vault).await, VAULT_BALANCE - CRANK_FEE);
+    assert_eq!(harness.config().await.crank_bounties_paid, CRANK_FEE);
+}
+
+#[tokio::test]
+async fn test_crank_without_elapsed_time_pays_nothing() {
+    let mut harness = setup(1_000).await;
+
+    // Straight after the purchase, and again at the same time after a crank
+    // that paid.
+    assert_eq!(crank_after(&mut harness, 0).await, (0, 0));
+    let (lock, _) = LOCK_TIERS[DEFAULT_LOCK_TIER as usize];
+    assert_eq!(crank_after(&mut harness, lock / 4).await.0, CRANK_FEE);
+    assert_eq!(crank_after(&mut harness, 0).await, (0, 0));
+    assert_eq!(harness.config().await.crank_bounties_paid, CRANK_FEE);
+}
+
+#[tokio::test]
+async fn test_exhausted_crank_budget_stops_payments_but_still_accrues() {
+    let mut harness = setup(CRANK_FEE + 2).await;
+    let (lock, _) = LOCK_TIERS[DEFAULT_LOCK_TIER as usize];
+
+    assert_eq!(crank_after(&mut harness, lock / 4).await.0, CRANK_FEE);
+    // Only what is left of the budget.
+    assert_eq!(crank_after(&mut harness, lock / 4).await.0, 2);
+    let (bounty, accrued) = crank_after(&mut harness, lock / 4).await;
+    assert_eq!(bounty, 0);
+    assert!(accrued > 0);
+    assert_eq!(harness.config().await.crank_bounties_paid, CRANK_FEE + 2);
+}
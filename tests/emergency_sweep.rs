@@ -0,0 +1,123 @@
+// EmergencySweep: the guardian chosen at InitializeContract emptying a vault
+// into the recovery destination's token account while the contract is paused.
+mod common;
+
+use common::{token_account, Harness, VAULT_BALANCE};
+use pledge::{find_pledge_vault_address, PledgeError, PledgeInstruction, PHASE_CAPS};
+use solana_program::{
+    instruction::{AccountMeta, Instruction, InstructionError},
+    program_pack::Pack,
+    pubkey::Pubkey,
+};
+use solana_program_test::tokio;
+use solana_sdk::{
+    signature::{Keypair, Signer},
+    transaction::TransactionError,
+};
+
+struct Sweep {
+    harness: Harness,
+    guardian: Keypair,
+    // The recovery destination's pledge token account.
+    recovery_token: Pubkey,
+}
+
+// An initialized contract with a guardian, and an empty pledge token account
+// of the recovery destination. The contract is paused when `paused` is set.
+async fn setup(paused: bool) -> Sweep {
+    let mut harness = Harness::start().await;
+    let guardian = Keypair::new();
+    let recovery_destination = Pubkey::new_unique();
+    let initialize = Instruction::new_with_bytes(
+        harness.program_id,
+        &PledgeInstruction::InitializeContract {
+            sale_start_time: 0,
+            phase_caps: PHASE_CAPS,
+            max_per_user: 0,
+            guardian: guardian.pubkey(),
+            recovery_destination,
+        }
+        .pack(),
+        harness.initialize_contract(0).accounts,
+    );
+    harness.send_as_admin(initialize).await.unwrap();
+    if paused {
+        harness.send_as_admin(set_paused(&harness)).await.unwrap();
+    }
+
+    let pledge_mint = pledge_mint(&mut harness).await;
+    let recovery_token = Pubkey::new_unique();
+    harness.context.set_account(&recovery_token, &token_account(&pledge_mint, &recovery_destination, 0).into());
+    Sweep { harness, guardian, recovery_token }
+}
+
+async fn pledge_mint(harness: &mut Harness) -> Pubkey {
+    let vault = harness.context.banks_client.get_account(harness.pledge_vault).await.unwrap().unwrap();
+    spl_token::state::Account::unpack(&vault.data).unwrap().mint
+}
+
+fn set_paused(harness: &Harness) -> Instruction {
+    Instruction::new_with_bytes(
+        harness.program_id,
+        &PledgeInstruction::SetPaused { paused: true }.pack(),
+        vec![AccountMeta::new(harness.config_address(), false), AccountMeta::new_readonly(harness.admin.pubkey(), true)],
+    )
+}
+
+// Sweeps the pledge vault into `destination`, signed by `signer`.
+fn sweep(harness: &Harness, signer: &Pubkey, destination: Pubkey) -> Instruction {
+    Instruction::new_with_bytes(
+        harness.program_id,
+        &PledgeInstruction::EmergencySweep.pack(),
+        vec![
+            AccountMeta::new(harness.config_address(), false),
+            AccountMeta::new_readonly(*signer, true),
+            AccountMeta::new(harness.pledge_vault, false),
+            AccountMeta::new(destination, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(find_pledge_vault_address(&harness.program_id).0, false),
+        ],
+    )
+}
+
+fn error(error: PledgeError) -> TransactionError {
+    TransactionError::InstructionError(0, InstructionError::Custom(error as u32))
+}
+
+#[tokio::test]
+async fn test_sweep_while_unpaused_fails() {
+    let Sweep { mut harness, guardian, recovery_token } = setup(false).await;
+    let result = harness.try_send(sweep(&harness, &guardian.pubkey(), recovery_token), &[&guardian]).await;
+    assert_eq!(result, Err(error(PledgeError::ContractNotPaused)));
+    assert_eq!(harness.token_balance(harness.pledge_vault).await, VAULT_BALANCE);
+}
+
+#[tokio::test]
+async fn test_sweep_by_another_signer_fails() {
+    let Sweep { mut harness, recovery_token, .. } = setup(true).await;
+    // Not even the admin may sweep.
+    let admin = harness.admin.insecure_clone();
+    let result = harness.try_send(sweep(&harness, &admin.pubkey(), recovery_token), &[&admin]).await;
+    assert_eq!(result, Err(error(PledgeError::Unauthorized)));
+    assert_eq!(harness.token_balance(harness.pledge_vault).await, VAULT_BALANCE);
+}
+
+#[tokio::test]
+async fn test_sweep_to_another_destination_fails() {
+    let Sweep { mut harness, guardian, .. } = setup(true).await;
+    let pledge_mint = pledge_mint(&mut harness).await;
+    let elsewhere = Pubkey::new_unique();
+    harness.context.set_account(&elsewhere, &token_account(&pledge_mint, &guardian.pubkey(), 0).into());
+    let result = harness.try_send(sweep(&harness, &guardian.pubkey(), elsewhere), &[&guardian]).await;
+    assert_eq!(result, Err(error(PledgeError::InvalidRecoveryDestination)));
+    assert_eq!(harness.token_balance(harness.pledge_vault).await, VAULT_BALANCE);
+}
+
+#[tokio::test]
+async fn test_sweep_drains_the_vault() {
+    let Sweep { mut harness, guardian, recovery_token } = setup(true).await;
+    harness.try_send(sweep(&harness, &guardian.pubkey(), recovery_token), &[&guardian]).await.unwrap();
+    assert_eq!(harness.token_balance(harness.pledge_vault).await, 0);
+    assert_eq!(harness.token_balance(recovery_token).await, VAULT_BALANCE);
+    assert_eq!(harness.config().await.event_seq, 1);
+}
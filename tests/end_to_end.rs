@@ -0,0 +1,89 @@
+// A pledge from an empty deployment to a claim, through the real runtime:
+// system program CPIs for rent and payments, SPL token transfers and the
+// Clock sysvar.
+mod common;
+
+use common::{Harness, VAULT_BALANCE, WALLET_LAMPORTS};
+use pledge::{UserState, BASIS_POINTS, DEFAULT_LOCK_TIER, LOCK_TIERS, PHASE_RATES, REWARD_RATE_BPS};
+use solana_program::rent::Rent;
+use solana_program_test::tokio;
+use solana_sdk::signature::Signer;
+
+#[tokio::test]
+async fn test_buy_update_and_claim() {
+    let mut harness = Harness::start().await;
+    let wallet = harness.wallet.pubkey();
+    let rent = Rent::default();
+
+    // A sale that started long ago sells at the final phase rate without a
+    // whitelist.
+    harness.send_as_admin(harness.initialize_contract(0)).await.unwrap();
+    let config = harness.config().await;
+    assert!(config.is_initialized);
+    assert_eq!(config.admin, harness.admin.pubkey());
+    let treasury = harness.treasury_address();
+    let treasury_lamports = harness.lamports(treasury).await;
+    assert!(treasury_lamports >= rent.minimum_balance(0));
+
+    harness.send_as_wallet(harness.initialize_user()).await.unwrap();
+    let user_state = harness.user_state().await;
+    assert!(user_state.is_initialized);
+    assert_eq!((user_state.owner, user_state.beneficiary), (wallet, wallet));
+    let user_state_lamports = harness.lamports(harness.user_state_address()).await;
+    assert!(user_state_lamports >= rent.minimum_balance(UserState::LEN));
+    assert_eq!(harness.lamports(wallet).await, WALLET_LAMPORTS - user_state_lamports);
+
+    // Small enough that the full reward fits in the claimable Solheist supply.
+    let amount = 100_000;
+    harness.send_as_wallet(harness.buy(amount, DEFAULT_LOCK_TIER)).await.unwrap();
+    let bought = amount * PHASE_RATES[4] / 100;
+    assert_eq!(harness.lamports(wallet).await, WALLET_LAMPORTS - user_state_lamports - amount);
+    assert_eq!(harness.lamports(treasury).await, treasury_lamports + amount);
+    let user_state = harness.user_state().await;
+    assert_eq!(user_state.locked_pledge_tokens, bought);
+    assert_eq!(user_state.solhit_rewards, 0);
+    assert_eq!(harness.config().await.total_pledge_sold, bought);
+
+    // Past the end of the lock the whole reward has accrued.
+    let (lock, multiplier_bps) = LOCK_TIERS[DEFAULT_LOCK_TIER as usize];
+    harness.advance_clock(lock + 1).await;
+    harness.send(harness.update_reward(), &[]).await.unwrap();
+    let full_reward = bought * REWARD_RATE_BPS * multiplier_bps / (BASIS_POINTS * BASIS_POINTS);
+    let user_state = harness.user_state().await;
+    assert_eq!(user_state.solhit_rewards, full_reward);
+    assert_eq!(user_state.last_reward_update, harness.now().await);
+
+    harness.send_as_wallet(harness.claim(0)).await.unwrap();
+    assert_eq!(harness.token_balance(harness.solhit_token).await, full_reward);
+    assert_eq!(harness.token_balance(harness.solhit_vault).await, VAULT_BALANCE - full_reward);
+    let user_state = harness.user_state().await;
+    assert_eq!(user_state.solhit_rewards, 0);
+    assert_eq!(user_state.total_claimed, full_reward);
+    assert_eq!(harness.config().await.global_solhit_claimed, full_reward);
+
+    // Nothing is left to claim, and the vested pledge tokens come out.
+    assert!(harness.send_as_wallet(harness.claim(0)).await.is_err());
+    harness.send_as_wallet(harness.unlock()).await.unwrap();
+    assert_eq!(harness.token_balance(harness.pledge_token).await, bought);
+    assert_eq!(harness.user_state().await.locked_pledge_tokens, 0);
+}
+
+#[tokio::test]
+async fn test_claim_part_way_through_the_lock() {
+    let mut harness = Harness::start().await;
+    harness.send_as_admin(harness.initialize_contract(0)).await.unwrap();
+    harness.send_as_wallet(harness.initialize_user()).await.unwrap();
+    harness.send_as_wallet(harness.buy(100_000, DEFAULT_LOCK_TIER)).await.unwrap();
+    let bought = harness.user_state().await.locked_pledge_tokens;
+
+    let (lock, _) = LOCK_TIERS[DEFAULT_LOCK_TIER as usize];
+    harness.advance_clock(lock / 4).await;
+    harness.send_as_wallet(harness.claim(100)).await.unwrap();
+    assert_eq!(harness.token_balance(harness.solhit_token).await, 100);
+    let user_state = harness.user_state().await;
+    assert_eq!(user_state.solhit_rewards, bought * REWARD_RATE_BPS / BASIS_POINTS / 4 - 100);
+
+    // The lock still holds the pledge tokens.
+    assert!(harness.send_as_wallet(harness.unlock()).await.is_err());
+    assert_eq!(harness.token_balance(harness.pledge_token).await, 0);
+}
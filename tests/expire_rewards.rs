@@ -0,0 +1,104 @@
+// ExpireRewards moving a user's unclaimed rewards into the config's
+// expired_rewards once reward_expiry_secs have passed since the vesting end.
+mod common;
+
+use common::Harness;
+use pledge::{PledgeError, PledgeInstruction, DEFAULT_LOCK_TIER};
+use solana_program::instruction::{AccountMeta, Instruction, InstructionError};
+use solana_program_test::tokio;
+use solana_sdk::{signature::Signer, transaction::TransactionError};
+
+const EXPIRY: u64 = 30 * 24 * 60 * 60;
+
+// A user with a locked purchase, rewards expiring EXPIRY seconds after the
+// vesting end and, with `permissionless`, anyone allowed to expire them.
+async fn setup(permissionless: bool) -> Harness {
+    let mut harness = Harness::start().await;
+    harness.send_as_admin(harness.initialize_contract(0)).await.unwrap();
+    harness.send_as_wallet(harness.initialize_user()).await.unwrap();
+    harness.send_as_wallet(harness.buy(100_000, DEFAULT_LOCK_TIER)).await.unwrap();
+    let set_reward_expiry = Instruction::new_with_bytes(
+        harness.program_id,
+        &PledgeInstruction::SetRewardExpiry { seconds: EXPIRY, permissionless }.pack(),
+        vec![AccountMeta::new(harness.config_address(), false), AccountMeta::new_readonly(harness.admin.pubkey(), true)],
+    );
+    harness.send_as_admin(set_reward_expiry).await.unwrap();
+    harness
+}
+
+// ExpireRewards over the user, signed by the admin unless `admin` is false.
+fn expire(harness: &Harness, admin: bool) -> Instruction {
+    let mut accounts =
+        vec![AccountMeta::new(harness.user_state_address(), false), AccountMeta::new(harness.config_address(), false)];
+    if admin {
+        accounts.push(AccountMeta::new_readonly(harness.admin.pubkey(), true));
+    }
+    Instruction::new_with_bytes(harness.program_id, &PledgeInstruction::ExpireRewards.pack(), accounts)
+}
+
+// Moves the clock to `seconds` after the user's rewards expire.
+async fn advance_past_expiry(harness: &mut Harness, seconds: i64) {
+    let expires_at = harness.user_state().await.vesting_end_time + EXPIRY;
+    let now = harness.now().await;
+    harness.advance_clock((expires_at as i64 + seconds - now as i64) as u64).await;
+}
+
+fn error(error: PledgeError) -> TransactionError {
+    TransactionError::InstructionError(0, InstructionError::Custom(error as u32))
+}
+
+// Expires the user's rewards as the admin and checks they all went into the
+// config's pool.
+async fn assert_expires(harness: &mut Harness) {
+    let admin = harness.admin.insecure_clone();
+    harness.send(harness.update_reward(), &[]).await.unwrap();
+    let rewards = harness.user_state().await.solhit_rewards;
+    assert!(rewards > 0);
+
+    harness.try_send(expire(harness, true), &[&admin]).await.unwrap();
+    assert_eq!(harness.user_state().await.solhit_rewards, 0);
+    assert_eq!(harness.config().await.expired_rewards, rewards);
+}
+
+#[tokio::test]
+async fn test_expire_rewards_at_deadline() {
+    let mut harness = setup(false).await;
+    advance_past_expiry(&mut harness, 0).await;
+    assert_expires(&mut harness).await;
+}
+
+#[tokio::test]
+async fn test_expire_rewards_well_after_deadline() {
+    let mut harness = setup(false).await;
+    advance_past_expiry(&mut harness, EXPIRY as i64 * 10).await;
+    assert_expires(&mut harness).await;
+}
+
+#[tokio::test]
+async fn test_expire_rewards_before_deadline_fails() {
+    let mut harness = setup(false).await;
+    let admin = harness.admin.insecure_clone();
+    advance_past_expiry(&mut harness, -1).await;
+    assert_eq!(harness.try_send(expire(&harness, true), &[&admin]).await, Err(error(PledgeError::NotExpired)));
+
+    // The user can still claim up to the deadline.
+    harness.send_as_wallet(harness.claim(0)).await.unwrap();
+    assert_eq!(harness.user_state().await.solhit_rewards, 0);
+    assert_eq!(harness.config().await.expired_rewards, 0);
+}
+
+#[tokio::test]
+async fn test_expire_rewards_requires_admin_unless_permissionless() {
+    let mut harness = setup(false).await;
+    advance_past_expiry(&mut harness, 0).await;
+    assert_eq!(
+        harness.try_send(expire(&harness, false), &[]).await,
+        Err(TransactionError::InstructionError(0, InstructionError::NotEnoughAccountKeys))
+    );
+
+    let mut harness = setup(true).await;
+    advance_past_expiry(&mut harness, 0).await;
+    harness.try_send(expire(&harness, false), &[]).await.unwrap();
+    assert_eq!(harness.user_state().await.solhit_rewards, 0);
+    assert!(harness.config().await.expired_rewards > 0);
+}
@@ -0,0 +1,75 @@
+// GetGlobalStats following the sale as users come and go, buy and claim.
+mod common;
+
+use borsh::BorshDeserialize;
+use common::Harness;
+use pledge::{find_user_state_address, GlobalStats, PledgeInstruction, DEFAULT_LOCK_TIER, LOCK_TIERS};
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    system_program,
+};
+use solana_program_test::tokio;
+use solana_sdk::signature::{Keypair, Signer};
+
+async fn stats(harness: &mut Harness) -> GlobalStats {
+    let get_global_stats = Instruction::new_with_bytes(
+        harness.program_id,
+        &PledgeInstruction::GetGlobalStats.pack(),
+        vec![AccountMeta::new_readonly(harness.config_address(), false)],
+    );
+    GlobalStats::try_from_slice(&harness.return_data(get_global_stats).await).unwrap()
+}
+
+// InitializeUser or CloseUserState for `wallet` rather than the harness wallet.
+fn for_wallet(harness: &Harness, instruction: PledgeInstruction, wallet: &Keypair) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(find_user_state_address(&harness.program_id, &wallet.pubkey()).0, false),
+        AccountMeta::new(wallet.pubkey(), true),
+    ];
+    if instruction == PledgeInstruction::InitializeUser {
+        accounts.push(AccountMeta::new_readonly(system_program::ID, false));
+    }
+    accounts.push(AccountMeta::new(harness.config_address(), false));
+    Instruction::new_with_bytes(harness.program_id, &instruction.pack(), accounts)
+}
+
+#[tokio::test]
+async fn test_global_stats_follow_the_sale() {
+    let mut harness = Harness::start().await;
+    harness.send_as_admin(harness.initialize_contract(0)).await.unwrap();
+    // The sale started at 0, so the open-ended last phase is running.
+    let expected = GlobalStats {
+        total_sold: 0,
+        total_claimed: 0,
+        active_phase: 4,
+        phase_time_remaining: u64::MAX,
+        initialized_users: 0,
+        paused: false,
+    };
+    assert_eq!(stats(&mut harness).await, expected);
+
+    harness.send_as_wallet(harness.initialize_user()).await.unwrap();
+    assert_eq!(stats(&mut harness).await, GlobalStats { initialized_users: 1, ..expected });
+
+    harness.send_as_wallet(harness.buy(100_000, DEFAULT_LOCK_TIER)).await.unwrap();
+    let total_sold = harness.config().await.total_pledge_sold;
+    assert!(total_sold > 0);
+    assert_eq!(stats(&mut harness).await, GlobalStats { total_sold, initialized_users: 1, ..expected });
+
+    let (lock, _) = LOCK_TIERS[DEFAULT_LOCK_TIER as usize];
+    harness.advance_clock(lock / 2).await;
+    harness.send_as_wallet(harness.claim(0)).await.unwrap();
+    let total_claimed = harness.token_balance(harness.solhit_token).await;
+    assert!(total_claimed > 0);
+    let expected = GlobalStats { total_sold, total_claimed, initialized_users: 1, ..expected };
+    assert_eq!(stats(&mut harness).await, expected);
+
+    // Wallets added before advance_clock break the bank's lamport total when
+    // it warps, so the second user only comes now.
+    let other = harness.add_wallet();
+    harness.send(for_wallet(&harness, PledgeInstruction::InitializeUser, &other), &[&other]).await.unwrap();
+    assert_eq!(stats(&mut harness).await, GlobalStats { initialized_users: 2, ..expected });
+
+    harness.send(for_wallet(&harness, PledgeInstruction::CloseUserState, &other), &[&other]).await.unwrap();
+    assert_eq!(stats(&mut harness).await, expected);
+}
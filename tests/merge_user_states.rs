@@ -0,0 +1,97 @@
+// MergeUserStates folding a second user state of the same owner, left at an
+// arbitrary address from before user states were PDAs, into the owner's PDA.
+mod common;
+
+use common::Harness;
+use pledge::{PledgeError, PledgeInstruction, UserState, DEFAULT_LOCK_TIER};
+use solana_program::{
+    instruction::{AccountMeta, Instruction, InstructionError},
+    pubkey::Pubkey,
+    rent::Rent,
+};
+use solana_program_test::tokio;
+use solana_sdk::{account::Account, signature::Signer, transaction::TransactionError};
+
+fn merge(harness: &Harness, duplicate: Pubkey) -> Instruction {
+    Instruction::new_with_bytes(
+        harness.program_id,
+        &PledgeInstruction::MergeUserStates.pack(),
+        vec![
+            AccountMeta::new(harness.user_state_address(), false),
+            AccountMeta::new(duplicate, false),
+            AccountMeta::new(harness.wallet.pubkey(), true),
+            AccountMeta::new_readonly(harness.config_address(), false),
+        ],
+    )
+}
+
+// A user with one purchase in its PDA, and a copy of that state owned by
+// `owner` planted at a fresh address, as a pre-PDA account would be.
+async fn setup(owner: Option<Pubkey>) -> (Harness, Pubkey) {
+    let mut harness = Harness::start().await;
+    harness.send_as_admin(harness.initialize_contract(0)).await.unwrap();
+    harness.send_as_wallet(harness.initialize_user()).await.unwrap();
+    harness.send_as_wallet(harness.buy(100_000, DEFAULT_LOCK_TIER)).await.unwrap();
+
+    let mut legacy = harness.user_state().await;
+    legacy.owner = owner.unwrap_or(harness.wallet.pubkey());
+    legacy.bump = 0;
+    let mut data = vec![0; UserState::LEN];
+    legacy.pack_into_slice(&mut data).unwrap();
+    let duplicate = Pubkey::new_unique();
+    let account = Account {
+        lamports: Rent::default().minimum_balance(data.len()),
+        data,
+        owner: harness.program_id,
+        executable: false,
+        rent_epoch: 0,
+    };
+    harness.context.set_account(&duplicate, &account.into());
+    (harness, duplicate)
+}
+
+fn error(error: PledgeError) -> TransactionError {
+    TransactionError::InstructionError(0, InstructionError::Custom(error as u32))
+}
+
+#[tokio::test]
+async fn test_merge_user_states() {
+    let (mut harness, duplicate) = setup(None).await;
+    let before = harness.user_state().await;
+    let users = harness.config().await.initialized_users;
+    let rent = harness.lamports(duplicate).await;
+    let wallet_lamports = harness.lamports(harness.wallet.pubkey()).await;
+    harness.send_as_wallet(merge(&harness, duplicate)).await.unwrap();
+
+    let merged = harness.user_state().await;
+    assert_eq!(merged.locked_pledge_tokens, 2 * before.locked_pledge_tokens);
+    assert_eq!(merged.total_purchased, 2 * before.total_purchased);
+    assert_eq!(merged.purchase_count, 2);
+    assert_eq!(merged.lot_count, 2);
+    assert_eq!(merged.owner, harness.wallet.pubkey());
+    assert_eq!(merged.bump, before.bump);
+    // Only the PDA was ever counted.
+    assert_eq!(harness.config().await.initialized_users, users);
+
+    // The second account is gone and its rent back with the wallet.
+    assert_eq!(harness.context.banks_client.get_account(duplicate).await.unwrap(), None);
+    assert_eq!(harness.lamports(harness.wallet.pubkey()).await, wallet_lamports + rent);
+    let wallet = harness.wallet.insecure_clone();
+    let again = harness.try_send(merge(&harness, duplicate), &[&wallet]).await;
+    assert_eq!(again, Err(TransactionError::InstructionError(0, InstructionError::IncorrectProgramId)));
+    assert_eq!(harness.user_state().await.locked_pledge_tokens, merged.locked_pledge_tokens);
+}
+
+#[tokio::test]
+async fn test_merge_user_states_of_another_owner_fails() {
+    let (mut harness, duplicate) = setup(Some(Pubkey::new_unique())).await;
+    let wallet = harness.wallet.insecure_clone();
+    let result = harness.try_send(merge(&harness, duplicate), &[&wallet]).await;
+    assert_eq!(result, Err(TransactionError::InstructionError(0, InstructionError::InvalidSeeds)));
+
+    // Nor may the PDA be merged into itself.
+    let itself = harness.try_send(merge(&harness, harness.user_state_address()), &[&wallet]).await;
+    assert_eq!(itself, Err(error(PledgeError::DuplicateAccount)));
+    let account = harness.context.banks_client.get_account(duplicate).await.unwrap().unwrap();
+    assert_eq!(account.owner, harness.program_id);
+}
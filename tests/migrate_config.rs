@@ -0,0 +1,508 @@
+use pledge::{
+    find_config_address, process_instruction, ContractConfig, PledgeError, PledgeInstruction, TeamLock,
+    CONFIG_DISCRIMINATOR, CONFIG_VERSION, USERS_UNCOUNTED,
+};
+use solana_program::{
+    instruction::{AccountMeta, Instruction, InstructionError},
+    pubkey::Pubkey,
+    rent::Rent,
+    system_program,
+};
+use solana_program_test::{processor, tokio, ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    account::Account,
+    signature::{Keypair, Signer},
+    transaction::{Transaction, TransactionError},
+};
+
+// The config a version 1 program left behind, as the current struct reads it.
+fn v1_config(admin: Pubkey, bump: u8, pending_admin: Option<Pubkey>) -> ContractConfig {
+    ContractConfig {
+        is_initialized: true,
+        total_pledge_sold: 1_234,
+        sale_start_time: 1_700_000_000,
+        bump,
+        admin,
+        pending_admin,
+        event_seq: 42,
+        team_lock: TeamLock { unlock_start: 1_700_000_000, ..ContractConfig::new().team_lock },
+        post_vesting_grace_secs: 600,
+        initialized_users: 3,
+        ..ContractConfig::new()
+    }
+}
+
+// The fields of `config` version 1 has, written out one by one.
+fn v1_fields(config: &ContractConfig) -> Vec<u8> {
+    let u64s = |data: &mut Vec<u8>, values: &[u64]| values.iter().for_each(|value| data.extend(value.to_le_bytes()));
+    let mut data = Vec::new();
+    data.push(config.is_initialized as u8);
+    u64s(
+        &mut data,
+        &[
+            config.total_pledge_supply,
+            config.solhit_token_supply,
+            config.locked_solhit_tokens,
+            config.vesting_period,
+            config.reward_rate_bps,
+        ],
+    );
+    u64s(&mut data, &config.phase_durations);
+    u64s(&mut data, &config.phase_rates);
+    u64s(
+        &mut data,
+        &[
+            config.total_pledge_sold,
+            config.sale_start_time,
+            config.early_exit_penalty_percent,
+            config.total_penalties,
+            config.global_solhit_claimed,
+        ],
+    );
+    data.push(config.bump);
+    u64s(&mut data, &config.phase_caps);
+    u64s(&mut data, &config.phase_sold);
+    u64s(&mut data, &[config.max_per_user]);
+    data.extend(config.admin.to_bytes());
+    data.push(config.paused as u8);
+    match config.pending_admin {
+        Some(pending_admin) => {
+            data.push(1);
+            data.extend(pending_admin.to_bytes());
+        }
+        None => data.push(0),
+    }
+    u64s(&mut data, &[config.total_withdrawn]);
+    data.extend(config.payment_mint.to_bytes());
+    u64s(&mut data, &[config.purchase_cooldown_secs, config.referral_bonus_percent]);
+    for (duration, multiplier_bps) in config.lock_tiers {
+        u64s(&mut data, &[duration, multiplier_bps]);
+    }
+    u64s(&mut data, &[config.compound_rate, config.compound_vesting_boost, config.event_seq]);
+    data.push(config.sale_finalized as u8);
+    let team_lock = &config.team_lock;
+    u64s(
+        &mut data,
+        &[
+            config.unsold_supply,
+            config.last_snapshot_time,
+            config.snapshot_interval_secs,
+            team_lock.locked_amount,
+            team_lock.unlock_start,
+            team_lock.unlock_cliff,
+            team_lock.unlock_duration,
+            config.team_released,
+            config.post_vesting_grace_secs,
+        ],
+    );
+    data
+}
+
+// `config` in the version 1 layout, in an account of LEN_V1 bytes. Whatever
+// follows an absent pending admin must not pass for a version.
+fn v1_bytes(config: &ContractConfig) -> Vec<u8> {
+    let mut data = v1_fields(config);
+    data.resize(ContractConfig::LEN_V1, 0xff);
+    data
+}
+
+// `config` in the version 2 layout: version 1 and config_version, in an
+// account of LEN_V2 bytes. Whatever follows must not pass for rewards_funded.
+fn v2_bytes(config: &ContractConfig) -> Vec<u8> {
+    let mut data = v1_fields(config);
+    data.extend(2u16.to_le_bytes());
+    data.resize(ContractConfig::LEN_V2, 0xff);
+    data
+}
+
+// `config` in the version 3 layout: version 2 and rewards_funded, in an
+// account of LEN_V3 bytes. Whatever follows must not pass for
+// enforce_group_cap.
+fn v3_bytes(config: &ContractConfig) -> Vec<u8> {
+    let mut data = v1_fields(config);
+    data.extend(3u16.to_le_bytes());
+    data.extend(config.rewards_funded.to_le_bytes());
+    data.resize(ContractConfig::LEN_V3, 0xff);
+    data
+}
+
+// `config` in the version 4 layout: version 3 and enforce_group_cap, in an
+// account of LEN_V4 bytes, without the discriminator. Whatever follows must
+// not pass for it.
+fn v4_bytes(config: &ContractConfig) -> Vec<u8> {
+    let mut data = v1_fields(config);
+    data.extend(4u16.to_le_bytes());
+    data.extend(config.rewards_funded.to_le_bytes());
+    data.push(config.enforce_group_cap as u8);
+    data.resize(ContractConfig::LEN_V4, 0xff);
+    data
+}
+
+// `config` in the version 5 layout: the discriminator and version 4, in an
+// account of LEN_V5 bytes. Whatever follows must not pass for the crank
+// bounty.
+fn v5_bytes(config: &ContractConfig) -> Vec<u8> {
+    let mut data = CONFIG_DISCRIMINATOR.to_vec();
+    data.extend(v1_fields(config));
+    data.extend(5u16.to_le_bytes());
+    data.extend(config.rewards_funded.to_le_bytes());
+    data.push(config.enforce_group_cap as u8);
+    data.resize(ContractConfig::LEN_V5, 0xff);
+    data
+}
+
+// `config` in the version 6 layout: version 5 and the crank bounty, in an
+// account of LEN_V6 bytes. Whatever follows must not pass for reward expiry.
+fn v6_bytes(config: &ContractConfig) -> Vec<u8> {
+    let mut data = CONFIG_DISCRIMINATOR.to_vec();
+    data.extend(v1_fields(config));
+    data.extend(6u16.to_le_bytes());
+    data.extend(config.rewards_funded.to_le_bytes());
+    data.push(config.enforce_group_cap as u8);
+    for value in [config.crank_fee, config.crank_budget, config.crank_bounties_paid] {
+        data.extend(value.to_le_bytes());
+    }
+    data.resize(ContractConfig::LEN_V6, 0xff);
+    data
+}
+
+// `config` in the version 7 layout: version 6 and reward expiry, in an
+// account of LEN_V7 bytes. Whatever follows must not pass for the user count.
+fn v7_bytes(config: &ContractConfig) -> Vec<u8> {
+    let mut data = CONFIG_DISCRIMINATOR.to_vec();
+    data.extend(v1_fields(config));
+    data.extend(7u16.to_le_bytes());
+    data.extend(config.rewards_funded.to_le_bytes());
+    data.push(config.enforce_group_cap as u8);
+    for value in [config.crank_fee, config.crank_budget, config.crank_bounties_paid, config.reward_expiry_secs] {
+        data.extend(value.to_le_bytes());
+    }
+    data.push(config.expire_permissionless as u8);
+    data.extend(config.expired_rewards.to_le_bytes());
+    data.resize(ContractConfig::LEN_V7, 0xff);
+    data
+}
+
+// `config` in the version 8 layout: version 7 and the user count, in an
+// account of LEN_V8 bytes. Whatever follows must not pass for the claim fee.
+fn v8_bytes(config: &ContractConfig) -> Vec<u8> {
+    let mut data = CONFIG_DISCRIMINATOR.to_vec();
+    data.extend(v1_fields(config));
+    data.extend(8u16.to_le_bytes());
+    data.extend(config.rewards_funded.to_le_bytes());
+    data.push(config.enforce_group_cap as u8);
+    for value in [config.crank_fee, config.crank_budget, config.crank_bounties_paid, config.reward_expiry_secs] {
+        data.extend(value.to_le_bytes());
+    }
+    data.push(config.expire_permissionless as u8);
+    data.extend(config.expired_rewards.to_le_bytes());
+    data.extend(config.initialized_users.to_le_bytes());
+    data.resize(ContractConfig::LEN_V8, 0xff);
+    data
+}
+
+// `config` in the version 9 layout: version 8 and the claim fee, in an
+// account of LEN_V9 bytes. Whatever follows must not pass for the decimals.
+fn v9_bytes(config: &ContractConfig) -> Vec<u8> {
+    let mut data = CONFIG_DISCRIMINATOR.to_vec();
+    data.extend(v1_fields(config));
+    data.extend(9u16.to_le_bytes());
+    data.extend(config.rewards_funded.to_le_bytes());
+    data.push(config.enforce_group_cap as u8);
+    for value in [config.crank_fee, config.crank_budget, config.crank_bounties_paid, config.reward_expiry_secs] {
+        data.extend(value.to_le_bytes());
+    }
+    data.push(config.expire_permissionless as u8);
+    data.extend(config.expired_rewards.to_le_bytes());
+    data.extend(config.initialized_users.to_le_bytes());
+    data.extend(config.claim_fee_bps.to_le_bytes());
+    data.resize(ContractConfig::LEN_V9, 0xff);
+    data
+}
+
+// `config` in the version 10 layout: version 9 and the decimals, in an
+// account of LEN_V10 bytes. Whatever follows must not pass for the guardian.
+fn v10_bytes(config: &ContractConfig) -> Vec<u8> {
+    let mut data = CONFIG_DISCRIMINATOR.to_vec();
+    data.extend(v1_fields(config));
+    data.extend(10u16.to_le_bytes());
+    data.extend(config.rewards_funded.to_le_bytes());
+    data.push(config.enforce_group_cap as u8);
+    for value in [config.crank_fee, config.crank_budget, config.crank_bounties_paid, config.reward_expiry_secs] {
+        data.extend(value.to_le_bytes());
+    }
+    data.push(config.expire_permissionless as u8);
+    data.extend(config.expired_rewards.to_le_bytes());
+    data.extend(config.initialized_users.to_le_bytes());
+    data.extend(config.claim_fee_bps.to_le_bytes());
+    data.extend([config.payment_decimals, config.pledge_decimals]);
+    data.resize(ContractConfig::LEN_V10, 0xff);
+    data
+}
+
+// `config` in the version 11 layout: version 10 and the guardian with its
+// recovery destination, in an account of LEN_V11 bytes. Whatever follows
+// must not pass for noop_events.
+fn v11_bytes(config: &ContractConfig) -> Vec<u8> {
+    let mut data = CONFIG_DISCRIMINATOR.to_vec();
+    data.extend(v1_fields(config));
+    data.extend(11u16.to_le_bytes());
+    data.extend(config.rewards_funded.to_le_bytes());
+    data.push(config.enforce_group_cap as u8);
+    for value in [config.crank_fee, config.crank_budget, config.crank_bounties_paid, config.reward_expiry_secs] {
+        data.extend(value.to_le_bytes());
+    }
+    data.push(config.expire_permissionless as u8);
+    data.extend(config.expired_rewards.to_le_bytes());
+    data.extend(config.initialized_users.to_le_bytes());
+    data.extend(config.claim_fee_bps.to_le_bytes());
+    data.extend([config.payment_decimals, config.pledge_decimals]);
+    data.extend(config.guardian.to_bytes());
+    data.extend(config.recovery_destination.to_bytes());
+    data.resize(ContractConfig::LEN_V11, 0xff);
+    data
+}
+
+struct MigrateSetup {
+    context: ProgramTestContext,
+    program_id: Pubkey,
+    admin: Keypair,
+    config: Pubkey,
+    expected: ContractConfig,
+}
+
+// A running test validator with a config laid out by `bytes` in the config
+// PDA, exactly rent exempt at its old size.
+async fn setup(bytes: fn(&ContractConfig) -> Vec<u8>, pending_admin: Option<Pubkey>) -> MigrateSetup {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new("pledge", program_id, processor!(process_instruction));
+
+    let admin = Keypair::new();
+    program_test.add_account(
+        admin.pubkey(),
+        Account { lamports: 1_000_000_000, data: vec![], owner: system_program::ID, executable: false, rent_epoch: 0 },
+    );
+    let (config, bump) = find_config_address(&program_id);
+    let expected = v1_config(admin.pubkey(), bump, pending_admin);
+    let data = bytes(&expected);
+    let data_len = data.len();
+    program_test.add_account(
+        config,
+        Account {
+            lamports: Rent::default().minimum_balance(data.len()),
+            data,
+            owner: program_id,
+            executable: false,
+            rent_epoch: 0,
+        },
+    );
+
+    let context = program_test.start_with_context().await;
+    let mut expected = ContractConfig { config_version: CONFIG_VERSION, ..expected };
+    // Layouts from before version 8 never counted users.
+    if data_len < ContractConfig::LEN_V8 {
+        expected.initialized_users = USERS_UNCOUNTED;
+    }
+    MigrateSetup { context, program_id, admin, config, expected }
+}
+
+impl MigrateSetup {
+    fn migrate(&self, admin: Pubkey) -> Instruction {
+        Instruction::new_with_bytes(
+            self.program_id,
+            &PledgeInstruction::MigrateConfig.pack(),
+            vec![
+                AccountMeta::new(self.config, false),
+                AccountMeta::new(admin, true),
+                AccountMeta::new_readonly(system_program::ID, false),
+            ],
+        )
+    }
+
+    fn set_paused(&self, paused: bool) -> Instruction {
+        Instruction::new_with_bytes(
+            self.program_id,
+            &PledgeInstruction::SetPaused { paused }.pack(),
+            vec![AccountMeta::new(self.config, false), AccountMeta::new_readonly(self.admin.pubkey(), true)],
+        )
+    }
+
+    async fn send(&mut self, instruction: Instruction, signer: &Keypair) -> Result<(), TransactionError> {
+        let blockhash = self.context.banks_client.get_latest_blockhash().await.unwrap();
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&self.context.payer.pubkey()));
+        transaction.sign(&[&self.context.payer, signer], blockhash);
+        self.context.banks_client.process_transaction(transaction).await.map_err(|error| error.unwrap())
+    }
+
+    async fn config_account(&mut self) -> Account {
+        self.context.banks_client.get_account(self.config).await.unwrap().unwrap()
+    }
+}
+
+fn version_mismatch() -> TransactionError {
+    TransactionError::InstructionError(0, InstructionError::Custom(PledgeError::ConfigVersionMismatch as u32))
+}
+
+async fn assert_migrates(mut setup: MigrateSetup) {
+    let admin = setup.admin.insecure_clone();
+    assert_eq!(setup.send(setup.set_paused(false), &admin).await, Err(version_mismatch()));
+
+    setup.send(setup.migrate(admin.pubkey()), &admin).await.unwrap();
+    let account = setup.config_account().await;
+    assert_eq!(account.data.len(), ContractConfig::LEN);
+    assert!(account.lamports >= Rent::default().minimum_balance(ContractConfig::LEN));
+    let migrated = ContractConfig::unpack(&account.data).unwrap();
+    assert_eq!(borsh::to_vec(&migrated).unwrap(), borsh::to_vec(&setup.expected).unwrap());
+
+    // Every instruction reads it again.
+    setup.send(setup.set_paused(true), &admin).await.unwrap();
+    assert!(ContractConfig::unpack(&setup.config_account().await.data).unwrap().paused);
+}
+
+#[tokio::test]
+async fn test_migrate_config_upgrades_version_1() {
+    assert_migrates(setup(v1_bytes, None).await).await;
+}
+
+#[tokio::test]
+async fn test_migrate_config_upgrades_version_1_with_pending_admin() {
+    assert_migrates(setup(v1_bytes, Some(Pubkey::new_unique())).await).await;
+}
+
+#[tokio::test]
+async fn test_migrate_config_upgrades_version_2() {
+    assert_migrates(setup(v2_bytes, None).await).await;
+}
+
+#[tokio::test]
+async fn test_migrate_config_upgrades_version_2_with_pending_admin() {
+    assert_migrates(setup(v2_bytes, Some(Pubkey::new_unique())).await).await;
+}
+
+#[tokio::test]
+async fn test_migrate_config_upgrades_version_3() {
+    assert_migrates(setup(v3_bytes, None).await).await;
+}
+
+#[tokio::test]
+async fn test_migrate_config_upgrades_version_3_with_pending_admin() {
+    assert_migrates(setup(v3_bytes, Some(Pubkey::new_unique())).await).await;
+}
+
+#[tokio::test]
+async fn test_migrate_config_upgrades_version_4() {
+    assert_migrates(setup(v4_bytes, None).await).await;
+}
+
+#[tokio::test]
+async fn test_migrate_config_upgrades_version_4_with_pending_admin() {
+    assert_migrates(setup(v4_bytes, Some(Pubkey::new_unique())).await).await;
+}
+
+#[tokio::test]
+async fn test_migrate_config_upgrades_version_5() {
+    assert_migrates(setup(v5_bytes, None).await).await;
+}
+
+#[tokio::test]
+async fn test_migrate_config_upgrades_version_5_with_pending_admin() {
+    assert_migrates(setup(v5_bytes, Some(Pubkey::new_unique())).await).await;
+}
+
+#[tokio::test]
+async fn test_migrate_config_upgrades_version_6() {
+    assert_migrates(setup(v6_bytes, None).await).await;
+}
+
+#[tokio::test]
+async fn test_migrate_config_upgrades_version_6_with_pending_admin() {
+    assert_migrates(setup(v6_bytes, Some(Pubkey::new_unique())).await).await;
+}
+
+#[tokio::test]
+async fn test_migrate_config_upgrades_version_7() {
+    assert_migrates(setup(v7_bytes, None).await).await;
+}
+
+#[tokio::test]
+async fn test_migrate_config_upgrades_version_7_with_pending_admin() {
+    assert_migrates(setup(v7_bytes, Some(Pubkey::new_unique())).await).await;
+}
+
+#[tokio::test]
+async fn test_migrate_config_upgrades_version_8() {
+    assert_migrates(setup(v8_bytes, None).await).await;
+}
+
+#[tokio::test]
+async fn test_migrate_config_upgrades_version_8_with_pending_admin() {
+    assert_migrates(setup(v8_bytes, Some(Pubkey::new_unique())).await).await;
+}
+
+#[tokio::test]
+async fn test_migrate_config_upgrades_version_9() {
+    assert_migrates(setup(v9_bytes, None).await).await;
+}
+
+#[tokio::test]
+async fn test_migrate_config_upgrades_version_9_with_pending_admin() {
+    assert_migrates(setup(v9_bytes, Some(Pubkey::new_unique())).await).await;
+}
+
+#[tokio::test]
+async fn test_migrate_config_upgrades_version_10() {
+    assert_migrates(setup(v10_bytes, None).await).await;
+}
+
+#[tokio::test]
+async fn test_migrate_config_upgrades_version_10_with_pending_admin() {
+    assert_migrates(setup(v10_bytes, Some(Pubkey::new_unique())).await).await;
+}
+
+#[tokio::test]
+async fn test_migrate_config_upgrades_version_11() {
+    assert_migrates(setup(v11_bytes, None).await).await;
+}
+
+#[tokio::test]
+async fn test_migrate_config_upgrades_version_11_with_pending_admin() {
+    assert_migrates(setup(v11_bytes, Some(Pubkey::new_unique())).await).await;
+}
+
+#[tokio::test]
+async fn test_migrate_config_requires_admin() {
+    let mut setup = setup(v1_bytes, None).await;
+    let stranger = Keypair::new();
+    let error = setup.send(setup.migrate(stranger.pubkey()), &stranger).await.unwrap_err();
+    assert_eq!(error, TransactionError::InstructionError(0, InstructionError::Custom(PledgeError::Unauthorized as u32)));
+    assert_eq!(setup.config_account().await.data.len(), ContractConfig::LEN_V1);
+}
+
+#[test]
+fn test_old_layouts_are_prefixes_of_current() {
+    let config = v1_config(Pubkey::new_unique(), 255, Some(Pubkey::new_unique()));
+    let current = borsh::to_vec(&ContractConfig { config_version: 2, ..config }).unwrap();
+    assert_eq!(current.len(), ContractConfig::LEN);
+    assert_eq!(current[..8], CONFIG_DISCRIMINATOR);
+    let current = &current[8..];
+    assert_eq!(v1_bytes(&config), current[..ContractConfig::LEN_V1]);
+    assert_eq!(v2_bytes(&config), current[..ContractConfig::LEN_V2]);
+    let current = borsh::to_vec(&ContractConfig { config_version: 3, ..config }).unwrap();
+    assert_eq!(v3_bytes(&config), current[8..8 + ContractConfig::LEN_V3]);
+    let current = borsh::to_vec(&ContractConfig { config_version: 4, ..config }).unwrap();
+    assert_eq!(v4_bytes(&config), current[8..ContractConfig::LEN_V5]);
+    let current = borsh::to_vec(&ContractConfig { config_version: 5, ..config }).unwrap();
+    assert_eq!(v5_bytes(&config), current[..ContractConfig::LEN_V5]);
+    let current = borsh::to_vec(&ContractConfig { config_version: 6, ..config }).unwrap();
+    assert_eq!(v6_bytes(&config), current[..ContractConfig::LEN_V6]);
+    let current = borsh::to_vec(&ContractConfig { config_version: 7, ..config }).unwrap();
+    assert_eq!(v7_bytes(&config), current[..ContractConfig::LEN_V7]);
+    let current = borsh::to_vec(&ContractConfig { config_version: 8, ..config }).unwrap();
+    assert_eq!(v8_bytes(&config), current[..ContractConfig::LEN_V8]);
+    let current = borsh::to_vec(&ContractConfig { config_version: 9, ..config }).unwrap();
+    assert_eq!(v9_bytes(&config), current[..ContractConfig::LEN_V9]);
+    let current = borsh::to_vec(&ContractConfig { config_version: 10, ..config }).unwrap();
+    assert_eq!(v10_bytes(&config), current[..ContractConfig::LEN_V10]);
+    let current = borsh::to_vec(&ContractConfig { config_version: 11, ..config }).unwrap();
+    assert_eq!(v11_bytes(&config), current[..ContractConfig::LEN_V11]);
+}
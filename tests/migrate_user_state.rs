@@ -0,0 +1,265 @@
+use borsh::BorshDeserialize;
+use pledge::{
+    find_user_state_address, process_instruction, PledgeError, PledgeInstruction, PledgeLot, UserState, BASIS_POINTS,
+    MAX_PLEDGE_LOTS, USER_STATE_DISCRIMINATOR, USER_STATE_VERSION,
+};
+use solana_program::{
+    instruction::{AccountMeta, Instruction, InstructionError},
+    pubkey::Pubkey,
+    rent::Rent,
+    system_program,
+};
+use solana_program_test::{processor, tokio, ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    account::Account,
+    signature::{Keypair, Signer},
+    transaction::{Transaction, TransactionError},
+};
+
+// `state` laid out as version 8: the current layout without the trailing
+// hold_until.
+fn v8_bytes(state: &UserState) -> Vec<u8> {
+    let mut data = borsh::to_vec(state).unwrap();
+    data.truncate(UserState::LEN_V8);
+    data[USER_STATE_DISCRIMINATOR.len()] = 8;
+    data
+}
+
+// `state` laid out as version 7: version 8 without the leading discriminator.
+fn v7_bytes(state: &UserState) -> Vec<u8> {
+    let mut data = v8_bytes(state)[USER_STATE_DISCRIMINATOR.len()..].to_vec();
+    data[0] = 7;
+    data
+}
+
+// `state` laid out as version 6: version 7 without the trailing group.
+fn v6_bytes(state: &UserState) -> Vec<u8> {
+    let mut data = v7_bytes(state);
+    data.truncate(UserState::LEN_V6);
+    data[0] = 6;
+    data
+}
+
+// `state` laid out as version 5: version 6 without the trailing delegate.
+fn v5_bytes(state: &UserState) -> Vec<u8> {
+    let mut data = v6_bytes(state);
+    data.truncate(UserState::LEN_V5);
+    data[0] = 5;
+    data
+}
+
+// `state` laid out as version 4: version 5 without the trailing beneficiary.
+fn v4_bytes(state: &UserState) -> Vec<u8> {
+    let mut data = v5_bytes(state);
+    data.truncate(UserState::LEN_V4);
+    data[0] = 4;
+    data
+}
+
+// `state` laid out as version 3: version 4 with lots lacking their trailing
+// multiplier_bps.
+fn v3_bytes(state: &UserState) -> Vec<u8> {
+    let data = v4_bytes(state);
+    let lots = UserState::LEN_V4 - PledgeLot::LEN * MAX_PLEDGE_LOTS;
+    let mut v3 = data[..lots].to_vec();
+    for lot in data[lots..].chunks_exact(PledgeLot::LEN) {
+        v3.extend_from_slice(&lot[..PledgeLot::LEN_V3]);
+    }
+    v3[0] = 3;
+    assert_eq!(v3.len(), UserState::LEN_V3);
+    v3
+}
+
+// `state` laid out as version 2: version 3 without purchase_count and
+// last_activity, which follow the seven u64 fields.
+fn v2_bytes(state: &UserState) -> Vec<u8> {
+    let mut data = v3_bytes(state);
+    data.drain(2 + 8 * 7..2 + 8 * 7 + 4 + 8);
+    data[0] = 2;
+    assert_eq!(data.len(), UserState::LEN_V2);
+    data
+}
+
+// A running test validator with a user state account holding `data` sized
+// to fit it, and the funded wallet owning it.
+async fn start(data: impl FnOnce(&UserState) -> Vec<u8>) -> (ProgramTestContext, Pubkey, Keypair, Pubkey) {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new("pledge", program_id, processor!(process_instruction));
+
+    let wallet = Keypair::new();
+    program_test.add_account(
+        wallet.pubkey(),
+        Account { lamports: 1_000_000_000, data: vec![], owner: system_program::ID, executable: false, rent_epoch: 0 },
+    );
+    let (user_state, bump) = find_user_state_address(&program_id, &wallet.pubkey());
+    let mut lots = [PledgeLot::default(); MAX_PLEDGE_LOTS];
+    lots[0] =
+        PledgeLot { amount: 1_000, rate: 100, lock_start_time: 0, vesting_end_time: u64::MAX, multiplier_bps: BASIS_POINTS };
+    let state = UserState {
+        is_initialized: true,
+        locked_pledge_tokens: 1_000,
+        vesting_end_time: u64::MAX,
+        solhit_rewards: 77,
+        owner: wallet.pubkey(),
+        bump,
+        lot_count: 1,
+        lots,
+        beneficiary: wallet.pubkey(),
+        ..UserState::default()
+    };
+    let data = data(&state);
+    let rent = Rent::default();
+    program_test.add_account(
+        user_state,
+        Account {
+            lamports: rent.minimum_balance(data.len()),
+            data,
+            owner: program_id,
+            executable: false,
+            rent_epoch: 0,
+        },
+    );
+
+    (program_test.start_with_context().await, program_id, wallet, user_state)
+}
+
+// Migrates an account holding `data` sized to fit it, and returns the
+// account afterwards.
+async fn migrate(data: impl FnOnce(&UserState) -> Vec<u8>) -> (Account, Pubkey) {
+    let (mut context, program_id, wallet, user_state) = start(data).await;
+    let migrate = Instruction::new_with_bytes(
+        program_id,
+        &PledgeInstruction::MigrateUserState.pack(),
+        vec![
+            AccountMeta::new(user_state, false),
+            AccountMeta::new(context.payer.pubkey(), true),
+            AccountMeta::new_readonly(system_program::ID, false),
+        ],
+    );
+    let mut transaction = Transaction::new_with_payer(&[migrate], Some(&context.payer.pubkey()));
+    transaction.sign(&[&context.payer], context.last_blockhash);
+    context.banks_client.process_transaction(transaction).await.unwrap();
+
+    let account = context.banks_client.get_account(user_state).await.unwrap().unwrap();
+    (account, wallet.pubkey())
+}
+
+fn assert_migrated(account: &Account, wallet: Pubkey) {
+    assert_eq!(account.data.len(), UserState::LEN);
+    assert_eq!(account.data[..8], USER_STATE_DISCRIMINATOR);
+    assert_eq!(account.data[8], USER_STATE_VERSION);
+    assert!(account.lamports >= Rent::default().minimum_balance(UserState::LEN));
+    let migrated = UserState::try_from_slice(&account.data).unwrap();
+    assert_eq!(migrated.locked_pledge_tokens, 1_000);
+    assert_eq!(migrated.solhit_rewards, 77);
+    assert_eq!(migrated.owner, wallet);
+    assert_eq!(migrated.beneficiary, wallet);
+}
+
+#[tokio::test]
+async fn test_migrate_user_state_grows_version_1_account() {
+    let (account, wallet) = migrate(v1_bytes).await;
+    assert_migrated(&account, wallet);
+}
+
+#[tokio::test]
+async fn test_migrate_user_state_grows_version_2_account() {
+    let (account, wallet) = migrate(v2_bytes).await;
+    assert_migrated(&account, wallet);
+}
+
+#[tokio::test]
+async fn test_migrate_user_state_grows_version_3_account() {
+    let (account, wallet) = migrate(v3_bytes).await;
+    assert_migrated(&account, wallet);
+}
+
+#[tokio::test]
+async fn test_migrate_user_state_grows_version_4_account() {
+    let (account, wallet) = migrate(v4_bytes).await;
+    assert_migrated(&account, wallet);
+}
+
+#[tokio::test]
+async fn test_migrate_user_state_grows_version_5_account() {
+    let (account, wallet) = migrate(v5_bytes).await;
+    assert_migrated(&account, wallet);
+    assert_eq!(UserState::try_from_slice(&account.data).unwrap().delegate, None);
+}
+
+#[tokio::test]
+async fn test_migrate_user_state_grows_version_6_account() {
+    let (account, wallet) = migrate(v6_bytes).await;
+    assert_migrated(&account, wallet);
+    assert_eq!(UserState::try_from_slice(&account.data).unwrap().group, None);
+}
+
+#[tokio::test]
+async fn test_migrate_user_state_grows_version_7_account() {
+    let (account, wallet) = migrate(v7_bytes).await;
+    assert_migrated(&account, wallet);
+}
+
+#[tokio::test]
+async fn test_migrate_user_state_grows_version_8_account() {
+    let (account, wallet) = migrate(v8_bytes).await;
+    assert_migrated(&account, wallet);
+    assert_eq!(UserState::try_from_slice(&account.data).unwrap().hold_until, 0);
+}
+
+// Version 1 is version 2 without the leading version byte.
+fn v1_bytes(state: &UserState) -> Vec<u8> {
+    v2_bytes(state)[1..].to_vec()
+}
+
+async fn send(context: &mut ProgramTestContext, instruction: Instruction, wallet: &Keypair) -> Result<(), TransactionError> {
+    let blockhash = context.banks_client.get_latest_blockhash().await.unwrap();
+    let mut transaction = Transaction::new_with_payer(&[instruction], Some(&context.payer.pubkey()));
+    transaction.sign(&[&context.payer, wallet], blockhash);
+    context.banks_client.process_transaction(transaction).await.map_err(|error| error.unwrap())
+}
+
+#[tokio::test]
+async fn test_resize_user_state_grows_version_1_account() {
+    let (mut context, program_id, wallet, user_state) = start(v1_bytes).await;
+    let resize = |new_len| {
+        Instruction::new_with_bytes(
+            program_id,
+            &PledgeInstruction::ResizeUserState { new_len }.pack(),
+            vec![
+                AccountMeta::new(user_state, false),
+                AccountMeta::new(wallet.pubkey(), true),
+                AccountMeta::new_readonly(system_program::ID, false),
+            ],
+        )
+    };
+
+    // Room to spare past the latest layout, paid for by the wallet.
+    let new_len = UserState::LEN + 64;
+    let lamports = context.banks_client.get_balance(wallet.pubkey()).await.unwrap();
+    send(&mut context, resize(new_len as u64), &wallet).await.unwrap();
+    let account = context.banks_client.get_account(user_state).await.unwrap().unwrap();
+    assert_eq!(account.data.len(), new_len);
+    assert_eq!(account.lamports, Rent::default().minimum_balance(new_len));
+    let paid = lamports - context.banks_client.get_balance(wallet.pubkey()).await.unwrap();
+    assert_eq!(paid, account.lamports - Rent::default().minimum_balance(UserState::LEN_V1));
+    assert_migrated(&Account { data: account.data[..UserState::LEN].to_vec(), ..account.clone() }, wallet.pubkey());
+    assert!(account.data[UserState::LEN..].iter().all(|&byte| byte == 0));
+
+    let shrink = send(&mut context, resize(UserState::LEN as u64), &wallet).await;
+    let error = InstructionError::Custom(PledgeError::AccountShrinkNotSupported as u32);
+    assert_eq!(shrink, Err(TransactionError::InstructionError(0, error)));
+
+    // The resized account works like any other.
+    let cold_wallet = Pubkey::new_unique();
+    let set_beneficiary = Instruction::new_with_bytes(
+        program_id,
+        &PledgeInstruction::SetBeneficiary { new: cold_wallet }.pack(),
+        vec![AccountMeta::new(user_state, false), AccountMeta::new_readonly(wallet.pubkey(), true)],
+    );
+    send(&mut context, set_beneficiary, &wallet).await.unwrap();
+    let account = context.banks_client.get_account(user_state).await.unwrap().unwrap();
+    assert_eq!(account.data.len(), new_len);
+    let resized = UserState::try_from_slice(&account.data[..UserState::LEN]).unwrap();
+    assert_eq!((resized.beneficiary, resized.locked_pledge_tokens), (cold_wallet, 1_000));
+}
@@ -0,0 +1,121 @@
+// The markers a profiling build logs around every handler. Native processors
+// are not metered, so here the compute unit lines only say so; on devnet they
+// read "Program consumption: <units> units remaining".
+mod common;
+
+use common::Harness;
+use pledge::{PledgeInstruction, DEFAULT_LOCK_TIER, PHASE_CAPS};
+use solana_program::{instruction::Instruction, pubkey::Pubkey};
+use solana_program_test::tokio;
+
+// One of every instruction.
+fn every_instruction() -> Vec<PledgeInstruction> {
+    vec![
+        PledgeInstruction::BuyPledge { amount: 1_000, tier: 0, referrer: None, allow_partial: false },
+        PledgeInstruction::UpdateReward,
+        PledgeInstruction::ViewRewards { soft: false },
+        PledgeInstruction::ClaimRewards { amount: 0 },
+        PledgeInstruction::InitializeUser,
+        PledgeInstruction::InitializeContract {
+            sale_start_time: 0,
+            phase_caps: PHASE_CAPS,
+            max_per_user: 0,
+            guardian: Pubkey::default(),
+            recovery_destination: Pubkey::default(),
+        },
+        PledgeInstruction::UnlockPledge,
+        PledgeInstruction::EarlyExit {},
+        PledgeInstruction::SetPaused { paused: true },
+        PledgeInstruction::UpdatePhaseConfig { durations: [1; 5], rates: [1; 5] },
+        PledgeInstruction::NominateAdmin { new_admin: Pubkey::new_unique() },
+        PledgeInstruction::AcceptAdmin,
+        PledgeInstruction::WithdrawTreasury { amount: 1 },
+        PledgeInstruction::BuyPledgeWithToken { amount: 1 },
+        PledgeInstruction::SetPaymentMint { payment_mint: Pubkey::new_unique() },
+        PledgeInstruction::MigrateUserState,
+        PledgeInstruction::UpdateRewardBatch,
+        PledgeInstruction::CloseUserState,
+        PledgeInstruction::SetPurchaseCooldown { seconds: 1 },
+        PledgeInstruction::SetReferralBonus { percent: 1 },
+        PledgeInstruction::AddToWhitelist { wallet: Pubkey::new_unique() },
+        PledgeInstruction::RemoveFromWhitelist { wallet: Pubkey::new_unique() },
+        PledgeInstruction::CompoundRewards,
+        PledgeInstruction::SetBeneficiary { new: Pubkey::new_unique() },
+        PledgeInstruction::GetVestingSchedule,
+        PledgeInstruction::FinalizeSale,
+        PledgeInstruction::RecordSnapshot,
+        PledgeInstruction::GetLatestSnapshot,
+        PledgeInstruction::SetSnapshotInterval { seconds: 1 },
+        PledgeInstruction::TransferPosition,
+        PledgeInstruction::SetDelegate { delegate: Pubkey::new_unique() },
+        PledgeInstruction::RevokeDelegate,
+        PledgeInstruction::ReleaseTeamTokens,
+        PledgeInstruction::QuoteBuy { amount: 1 },
+        PledgeInstruction::SetPostVestingGrace { seconds: 1 },
+        PledgeInstruction::MigrateConfig,
+        PledgeInstruction::FundRewards { amount: 1 },
+        PledgeInstruction::PreviewReward { soft: false },
+        PledgeInstruction::JoinGroup { group_id: Pubkey::new_unique() },
+        PledgeInstruction::SetGroupCapEnforced { enforced: true },
+        PledgeInstruction::ResizeUserState { new_len: 1 },
+        PledgeInstruction::SetCrankBounty { crank_fee: 1, crank_budget: 1 },
+        PledgeInstruction::SetRewardExpiry { seconds: 1, permissionless: true },
+        PledgeInstruction::ExpireRewards,
+        PledgeInstruction::GetGlobalStats,
+        PledgeInstruction::StartSale { start_time: None },
+        PledgeInstruction::RepairUserState { wallet: Pubkey::new_unique() },
+        PledgeInstruction::SetClaimFee { bps: 1 },
+        PledgeInstruction::SetTokenDecimals { payment_decimals: 9, pledge_decimals: 6 },
+        PledgeInstruction::MergeUserStates,
+        PledgeInstruction::EmergencySweep,
+        PledgeInstruction::SetNoOpEvents { enabled: true },
+        PledgeInstruction::SetComplianceHold { wallet: Pubkey::new_unique(), hold_until: 1 },
+    ]
+}
+
+fn is_compute_units(log: &str) -> bool {
+    log.starts_with("Program consumption: ") || log.contains("sol_log_compute_units")
+}
+
+// Asserts `logs` hold the start marker for `name` right before a compute
+// unit line, then later the end marker right before another, and nothing
+// after that but the program's own result lines.
+fn assert_profiled(logs: &[String], name: &str) {
+    let start = format!("Program log: Profile {} start", name);
+    let end = format!("Program log: Profile {} end", name);
+    let position = |marker: &str| {
+        logs.iter().position(|log| *log == marker).unwrap_or_else(|| panic!("no {:?} in {:#?}", marker, logs))
+    };
+    let (start, end) = (position(&start), position(&end));
+    assert!(start < end, "{:#?}", logs);
+    assert!(is_compute_units(&logs[start + 1]), "{:#?}", logs);
+    assert!(is_compute_units(&logs[end + 1]), "{:#?}", logs);
+    assert!(!logs[start + 2..end].iter().any(|log| log.starts_with("Program log: Profile ")), "{:#?}", logs);
+}
+
+#[tokio::test]
+async fn test_every_instruction_is_profiled() {
+    let mut harness = Harness::start().await;
+    // Without accounts every handler fails, but only after it ran.
+    for instruction in every_instruction() {
+        let name = instruction.name();
+        let logs = harness.logs(Instruction::new_with_bytes(harness.program_id, &instruction.pack(), vec![]), &[]).await;
+        assert_profiled(&logs, name);
+    }
+}
+
+#[tokio::test]
+async fn test_profiled_instructions_succeed() {
+    let mut harness = Harness::start().await;
+    let (admin, wallet) = (harness.admin.insecure_clone(), harness.wallet.insecure_clone());
+    let steps = [
+        (harness.initialize_contract(0), &admin, "InitializeContract"),
+        (harness.initialize_user(), &wallet, "InitializeUser"),
+        (harness.buy(1_000, DEFAULT_LOCK_TIER), &wallet, "BuyPledge"),
+    ];
+    for (instruction, signer, name) in steps {
+        assert_profiled(&harness.logs(instruction.clone(), &[signer]).await, name);
+        harness.send(instruction, &[signer]).await.unwrap();
+    }
+    assert_profiled(&harness.logs(harness.update_reward(), &[]).await, "UpdateReward");
+}
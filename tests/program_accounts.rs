@@ -0,0 +1,89 @@
+// Handlers that make CPIs refusing a lookalike in place of the system or SPL
+// token program, before they change anything.
+mod common;
+
+use common::{Harness, VAULT_BALANCE};
+use pledge::{PledgeError, DEFAULT_LOCK_TIER, LOCK_TIERS};
+use solana_program::{
+    instruction::{Instruction, InstructionError},
+    pubkey::Pubkey,
+    system_program,
+};
+use solana_program_test::tokio;
+use solana_sdk::transaction::TransactionError;
+
+// `instruction` with a fresh pubkey wherever it passed `program`.
+fn with_fake_program(mut instruction: Instruction, program: Pubkey) -> Instruction {
+    let fake = Pubkey::new_unique();
+    let account = instruction.accounts.iter_mut().find(|account| account.pubkey == program).unwrap();
+    account.pubkey = fake;
+    instruction
+}
+
+fn error(error: PledgeError) -> TransactionError {
+    TransactionError::InstructionError(0, InstructionError::Custom(error as u32))
+}
+
+async fn account_data(harness: &mut Harness, address: Pubkey) -> Option<Vec<u8>> {
+    harness.context.banks_client.get_account(address).await.unwrap().map(|account| account.data)
+}
+
+#[tokio::test]
+async fn test_fake_system_program_is_refused() {
+    let mut harness = Harness::start().await;
+    let admin = harness.admin.insecure_clone();
+    let wallet = harness.wallet.insecure_clone();
+    let rejected = Err(error(PledgeError::InvalidProgramAccount));
+    let (config_address, user_state_address) = (harness.config_address(), harness.user_state_address());
+
+    let initialize = with_fake_program(harness.initialize_contract(0), system_program::ID);
+    assert_eq!(harness.try_send(initialize, &[&admin]).await, rejected);
+    assert_eq!(account_data(&mut harness, config_address).await, None);
+    let treasury_address = harness.treasury_address();
+    assert_eq!(account_data(&mut harness, treasury_address).await, None);
+    harness.send_as_admin(harness.initialize_contract(0)).await.unwrap();
+
+    let initialize_user = with_fake_program(harness.initialize_user(), system_program::ID);
+    assert_eq!(harness.try_send(initialize_user, &[&wallet]).await, rejected);
+    assert_eq!(account_data(&mut harness, user_state_address).await, None);
+    harness.send_as_wallet(harness.initialize_user()).await.unwrap();
+
+    // BuyPledge pays the treasury through the system program.
+    let user_state = account_data(&mut harness, user_state_address).await;
+    let treasury_lamports = harness.lamports(harness.treasury_address()).await;
+    let buy = with_fake_program(harness.buy(100_000, DEFAULT_LOCK_TIER), system_program::ID);
+    assert_eq!(harness.try_send(buy, &[&wallet]).await, rejected);
+    assert_eq!(account_data(&mut harness, user_state_address).await, user_state);
+    assert_eq!(harness.lamports(harness.treasury_address()).await, treasury_lamports);
+    assert_eq!(harness.config().await.total_pledge_sold, 0);
+}
+
+#[tokio::test]
+async fn test_fake_token_program_is_refused() {
+    let mut harness = Harness::start().await;
+    let wallet = harness.wallet.insecure_clone();
+    let rejected = Err(error(PledgeError::InvalidProgramAccount));
+    let (config_address, user_state_address) = (harness.config_address(), harness.user_state_address());
+    harness.send_as_admin(harness.initialize_contract(0)).await.unwrap();
+    harness.send_as_wallet(harness.initialize_user()).await.unwrap();
+    harness.send_as_wallet(harness.buy(1_000_000, DEFAULT_LOCK_TIER)).await.unwrap();
+    let (lock, _) = LOCK_TIERS[DEFAULT_LOCK_TIER as usize];
+    harness.advance_clock(lock / 2).await;
+    harness.send(harness.update_reward(), &[]).await.unwrap();
+    let user_state = account_data(&mut harness, user_state_address).await;
+    let config = account_data(&mut harness, config_address).await;
+
+    let claim = with_fake_program(harness.claim(1_000), spl_token::id());
+    assert_eq!(harness.try_send(claim, &[&wallet]).await, rejected);
+    let unlock = with_fake_program(harness.unlock(), spl_token::id());
+    assert_eq!(harness.try_send(unlock, &[&wallet]).await, rejected);
+
+    assert_eq!(account_data(&mut harness, user_state_address).await, user_state);
+    assert_eq!(account_data(&mut harness, config_address).await, config);
+    assert_eq!(harness.token_balance(harness.solhit_token).await, 0);
+    assert_eq!(harness.token_balance(harness.pledge_vault).await, VAULT_BALANCE);
+
+    // The real program still goes through.
+    harness.send_as_wallet(harness.claim(1_000)).await.unwrap();
+    assert_eq!(harness.token_balance(harness.solhit_token).await, 1_000);
+}
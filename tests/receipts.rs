@@ -0,0 +1,91 @@
+// Purchase receipts: the write-once Receipt PDA a BuyPledge records when
+// passed its address.
+mod common;
+
+use common::Harness;
+use pledge::{find_receipt_address, Receipt, DEFAULT_LOCK_TIER, PHASE_RATES};
+use solana_program::{instruction::AccountMeta, pubkey::Pubkey, rent::Rent};
+use solana_program_test::tokio;
+use solana_sdk::{instruction::Instruction, signature::Signer};
+
+fn receipt_address(harness: &Harness, index: u32) -> Pubkey {
+    find_receipt_address(&harness.program_id, &harness.wallet.pubkey(), index).0
+}
+
+// A purchase of `amount` lamports on the default tier recording receipt `index`.
+fn buy_with_receipt(harness: &Harness, amount: u64, index: u32) -> Instruction {
+    let mut buy = harness.buy(amount, DEFAULT_LOCK_TIER);
+    buy.accounts.push(AccountMeta::new(receipt_address(harness, index), false));
+    buy
+}
+
+async fn receipt_data(harness: &mut Harness, index: u32) -> Option<Vec<u8>> {
+    let address = receipt_address(harness, index);
+    harness.context.banks_client.get_account(address).await.unwrap().map(|account| account.data)
+}
+
+async fn setup() -> Harness {
+    let mut harness = Harness::start().await;
+    harness.send_as_admin(harness.initialize_contract(0)).await.unwrap();
+    harness.send_as_wallet(harness.initialize_user()).await.unwrap();
+    harness
+}
+
+#[tokio::test]
+async fn test_purchases_record_receipts() {
+    let mut harness = setup().await;
+    let timestamp = harness.now().await;
+    let wallet_lamports = harness.lamports(harness.wallet.pubkey()).await;
+    harness.send_as_wallet(buy_with_receipt(&harness, 100_000, 0)).await.unwrap();
+    let first_tokens = harness.user_state().await.locked_pledge_tokens;
+    harness.send_as_wallet(buy_with_receipt(&harness, 250_000, 1)).await.unwrap();
+    let user_state = harness.user_state().await;
+    assert_eq!(user_state.purchase_count, 2);
+
+    // Two accounts, each holding its own purchase, paid for by the buyer.
+    assert_ne!(receipt_address(&harness, 0), receipt_address(&harness, 1));
+    let data = [receipt_data(&mut harness, 0).await.unwrap(), receipt_data(&mut harness, 1).await.unwrap()];
+    let receipts = data.each_ref().map(|data| Receipt::unpack(data).unwrap());
+    let expected = [(100_000, first_tokens), (250_000, user_state.locked_pledge_tokens - first_tokens)];
+    for (index, (receipt, (lamports_paid, pledge_tokens))) in receipts.iter().zip(expected).enumerate() {
+        let (_, bump) = find_receipt_address(&harness.program_id, &harness.wallet.pubkey(), index as u32);
+        assert_eq!(
+            *receipt,
+            Receipt {
+                is_initialized: true,
+                wallet: harness.wallet.pubkey(),
+                index: index as u32,
+                bump,
+                lamports_paid,
+                pledge_tokens,
+                rate: PHASE_RATES[4],
+                phase: 4,
+                timestamp,
+            }
+        );
+    }
+    let rent = Rent::default().minimum_balance(Receipt::LEN);
+    assert_eq!(harness.lamports(harness.wallet.pubkey()).await, wallet_lamports - 350_000 - 2 * rent);
+
+    // Rewards, claims and later purchases leave them as they were.
+    harness.advance_clock(86_400).await;
+    harness.send(harness.update_reward(), &[]).await.unwrap();
+    harness.send_as_wallet(harness.claim(0)).await.unwrap();
+    harness.send_as_wallet(harness.buy(50_000, DEFAULT_LOCK_TIER)).await.unwrap();
+    harness.send_as_wallet(buy_with_receipt(&harness, 70_000, 3)).await.unwrap();
+    assert_eq!(receipt_data(&mut harness, 0).await.unwrap(), data[0]);
+    assert_eq!(receipt_data(&mut harness, 1).await.unwrap(), data[1]);
+}
+
+// Receipts are numbered by purchase, so one bought without a receipt leaves
+// a gap rather than shifting the next.
+#[tokio::test]
+async fn test_purchase_without_receipt_leaves_a_gap() {
+    let mut harness = setup().await;
+    harness.send_as_wallet(harness.buy(100_000, DEFAULT_LOCK_TIER)).await.unwrap();
+    harness.send_as_wallet(buy_with_receipt(&harness, 200_000, 1)).await.unwrap();
+
+    assert_eq!(receipt_data(&mut harness, 0).await, None);
+    let receipt = Receipt::unpack(&receipt_data(&mut harness, 1).await.unwrap()).unwrap();
+    assert_eq!((receipt.index, receipt.lamports_paid), (1, 200_000));
+}
@@ -0,0 +1,62 @@
+// A user state corrupted on chain refused by every handler until the admin
+// zeroes it with RepairUserState, after which the wallet starts over.
+mod common;
+
+use common::Harness;
+use pledge::{PledgeError, PledgeInstruction, DEFAULT_LOCK_TIER};
+use solana_program::instruction::{AccountMeta, Instruction, InstructionError};
+use solana_program_test::tokio;
+use solana_sdk::{account::AccountSharedData, signature::{Keypair, Signer}, transaction::TransactionError};
+
+// RepairUserState over the harness wallet's state, signed by `signer`.
+fn repair(harness: &Harness, signer: &Keypair) -> Instruction {
+    Instruction::new_with_bytes(
+        harness.program_id,
+        &PledgeInstruction::RepairUserState { wallet: harness.wallet.pubkey() }.pack(),
+        vec![
+            AccountMeta::new(harness.user_state_address(), false),
+            AccountMeta::new(harness.config_address(), false),
+            AccountMeta::new_readonly(signer.pubkey(), true),
+        ],
+    )
+}
+
+fn error(error: PledgeError) -> TransactionError {
+    TransactionError::InstructionError(0, InstructionError::Custom(error as u32))
+}
+
+#[tokio::test]
+async fn test_repair_corrupted_user_state() {
+    let mut harness = Harness::start().await;
+    let admin = harness.admin.insecure_clone();
+    let wallet = harness.wallet.insecure_clone();
+    harness.send_as_admin(harness.initialize_contract(0)).await.unwrap();
+    harness.send_as_wallet(harness.initialize_user()).await.unwrap();
+    harness.send_as_wallet(harness.buy(100_000, DEFAULT_LOCK_TIER)).await.unwrap();
+    assert_eq!(harness.try_send(repair(&harness, &admin), &[&admin]).await, Err(error(PledgeError::StateNotCorrupted)));
+
+    // locked_pledge_tokens, after the discriminator, version and initialized
+    // flag, no longer adds up to the lots.
+    let address = harness.user_state_address();
+    let mut account = harness.context.banks_client.get_account(address).await.unwrap().unwrap();
+    account.data[10..18].copy_from_slice(&u64::MAX.to_le_bytes());
+    harness.context.set_account(&address, &AccountSharedData::from(account));
+
+    let corrupted = Err(error(PledgeError::StateCorrupted));
+    assert_eq!(harness.try_send(harness.buy(100_001, DEFAULT_LOCK_TIER), &[&wallet]).await, corrupted);
+    assert_eq!(harness.try_send(harness.update_reward(), &[]).await, corrupted);
+    assert_eq!(harness.try_send(harness.claim(0), &[&wallet]).await, corrupted);
+    assert_eq!(harness.try_send(harness.initialize_user(), &[&wallet]).await, Err(error(PledgeError::AlreadyInitialized)));
+
+    let repair_as_wallet = harness.try_send(repair(&harness, &wallet), &[&wallet]).await;
+    assert_eq!(repair_as_wallet, Err(error(PledgeError::Unauthorized)));
+    harness.try_send(repair(&harness, &admin), &[&admin]).await.unwrap();
+    let data = harness.context.banks_client.get_account(address).await.unwrap().unwrap().data;
+    assert!(data.iter().all(|&byte| byte == 0));
+    assert_eq!(harness.config().await.initialized_users, 0);
+
+    harness.send_as_wallet(harness.initialize_user()).await.unwrap();
+    harness.send_as_wallet(harness.buy(100_002, DEFAULT_LOCK_TIER)).await.unwrap();
+    assert!(harness.user_state().await.locked_pledge_tokens > 0);
+    assert_eq!(harness.config().await.initialized_users, 1);
+}
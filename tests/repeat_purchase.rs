@@ -0,0 +1,48 @@
+// Two purchases a year apart on the two-year lock: each unlocks on its own
+// schedule, so the second never holds back the first.
+mod common;
+
+use common::Harness;
+use pledge::{PledgeError, LOCK_TIERS};
+use solana_program::instruction::InstructionError;
+use solana_program_test::tokio;
+use solana_sdk::transaction::TransactionError;
+
+const TWO_YEAR_TIER: u8 = 2;
+
+#[tokio::test]
+async fn test_first_purchase_unlocks_at_its_own_end() {
+    let mut harness = Harness::start().await;
+    let wallet = harness.wallet.insecure_clone();
+    let (lock, _) = LOCK_TIERS[TWO_YEAR_TIER as usize];
+    harness.send_as_admin(harness.initialize_contract(0)).await.unwrap();
+    harness.send_as_wallet(harness.initialize_user()).await.unwrap();
+
+    let t0 = harness.now().await;
+    harness.send_as_wallet(harness.buy(100_000, TWO_YEAR_TIER)).await.unwrap();
+    let first = harness.user_state().await.locked_pledge_tokens;
+    harness.advance_clock(lock / 2).await;
+    harness.send_as_wallet(harness.buy(200_000, TWO_YEAR_TIER)).await.unwrap();
+    let user_state = harness.user_state().await;
+    let second = user_state.locked_pledge_tokens - first;
+    assert_eq!(user_state.vesting_end_time, t0 + lock / 2 * 3);
+
+    // A second short of t0 + 2y nothing has vested yet.
+    harness.advance_clock(lock / 2 - 1).await;
+    let still_vesting = TransactionError::InstructionError(0, InstructionError::Custom(PledgeError::StillVesting as u32));
+    assert_eq!(harness.try_send(harness.unlock(), &[&wallet]).await, Err(still_vesting));
+
+    // At t0 + 2y the first principal comes out, not at t0 + 3y.
+    harness.advance_clock(1).await;
+    assert_eq!(harness.now().await, t0 + lock);
+    harness.try_send(harness.unlock(), &[&wallet]).await.unwrap();
+    assert_eq!(harness.token_balance(harness.pledge_token).await, first);
+    let user_state = harness.user_state().await;
+    assert_eq!(user_state.locked_pledge_tokens, second);
+    assert_eq!(user_state.vesting_end_time, t0 + lock / 2 * 3);
+
+    harness.advance_clock(lock / 2).await;
+    harness.try_send(harness.unlock(), &[&wallet]).await.unwrap();
+    assert_eq!(harness.token_balance(harness.pledge_token).await, first + second);
+    assert_eq!(harness.user_state().await.locked_pledge_tokens, 0);
+}
@@ -0,0 +1,119 @@
+// StartSale opening a sale initialized with SALE_UNSCHEDULED, right away or
+// at a scheduled time.
+mod common;
+
+use common::Harness;
+use pledge::{find_whitelist_address, PledgeError, PledgeInstruction, DEFAULT_LOCK_TIER, SALE_UNSCHEDULED};
+use solana_program::{
+    instruction::{AccountMeta, Instruction, InstructionError},
+    pubkey::Pubkey,
+    system_program,
+};
+use solana_program_test::tokio;
+use solana_sdk::{signature::Signer, transaction::TransactionError};
+
+// An initialized contract whose sale has no start time, with a user ready to
+// buy. The user is whitelisted, since a sale opens on its whitelist-only
+// first phase.
+async fn setup() -> Harness {
+    let mut harness = Harness::start().await;
+    harness.send_as_admin(harness.initialize_contract(SALE_UNSCHEDULED)).await.unwrap();
+    harness.send_as_wallet(harness.initialize_user()).await.unwrap();
+    let add_to_whitelist = Instruction::new_with_bytes(
+        harness.program_id,
+        &PledgeInstruction::AddToWhitelist { wallet: harness.wallet.pubkey() }.pack(),
+        vec![
+            AccountMeta::new_readonly(harness.config_address(), false),
+            AccountMeta::new(harness.admin.pubkey(), true),
+            AccountMeta::new(whitelist_address(&harness), false),
+            AccountMeta::new_readonly(system_program::ID, false),
+        ],
+    );
+    harness.send_as_admin(add_to_whitelist).await.unwrap();
+    harness
+}
+
+fn whitelist_address(harness: &Harness) -> Pubkey {
+    find_whitelist_address(&harness.program_id, &harness.wallet.pubkey()).0
+}
+
+fn start_sale(harness: &Harness, start_time: Option<u64>) -> Instruction {
+    Instruction::new_with_bytes(
+        harness.program_id,
+        &PledgeInstruction::StartSale { start_time }.pack(),
+        vec![AccountMeta::new(harness.config_address(), false), AccountMeta::new_readonly(harness.admin.pubkey(), true)],
+    )
+}
+
+fn error(error: PledgeError) -> TransactionError {
+    TransactionError::InstructionError(0, InstructionError::Custom(error as u32))
+}
+
+// The amounts differ between attempts so a retry is never a duplicate
+// transaction.
+async fn try_buy(harness: &mut Harness, amount: u64) -> Result<(), TransactionError> {
+    let wallet = harness.wallet.insecure_clone();
+    let mut buy = harness.buy(amount, DEFAULT_LOCK_TIER);
+    buy.accounts.push(AccountMeta::new_readonly(whitelist_address(harness), false));
+    harness.try_send(buy, &[&wallet]).await
+}
+
+#[tokio::test]
+async fn test_buy_before_start_sale_fails() {
+    let mut harness = setup().await;
+    assert_eq!(try_buy(&mut harness, 100_000).await, Err(error(PledgeError::SaleNotStarted)));
+
+    harness.send_as_admin(start_sale(&harness, None)).await.unwrap();
+    let now = harness.now().await;
+    let config = harness.config().await;
+    assert_eq!(config.sale_start_time, now);
+    assert_eq!(config.team_lock.unlock_start, now);
+    try_buy(&mut harness, 100_001).await.unwrap();
+    assert!(harness.user_state().await.locked_pledge_tokens > 0);
+}
+
+#[tokio::test]
+async fn test_scheduled_start_blocks_buys_until_it_passes() {
+    let mut harness = setup().await;
+    let start_time = harness.now().await + 3_600;
+    harness.send_as_admin(start_sale(&harness, Some(start_time))).await.unwrap();
+    assert_eq!(harness.config().await.sale_start_time, start_time);
+    assert_eq!(try_buy(&mut harness, 100_000).await, Err(error(PledgeError::SaleNotStarted)));
+
+    let now = harness.now().await;
+    harness.advance_clock(start_time - now - 60).await;
+    assert_eq!(try_buy(&mut harness, 100_001).await, Err(error(PledgeError::SaleNotStarted)));
+
+    harness.advance_clock(60).await;
+    try_buy(&mut harness, 100_002).await.unwrap();
+    assert!(harness.user_state().await.locked_pledge_tokens > 0);
+}
+
+#[tokio::test]
+async fn test_start_sale_twice_fails() {
+    let mut harness = setup().await;
+    let admin = harness.admin.insecure_clone();
+    let start_time = harness.now().await + 3_600;
+    harness.send_as_admin(start_sale(&harness, Some(start_time))).await.unwrap();
+
+    // Neither before the scheduled start nor after it.
+    assert_eq!(harness.try_send(start_sale(&harness, None), &[&admin]).await, Err(error(PledgeError::SaleStarted)));
+    harness.advance_clock(3_600).await;
+    assert_eq!(
+        harness.try_send(start_sale(&harness, Some(start_time + 7_200)), &[&admin]).await,
+        Err(error(PledgeError::SaleStarted))
+    );
+    assert_eq!(harness.config().await.sale_start_time, start_time);
+}
+
+#[tokio::test]
+async fn test_start_sale_in_the_past_fails() {
+    let mut harness = setup().await;
+    let admin = harness.admin.insecure_clone();
+    let now = harness.now().await;
+    assert_eq!(
+        harness.try_send(start_sale(&harness, Some(now - 1)), &[&admin]).await,
+        Err(error(PledgeError::InvalidStartTime))
+    );
+    assert_eq!(harness.config().await.sale_start_time, SALE_UNSCHEDULED);
+}
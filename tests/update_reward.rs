@@ -0,0 +1,53 @@
+// UpdateReward cranked again before anything more accrues: the user state
+// keeps its bytes, and only a NoOp event, if any, is emitted.
+mod common;
+
+use common::Harness;
+use pledge::{PledgeInstruction, DEFAULT_LOCK_TIER};
+use solana_program::instruction::{AccountMeta, Instruction};
+use solana_program_test::tokio;
+use solana_sdk::signature::Signer;
+
+fn set_noop_events(harness: &Harness, enabled: bool) -> Instruction {
+    Instruction::new_with_bytes(
+        harness.program_id,
+        &PledgeInstruction::SetNoOpEvents { enabled }.pack(),
+        vec![AccountMeta::new(harness.config_address(), false), AccountMeta::new_readonly(harness.admin.pubkey(), true)],
+    )
+}
+
+async fn user_state_data(harness: &mut Harness) -> Vec<u8> {
+    let address = harness.user_state_address();
+    harness.context.banks_client.get_account(address).await.unwrap().unwrap().data
+}
+
+#[tokio::test]
+async fn test_update_reward_without_accrual_leaves_state_untouched() {
+    let mut harness = Harness::start().await;
+    harness.send_as_admin(harness.initialize_contract(0)).await.unwrap();
+    harness.send_as_wallet(harness.initialize_user()).await.unwrap();
+    harness.send_as_wallet(harness.buy(1_000_000, DEFAULT_LOCK_TIER)).await.unwrap();
+    harness.advance_clock(86_400).await;
+    harness.send(harness.update_reward(), &[]).await.unwrap();
+    let data = user_state_data(&mut harness).await;
+    let event_seq = harness.config().await.event_seq;
+
+    // Same clock, fresh blockhash: nothing accrues and a NoOp takes the
+    // RewardUpdate's place, still counted by event_seq.
+    harness.advance_clock(0).await;
+    harness.send(harness.update_reward(), &[]).await.unwrap();
+    assert_eq!(user_state_data(&mut harness).await, data);
+    assert_eq!(harness.config().await.event_seq, event_seq + 1);
+
+    // Switched off, the crank emits nothing and counts no event.
+    harness.send_as_admin(set_noop_events(&harness, false)).await.unwrap();
+    assert!(!harness.config().await.noop_events);
+    harness.advance_clock(0).await;
+    harness.send(harness.update_reward(), &[]).await.unwrap();
+    assert_eq!(user_state_data(&mut harness).await, data);
+    assert_eq!(harness.config().await.event_seq, event_seq + 1);
+
+    harness.advance_clock(86_400).await;
+    harness.send(harness.update_reward(), &[]).await.unwrap();
+    assert!(harness.user_state().await.solhit_rewards > pledge::UserState::unpack(&data).unwrap().solhit_rewards);
+}
@@ -0,0 +1,97 @@
+use borsh::BorshDeserialize;
+use pledge::{find_config_address, find_whitelist_address, process_instruction, ContractConfig, PledgeInstruction, Whitelist};
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+    system_program,
+};
+use solana_program_test::{processor, tokio, ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    account::Account,
+    signature::{Keypair, Signer},
+    transaction::Transaction,
+};
+
+// A running test validator whose config names `admin` as the admin.
+async fn setup(admin: &Keypair) -> (ProgramTestContext, Pubkey, Pubkey) {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new("pledge", program_id, processor!(process_instruction));
+
+    program_test.add_account(
+        admin.pubkey(),
+        Account { lamports: 1_000_000_000, data: vec![], owner: system_program::ID, executable: false, rent_epoch: 0 },
+    );
+    let (config, bump) = find_config_address(&program_id);
+    let pledge_contract = ContractConfig { is_initialized: true, bump, admin: admin.pubkey(), ..ContractConfig::new() };
+    let mut config_data = vec![0; ContractConfig::LEN];
+    borsh::to_writer(config_data.as_mut_slice(), &pledge_contract).unwrap();
+    program_test.add_account(
+        config,
+        Account { lamports: 1_000_000_000, data: config_data, owner: program_id, executable: false, rent_epoch: 0 },
+    );
+
+    (program_test.start_with_context().await, program_id, config)
+}
+
+fn whitelist_instruction(program_id: Pubkey, config: Pubkey, signer: Pubkey, instruction: PledgeInstruction, wallet: &Pubkey) -> Instruction {
+    let (whitelist, _) = find_whitelist_address(&program_id, wallet);
+    Instruction::new_with_bytes(
+        program_id,
+        &instruction.pack(),
+        vec![
+            AccountMeta::new_readonly(config, false),
+            AccountMeta::new(signer, true),
+            AccountMeta::new(whitelist, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+        ],
+    )
+}
+
+async fn send(context: &mut ProgramTestContext, instruction: Instruction, signer: &Keypair) -> Result<(), ()> {
+    let mut transaction = Transaction::new_with_payer(&[instruction], Some(&context.payer.pubkey()));
+    transaction.sign(&[&context.payer, signer], context.last_blockhash);
+    context.banks_client.process_transaction(transaction).await.map_err(|_| ())
+}
+
+#[tokio::test]
+async fn test_add_and_remove_whitelist() {
+    let admin = Keypair::new();
+    let (mut context, program_id, config) = setup(&admin).await;
+    let wallet = Pubkey::new_unique();
+    let (whitelist, _) = find_whitelist_address(&program_id, &wallet);
+
+    let add = whitelist_instruction(program_id, config, admin.pubkey(), PledgeInstruction::AddToWhitelist { wallet }, &wallet);
+    send(&mut context, add, &admin).await.unwrap();
+    let account = context.banks_client.get_account(whitelist).await.unwrap().unwrap();
+    assert_eq!(account.owner, program_id);
+    let entry = Whitelist::try_from_slice(&account.data).unwrap();
+    assert!(entry.is_initialized);
+    assert_eq!(entry.wallet, wallet);
+
+    // The admin paid the rent and gets it back on removal.
+    let admin_lamports = context.banks_client.get_balance(admin.pubkey()).await.unwrap();
+    let remove =
+        whitelist_instruction(program_id, config, admin.pubkey(), PledgeInstruction::RemoveFromWhitelist { wallet }, &wallet);
+    send(&mut context, remove, &admin).await.unwrap();
+    assert!(context.banks_client.get_account(whitelist).await.unwrap().is_none());
+    assert_eq!(context.banks_client.get_balance(admin.pubkey()).await.unwrap(), admin_lamports + account.lamports);
+}
+
+#[tokio::test]
+async fn test_whitelist_rejects_non_admin() {
+    let admin = Keypair::new();
+    let (mut context, program_id, config) = setup(&admin).await;
+    let wallet = Keypair::new();
+    let (whitelist, _) = find_whitelist_address(&program_id, &wallet.pubkey());
+
+    let add = whitelist_instruction(
+        program_id,
+        config,
+        context.payer.pubkey(),
+        PledgeInstruction::AddToWhitelist { wallet: wallet.pubkey() },
+        &wallet.pubkey(),
+    );
+    let payer = context.payer.insecure_clone();
+    assert!(send(&mut context, add, &payer).await.is_err());
+    assert!(context.banks_client.get_account(whitelist).await.unwrap().is_none());
+}